@@ -7,6 +7,7 @@ mod benches {
         extern crate serde_json;
         extern crate test;
 
+        use self::eep::ring_buffer::NsSinceEpoch;
         use self::eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
         use self::eep::traits::TraceSink;
 
@@ -24,6 +25,25 @@ mod benches {
             test::black_box(buffer);
         }
 
+        // Isolate the write path from the cost of reading the system clock.
+        fn fixed_clock() -> NsSinceEpoch {
+            NsSinceEpoch(0)
+        }
+
+        #[bench]
+        fn small_capacity_fixed_clock(b: &mut test::Bencher) {
+            let mut buffer = SimpleTraceBuffer::with_clock(100, fixed_clock);
+            b.iter(|| buffer.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(buffer);
+        }
+
+        #[bench]
+        fn large_capacity_fixed_clock(b: &mut test::Bencher) {
+            let mut buffer = SimpleTraceBuffer::with_clock(2 * 1024 * 1024, fixed_clock);
+            b.iter(|| buffer.trace_event(SimpleTrace::FooEvent, None));
+            test::black_box(buffer);
+        }
+
         #[bench]
         fn in_mutex(b: &mut test::Bencher) {
             use std::sync::Mutex;
@@ -36,6 +56,18 @@ mod benches {
             test::black_box(buffer);
         }
 
+        #[bench]
+        fn batched_in_mutex(b: &mut test::Bencher) {
+            use self::eep::sink_combinators::{BatchingSink, WeakSinkHandle};
+            use std::sync::{Arc, Mutex};
+
+            let buffer = Arc::new(Mutex::new(SimpleTraceBuffer::new(2 * 1024 * 1024)));
+            let mut sink = BatchingSink::new(WeakSinkHandle::new(&buffer), 64);
+            b.iter(|| sink.trace_event(SimpleTrace::FooEvent, None));
+            drop(sink);
+            test::black_box(buffer);
+        }
+
         #[bench]
         fn serialize_entry(b: &mut test::Bencher) {
             let mut buffer = SimpleTraceBuffer::default();
@@ -0,0 +1,270 @@
+//! `#[derive(Trace)]` for C-like enums.
+//!
+//! Use this through `eep`'s `derive` feature rather than depending on this
+//! crate directly.
+//!
+//! The variant's discriminant is used as its tag, and the variant's name is
+//! used as its label unless overridden with `#[trace(label = "...")]`.
+//! Variants may also give exporters a `#[trace(category = "...")]` and a
+//! `#[trace(color = "...")]` hint. The `Trace::Id` type defaults to
+//! `eep::ThreadedTraceId`, and may be overridden with `#[trace(id = "...")]` on
+//! the enum itself.
+//!
+//! ```ignore
+//! #[macro_use]
+//! extern crate eep;
+//!
+//! #[derive(Clone, Copy, Trace)]
+//! #[trace(id = "eep::simple_trace::SimpleTraceId")]
+//! enum WebBrowserEngineTrace {
+//!     Compositing,
+//!     Painting,
+//!     #[trace(label = "GC", category = "memory", color = "bad")]
+//!     GarbageCollection,
+//! }
+//! ```
+
+#![deny(missing_docs)]
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+use std::iter::Peekable;
+
+/// Derive `eep::traits::Trace` for a C-like enum.
+#[proc_macro_derive(Trace, attributes(trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let code = match derive(input) {
+        Ok(code) => code,
+        Err(message) => format!("compile_error!({:?});", message),
+    };
+    code.parse().expect("generated code should always parse")
+}
+
+struct Variant {
+    name: String,
+    label: String,
+    category: Option<String>,
+    color: Option<String>,
+}
+
+fn derive(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter().peekable();
+
+    let mut id = "::eep::ThreadedTraceId".to_string();
+    for (key, value) in try!(parse_attributes(&mut tokens)) {
+        match &key[..] {
+            "id" => id = unquote(&value),
+            _ => return Err(format!("unknown `#[trace({} = ...)]` on an enum", key)),
+        }
+    }
+
+    skip_visibility(&mut tokens);
+    match tokens.next() {
+        Some(TokenTree::Ident(ref ident)) if ident.to_string() == "enum" => {}
+        _ => return Err("`#[derive(Trace)]` only supports enums".to_string()),
+    }
+
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return Err("expected the enum's name".to_string()),
+    };
+
+    let body = match tokens.next() {
+        Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Brace => {
+            group.stream()
+        }
+        _ => return Err("`#[derive(Trace)]` does not support generic enums".to_string()),
+    };
+
+    let variants = try!(parse_variants(body));
+
+    let mut labels = String::new();
+    let mut categories = String::new();
+    let mut colors = String::new();
+    for variant in &variants {
+        labels.push_str(&format!("if tag == {}::{} as u32 {{ return {}; }}\n",
+                                 name,
+                                 variant.name,
+                                 variant.label));
+        if let Some(ref category) = variant.category {
+            categories.push_str(&format!("if tag == {}::{} as u32 {{ return Some({}); }}\n",
+                                         name,
+                                         variant.name,
+                                         category));
+        }
+        if let Some(ref color) = variant.color {
+            colors.push_str(&format!("if tag == {}::{} as u32 {{ return Some({}); }}\n",
+                                     name,
+                                     variant.name,
+                                     color));
+        }
+    }
+
+    Ok(format!("
+        impl ::eep::traits::Trace for {name} {{
+            type Id = {id};
+
+            fn label(tag: u32) -> &'static str {{
+                {labels}
+                unreachable!()
+            }}
+
+            fn tag(&self) -> u32 {{
+                *self as u32
+            }}
+
+            fn category(tag: u32) -> Option<&'static str> {{
+                {categories}
+                let _ = tag;
+                None
+            }}
+
+            fn color(tag: u32) -> Option<&'static str> {{
+                {colors}
+                let _ = tag;
+                None
+            }}
+        }}",
+               name = name,
+               id = id,
+               labels = labels,
+               categories = categories,
+               colors = colors))
+}
+
+fn parse_variants(body: TokenStream) -> Result<Vec<Variant>, String> {
+    let mut variants = vec![];
+    let mut tokens = body.into_iter().peekable();
+
+    while tokens.peek().is_some() {
+        let mut label = None;
+        let mut category = None;
+        let mut color = None;
+        for (key, value) in try!(parse_attributes(&mut tokens)) {
+            match &key[..] {
+                "label" => label = Some(value),
+                "category" => category = Some(value),
+                "color" => color = Some(value),
+                _ => return Err(format!("unknown `#[trace({} = ...)]` on a variant", key)),
+            }
+        }
+
+        let name = match tokens.next() {
+            Some(TokenTree::Ident(ident)) => ident.to_string(),
+            _ => return Err("expected a variant name".to_string()),
+        };
+
+        // Skip over any explicit discriminant; we always get the tag by casting
+        // the variant to `u32`. A group before the `=` holds fields, but one
+        // after it is part of the discriminant, such as `A = (1)`.
+        let mut discriminant = false;
+        loop {
+            match tokens.next() {
+                None => break,
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == ',' => break,
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == '=' => {
+                    discriminant = true
+                }
+                Some(TokenTree::Group(_)) if !discriminant => {
+                    return Err(format!("`#[derive(Trace)]` does not support variants with \
+                                        fields, found `{}`",
+                                       name))
+                }
+                Some(_) => {}
+            }
+        }
+
+        variants.push(Variant {
+            label: label.unwrap_or_else(|| format!("{:?}", name)),
+            name: name,
+            category: category,
+            color: color,
+        });
+    }
+
+    Ok(variants)
+}
+
+/// Parse any outer attributes, returning the `key = "value"` pairs found in
+/// `#[trace(...)]` attributes. Values are returned as string literal source
+/// text, including the quotes.
+fn parse_attributes<I>(tokens: &mut Peekable<I>) -> Result<Vec<(String, String)>, String>
+    where I: Iterator<Item = TokenTree>
+{
+    let mut pairs = vec![];
+
+    loop {
+        match tokens.peek() {
+            Some(&TokenTree::Punct(ref punct)) if punct.as_char() == '#' => {}
+            _ => return Ok(pairs),
+        }
+        tokens.next();
+
+        let attribute = match tokens.next() {
+            Some(TokenTree::Group(group)) => group.stream(),
+            _ => return Err("expected an attribute".to_string()),
+        };
+
+        let mut attribute = attribute.into_iter();
+        match attribute.next() {
+            Some(TokenTree::Ident(ref ident)) if ident.to_string() == "trace" => {}
+            _ => continue,
+        }
+
+        let args = match attribute.next() {
+            Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Parenthesis => {
+                group.stream()
+            }
+            _ => return Err("expected `#[trace(key = \"value\")]`".to_string()),
+        };
+
+        let mut args = args.into_iter();
+        loop {
+            let key = match args.next() {
+                None => break,
+                Some(TokenTree::Ident(ident)) => ident.to_string(),
+                _ => return Err("expected `#[trace(key = \"value\")]`".to_string()),
+            };
+            match args.next() {
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == '=' => {}
+                _ => return Err("expected `#[trace(key = \"value\")]`".to_string()),
+            }
+            let value = match args.next() {
+                Some(TokenTree::Literal(literal)) => literal.to_string(),
+                _ => return Err("expected `#[trace(key = \"value\")]`".to_string()),
+            };
+            if !value.starts_with('"') {
+                return Err(format!("expected a string literal for `{}`", key));
+            }
+            pairs.push((key, value));
+
+            match args.next() {
+                None => break,
+                Some(TokenTree::Punct(ref punct)) if punct.as_char() == ',' => {}
+                _ => return Err("expected `,` between `#[trace(...)]` arguments".to_string()),
+            }
+        }
+    }
+}
+
+fn skip_visibility<I>(tokens: &mut Peekable<I>)
+    where I: Iterator<Item = TokenTree>
+{
+    match tokens.peek() {
+        Some(&TokenTree::Ident(ref ident)) if ident.to_string() == "pub" => {}
+        _ => return,
+    }
+    tokens.next();
+
+    // `pub(crate)` and friends.
+    if let Some(&TokenTree::Group(ref group)) = tokens.peek() {
+        if group.delimiter() == Delimiter::Parenthesis {
+            tokens.next();
+        }
+    }
+}
+
+fn unquote(literal: &str) -> String {
+    literal.trim_matches('"').to_string()
+}
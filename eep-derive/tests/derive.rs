@@ -0,0 +1,60 @@
+extern crate eep;
+
+use eep::Trace;
+use eep::simple_trace::SimpleTraceId;
+use eep::traits::Trace;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Trace)]
+#[trace(id = "SimpleTraceId")]
+enum Phase {
+    Parse,
+    #[trace(label = "Type checking", category = "frontend", color = "good")]
+    TypeCheck,
+    Codegen = 7,
+    Link,
+    Optimize = (10),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Trace)]
+pub enum Threaded {
+    Only,
+}
+
+#[test]
+fn tags_are_discriminants() {
+    assert_eq!(Phase::Parse.tag(), 0);
+    assert_eq!(Phase::TypeCheck.tag(), 1);
+    assert_eq!(Phase::Codegen.tag(), 7);
+    assert_eq!(Phase::Link.tag(), 8);
+    assert_eq!(Phase::Optimize.tag(), 10);
+    assert_eq!(Threaded::Only.tag(), 0);
+}
+
+#[test]
+fn labels() {
+    assert_eq!(Phase::label(Phase::Parse.tag()), "Parse");
+    assert_eq!(Phase::label(Phase::TypeCheck.tag()), "Type checking");
+    assert_eq!(Phase::label(Phase::Codegen.tag()), "Codegen");
+    assert_eq!(Phase::label(Phase::Link.tag()), "Link");
+    assert_eq!(Phase::label(Phase::Optimize.tag()), "Optimize");
+    assert_eq!(Threaded::label(0), "Only");
+}
+
+#[test]
+fn hints() {
+    assert_eq!(Phase::category(Phase::TypeCheck.tag()), Some("frontend"));
+    assert_eq!(Phase::color(Phase::TypeCheck.tag()), Some("good"));
+    assert_eq!(Phase::category(Phase::Parse.tag()), None);
+    assert_eq!(Phase::color(Phase::Link.tag()), None);
+    assert_eq!(Threaded::category(0), None);
+}
+
+#[test]
+fn ids() {
+    fn id_of<T: Trace>(_: T) -> T::Id {
+        <T::Id as eep::traits::TraceId>::new_id()
+    }
+
+    let _: SimpleTraceId = id_of(Phase::Parse);
+    let _: eep::ThreadedTraceId = id_of(Threaded::Only);
+}
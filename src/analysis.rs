@@ -0,0 +1,1368 @@
+//! Analyses over captured trace entries.
+
+extern crate serde;
+
+use ring_buffer::{Entry, TraceKind};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map;
+use std::io;
+use thread_local_trace::Source;
+use traits::{ThreadId, Trace};
+
+/// A completed span, reconstructed from a matching pair of start and stop
+/// entries.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Interval {
+    /// The tag of the traced operation.
+    pub tag: u32,
+
+    /// The label of the traced operation.
+    pub label: &'static str,
+
+    /// The thread that traced this span, if available.
+    pub thread: Option<ThreadId>,
+
+    /// The ID shared by this span's start and stop entries.
+    pub id: u32,
+
+    /// When this span started, in nanoseconds since the epoch.
+    pub start_ns: u64,
+
+    /// How long this span lasted, in nanoseconds.
+    pub duration_ns: u64,
+
+    /// The ID of the innermost span on the same thread that was still open
+    /// when this span started, if any.
+    pub parent: Option<u32>,
+
+    /// Whether a `TraceKind::ClockJump` was recorded while this span was
+    /// open, in which case `duration_ns` is unreliable.
+    pub clock_jumped: bool,
+
+    /// Whether this span was ended by a `TraceKind::Cancel` rather than a
+    /// `TraceKind::Stop`.
+    pub cancelled: bool,
+}
+
+impl serde::Serialize for Interval {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("Interval", 9));
+        try!(serializer.serialize_struct_elt(&mut state, "tag", self.tag));
+        try!(serializer.serialize_struct_elt(&mut state, "label", self.label));
+        try!(serializer.serialize_struct_elt(&mut state, "thread", self.thread));
+        try!(serializer.serialize_struct_elt(&mut state, "id", self.id));
+        try!(serializer.serialize_struct_elt(&mut state, "start_ns", self.start_ns));
+        try!(serializer.serialize_struct_elt(&mut state, "duration_ns", self.duration_ns));
+        try!(serializer.serialize_struct_elt(&mut state, "parent", self.parent));
+        try!(serializer.serialize_struct_elt(&mut state, "clock_jumped", self.clock_jumped));
+        try!(serializer.serialize_struct_elt(&mut state, "cancelled", self.cancelled));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+/// An iterator that pairs up start and stop (or cancel) entries into
+/// `Interval`s.
+///
+/// Starts and stops are matched by their thread and ID, and each thread's
+/// open spans are tracked as a stack to determine their nesting. Intervals are
+/// yielded in the order that their stop entries appear. Stops whose start is
+/// missing, for example because it was overwritten in a `RingBuffer`, and
+/// starts that were never stopped are collected and can be inspected with
+/// `unmatched_stops` and `unmatched_starts` once iteration is complete.
+///
+/// ```
+/// use eep::analysis::Intervals;
+/// use eep::simple_trace::SimpleTrace;
+/// use eep::testing::TraceBuilder;
+///
+/// let buffer = TraceBuilder::new()
+///     .span(SimpleTrace::OperationThing, 0..10, |b| {
+///         b.leaf_span(SimpleTrace::OperationAnother, 2..5)
+///     })
+///     .build();
+///
+/// for interval in Intervals::new(buffer.iter()) {
+///     println!("{} took {}ns", interval.label, interval.duration_ns);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Intervals<I, T>
+    where T: Trace
+{
+    entries: I,
+
+    // Each thread's open spans, along with the number of clock jumps seen
+    // before each started.
+    open: HashMap<Option<ThreadId>, Vec<(Entry<T>, usize)>>,
+
+    unmatched_stops: Vec<Entry<T>>,
+    clock_jumps: usize,
+}
+
+impl<I, T> Intervals<I, T>
+    where I: Iterator<Item = Entry<T>>,
+          T: Trace
+{
+    /// Construct a new `Intervals` iterator over the given entries, which
+    /// should be in the order they were traced.
+    pub fn new<J>(entries: J) -> Intervals<I, T>
+        where J: IntoIterator<IntoIter = I, Item = Entry<T>>
+    {
+        Intervals {
+            entries: entries.into_iter(),
+            open: HashMap::new(),
+            unmatched_stops: vec![],
+            clock_jumps: 0,
+        }
+    }
+
+    /// Get the start entries that have not been matched with a stop entry (so
+    /// far), in the order they were traced on each thread.
+    pub fn unmatched_starts(&self) -> Vec<Entry<T>> {
+        let mut starts: Vec<_> = self.open
+            .values()
+            .flat_map(|stack| stack.iter().map(|&(start, _)| start))
+            .collect();
+        starts.sort_by_key(|entry| entry.timestamp().0);
+        starts
+    }
+
+    /// Get the stop entries that were not preceded by a matching start entry.
+    pub fn unmatched_stops(&self) -> &[Entry<T>] {
+        &self.unmatched_stops
+    }
+}
+
+impl<I, T> Iterator for Intervals<I, T>
+    where I: Iterator<Item = Entry<T>>,
+          T: Trace
+{
+    type Item = Interval;
+
+    fn next(&mut self) -> Option<Interval> {
+        while let Some(entry) = self.entries.next() {
+            match entry.kind() {
+                TraceKind::Event | TraceKind::Park | TraceKind::Unpark | TraceKind::Counter => {}
+                TraceKind::ClockJump => self.clock_jumps += 1,
+                TraceKind::Start => {
+                    self.open
+                        .entry(entry.thread())
+                        .or_insert_with(Vec::new)
+                        .push((entry, self.clock_jumps));
+                }
+                TraceKind::Complete => {
+                    let parent = self.open
+                        .get(&entry.thread())
+                        .and_then(|stack| stack.last())
+                        .map(|&(start, _)| start.id());
+
+                    return Some(Interval {
+                        tag: entry.tag(),
+                        label: entry.label(),
+                        thread: entry.thread(),
+                        id: entry.id(),
+                        start_ns: entry.start().0,
+                        duration_ns: entry.duration().unwrap(),
+                        parent: parent,
+                        clock_jumped: false,
+                        cancelled: false,
+                    });
+                }
+                TraceKind::Stop | TraceKind::Cancel => {
+                    let stack = self.open.entry(entry.thread()).or_insert_with(Vec::new);
+
+                    // Spans are usually stopped in LIFO order, but don't
+                    // require it.
+                    let position = stack.iter().rposition(|&(start, _)| start.id() == entry.id());
+                    let position = match position {
+                        Some(position) => position,
+                        None => {
+                            self.unmatched_stops.push(entry);
+                            continue;
+                        }
+                    };
+
+                    let (start, clock_jumps) = stack.remove(position);
+                    let parent = if position == 0 {
+                        None
+                    } else {
+                        Some(stack[position - 1].0.id())
+                    };
+
+                    return Some(Interval {
+                        tag: start.tag(),
+                        label: start.label(),
+                        thread: start.thread(),
+                        id: start.id(),
+                        start_ns: start.timestamp().0,
+                        duration_ns: entry.timestamp().0.saturating_sub(start.timestamp().0),
+                        parent: parent,
+                        clock_jumped: clock_jumps != self.clock_jumps,
+                        cancelled: entry.kind() == TraceKind::Cancel,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Summary statistics of the durations of a set of spans, in nanoseconds.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DurationStats {
+    /// The shortest duration.
+    pub min: u64,
+
+    /// The longest duration.
+    pub max: u64,
+
+    /// The mean duration, rounded down.
+    pub mean: u64,
+
+    /// The median duration.
+    pub p50: u64,
+
+    /// The 95th percentile duration.
+    pub p95: u64,
+
+    /// The 99th percentile duration.
+    pub p99: u64,
+}
+
+impl DurationStats {
+    /// Summarize the given durations, or return `None` if there are none.
+    pub fn new(durations: &mut [u64]) -> Option<DurationStats> {
+        if durations.is_empty() {
+            return None;
+        }
+
+        durations.sort();
+        let sum = durations.iter().fold(0u128, |sum, &d| sum + d as u128);
+        Some(DurationStats {
+            min: durations[0],
+            max: durations[durations.len() - 1],
+            mean: (sum / durations.len() as u128) as u64,
+            p50: percentile(durations, 50),
+            p95: percentile(durations, 95),
+            p99: percentile(durations, 99),
+        })
+    }
+}
+
+/// Statistics for all the entries with a single tag.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TagStats {
+    /// The label of the tag.
+    pub label: &'static str,
+
+    /// The number of one-off events.
+    pub events: usize,
+
+    /// The number of completed spans, whether from start/stop pairs or
+    /// `TraceKind::Complete` entries.
+    pub spans: usize,
+
+    /// The number of cancelled spans, which are not included in `spans` or
+    /// `durations`.
+    pub cancelled: usize,
+
+    /// Statistics of the durations of completed spans, or `None` if there were
+    /// none.
+    pub durations: Option<DurationStats>,
+}
+
+/// Per-tag counts and span duration statistics over a set of trace entries.
+///
+/// ```
+/// use eep::analysis::Stats;
+/// use eep::simple_trace::SimpleTrace;
+/// use eep::testing::TraceBuilder;
+/// use eep::traits::Trace;
+///
+/// let buffer = TraceBuilder::new()
+///     .leaf_span(SimpleTrace::OperationThing, 0..10)
+///     .leaf_span(SimpleTrace::OperationThing, 10..30)
+///     .build();
+///
+/// let stats = Stats::new(buffer.iter());
+/// let thing = stats.get(SimpleTrace::OperationThing.tag()).unwrap();
+/// assert_eq!(thing.spans, 2);
+/// assert!(thing.durations.unwrap().p99 <= 20);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    tags: BTreeMap<u32, TagStats>,
+}
+
+impl Stats {
+    /// Compute statistics over the given entries, which should be in the
+    /// order they were traced.
+    pub fn new<I, T>(entries: I) -> Stats
+        where I: IntoIterator<Item = Entry<T>>,
+              T: Trace
+    {
+        let mut tags = BTreeMap::new();
+        let mut events: HashMap<u32, usize> = HashMap::new();
+        let mut durations: HashMap<u32, Vec<u64>> = HashMap::new();
+
+        let entries = entries.into_iter().inspect(|entry| {
+            if entry.kind() == TraceKind::Event {
+                *events.entry(entry.tag()).or_insert(0) += 1;
+            }
+        });
+
+        for interval in Intervals::new(entries) {
+            let stats = tag_stats::<T>(&mut tags, interval.tag);
+            if interval.cancelled {
+                stats.cancelled += 1;
+            } else {
+                stats.spans += 1;
+                durations.entry(interval.tag)
+                    .or_insert_with(Vec::new)
+                    .push(interval.duration_ns);
+            }
+        }
+
+        for (tag, events) in events {
+            tag_stats::<T>(&mut tags, tag).events = events;
+        }
+
+        for (tag, mut durations) in durations {
+            tags.get_mut(&tag).unwrap().durations = DurationStats::new(&mut durations);
+        }
+
+        Stats { tags: tags }
+    }
+
+    /// Get the statistics for the given tag, or `None` if it never appeared.
+    pub fn get(&self, tag: u32) -> Option<&TagStats> {
+        self.tags.get(&tag)
+    }
+
+    /// Iterate over each tag that appeared and its statistics, in tag order.
+    pub fn iter(&self) -> btree_map::Iter<'_, u32, TagStats> {
+        self.tags.iter()
+    }
+}
+
+/// Split entries merged from several sources, as by
+/// `thread_local_trace::merge_with_sources`, into each source's entries, in
+/// the order that each source first appears.
+///
+/// Each source's entries stay in the order they were given, so any analysis,
+/// report, or exporter can then be run on each source separately.
+///
+/// ```
+/// use eep::analysis::{self, Stats};
+/// use eep::simple_trace::SimpleTrace;
+/// use eep::thread_local_trace::{self, ThreadLocalSink};
+/// use eep::traits::TraceSink;
+///
+/// ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+///
+/// let merged = thread_local_trace::merge_with_sources::<SimpleTrace>();
+/// for (source, entries) in analysis::group_by_source(merged) {
+///     let stats = Stats::new(entries);
+///     println!("{} on thread {:?}: {:?}", source.sink, source.thread, stats);
+/// }
+/// ```
+pub fn group_by_source<I, T>(entries: I) -> Vec<(Source, Vec<Entry<T>>)>
+    where I: IntoIterator<Item = (Source, Entry<T>)>
+{
+    let mut indices = HashMap::new();
+    let mut groups: Vec<(Source, Vec<Entry<T>>)> = vec![];
+    for (source, entry) in entries {
+        let index = *indices.entry(source).or_insert_with(|| {
+            groups.push((source, vec![]));
+            groups.len() - 1
+        });
+        groups[index].1.push(entry);
+    }
+    groups
+}
+
+/// How busy a single thread, such as a thread pool worker, was during a
+/// capture.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ThreadUtilization {
+    /// The thread, if entries record it.
+    pub thread: Option<ThreadId>,
+
+    /// The time from the thread's first entry to its last, in nanoseconds.
+    pub total_ns: u64,
+
+    /// The time spent parked, between `TraceKind::Park` and
+    /// `TraceKind::Unpark` entries, in nanoseconds.
+    pub parked_ns: u64,
+
+    /// The time spent inside outermost spans, minus any time parked within
+    /// them, in nanoseconds.
+    pub busy_ns: u64,
+}
+
+impl ThreadUtilization {
+    /// Get the fraction of the time this thread was not parked that it spent
+    /// busy, or `None` if it was always parked.
+    pub fn utilization(&self) -> Option<f64> {
+        let unparked = self.total_ns.saturating_sub(self.parked_ns);
+        if unparked == 0 {
+            None
+        } else {
+            Some(self.busy_ns as f64 / unparked as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct ThreadTimes {
+    // The earliest and latest timestamps, which needn't be the first and last
+    // entries' if the clock went backwards.
+    earliest: u64,
+    latest: u64,
+    parked_since: Option<u64>,
+    parked: Vec<(u64, u64)>,
+    busy: Vec<(u64, u64)>,
+}
+
+/// Compute the utilization of every thread in the given entries, which should
+/// be in the order they were traced, so that idle workers don't skew pool
+/// efficiency numbers.
+///
+/// An unpark whose park is missing counts as parked since the thread's
+/// earliest entry, and a park without an unpark counts as parked until its
+/// latest. A parked interval that the clock went backwards across counts as
+/// empty.
+pub fn utilization<I, T>(entries: I) -> Vec<ThreadUtilization>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace
+{
+    let entries: Vec<_> = entries.into_iter().collect();
+    let mut threads: HashMap<Option<ThreadId>, ThreadTimes> = HashMap::new();
+
+    for entry in &entries {
+        let now = entry.timestamp().0;
+        let times = threads.entry(entry.thread()).or_insert_with(|| {
+            ThreadTimes {
+                earliest: now,
+                latest: now,
+                ..Default::default()
+            }
+        });
+        times.earliest = times.earliest.min(now);
+        times.latest = times.latest.max(now);
+
+        match entry.kind() {
+            TraceKind::Park if times.parked_since.is_none() => times.parked_since = Some(now),
+            TraceKind::Unpark => {
+                let since = times.parked_since.take().unwrap_or(times.earliest);
+                times.parked.push((since, now));
+            }
+            _ => {}
+        }
+    }
+
+    for interval in Intervals::new(entries).filter(|i| i.parent.is_none()) {
+        if let Some(times) = threads.get_mut(&interval.thread) {
+            times.busy.push((interval.start_ns, interval.start_ns + interval.duration_ns));
+        }
+    }
+
+    threads.into_iter()
+        .map(|(thread, mut times)| {
+            if let Some(since) = times.parked_since.take() {
+                times.parked.push((since, times.latest));
+            }
+
+            let parked_ns = times.parked
+                .iter()
+                .map(|&(start, end)| end.saturating_sub(start))
+                .sum();
+            let busy_ns = times.busy
+                .iter()
+                .map(|&(start, end)| {
+                    let parked_within: u64 = times.parked
+                        .iter()
+                        .map(|&(p_start, p_end)| {
+                            p_end.min(end).saturating_sub(p_start.max(start))
+                        })
+                        .sum();
+                    (end - start).saturating_sub(parked_within)
+                })
+                .sum();
+
+            ThreadUtilization {
+                thread: thread,
+                total_ns: times.latest - times.earliest,
+                parked_ns: parked_ns,
+                busy_ns: busy_ns,
+            }
+        })
+        .collect()
+}
+
+/// A breakdown of what a capture spends its bytes on, to guide the choice of
+/// a compact encoding or sampling policy.
+///
+/// Varint sizes are those of unsigned LEB128.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodingReport {
+    /// The number of entries.
+    pub entries: usize,
+
+    /// The number of bytes the entries take in a `RingBuffer`.
+    pub encoded_bytes: usize,
+
+    /// The number of entries with each tag.
+    pub tags: BTreeMap<u32, usize>,
+
+    /// The Shannon entropy of the tag distribution, in bits per entry. This is
+    /// a lower bound on the bits needed to encode each entry's tag.
+    pub tag_entropy_bits: f64,
+
+    /// `timestamp_deltas[n]` is the number of entries whose timestamp is
+    /// greater than the previous entry's by a delta needing exactly `n`
+    /// significant bits. The first entry and clock regressions are counted as
+    /// needing all 64.
+    pub timestamp_deltas: Vec<usize>,
+
+    /// The number of bytes the entries would take with their kind as a single
+    /// byte, and their tag, ID, and delta-encoded timestamp as varints.
+    pub compact_bytes: usize,
+}
+
+impl EncodingReport {
+    /// Compute a report over the given entries, which should be in the order
+    /// they were traced.
+    pub fn new<I, T>(entries: I) -> EncodingReport
+        where I: IntoIterator<Item = Entry<T>>,
+              T: Trace
+    {
+        let mut report = EncodingReport {
+            entries: 0,
+            encoded_bytes: 0,
+            tags: BTreeMap::new(),
+            tag_entropy_bits: 0.0,
+            timestamp_deltas: vec![0; 65],
+            compact_bytes: 0,
+        };
+
+        let mut last = None;
+        for entry in entries {
+            report.entries += 1;
+            *report.tags.entry(entry.tag()).or_insert(0) += 1;
+
+            let now = entry.timestamp().0;
+            let delta = match last {
+                Some(last) if last <= now => now - last,
+                _ => u64::MAX,
+            };
+            last = Some(now);
+
+            let bits = 64 - delta.leading_zeros() as usize;
+            report.timestamp_deltas[bits] += 1;
+            report.compact_bytes += 1 + varint_len(entry.tag() as u64) +
+                                    varint_len(entry.id() as u64) +
+                                    varint_len(delta);
+        }
+
+        report.encoded_bytes = report.entries * Entry::<T>::size();
+        report.tag_entropy_bits = report.tags
+            .values()
+            .map(|&count| {
+                let p = count as f64 / report.entries as f64;
+                -p * p.log2()
+            })
+            .sum();
+
+        report
+    }
+
+    /// Write this report in a human-readable form.
+    pub fn write_report<W>(&self, writer: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        try!(writeln!(writer,
+                      "{} entries in {} bytes ({} bytes compact)",
+                      self.entries,
+                      self.encoded_bytes,
+                      self.compact_bytes));
+
+        try!(writeln!(writer, "tags ({:.2} bits of entropy per entry):", self.tag_entropy_bits));
+        for (tag, &count) in &self.tags {
+            try!(writeln!(writer,
+                          "  {}: {} ({:.1}%)",
+                          tag,
+                          count,
+                          100.0 * count as f64 / self.entries as f64));
+        }
+
+        try!(writeln!(writer, "timestamp deltas by significant bits:"));
+        for (bits, &count) in self.timestamp_deltas.iter().enumerate().filter(|&(_, &c)| c > 0) {
+            try!(writeln!(writer, "  {}: {}", bits, count));
+        }
+
+        Ok(())
+    }
+}
+
+/// A latency objective for the spans with one label, such as "Render < 16ms
+/// p99": that percentile of their durations must be below the maximum.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Slo {
+    /// The label of the spans this objective applies to.
+    pub label: String,
+
+    /// The exclusive maximum duration, in nanoseconds.
+    pub max_ns: u64,
+
+    /// The percentile of durations that must be below `max_ns`, from 0 to 100.
+    pub percentile: usize,
+}
+
+impl Slo {
+    /// Parse an objective of the form
+    /// `"<label> < <duration><unit> p<percentile>"`, where the unit is one of
+    /// `ns`, `us`, `ms`, or `s`, or return `None` if it is malformed.
+    ///
+    /// ```
+    /// use eep::analysis::Slo;
+    ///
+    /// let slo = Slo::parse("Render < 16ms p99").unwrap();
+    /// assert_eq!(slo.label, "Render");
+    /// assert_eq!(slo.max_ns, 16_000_000);
+    /// assert_eq!(slo.percentile, 99);
+    /// ```
+    pub fn parse(slo: &str) -> Option<Slo> {
+        let mut parts = slo.rsplitn(2, '<');
+        let (max, label) = match (parts.next(), parts.next()) {
+            (Some(max), Some(label)) => (max, label.trim()),
+            _ => return None,
+        };
+
+        let mut max = max.split_whitespace();
+        let (duration, percentile) = match (max.next(), max.next(), max.next()) {
+            (Some(duration), Some(percentile), None) => (duration, percentile),
+            _ => return None,
+        };
+
+        let unit_start = duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len());
+        let scale = match &duration[unit_start..] {
+            "ns" => 1,
+            "us" => 1_000,
+            "ms" => 1_000_000,
+            "s" => 1_000_000_000,
+            _ => return None,
+        };
+        let max_ns = match duration[..unit_start].parse::<u64>() {
+            Ok(max) => max.checked_mul(scale),
+            Err(_) => None,
+        };
+
+        let percentile = percentile.strip_prefix('p').and_then(|p| p.parse().ok());
+
+        match (max_ns, percentile) {
+            (Some(max_ns), Some(percentile)) if !label.is_empty() && percentile <= 100 => {
+                Some(Slo {
+                    label: label.to_string(),
+                    max_ns: max_ns,
+                    percentile: percentile,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How a capture fared against a single `Slo`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloResult {
+    /// The objective.
+    pub slo: Slo,
+
+    /// The number of completed spans with the objective's label.
+    pub spans: usize,
+
+    /// The duration at the objective's percentile, or `None` if there were no
+    /// spans.
+    pub observed_ns: Option<u64>,
+
+    /// The spans that took at least the objective's maximum, in the order they
+    /// were stopped.
+    pub offending: Vec<Interval>,
+}
+
+impl SloResult {
+    /// Whether the objective was met. Objectives with no spans are met.
+    pub fn passed(&self) -> bool {
+        self.observed_ns.map_or(true, |observed| observed < self.slo.max_ns)
+    }
+}
+
+/// An evaluation of a capture against a set of latency objectives, for
+/// example to gate a CI performance job.
+///
+/// ```
+/// use eep::analysis::{Slo, SloReport};
+/// use eep::simple_trace::SimpleTrace;
+/// use eep::testing::TraceBuilder;
+///
+/// let buffer = TraceBuilder::new()
+///     .leaf_span(SimpleTrace::OperationThing, 0..10)
+///     .leaf_span(SimpleTrace::OperationThing, 10..40)
+///     .build();
+///
+/// let slos = vec![Slo::parse("Thing < 20ns p50").unwrap(),
+///                 Slo::parse("Thing < 20ns p99").unwrap()];
+/// let report = SloReport::new(buffer.iter(), slos);
+/// assert!(report.results[0].passed());
+/// assert!(!report.results[1].passed());
+/// assert!(!report.passed());
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SloReport {
+    /// The result for each objective, in the order they were given.
+    pub results: Vec<SloResult>,
+}
+
+impl SloReport {
+    /// Evaluate the given entries, which should be in the order they were
+    /// traced, against the given objectives. Cancelled spans are ignored.
+    pub fn new<I, T, S>(entries: I, slos: S) -> SloReport
+        where I: IntoIterator<Item = Entry<T>>,
+              T: Trace,
+              S: IntoIterator<Item = Slo>
+    {
+        let mut durations: HashMap<&'static str, Vec<Interval>> = HashMap::new();
+        for interval in Intervals::new(entries).filter(|i| !i.cancelled) {
+            durations.entry(interval.label).or_insert_with(Vec::new).push(interval);
+        }
+
+        let results = slos.into_iter()
+            .map(|slo| {
+                let intervals = durations.get(&slo.label[..]).map_or(&[][..], |i| &i[..]);
+                let mut sorted: Vec<_> = intervals.iter().map(|i| i.duration_ns).collect();
+                sorted.sort();
+                SloResult {
+                    spans: intervals.len(),
+                    observed_ns: if sorted.is_empty() {
+                        None
+                    } else {
+                        Some(percentile(&sorted, slo.percentile))
+                    },
+                    offending: intervals.iter()
+                        .filter(|i| i.duration_ns >= slo.max_ns)
+                        .cloned()
+                        .collect(),
+                    slo: slo,
+                }
+            })
+            .collect();
+
+        SloReport { results: results }
+    }
+
+    /// Whether every objective was met.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|r| r.passed())
+    }
+
+    /// Write a pass/fail summary of each objective, listing up to
+    /// `max_offending` of the spans that took too long for each failed one.
+    pub fn write_report<W>(&self, writer: &mut W, max_offending: usize) -> io::Result<()>
+        where W: io::Write
+    {
+        for result in &self.results {
+            let slo = &result.slo;
+            try!(write!(writer,
+                        "{} {} < {}ns p{}: ",
+                        if result.passed() { "PASS" } else { "FAIL" },
+                        slo.label,
+                        slo.max_ns,
+                        slo.percentile));
+            match result.observed_ns {
+                Some(observed) => {
+                    try!(writeln!(writer, "{}ns over {} spans", observed, result.spans))
+                }
+                None => try!(writeln!(writer, "no spans")),
+            }
+
+            if result.passed() {
+                continue;
+            }
+            for interval in result.offending.iter().take(max_offending) {
+                try!(writeln!(writer,
+                              "  id {} at {}ns took {}ns",
+                              interval.id,
+                              interval.start_ns,
+                              interval.duration_ns));
+            }
+            if result.offending.len() > max_offending {
+                try!(writeln!(writer, "  ... and {} more", result.offending.len() - max_offending));
+            }
+        }
+
+        try!(writeln!(writer,
+                      "{}/{} objectives met",
+                      self.results.iter().filter(|r| r.passed()).count(),
+                      self.results.len()));
+        Ok(())
+    }
+}
+
+/// The thread and ID that identify a trace in a `CausalityGraph`, as returned
+/// by `Entry::why`.
+pub type TraceKey = (Option<ThreadId>, u32);
+
+/// A trace in a `CausalityGraph`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CausalNode {
+    /// The tag of the trace.
+    pub tag: u32,
+
+    /// The label of the trace.
+    pub label: &'static str,
+
+    /// The kind of the trace's entry: an event, start, or complete.
+    pub kind: TraceKind,
+
+    /// When the trace was taken, in nanoseconds since the epoch.
+    pub timestamp_ns: u64,
+
+    /// The trace named by this trace's `why`, if it had one, even if that
+    /// trace is not in the graph.
+    pub parent: Option<TraceKey>,
+
+    /// The traces in the graph whose `why` names this trace, in the order
+    /// they were traced.
+    pub children: Vec<TraceKey>,
+}
+
+/// The tree of causes between traces, reconstructed from the `why` each was
+/// given.
+///
+/// Every event, start, and complete entry is a node, keyed by its thread and
+/// ID. A node whose `why` names a trace that is not in the graph, for example
+/// because it was overwritten in a `RingBuffer`, is a root, like one with no
+/// `why` at all.
+///
+/// ```
+/// use eep::analysis::CausalityGraph;
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::traits::TraceSink;
+///
+/// let mut buffer = SimpleTraceBuffer::default();
+/// let request = buffer.trace_event(SimpleTrace::FooEvent, None);
+/// let handler = buffer.trace_start(SimpleTrace::OperationThing, Some(request));
+/// buffer.trace_event(SimpleTrace::FooEvent, Some(handler));
+/// buffer.trace_stop(handler, SimpleTrace::OperationThing);
+///
+/// let graph = CausalityGraph::new(buffer.iter());
+/// let root = graph.roots()[0];
+/// assert_eq!(graph.descendants(root).len(), 2);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CausalityGraph {
+    nodes: HashMap<TraceKey, CausalNode>,
+    roots: Vec<TraceKey>,
+}
+
+impl CausalityGraph {
+    /// Build the graph of the given entries, which should be in the order they
+    /// were traced.
+    ///
+    /// If an ID is reused, for example because a counter wrapped, its later
+    /// trace replaces its earlier one, and later `why`s name the later one.
+    pub fn new<I, T>(entries: I) -> CausalityGraph
+        where I: IntoIterator<Item = Entry<T>>,
+              T: Trace
+    {
+        let mut graph = CausalityGraph::default();
+        for entry in entries {
+            match entry.kind() {
+                TraceKind::Event | TraceKind::Start | TraceKind::Complete => {}
+                _ => continue,
+            }
+
+            let key = (entry.thread(), entry.id());
+            let parent = entry.why();
+            match parent.and_then(|parent| graph.nodes.get_mut(&parent)) {
+                Some(parent) => parent.children.push(key),
+                None => graph.roots.push(key),
+            }
+            graph.nodes.insert(key,
+                               CausalNode {
+                                   tag: entry.tag(),
+                                   label: entry.label(),
+                                   kind: entry.kind(),
+                                   timestamp_ns: entry.timestamp().0,
+                                   parent: parent,
+                                   children: vec![],
+                               });
+        }
+        graph
+    }
+
+    /// Get the number of traces in the graph.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Return `true` if the graph has no traces, `false` otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Get the trace with the given key, if it is in the graph.
+    pub fn get(&self, key: TraceKey) -> Option<&CausalNode> {
+        self.nodes.get(&key)
+    }
+
+    /// Get the traces with no parent in the graph, in the order they were
+    /// traced.
+    pub fn roots(&self) -> &[TraceKey] {
+        &self.roots
+    }
+
+    /// Get the chain of causes of the trace with the given key that are in
+    /// the graph, nearest first.
+    pub fn ancestors(&self, key: TraceKey) -> Vec<TraceKey> {
+        let mut ancestors = vec![];
+        let mut parent = self.get(key).and_then(|node| node.parent);
+        while let Some(key) = parent {
+            // Reused IDs can make a cycle, so don't follow one around.
+            if !self.nodes.contains_key(&key) || ancestors.len() == self.nodes.len() {
+                break;
+            }
+            ancestors.push(key);
+            parent = self.nodes[&key].parent;
+        }
+        ancestors
+    }
+
+    /// Get every trace caused, directly or indirectly, by the trace with the
+    /// given key, depth first, in the order they were traced.
+    pub fn descendants(&self, key: TraceKey) -> Vec<TraceKey> {
+        let mut descendants = vec![];
+        let mut stack: Vec<_> = self.get(key)
+            .map_or(vec![], |node| node.children.iter().rev().cloned().collect());
+        while let Some(key) = stack.pop() {
+            if descendants.len() == self.nodes.len() {
+                break;
+            }
+            descendants.push(key);
+            if let Some(node) = self.get(key) {
+                stack.extend(node.children.iter().rev().cloned());
+            }
+        }
+        descendants
+    }
+}
+
+fn varint_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn tag_stats<T>(tags: &mut BTreeMap<u32, TagStats>, tag: u32) -> &mut TagStats
+    where T: Trace
+{
+    tags.entry(tag).or_insert_with(|| {
+        TagStats {
+            label: T::label(tag),
+            events: 0,
+            spans: 0,
+            cancelled: 0,
+            durations: None,
+        }
+    })
+}
+
+/// Get the `p`th percentile of the given sorted, non-empty values, using the
+/// nearest-rank method.
+pub(crate) fn percentile(sorted: &[u64], p: usize) -> u64 {
+    debug_assert!(!sorted.is_empty());
+    debug_assert!(p <= 100);
+    let rank = (p * sorted.len()).div_ceil(100);
+    sorted[if rank == 0 { 0 } else { rank - 1 }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::{ClockRegression, NsSinceEpoch, RingBuffer};
+    use std::process;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use testing::TraceBuilder;
+    use threaded_trace_id::LinkedTraceId;
+    use traits::{Trace, TraceId, TraceSink};
+
+    #[test]
+    fn groups_by_source() {
+        let source = |thread, sink| {
+            Source {
+                process: process::id(),
+                thread: ThreadId(thread),
+                sink: sink,
+            }
+        };
+        let buffer = TraceBuilder::new()
+            .leaf_span(SimpleTrace::OperationThing, 0..10)
+            .event(SimpleTrace::FooEvent, 20)
+            .build();
+        let entries: Vec<_> = buffer.iter().collect();
+        let merged = vec![(source(1, "a"), entries[0]),
+                          (source(2, "b"), entries[2]),
+                          (source(1, "a"), entries[1])];
+
+        let groups = group_by_source(merged);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0, source(1, "a"));
+        assert_eq!(groups[0].1, vec![entries[0], entries[1]]);
+        assert_eq!(groups[1].0, source(2, "b"));
+        assert_eq!(groups[1].1, vec![entries[2]]);
+
+        // Each source's entries can be analyzed on their own.
+        let stats = Stats::new(groups[0].1.iter().cloned());
+        assert_eq!(stats.get(SimpleTrace::OperationThing.tag()).unwrap().spans, 1);
+    }
+
+    #[test]
+    fn nested_intervals() {
+        let buffer = TraceBuilder::new()
+            .span(SimpleTrace::OperationThing, 0..10, |b| {
+                b.leaf_span(SimpleTrace::OperationAnother, 2..5)
+                    .event(SimpleTrace::FooEvent, 6)
+            })
+            .leaf_span(SimpleTrace::OperationAnother, 20..21)
+            .build();
+
+        let intervals: Vec<_> = Intervals::new(buffer.iter()).collect();
+        assert_eq!(intervals.len(), 3);
+
+        let (outer, inner, last) = (intervals[1], intervals[0], intervals[2]);
+        assert_eq!((outer.label, outer.start_ns, outer.duration_ns, outer.parent),
+                   ("Thing", 0, 10, None));
+        assert_eq!((inner.label, inner.start_ns, inner.duration_ns, inner.parent),
+                   ("Another", 2, 3, Some(outer.id)));
+        assert_eq!((last.label, last.start_ns, last.duration_ns, last.parent),
+                   ("Another", 20, 1, None));
+    }
+
+    #[test]
+    fn complete_intervals() {
+        let buffer = TraceBuilder::new()
+            .span(SimpleTrace::OperationThing, 0..10, |b| {
+                b.complete(SimpleTrace::OperationAnother, 2..5)
+            })
+            .complete(SimpleTrace::OperationAnother, 20..21)
+            .build();
+
+        let intervals: Vec<_> = Intervals::new(buffer.iter()).collect();
+        assert_eq!(intervals.len(), 3);
+
+        let (inner, outer, last) = (intervals[0], intervals[1], intervals[2]);
+        assert_eq!((inner.label, inner.start_ns, inner.duration_ns, inner.parent),
+                   ("Another", 2, 3, Some(outer.id)));
+        assert_eq!((outer.label, outer.start_ns, outer.duration_ns, outer.parent),
+                   ("Thing", 0, 10, None));
+        assert_eq!((last.label, last.start_ns, last.duration_ns, last.parent),
+                   ("Another", 20, 1, None));
+    }
+
+    #[test]
+    fn unmatched_starts_and_stops() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let orphan = buffer.trace_start(SimpleTrace::OperationThing, None);
+        let never_stopped = buffer.trace_start(SimpleTrace::OperationAnother, None);
+        buffer.trace_stop(orphan, SimpleTrace::OperationThing);
+
+        // Pretend the first start was overwritten.
+        let mut intervals = Intervals::new(buffer.iter().skip(1));
+        assert_eq!(intervals.next(), None);
+
+        let starts = intervals.unmatched_starts();
+        assert_eq!(starts.len(), 1);
+        assert_eq!(starts[0].id(), never_stopped.u32());
+
+        let stops = intervals.unmatched_stops();
+        assert_eq!(stops.len(), 1);
+        assert_eq!(stops[0].id(), orphan.u32());
+    }
+
+    #[test]
+    fn cancelled_intervals() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let stopped = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(stopped, SimpleTrace::OperationThing);
+        let cancelled = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_cancel(cancelled, SimpleTrace::OperationThing);
+
+        let intervals: Vec<_> = Intervals::new(buffer.iter()).collect();
+        assert_eq!(intervals.len(), 2);
+        assert!(!intervals[0].cancelled);
+        assert!(intervals[1].cancelled);
+    }
+
+    #[test]
+    fn clock_jumps() {
+        use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+        static TICKS: [u64; 5] = [10, 20, 30, 5, 40];
+        static NEXT_TICK: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(TICKS[NEXT_TICK.fetch_add(1, Ordering::AcqRel)])
+        }
+
+        let mut buffer = SimpleTraceBuffer::with_clock(4096, clock);
+        buffer.set_clock_regression(ClockRegression::Mark);
+        let before = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(before, SimpleTrace::OperationThing);
+        let during = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        buffer.trace_stop(during, SimpleTrace::OperationThing);
+
+        let intervals: Vec<_> = Intervals::new(buffer.iter()).collect();
+        assert_eq!(intervals.len(), 2);
+        assert!(!intervals[0].clock_jumped);
+        assert!(intervals[1].clock_jumped);
+    }
+
+    #[test]
+    fn slo_report() {
+        let mut builder = TraceBuilder::new();
+        for i in 0..100 {
+            let duration = if i % 25 == 0 { 50 } else { 10 };
+            builder = builder.leaf_span(SimpleTrace::OperationThing, i * 100..i * 100 + duration);
+        }
+        let buffer = builder.build();
+
+        let slos = vec![Slo::parse("Thing < 20ns p95").unwrap(),
+                        Slo::parse("Thing < 20ns p99").unwrap(),
+                        Slo::parse("Another < 1s p50").unwrap()];
+        let report = SloReport::new(buffer.iter(), slos);
+
+        assert!(report.results[0].passed());
+        assert_eq!(report.results[0].observed_ns, Some(10));
+        assert!(!report.results[1].passed());
+        assert_eq!(report.results[1].observed_ns, Some(50));
+        assert_eq!(report.results[1].offending.len(), 4);
+        assert!(report.results[2].passed());
+        assert_eq!(report.results[2].spans, 0);
+        assert!(!report.passed());
+
+        let mut written = vec![];
+        report.write_report(&mut written, 2).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[0], "PASS Thing < 20ns p95: 10ns over 100 spans");
+        assert_eq!(lines[1], "FAIL Thing < 20ns p99: 50ns over 100 spans");
+        // IDs depend on what other tests traced first.
+        assert!(lines[2].starts_with("  id ") && lines[2].ends_with(" at 0ns took 50ns"));
+        assert!(lines[3].starts_with("  id ") && lines[3].ends_with(" at 2500ns took 50ns"));
+        assert_eq!(lines[4], "  ... and 2 more");
+        assert_eq!(lines[5], "PASS Another < 1000000000ns p50: no spans");
+        assert_eq!(lines[6], "2/3 objectives met");
+    }
+
+    #[test]
+    fn parse_slos() {
+        assert_eq!(Slo::parse("Layout and paint < 3us p50"),
+                   Some(Slo {
+                       label: "Layout and paint".to_string(),
+                       max_ns: 3_000,
+                       percentile: 50,
+                   }));
+        assert_eq!(Slo::parse("Render < 16ms"), None);
+        assert_eq!(Slo::parse("Render < 16 p99"), None);
+        assert_eq!(Slo::parse("Render < 16ms p101"), None);
+        assert_eq!(Slo::parse(" < 16ms p99"), None);
+    }
+
+    #[test]
+    fn percentiles() {
+        let values: Vec<u64> = (1..101).collect();
+        assert_eq!(percentile(&values, 50), 50);
+        assert_eq!(percentile(&values, 99), 99);
+        assert_eq!(percentile(&values, 100), 100);
+        assert_eq!(percentile(&[7], 0), 7);
+        assert_eq!(percentile(&[7], 99), 7);
+    }
+
+    #[test]
+    fn stats() {
+        let mut builder = TraceBuilder::new().event(SimpleTrace::FooEvent, 0);
+        for i in 0..100 {
+            builder = builder.leaf_span(SimpleTrace::OperationThing, i * 1000..i * 1000 + i + 1);
+        }
+        let mut buffer = SimpleTraceBuffer::new(1 << 16);
+        builder.build_into(&mut buffer);
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_cancel(id, SimpleTrace::OperationThing);
+
+        let stats = Stats::new(buffer.iter());
+        assert_eq!(stats.iter().count(), 2);
+        assert_eq!(stats.get(SimpleTrace::OperationAnother.tag()), None);
+
+        let foo = stats.get(SimpleTrace::FooEvent.tag()).unwrap();
+        assert_eq!((foo.label, foo.events, foo.spans, foo.durations),
+                   ("Foo", 1, 0, None));
+
+        let thing = stats.get(SimpleTrace::OperationThing.tag()).unwrap();
+        assert_eq!((thing.events, thing.spans, thing.cancelled), (0, 100, 1));
+        assert_eq!(thing.durations,
+                   Some(DurationStats {
+                       min: 1,
+                       max: 100,
+                       mean: 50,
+                       p50: 50,
+                       p95: 95,
+                       p99: 99,
+                   }));
+    }
+
+    #[test]
+    fn utilization_subtracts_parked_time() {
+        use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+        static TICKS: [u64; 6] = [0, 10, 20, 30, 60, 100];
+        static NEXT_TICK: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(TICKS[NEXT_TICK.fetch_add(1, Ordering::AcqRel)])
+        }
+
+        let mut buffer = SimpleTraceBuffer::with_clock(4096, clock);
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_park(SimpleTrace::FooEvent);
+        buffer.trace_unpark(SimpleTrace::FooEvent);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        buffer.trace_park(SimpleTrace::FooEvent);
+        buffer.trace_unpark(SimpleTrace::FooEvent);
+
+        let threads = utilization(buffer.iter());
+        assert_eq!(threads,
+                   vec![ThreadUtilization {
+                            thread: None,
+                            total_ns: 100,
+                            parked_ns: 50,
+                            busy_ns: 20,
+                        }]);
+        assert_eq!(threads[0].utilization(), Some(0.4));
+    }
+
+    #[test]
+    fn utilization_survives_a_regressing_clock() {
+        use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+        static TICKS: [u64; 3] = [100, 50, 80];
+        static NEXT_TICK: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(TICKS[NEXT_TICK.fetch_add(1, Ordering::AcqRel)])
+        }
+
+        // Unparked "before" it parked, and then parked until the end.
+        let mut buffer = SimpleTraceBuffer::with_clock(4096, clock);
+        buffer.trace_park(SimpleTrace::FooEvent);
+        buffer.trace_unpark(SimpleTrace::FooEvent);
+        buffer.trace_park(SimpleTrace::FooEvent);
+
+        assert_eq!(utilization(buffer.iter()),
+                   vec![ThreadUtilization {
+                            thread: None,
+                            total_ns: 50,
+                            parked_ns: 20,
+                            busy_ns: 0,
+                        }]);
+    }
+
+    #[test]
+    fn encoding_report() {
+        let buffer = TraceBuilder::new()
+            .event(SimpleTrace::FooEvent, 0)
+            .event(SimpleTrace::FooEvent, 1)
+            .leaf_span(SimpleTrace::OperationThing, 3..300)
+            .build();
+
+        let report = EncodingReport::new(buffer.iter());
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.encoded_bytes, 4 * Entry::<SimpleTrace>::size());
+        assert_eq!(report.tags.get(&SimpleTrace::FooEvent.tag()), Some(&2));
+        assert_eq!(report.tag_entropy_bits, 1.0);
+
+        let mut expected_deltas = vec![0; 65];
+        expected_deltas[64] = 1;
+        expected_deltas[1] = 1;
+        expected_deltas[2] = 1;
+        expected_deltas[9] = 1;
+        assert_eq!(report.timestamp_deltas, expected_deltas);
+
+        assert!(report.compact_bytes < report.encoded_bytes);
+
+        let mut out = vec![];
+        report.write_report(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("4 entries"));
+    }
+
+    #[test]
+    fn varint_lengths() {
+        assert_eq!(varint_len(0), 1);
+        assert_eq!(varint_len(127), 1);
+        assert_eq!(varint_len(128), 2);
+        assert_eq!(varint_len(u64::MAX), 10);
+    }
+
+    #[derive(Copy, Clone, Debug)]
+    struct Linked;
+
+    impl Trace for Linked {
+        type Id = LinkedTraceId;
+
+        fn label(_tag: u32) -> &'static str {
+            "Linked"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn causality_graph() {
+        let mut buffer = RingBuffer::<Linked>::new(4096);
+        let request = buffer.trace_event(Linked, None);
+        let handler = buffer.trace_start(Linked, Some(request));
+        let query = buffer.trace_event(Linked, Some(handler));
+        let render = buffer.trace_complete(Linked, NsSinceEpoch(1), NsSinceEpoch(2), Some(request));
+        buffer.trace_stop(handler, Linked);
+        let other = buffer.trace_event(Linked, None);
+
+        assert_eq!((request.depth(), handler.depth(), query.depth()), (0, 1, 2));
+        assert_eq!(query.parent(), Some(handler.id()));
+
+        let key = |id: LinkedTraceId| (id.thread(), id.u32());
+        let graph = CausalityGraph::new(buffer.iter());
+        assert_eq!(graph.len(), 5);
+        assert_eq!(graph.roots(), &[key(request), key(other)]);
+        assert_eq!(graph.get(key(request)).unwrap().children,
+                   vec![key(handler), key(render)]);
+        assert_eq!(graph.get(key(render)).unwrap().kind, TraceKind::Complete);
+        assert_eq!(graph.ancestors(key(query)), vec![key(handler), key(request)]);
+        assert_eq!(graph.ancestors(key(query)).len(), query.depth() as usize);
+        assert_eq!(graph.descendants(key(request)),
+                   vec![key(handler), key(query), key(render)]);
+        assert!(graph.descendants(key(other)).is_empty());
+
+        // Once the cause is gone, its effect is a root.
+        let graph = CausalityGraph::new(buffer.iter().skip(1));
+        assert_eq!(graph.roots(), &[key(handler), key(render), key(other)]);
+        assert_eq!(graph.get(key(handler)).unwrap().parent, Some(key(request)));
+        assert_eq!(graph.ancestors(key(query)), vec![key(handler)]);
+    }
+}
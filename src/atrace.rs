@@ -0,0 +1,208 @@
+//! A `TraceSink` for Android that writes every trace to ftrace's
+//! `trace_marker` file in the ATrace format, so that spans show up in Perfetto
+//! and systrace system traces alongside kernel scheduling data.
+//!
+//! Spans are written as `B|<pid>|<label>` when they start and `E|<pid>` when
+//! they stop or are cancelled, and counters as `C|<pid>|<label>|<value>`.
+//! One-off events are written as spans that stop as soon as they start, with
+//! a named event's name appended to its label as `<label>: <name>`. A parked
+//! thread is written as a span from its park until its unpark. Completes are
+//! only traced once they have ended, too late to write a span at the right
+//! time, so their durations are written as a counter instead, as
+//! `C|<pid>|<label>|<duration in ns>`. This
+//! is the same format that the NDK's `ATrace_beginSection` and
+//! `ATrace_endSection` write, and Perfetto attributes each span to the thread
+//! that wrote it, so spans must nest properly on each thread.
+//!
+//! Record a trace that includes the markers with the `atrace` data source,
+//! for example:
+//!
+//! ```text
+//! adb shell perfetto -o /data/misc/perfetto-traces/trace -t 10s sched atrace --app '*'
+//! ```
+//!
+//! Writing to `trace_marker` usually requires the app to be debuggable, or a
+//! rooted device.
+
+use ring_buffer::NsSinceEpoch;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::process;
+use traits::{Trace, TraceId, TraceSink};
+
+const TRACE_MARKER_PATHS: [&str; 2] = ["/sys/kernel/tracing/trace_marker",
+                                       "/sys/kernel/debug/tracing/trace_marker"];
+
+/// A `TraceSink` that writes ATrace markers to ftrace's `trace_marker` file.
+#[derive(Debug)]
+pub struct ATrace<T> {
+    file: File,
+    pid: u32,
+    line: Vec<u8>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> ATrace<T>
+    where T: Trace
+{
+    /// Open the `trace_marker` file in tracefs, wherever it is mounted.
+    pub fn open() -> io::Result<ATrace<T>> {
+        let mut result = Err(io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"));
+        for path in &TRACE_MARKER_PATHS {
+            result = ATrace::open_path(path);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Open the `trace_marker` file at the given path.
+    pub fn open_path<P>(path: P) -> io::Result<ATrace<T>>
+        where P: AsRef<Path>
+    {
+        let file = try!(OpenOptions::new().write(true).open(path));
+        Ok(ATrace {
+            file: file,
+            pid: process::id(),
+            line: vec![],
+            phantom: PhantomData,
+        })
+    }
+
+    fn begin(&mut self, trace: T) {
+        self.line.clear();
+        let _ = write!(self.line, "B|{}|{}", self.pid, T::label(trace.tag()));
+        self.mark();
+    }
+
+    fn counter(&mut self, trace: T, value: u64) {
+        self.line.clear();
+        let _ = write!(self.line, "C|{}|{}|{}", self.pid, T::label(trace.tag()), value);
+        self.mark();
+    }
+
+    fn end(&mut self) {
+        self.line.clear();
+        let _ = write!(self.line, "E|{}", self.pid);
+        self.mark();
+    }
+
+    fn mark(&mut self) {
+        // Each marker must be written with a single `write`, or ftrace will
+        // record it as several. Tracing must never fail the traced program,
+        // so drop the marker if it can't be written.
+        let _ = self.file.write(&self.line);
+    }
+}
+
+impl<T> TraceSink<T> for ATrace<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        self.begin(trace);
+        self.end();
+        T::Id::new_id()
+    }
+
+    fn trace_start(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        self.begin(trace);
+        T::Id::new_id()
+    }
+
+    fn trace_stop(&mut self, _id: T::Id, _trace: T) {
+        self.end();
+    }
+
+    fn trace_cancel(&mut self, _id: T::Id, _trace: T) {
+        self.end();
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      _why: Option<T::Id>)
+                      -> T::Id {
+        self.counter(trace, end.0.saturating_sub(start.0));
+        T::Id::new_id()
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.begin(trace);
+    }
+
+    fn trace_unpark(&mut self, _trace: T) {
+        self.end();
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.counter(trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.line.clear();
+        let _ = write!(self.line, "B|{}|{}: ", self.pid, T::label(trace.tag()));
+        // A newline would end the marker early.
+        self.line.extend(name.bytes().map(|b| if b == b'\n' { b' ' } else { b }));
+        self.mark();
+        self.end();
+        T::Id::new_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use traits::TraceSink;
+
+    #[test]
+    fn writes_atrace_markers() {
+        let path = env::temp_dir().join("eep-atrace-writes-atrace-markers");
+        fs::write(&path, b"").unwrap();
+        {
+            let mut sink = ATrace::open_path(&path).unwrap();
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_counter(SimpleTrace::FooEvent, 42);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let pid = process::id();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   format!("B|{}|ThingC|{}|Foo|42E|{}", pid, pid, pid));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_completes_parks_and_names() {
+        let path = env::temp_dir().join("eep-atrace-writes-completes-parks-and-names");
+        fs::write(&path, b"").unwrap();
+        {
+            let mut sink = ATrace::open_path(&path).unwrap();
+            sink.trace_complete(SimpleTrace::OperationThing,
+                                NsSinceEpoch(1_000),
+                                NsSinceEpoch(3_500),
+                                None);
+            sink.trace_park(SimpleTrace::FooEvent);
+            sink.trace_unpark(SimpleTrace::FooEvent);
+            sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        }
+
+        let pid = process::id();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   format!("C|{}|Thing|2500B|{}|FooE|{}B|{}|Foo: /etc/hostsE|{}",
+                           pid,
+                           pid,
+                           pid,
+                           pid,
+                           pid));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
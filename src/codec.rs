@@ -0,0 +1,345 @@
+//! Codecs for sequences of integers, such as the timestamps or tags of
+//! consecutive entries, as used by `export::trace_file`.
+//!
+//! Which codec is best depends on the pattern of values:
+//!
+//! * `Raw` stores every value in full, in 8 bytes.
+//!
+//! * `Leb128` stores every value as a varint, so small values, such as most
+//!   tags, take a byte or two.
+//!
+//! * `DeltaLeb128` stores the difference from the previous value as a varint,
+//!   so values that grow slowly, such as the timestamps of frequent events,
+//!   take a few bytes.
+//!
+//! * `DoubleDelta` stores the change in that difference as a varint, so values
+//!   that grow steadily, such as the timestamps of periodic events, take a
+//!   byte or so.
+//!
+//! Implement `Codec` to use another.
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// A way of encoding a sequence of integers, one at a time, in order.
+///
+/// A codec may keep state from one value to the next, so a sequence must be
+/// decoded by a codec in the same initial state as the one that encoded it.
+pub trait Codec {
+    /// Append the encoding of `value`, the next in the sequence, to `out`.
+    fn encode(&mut self, value: u64, out: &mut Vec<u8>);
+
+    /// Decode the next value in the sequence from the front of `bytes`, or
+    /// return `None` if `bytes` ends first or is corrupt.
+    fn decode(&mut self, bytes: &mut dyn Iterator<Item = u8>) -> Option<u64>;
+}
+
+/// A `Codec` that stores every value as a little-endian `u64`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Raw;
+
+impl Codec for Raw {
+    fn encode(&mut self, value: u64, out: &mut Vec<u8>) {
+        let mut bytes = [0; 8];
+        write_u64(&mut bytes, value);
+        out.extend_from_slice(&bytes);
+    }
+
+    fn decode(&mut self, bytes: &mut dyn Iterator<Item = u8>) -> Option<u64> {
+        let mut value = [0; 8];
+        for byte in &mut value {
+            *byte = bytes.next()?;
+        }
+        Some(read_u64(&value))
+    }
+}
+
+/// A `Codec` that stores every value as an unsigned LEB128 varint.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Leb128;
+
+impl Codec for Leb128 {
+    fn encode(&mut self, value: u64, out: &mut Vec<u8>) {
+        push_varint(value, out);
+    }
+
+    fn decode(&mut self, bytes: &mut dyn Iterator<Item = u8>) -> Option<u64> {
+        read_varint(bytes)
+    }
+}
+
+/// A `Codec` that stores the difference between every value and the previous
+/// one, which is initially zero, as a zigzagged LEB128 varint.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeltaLeb128 {
+    last: u64,
+}
+
+impl Codec for DeltaLeb128 {
+    fn encode(&mut self, value: u64, out: &mut Vec<u8>) {
+        push_varint(zigzag(value.wrapping_sub(self.last) as i64), out);
+        self.last = value;
+    }
+
+    fn decode(&mut self, bytes: &mut dyn Iterator<Item = u8>) -> Option<u64> {
+        let delta = unzigzag(read_varint(bytes)?);
+        self.last = self.last.wrapping_add(delta as u64);
+        Some(self.last)
+    }
+}
+
+/// A `Codec` that stores the difference between every value's delta from the
+/// previous value and the previous delta, both of which are initially zero,
+/// as a zigzagged LEB128 varint.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DoubleDelta {
+    last: u64,
+    last_delta: i64,
+}
+
+impl Codec for DoubleDelta {
+    fn encode(&mut self, value: u64, out: &mut Vec<u8>) {
+        let delta = value.wrapping_sub(self.last) as i64;
+        push_varint(zigzag(delta.wrapping_sub(self.last_delta)), out);
+        self.last = value;
+        self.last_delta = delta;
+    }
+
+    fn decode(&mut self, bytes: &mut dyn Iterator<Item = u8>) -> Option<u64> {
+        let delta = self.last_delta.wrapping_add(unzigzag(read_varint(bytes)?));
+        self.last = self.last.wrapping_add(delta as u64);
+        self.last_delta = delta;
+        Some(self.last)
+    }
+}
+
+fn push_varint(value: u64, out: &mut Vec<u8>) {
+    let mut bytes = [0; MAX_VARINT_LEN];
+    let len = write_varint(&mut bytes, value);
+    out.extend_from_slice(&bytes[..len]);
+}
+
+/// The most bytes a `u64` takes as a varint.
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+/// Write `value` into the front of `out` as an unsigned LEB128 varint, and
+/// return the number of bytes written.
+///
+/// Varints are encoded here, into plain slices, rather than with a crate that
+/// needs `std::io`, so that compact `RingBuffer`s work without `std`.
+#[inline]
+pub(crate) fn write_varint(out: &mut [u8], mut value: u64) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[len] = byte;
+            return len + 1;
+        }
+        out[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+/// Read an unsigned LEB128 varint from the front of `bytes`, or return `None`
+/// if `bytes` ends first or it doesn't fit in a `u64`.
+#[inline]
+pub(crate) fn read_varint<I>(bytes: &mut I) -> Option<u64>
+    where I: Iterator<Item = u8> + ?Sized
+{
+    let mut value = 0;
+    for shift in (0..MAX_VARINT_LEN).map(|i| 7 * i) {
+        let byte = match bytes.next() {
+            Some(byte) => byte,
+            None => return None,
+        };
+        // The last byte only has room for the top bit of a `u64`.
+        if shift == 63 && byte > 1 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Write `value` into the front of `out` in little-endian byte order.
+#[inline]
+pub(crate) fn write_u32(out: &mut [u8], value: u32) {
+    for (i, byte) in out[..4].iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Write `value` into the front of `out` in little-endian byte order.
+#[inline]
+pub(crate) fn write_u64(out: &mut [u8], value: u64) {
+    for (i, byte) in out[..8].iter_mut().enumerate() {
+        *byte = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Read a little-endian `u32` from the front of `bytes`.
+#[inline]
+pub(crate) fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[..4].iter().enumerate().fold(0, |value, (i, &byte)| value | (byte as u32) << (8 * i))
+}
+
+/// Read a little-endian `u64` from the front of `bytes`.
+#[inline]
+pub(crate) fn read_u64(bytes: &[u8]) -> u64 {
+    bytes[..8].iter().enumerate().fold(0, |value, (i, &byte)| value | (byte as u64) << (8 * i))
+}
+
+/// Map signed integers to unsigned ones such that small magnitudes, whether
+/// negative or positive, stay small as varints.
+#[inline]
+pub(crate) fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// The inverse of `zigzag`.
+#[inline]
+pub(crate) fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trips() {
+        let values = [0, 1, 0x7f, 0x80, 300, 0xffff_ffff, u64::MAX];
+        let lens = [1, 1, 1, 2, 2, 5, MAX_VARINT_LEN];
+        for (&value, &len) in values.iter().zip(lens.iter()) {
+            let mut bytes = [0; MAX_VARINT_LEN];
+            assert_eq!(write_varint(&mut bytes, value), len);
+            assert_eq!(read_varint(&mut bytes[..len].iter().cloned()), Some(value));
+            assert_eq!(read_varint(&mut bytes[..len - 1].iter().cloned()), None);
+        }
+
+        // The example from the LEB128 specification.
+        let mut bytes = [0; MAX_VARINT_LEN];
+        assert_eq!(write_varint(&mut bytes, 624485), 3);
+        assert_eq!(&bytes[..3], &[0xe5, 0x8e, 0x26]);
+    }
+
+    #[test]
+    fn varint_rejects_overflow() {
+        let mut bytes = [0xff; MAX_VARINT_LEN];
+        bytes[MAX_VARINT_LEN - 1] = 0x01;
+        assert_eq!(read_varint(&mut bytes.iter().cloned()), Some(u64::MAX));
+
+        // Any more bits in the last byte don't fit.
+        bytes[MAX_VARINT_LEN - 1] = 0x02;
+        assert_eq!(read_varint(&mut bytes.iter().cloned()), None);
+        bytes[MAX_VARINT_LEN - 1] = 0x7f;
+        assert_eq!(read_varint(&mut bytes.iter().cloned()), None);
+        bytes[MAX_VARINT_LEN - 1] = 0x81;
+        assert_eq!(read_varint(&mut bytes.iter().cloned()), None);
+    }
+
+    #[test]
+    fn fixed_width_is_little_endian() {
+        let mut bytes = [0; 8];
+        write_u32(&mut bytes, 0x0403_0201);
+        assert_eq!(bytes, [1, 2, 3, 4, 0, 0, 0, 0]);
+        assert_eq!(read_u32(&bytes), 0x0403_0201);
+
+        write_u64(&mut bytes, 0x0807_0605_0403_0201);
+        assert_eq!(bytes, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(read_u64(&bytes), 0x0807_0605_0403_0201);
+    }
+
+    #[test]
+    fn zigzag_round_trips() {
+        for &(value, encoded) in &[(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MAX, !1),
+                                   (i64::MIN, !0)] {
+            assert_eq!(zigzag(value), encoded);
+            assert_eq!(unzigzag(encoded), value);
+        }
+    }
+
+    // A xorshift generator, so that the fuzz tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        // Values in the patterns the codecs are meant for, and the extremes.
+        fn value(&mut self, last: u64) -> u64 {
+            match self.next() % 6 {
+                0 => self.next(),
+                1 => self.next() % 256,
+                2 => last.wrapping_add(self.next() % 1_000),
+                3 => last.wrapping_sub(self.next() % 1_000),
+                4 => last,
+                _ => if self.next() & 1 == 0 { 0 } else { u64::MAX },
+            }
+        }
+    }
+
+    fn fuzz<C>(codec: C)
+        where C: Clone + Codec
+    {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let len = rng.next() as usize % 100;
+            let mut values = vec![];
+            let mut last = rng.next();
+            for _ in 0..len {
+                last = rng.value(last);
+                values.push(last);
+            }
+
+            let mut encoder = codec.clone();
+            let mut bytes = vec![];
+            for &value in &values {
+                encoder.encode(value, &mut bytes);
+            }
+
+            let mut decoder = codec.clone();
+            let mut iter = bytes.iter().cloned();
+            for &value in &values {
+                assert_eq!(decoder.decode(&mut iter), Some(value));
+            }
+            assert_eq!(decoder.decode(&mut iter), None);
+
+            // Truncated input is never misread as a whole value.
+            if !bytes.is_empty() {
+                let mut decoder = codec.clone();
+                let mut iter = bytes[..bytes.len() - 1].iter().cloned();
+                let decoded = values.iter().take_while(|&&v| decoder.decode(&mut iter) == Some(v));
+                assert!(decoded.count() < values.len());
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_round_trips() {
+        fuzz(Raw);
+        fuzz(Leb128);
+        fuzz(DeltaLeb128::default());
+        fuzz(DoubleDelta::default());
+    }
+
+    #[test]
+    fn double_delta_is_compact_for_steady_values() {
+        let mut codec = DoubleDelta::default();
+        let mut bytes = vec![];
+        for i in 0..100 {
+            codec.encode(1_000_000_000 + i * 16_666_667, &mut bytes);
+        }
+        // Only the first two values take more than a byte.
+        assert!(bytes.len() < 100 + 2 * MAX_VARINT_LEN);
+    }
+}
@@ -0,0 +1,533 @@
+//! Dump a flight recorder when the process crashes, so that it works as a
+//! black box recorder for production crashes.
+//!
+//! `install_panic_hook` installs a panic hook that snapshots a shared
+//! `RingBuffer` and writes it, as configured by a `CrashWriter`, to a file or
+//! to stderr, before calling the previously installed hook.
+//!
+//! ```no_run
+//! use eep::crash::{CrashFormat, CrashWriter};
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::sink_combinators::WeakSinkHandle;
+//! use eep::traits::TraceSink;
+//! use std::sync::{Arc, Mutex};
+//!
+//! let recorder = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+//! eep::install_panic_hook(WeakSinkHandle::new(&recorder),
+//!                         CrashWriter::path(CrashFormat::Binary, "eep-crash.bin"));
+//!
+//! recorder.lock().unwrap().trace_event(SimpleTrace::FooEvent, None);
+//! panic!("the recorder is dumped to eep-crash.bin");
+//! ```
+//!
+//! Dumps come in two formats:
+//!
+//! * `CrashFormat::Binary` is `MAGIC`, then the size of each entry as a
+//!   little-endian `u32`, then the recorder's `RingBufferStats`, so that a
+//!   post-mortem can tell whether history was evicted, as the little-endian
+//!   `u64`s `written`, `evicted`, `high_water`, `wraps`, `unnamed`, and
+//!   `depth_exceeded`, then the number of names interned by
+//!   `trace_event_named` as a little-endian `u32`, then each name, indexed by
+//!   ID, as its length in bytes as a little-endian `u32` followed by its
+//!   UTF-8, then every entry in the stable fixed encoding, oldest first. Read
+//!   it back with `read`.
+//!
+//! * `CrashFormat::Text` is the human-readable listing written by `fmt::dump`,
+//!   names included.
+//!
+//! With the `crash-signals` feature, on Unix, `install_panic_hook` also
+//! installs handlers for the fatal signals `SIGABRT`, `SIGBUS`, `SIGFPE`,
+//! `SIGILL`, and `SIGSEGV`, which dump the recorder passed to the most recent
+//! call, and then let the signal kill the process as it would have. Signal
+//! handlers can't allocate, so they always dump in the binary format, to
+//! `CrashWriter::signal_path` rather than over the panic hook's dump, which a
+//! `panic = "abort"` build raises `SIGABRT` right after writing. They skip the
+//! dump if the recorder is locked, rather than wait for it, and don't dump to
+//! stderr for `CrashFormat::Text` writers, whose listing binary would garble.
+//!
+//! Every call installs another panic hook, which calls the previously
+//! installed one after dumping, so call it once per recorder, at startup.
+
+use fmt;
+use ring_buffer::{Blocks, Entry, RingBuffer, RingBufferStats, TraceSnapshot};
+use sink_combinators::WeakSinkHandle;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::panic;
+use std::path::PathBuf;
+use traits::Trace;
+
+/// The bytes every binary crash dump begins with, the last of which is the
+/// format's version.
+pub const MAGIC: &[u8; 8] = b"EEPCRSH\x03";
+
+/// The format a crash dump is written in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CrashFormat {
+    /// Every entry in the stable fixed encoding, and their names, which `read`
+    /// decodes.
+    Binary,
+    /// A human-readable listing, as written by `fmt::dump`.
+    Text,
+}
+
+/// Where, and in what format, `install_panic_hook` writes crash dumps.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CrashWriter {
+    format: CrashFormat,
+    // Where to write dumps, or `None` for stderr.
+    path: Option<PathBuf>,
+}
+
+impl CrashWriter {
+    /// Write crash dumps in the given `format` to stderr.
+    pub fn stderr(format: CrashFormat) -> CrashWriter {
+        CrashWriter {
+            format: format,
+            path: None,
+        }
+    }
+
+    /// Write crash dumps in the given `format` to the file at `path`,
+    /// replacing it if it exists.
+    pub fn path<P>(format: CrashFormat, path: P) -> CrashWriter
+        where P: Into<PathBuf>
+    {
+        CrashWriter {
+            format: format,
+            path: Some(path.into()),
+        }
+    }
+
+    /// Get the format this writer writes crash dumps in.
+    pub fn format(&self) -> CrashFormat {
+        self.format
+    }
+
+    /// Get the path fatal signal handlers write binary crash dumps to, which is
+    /// this writer's path with `.signal` appended, or `None` for stderr.
+    pub fn signal_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| {
+            let mut path = path.clone().into_os_string();
+            path.push(".signal");
+            PathBuf::from(path)
+        })
+    }
+
+    /// Write a crash dump of `snapshot` now.
+    pub fn write<T>(&self, snapshot: &TraceSnapshot<T>) -> io::Result<()>
+        where T: Trace
+    {
+        match self.path {
+            Some(ref path) => {
+                let mut writer = BufWriter::new(try!(File::create(path)));
+                try!(self.write_to(snapshot, &mut writer));
+                writer.flush()
+            }
+            None => {
+                let stderr = io::stderr();
+                let mut writer = stderr.lock();
+                self.write_to(snapshot, &mut writer)
+            }
+        }
+    }
+
+    fn write_to<T, W>(&self, snapshot: &TraceSnapshot<T>, writer: &mut W) -> io::Result<()>
+        where T: Trace,
+              W: io::Write
+    {
+        match self.format {
+            CrashFormat::Binary => write(snapshot, snapshot.names(), snapshot.stats(), writer),
+            CrashFormat::Text => fmt::write_entries_with_names(snapshot, snapshot.names(), writer),
+        }
+    }
+}
+
+/// Install a panic hook that dumps the flight recorder behind `sink` with
+/// `writer` whenever a thread panics, and then calls the previously installed
+/// hook.
+///
+/// A panic while the recorder is locked, for example one raised while
+/// tracing into it, skips the dump rather than deadlock. With the
+/// `crash-signals` feature, fatal signals dump the recorder too; see the
+/// module documentation.
+pub fn install_panic_hook<T, B>(sink: WeakSinkHandle<RingBuffer<T, B>>, writer: CrashWriter)
+    where T: 'static + Send + Trace,
+          B: 'static + Send + AsRef<[u8]> + AsMut<[u8]>
+{
+    #[cfg(all(feature = "crash-signals", unix))]
+    signals::install(sink.clone(), &writer);
+
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if let Some(snapshot) = sink.try_with_sink(|buffer| buffer.snapshot()) {
+            let _ = writer.write(&snapshot);
+        }
+        previous(info);
+    }));
+}
+
+/// Write the given entries, oldest first, the `names` they refer to, indexed
+/// by ID, and the `stats` of the buffer they came from, as a binary crash
+/// dump.
+pub fn write<I, T, W>(entries: I,
+                      names: &[String],
+                      stats: RingBufferStats,
+                      writer: &mut W)
+                      -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          W: io::Write
+{
+    try!(writer.write_all(MAGIC));
+    try!(writer.write_all(&(Entry::<T>::size() as u32).to_le_bytes()));
+    for &counter in &[stats.written,
+                      stats.evicted,
+                      stats.high_water as u64,
+                      stats.wraps,
+                      stats.unnamed,
+                      stats.depth_exceeded] {
+        try!(writer.write_all(&counter.to_le_bytes()));
+    }
+    try!(writer.write_all(&(names.len() as u32).to_le_bytes()));
+    for name in names {
+        try!(writer.write_all(&(name.len() as u32).to_le_bytes()));
+        try!(writer.write_all(name.as_bytes()));
+    }
+    for entry in entries {
+        try!(writer.write_all(&entry.to_bytes()));
+    }
+    Ok(())
+}
+
+/// Read a binary crash dump into a `RingBuffer`, whose `stats` are those of
+/// the recorder that was dumped.
+///
+/// Returns an error of kind `InvalidData` if the dump is not a binary crash
+/// dump, was written with a different entry size, for example by a build with
+/// different features or for a different `Trace` type, is truncated, or is
+/// corrupt.
+pub fn read<R, T>(reader: &mut R) -> io::Result<RingBuffer<T>>
+    where R: io::Read
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut magic = [0; 8];
+    try!(reader.read_exact(&mut magic).map_err(|_| invalid("not a crash dump")));
+    if magic != *MAGIC {
+        return Err(invalid("not a crash dump"));
+    }
+    let entry_size = try!(read_u32(reader));
+    let stats = RingBufferStats {
+        written: try!(read_u64(reader)),
+        evicted: try!(read_u64(reader)),
+        high_water: try!(read_u64(reader)) as usize,
+        wraps: try!(read_u64(reader)),
+        unnamed: try!(read_u64(reader)),
+        depth_exceeded: try!(read_u64(reader)),
+    };
+
+    let mut names = vec![];
+    for _ in 0..try!(read_u32(reader)) {
+        // Read no more than there is, however long a corrupt length says it is.
+        let length = try!(read_u32(reader)) as u64;
+        let mut name = vec![];
+        try!((&mut *reader).take(length).read_to_end(&mut name));
+        if name.len() as u64 != length {
+            return Err(invalid("crash dump is truncated"));
+        }
+        names.push(try!(String::from_utf8(name).map_err(|_| invalid("corrupt crash dump name"))));
+    }
+
+    let mut entries = vec![];
+    try!(reader.read_to_end(&mut entries));
+    let blocks = Blocks {
+        entry_size: entry_size as usize,
+        first: &entries,
+        second: &[],
+        names: &names,
+    };
+    let mut buffer = try!(RingBuffer::from_blocks(blocks).ok_or_else(|| {
+        invalid("crash dump has a different entry size, or is truncated or corrupt")
+    }));
+    buffer.set_stats(stats);
+    Ok(buffer)
+}
+
+fn read_u32<R>(reader: &mut R) -> io::Result<u32>
+    where R: io::Read
+{
+    let mut bytes = [0; 4];
+    try!(reader.read_exact(&mut bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "crash dump is truncated")));
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R>(reader: &mut R) -> io::Result<u64>
+    where R: io::Read
+{
+    let mut bytes = [0; 8];
+    try!(reader.read_exact(&mut bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "crash dump is truncated")));
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(all(feature = "crash-signals", unix))]
+mod signals {
+    extern crate libc;
+
+    use super::{CrashFormat, CrashWriter};
+    use ring_buffer::RingBuffer;
+    use sink_combinators::WeakSinkHandle;
+    use std::ffi::CString;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStringExt;
+    use std::ptr;
+    use std::sync::Once;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+    use traits::Trace;
+
+    type Dump = Box<dyn Fn() + Send + Sync>;
+
+    const SIGNALS: &[libc::c_int] =
+        &[libc::SIGABRT, libc::SIGBUS, libc::SIGFPE, libc::SIGILL, libc::SIGSEGV];
+
+    // Dumps the most recently installed recorder without allocating, or null
+    // before the first call to `install`, or if the most recent call's writer
+    // can't be dumped to from a signal handler. Replaced dumps are leaked,
+    // since a signal handler may still be running them.
+    static DUMP: AtomicPtr<Dump> = AtomicPtr::new(ptr::null_mut());
+
+    static INSTALL: Once = Once::new();
+
+    pub(super) fn install<T, B>(sink: WeakSinkHandle<RingBuffer<T, B>>, writer: &CrashWriter)
+        where T: 'static + Send + Trace,
+              B: 'static + Send + AsRef<[u8]> + AsMut<[u8]>
+    {
+        // Where to dump, with `None` for stderr, or `None` to not dump.
+        let path = match writer.signal_path() {
+            // Paths with interior nul bytes can't be opened anyway.
+            Some(path) => CString::new(path.into_os_string().into_vec()).ok().map(Some),
+            None if writer.format() == CrashFormat::Binary => Some(None),
+            None => None,
+        };
+
+        let dump = match path {
+            Some(path) => {
+                let dump: Box<Dump> = Box::new(Box::new(move || dump(&sink, path.as_ref())));
+                Box::into_raw(dump)
+            }
+            None => ptr::null_mut(),
+        };
+        DUMP.swap(dump, Ordering::AcqRel);
+
+        INSTALL.call_once(|| {
+            for &signal in SIGNALS {
+                unsafe {
+                    let mut action: libc::sigaction = mem::zeroed();
+                    action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+                    // Run on the alternate stack, if there is one, so that
+                    // stack overflows can be dumped too, and restore the
+                    // default action on entry, so that the signal kills the
+                    // process afterwards.
+                    action.sa_flags = libc::SA_ONSTACK | libc::SA_RESETHAND;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    libc::sigaction(signal, &action, ptr::null_mut());
+                }
+            }
+        });
+    }
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let dump = DUMP.load(Ordering::Acquire);
+        if !dump.is_null() {
+            unsafe { (*dump)() };
+        }
+        // The default action was restored on entry, and the signal is blocked
+        // until this handler returns, so re-raising it kills the process once
+        // we do, even if it wasn't raised by a faulting instruction.
+        unsafe {
+            libc::raise(signal);
+        }
+    }
+
+    fn dump<T, B>(sink: &WeakSinkHandle<RingBuffer<T, B>>, path: Option<&CString>)
+        where T: Trace,
+              B: AsRef<[u8]> + AsMut<[u8]>
+    {
+        sink.try_with_sink(|buffer| {
+            let fd = match path {
+                Some(path) => unsafe {
+                    libc::open(path.as_ptr(),
+                               libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+                               0o644)
+                },
+                None => libc::STDERR_FILENO,
+            };
+            if fd < 0 {
+                return;
+            }
+            let _ = super::write(buffer.iter(), buffer.names(), buffer.stats(), &mut Fd(fd));
+            if path.is_some() {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        });
+    }
+
+    // Writes straight to a file descriptor, without buffering or allocating.
+    struct Fd(libc::c_int);
+
+    impl io::Write for Fd {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written =
+                unsafe { libc::write(self.0, buf.as_ptr() as *const libc::c_void, buf.len()) };
+            if written < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(written as usize)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use traits::TraceSink;
+
+    #[test]
+    fn binary_dumps_round_trip() {
+        // Small enough that the first entries are evicted.
+        let mut buffer = SimpleTraceBuffer::new(4 * Entry::<SimpleTrace>::size());
+        for _ in 0..5 {
+            buffer.trace_event(SimpleTrace::FooEvent, None);
+        }
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        assert!(buffer.stats().evicted > 0);
+
+        let mut dump = vec![];
+        write(buffer.iter(), buffer.names(), buffer.stats(), &mut dump).unwrap();
+        let decoded: SimpleTraceBuffer = read(&mut &dump[..]).unwrap();
+        assert_eq!(decoded.iter().collect::<Vec<_>>(), buffer.iter().collect::<Vec<_>>());
+        assert_eq!(decoded.names(), buffer.names());
+        assert_eq!(decoded.stats(), buffer.stats());
+
+        let error = read::<_, SimpleTrace>(&mut &b"not a crash dump"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = read::<_, SimpleTrace>(&mut &dump[..dump.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let last = dump.len() - Entry::<SimpleTrace>::size();
+        dump[last] = 0x0e;
+        let error = read::<_, SimpleTrace>(&mut &dump[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dumps_on_panic() {
+        let dir = env::temp_dir();
+        let binary_path = dir.join(format!("eep-crash-binary-{}", process::id()));
+        let text_path = dir.join(format!("eep-crash-text-{}", process::id()));
+
+        let recorder = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        install_panic_hook(WeakSinkHandle::new(&recorder),
+                           CrashWriter::path(CrashFormat::Binary, binary_path.clone()));
+        install_panic_hook(WeakSinkHandle::new(&recorder),
+                           CrashWriter::path(CrashFormat::Text, text_path.clone()));
+
+        let thread_recorder = recorder.clone();
+        thread::spawn(move || {
+                let mut recorder = thread_recorder.lock().unwrap();
+                recorder.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+                drop(recorder);
+                panic!("crash");
+            })
+            .join()
+            .unwrap_err();
+
+        let dump: SimpleTraceBuffer = read(&mut File::open(&binary_path).unwrap()).unwrap();
+        assert_eq!(dump.iter().map(|e| e.label()).collect::<Vec<_>>(), vec!["Foo"]);
+        assert_eq!(dump.names(), ["/etc/hosts"]);
+        assert!(fs::read_to_string(&text_path).unwrap().contains("Event Foo #"));
+        assert!(fs::read_to_string(&text_path).unwrap().contains("\"/etc/hosts\""));
+
+        let _ = fs::remove_file(binary_path);
+        let _ = fs::remove_file(text_path);
+    }
+
+    // Set to the path to dump to when `dumps_on_fatal_signals` runs
+    // `dumps_on_fatal_signals_child` in a subprocess.
+    #[cfg(all(feature = "crash-signals", unix))]
+    const SIGNAL_TEST_PATH: &str = "EEP_CRASH_SIGNAL_TEST_PATH";
+
+    #[cfg(all(feature = "crash-signals", unix))]
+    #[test]
+    fn dumps_on_fatal_signals() {
+        extern crate libc;
+
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::{Command, Stdio};
+
+        let path = env::temp_dir().join(format!("eep-crash-signal-{}", process::id()));
+        let writer = CrashWriter::path(CrashFormat::Text, path.clone());
+        let signal_path = writer.signal_path().unwrap();
+
+        // Signal handlers are process-wide, and can't be uninstalled, so crash
+        // a fresh copy of this test binary rather than the test harness.
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "crash::tests::dumps_on_fatal_signals_child"])
+            .env(SIGNAL_TEST_PATH, &path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGABRT));
+
+        // The panic hook's text dump survives the abort that follows it, as in
+        // a `panic = "abort"` build, and the signal handler dumps alongside.
+        assert!(fs::read_to_string(&path).unwrap().contains("Event Foo"));
+        let dump: SimpleTraceBuffer = read(&mut File::open(&signal_path).unwrap()).unwrap();
+        assert_eq!(dump.iter().map(|e| e.label()).collect::<Vec<_>>(), vec!["Foo"]);
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(signal_path);
+    }
+
+    #[cfg(all(feature = "crash-signals", unix))]
+    #[test]
+    fn dumps_on_fatal_signals_child() {
+        extern crate libc;
+
+        use std::panic;
+
+        let path = match env::var_os(SIGNAL_TEST_PATH) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let recorder = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        install_panic_hook(WeakSinkHandle::new(&recorder),
+                           CrashWriter::path(CrashFormat::Text, path));
+        recorder.lock().unwrap().trace_event(SimpleTrace::FooEvent, None);
+
+        let _ = panic::catch_unwind(|| panic!("crash"));
+        unsafe {
+            libc::raise(libc::SIGABRT);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! A `TraceSink` for Windows that writes every trace as an Event Tracing for
+//! Windows (ETW) TraceLogging event, so that traces show up in Windows
+//! Performance Analyzer, PerfView, and other ETW consumers.
+//!
+//! Events are named with `Trace::label` and carry the trace's ID in an `id`
+//! field. `trace_start` and `trace_stop` write activity start and stop events,
+//! whose activity ID is derived from the trace's ID so that consumers can
+//! pair them up, and `trace_cancel` writes a stop event whose `cancelled`
+//! field is set. Counters carry their value in a `value` field, named events
+//! their name in a `name` field, and completes, which are only traced once
+//! they have ended, their duration in a `duration_ns` field.
+//!
+//! ```no_run
+//! use eep::etw::{Etw, Guid};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//!
+//! let provider = Guid {
+//!     data1: 0x3970f9cf,
+//!     data2: 0x2c0c,
+//!     data3: 0x4f11,
+//!     data4: [0xb1, 0xcc, 0xe3, 0xa1, 0xe9, 0x95, 0x88, 0x33],
+//! };
+//! let mut sink = Etw::<SimpleTrace>::register("MyCompany.MyComponent", provider).unwrap();
+//! sink.trace_event(SimpleTrace::FooEvent, None);
+//! ```
+
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use ring_buffer::NsSinceEpoch;
+use std::os::raw::c_void;
+use std::process;
+use std::ptr;
+use traits::{Trace, TraceId, TraceSink};
+
+/// A globally unique identifier, as used by ETW to identify providers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Guid {
+    /// The first 4 bytes.
+    pub data1: u32,
+    /// The next 2 bytes.
+    pub data2: u16,
+    /// The next 2 bytes.
+    pub data3: u16,
+    /// The last 8 bytes.
+    pub data4: [u8; 8],
+}
+
+#[repr(C)]
+struct EventDescriptor {
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct EventDataDescriptor {
+    ptr: u64,
+    size: u32,
+    kind: u32,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(provider_id: *const Guid,
+                     enable_callback: *const c_void,
+                     callback_context: *mut c_void,
+                     reg_handle: *mut u64)
+                     -> u32;
+
+    fn EventUnregister(reg_handle: u64) -> u32;
+
+    fn EventWriteTransfer(reg_handle: u64,
+                          event_descriptor: *const EventDescriptor,
+                          activity_id: *const Guid,
+                          related_activity_id: *const Guid,
+                          user_data_count: u32,
+                          user_data: *mut EventDataDescriptor)
+                          -> u32;
+}
+
+// The channel that marks an event as using TraceLogging's self-describing
+// metadata.
+const CHANNEL_TRACELOGGING: u8 = 11;
+const LEVEL_VERBOSE: u8 = 5;
+
+const OPCODE_INFO: u8 = 0;
+const OPCODE_START: u8 = 1;
+const OPCODE_STOP: u8 = 2;
+
+const DATA_USER: u32 = 0;
+const DATA_EVENT_METADATA: u32 = 1;
+const DATA_PROVIDER_METADATA: u32 = 2;
+
+const IN_TYPE_ANSISTRING: u8 = 2;
+const IN_TYPE_UINT32: u8 = 8;
+const IN_TYPE_UINT64: u8 = 10;
+const IN_TYPE_BOOL32: u8 = 13;
+// Set on an in type that is followed by an out type.
+const IN_TYPE_CHAIN: u8 = 0x80;
+const OUT_TYPE_UTF8: u8 = 35;
+
+// The fields of each shape of event, as their names and TraceLogging types.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+enum Fields {
+    Id,
+    Stop,
+    Named,
+    Counter,
+    Complete,
+}
+
+impl Fields {
+    fn fields(self) -> &'static [(&'static [u8], &'static [u8])] {
+        const ID: (&[u8], &[u8]) = (b"id", &[IN_TYPE_UINT32]);
+        match self {
+            Fields::Id => &[ID],
+            Fields::Stop => &[ID, (b"cancelled", &[IN_TYPE_BOOL32])],
+            Fields::Named => &[ID, (b"name", &[IN_TYPE_ANSISTRING | IN_TYPE_CHAIN, OUT_TYPE_UTF8])],
+            Fields::Counter => &[(b"value", &[IN_TYPE_UINT64])],
+            Fields::Complete => &[ID, (b"duration_ns", &[IN_TYPE_UINT64])],
+        }
+    }
+}
+
+/// A `TraceSink` that writes ETW TraceLogging events from a registered
+/// provider.
+#[derive(Debug)]
+pub struct Etw<T> {
+    handle: u64,
+    provider_metadata: Vec<u8>,
+    // The metadata of each tag's events of each shape, built the first time
+    // one is written.
+    event_metadata: HashMap<(u32, Fields), Vec<u8>>,
+    // A named event's name, nul-terminated.
+    name: Vec<u8>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Etw<T>
+    where T: Trace
+{
+    /// Register an ETW provider with the given name and GUID, and write
+    /// traces as its events.
+    pub fn register(name: &str, provider: Guid) -> io::Result<Etw<T>> {
+        let mut handle = 0;
+        let error = unsafe { EventRegister(&provider, ptr::null(), ptr::null_mut(), &mut handle) };
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+
+        Ok(Etw {
+            handle: handle,
+            provider_metadata: metadata(|bytes| {
+                bytes.extend_from_slice(name.as_bytes());
+                bytes.push(0);
+            }),
+            event_metadata: HashMap::new(),
+            name: vec![],
+            phantom: PhantomData,
+        })
+    }
+
+    // Write an event of `trace` with the given shape and values, which are
+    // data descriptors of the values of all but its `id` field, if it has one.
+    fn write(&mut self,
+             opcode: u8,
+             trace: T,
+             id: T::Id,
+             fields: Fields,
+             values: &[EventDataDescriptor]) {
+        let tag = trace.tag();
+        let event_metadata = self.event_metadata.entry((tag, fields)).or_insert_with(|| {
+            metadata(|bytes| {
+                // No event tags.
+                bytes.push(0);
+                bytes.extend_from_slice(T::label(tag).as_bytes());
+                bytes.push(0);
+                for &(name, ty) in fields.fields() {
+                    bytes.extend_from_slice(name);
+                    bytes.push(0);
+                    bytes.extend_from_slice(ty);
+                }
+            })
+        });
+
+        let descriptor = EventDescriptor {
+            id: 0,
+            version: 0,
+            channel: CHANNEL_TRACELOGGING,
+            level: LEVEL_VERBOSE,
+            opcode: opcode,
+            task: 0,
+            keyword: 0,
+        };
+
+        let id_value = id.u32();
+        // The metadata, then the value of each field, of which there are at
+        // most two. Only the first `count` descriptors are written.
+        let mut data = [data_descriptor(&self.provider_metadata[..], DATA_PROVIDER_METADATA),
+                        data_descriptor(&event_metadata[..], DATA_EVENT_METADATA),
+                        value_descriptor(&id_value),
+                        value_descriptor(&id_value)];
+        let mut count = if fields == Fields::Counter { 2 } else { 3 };
+        for &value in values {
+            data[count] = value;
+            count += 1;
+        }
+
+        let activity = activity_id(id);
+        let activity = if opcode == OPCODE_INFO {
+            ptr::null()
+        } else {
+            &activity as *const Guid
+        };
+
+        // Tracing must never fail the traced program, so ignore errors, such
+        // as there being no session listening to this provider.
+        unsafe {
+            EventWriteTransfer(self.handle,
+                               &descriptor,
+                               activity,
+                               ptr::null(),
+                               count as u32,
+                               data.as_mut_ptr());
+        }
+    }
+
+    fn stop(&mut self, id: T::Id, trace: T, cancelled: bool) {
+        let cancelled = cancelled as u32;
+        self.write(OPCODE_STOP, trace, id, Fields::Stop, &[value_descriptor(&cancelled)]);
+    }
+}
+
+impl<T> Drop for Etw<T> {
+    fn drop(&mut self) {
+        unsafe {
+            EventUnregister(self.handle);
+        }
+    }
+}
+
+impl<T> TraceSink<T> for Etw<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.write(OPCODE_INFO, trace, id, Fields::Id, &[]);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.write(OPCODE_START, trace, id, Fields::Id, &[]);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.stop(id, trace, false);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.stop(id, trace, true);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      _why: Option<T::Id>)
+                      -> T::Id {
+        let id = T::Id::new_id();
+        let duration = end.0.saturating_sub(start.0);
+        self.write(OPCODE_INFO, trace, id, Fields::Complete, &[value_descriptor(&duration)]);
+        id
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let id = T::Id::new_id();
+        self.write(OPCODE_INFO, trace, id, Fields::Counter, &[value_descriptor(&value)]);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = T::Id::new_id();
+        // A nul would end the name early.
+        let mut bytes = mem::take(&mut self.name);
+        bytes.clear();
+        bytes.extend(name.bytes().map(|b| if b == 0 { b' ' } else { b }));
+        bytes.push(0);
+        self.write(OPCODE_INFO,
+                   trace,
+                   id,
+                   Fields::Named,
+                   &[data_descriptor(&bytes[..], DATA_USER)]);
+        self.name = bytes;
+        id
+    }
+}
+
+/// Build a TraceLogging metadata blob, which is prefixed with its own size.
+fn metadata<F>(f: F) -> Vec<u8>
+    where F: FnOnce(&mut Vec<u8>)
+{
+    let mut bytes = vec![0, 0];
+    f(&mut bytes);
+    let size = bytes.len() as u16;
+    bytes[0] = size as u8;
+    bytes[1] = (size >> 8) as u8;
+    bytes
+}
+
+// Describe `value` as a fixed-size field's data.
+fn value_descriptor<V>(value: &V) -> EventDataDescriptor {
+    EventDataDescriptor {
+        ptr: value as *const V as u64,
+        size: mem::size_of::<V>() as u32,
+        kind: DATA_USER,
+    }
+}
+
+fn data_descriptor(bytes: &[u8], kind: u32) -> EventDataDescriptor {
+    EventDataDescriptor {
+        ptr: bytes.as_ptr() as u64,
+        size: bytes.len() as u32,
+        kind: kind,
+    }
+}
+
+/// Derive an ETW activity ID from a trace ID, so that a stop can name the same
+/// activity as its start without keeping any state.
+fn activity_id<I>(id: I) -> Guid
+    where I: TraceId
+{
+    let thread = id.thread().map_or(0, |thread| thread.0 as u64);
+    let pid = process::id();
+    Guid {
+        data1: id.u32(),
+        data2: thread as u16,
+        data3: (thread >> 16) as u16,
+        data4: [b'e',
+                b'e',
+                b'p',
+                (thread >> 32) as u8,
+                pid as u8,
+                (pid >> 8) as u8,
+                (pid >> 16) as u8,
+                (pid >> 24) as u8],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use traits::TraceSink;
+
+    #[test]
+    fn etw_sanity_check() {
+        let provider = Guid {
+            data1: 0x3970f9cf,
+            data2: 0x2c0c,
+            data3: 0x4f11,
+            data4: [0xb1, 0xcc, 0xe3, 0xa1, 0xe9, 0x95, 0x88, 0x33],
+        };
+        let mut sink = Etw::register("Eep.Tests", provider).unwrap();
+
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        let thing_id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        sink.trace_stop(thing_id, SimpleTrace::OperationThing);
+
+        let cancelled_id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_cancel(cancelled_id, SimpleTrace::OperationThing);
+        sink.trace_complete(SimpleTrace::OperationThing,
+                            NsSinceEpoch(1_000),
+                            NsSinceEpoch(3_500),
+                            None);
+        sink.trace_counter(SimpleTrace::FooEvent, 42);
+        sink.trace_event_named(SimpleTrace::FooEvent, "C:\\Windows\0hosts");
+    }
+}
@@ -0,0 +1,390 @@
+//! Export entries in the Chrome trace event format, which can be loaded into
+//! `chrome://tracing`, Perfetto, and speedscope.
+//!
+//! Spans become begin and end events, completes become complete events,
+//! counters become counter events, and everything else becomes instant
+//! events. Every event's arguments hold the entry's ID, and what caused it, if
+//! anything, and a cancelled span's end event is marked as such. With
+//! `write_json_with_names` or `write_json_with_sources`, a named event's
+//! arguments hold its name too.
+//!
+//! Every virtual track gets a row of its own, labelled with its name if it was
+//! declared with `track::declare`. With `write_json_with_sources`, entries
+//! merged from several sources are grouped by their source's process and
+//! thread, and each source's row is labelled with its sink's name.
+//!
+//! ```
+//! use eep::export::chrome;
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::testing::TraceBuilder;
+//!
+//! let buffer = TraceBuilder::new()
+//!     .leaf_span(SimpleTrace::OperationThing, 0..10_000)
+//!     .build();
+//!
+//! let mut out = vec![];
+//! chrome::write_json(buffer.iter(), &mut out).unwrap();
+//! ```
+
+use ring_buffer::{Entry, TraceKind};
+use std::collections::HashSet;
+use std::io;
+use std::process;
+use thread_local_trace::Source;
+use track::{self, Track};
+use traits::{ThreadId, Trace};
+
+// The largest integer that JSON readers are guaranteed to hold exactly.
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Write the given entries as a Chrome trace event format JSON object.
+///
+/// Entries that don't carry a process ID are attributed to the current
+/// process, and those that don't carry a thread to thread `0`. Clock jumps are
+/// left out.
+pub fn write_json<I, T, W>(entries: I, writer: &mut W) -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          W: io::Write
+{
+    write_json_with_names(entries, &[], writer)
+}
+
+/// Like `write_json`, but look up the names of events traced with
+/// `trace_event_named` in `names`, as returned by `RingBuffer::names` or
+/// `TraceSnapshot::names`, and write them in the events' arguments.
+///
+/// ```
+/// use eep::export::chrome;
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::traits::TraceSink;
+///
+/// let mut buffer = SimpleTraceBuffer::default();
+/// buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+///
+/// let mut out = vec![];
+/// chrome::write_json_with_names(buffer.iter(), buffer.names(), &mut out).unwrap();
+/// ```
+pub fn write_json_with_names<I, T, W>(entries: I,
+                                     names: &[String],
+                                     writer: &mut W)
+                                     -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          W: io::Write
+{
+    write_events(entries.into_iter().map(|entry| (None, entry)), names, writer)
+}
+
+/// Like `write_json_with_names`, but for entries merged from several sources,
+/// as by `thread_local_trace::merge_with_sources`, which are attributed to
+/// their source's process and thread, on a row named after their source's
+/// sink. Named events' names are looked up in `names`, as returned by
+/// `Merged::names`.
+///
+/// ```
+/// use eep::export::chrome;
+/// use eep::simple_trace::SimpleTrace;
+/// use eep::thread_local_trace::{self, ThreadLocalSink};
+/// use eep::traits::TraceSink;
+///
+/// ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+///
+/// let mut out = vec![];
+/// let merged = thread_local_trace::merge_with_sources::<SimpleTrace>();
+/// let names = merged.names().to_vec();
+/// chrome::write_json_with_sources(merged, &names, &mut out).unwrap();
+/// ```
+pub fn write_json_with_sources<I, T, W>(entries: I,
+                                       names: &[String],
+                                       writer: &mut W)
+                                       -> io::Result<()>
+    where I: IntoIterator<Item = (Source, Entry<T>)>,
+          T: Trace,
+          W: io::Write
+{
+    write_events(entries.into_iter().map(|(source, entry)| (Some(source), entry)),
+                 names,
+                 writer)
+}
+
+fn write_events<I, T, W>(entries: I, names: &[String], writer: &mut W) -> io::Result<()>
+    where I: Iterator<Item = (Option<Source>, Entry<T>)>,
+          T: Trace,
+          W: io::Write
+{
+    try!(write!(writer, "{{\"traceEvents\":["));
+    let mut first = true;
+    let mut named_rows = HashSet::new();
+    for (source, entry) in entries {
+        let phase = match entry.kind() {
+            TraceKind::Start => "B",
+            TraceKind::Stop | TraceKind::Cancel => "E",
+            TraceKind::Complete => "X",
+            TraceKind::Counter => "C",
+            TraceKind::Event | TraceKind::Park | TraceKind::Unpark => "i",
+            TraceKind::ClockJump => continue,
+        };
+        if !first {
+            try!(write!(writer, ","));
+        }
+        first = false;
+
+        let pid = match source {
+            Some(source) => source.process,
+            None => entry.process_id().unwrap_or_else(process::id),
+        };
+        let thread = entry.thread().or_else(|| source.map(|source| source.thread));
+        let tid = thread.map_or(0, tid_of);
+
+        // Name each track's row after the track, and each source's after its
+        // sink, before their first event.
+        let row_name = match thread.and_then(Track::from_thread) {
+            Some(track) => track::name(track),
+            None => source.map(|source| source.sink.to_string()),
+        };
+        if let Some(row_name) = row_name {
+            if named_rows.insert((pid, tid)) {
+                try!(write!(writer,
+                            "\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\
+                             \"tid\":{},\"args\":{{\"name\":",
+                            pid,
+                            tid));
+                try!(write_str(writer, &row_name));
+                try!(write!(writer, "}}}},"));
+            }
+        }
+
+        try!(write!(writer, "\n{{\"name\":"));
+        try!(write_str(writer, entry.label()));
+        if let Some(category) = T::category(entry.tag()) {
+            try!(write!(writer, ",\"cat\":"));
+            try!(write_str(writer, category));
+        }
+        if let Some(color) = T::color(entry.tag()) {
+            try!(write!(writer, ",\"cname\":"));
+            try!(write_str(writer, color));
+        }
+        try!(write!(writer,
+                    ",\"ph\":\"{}\",\"ts\":{},\"pid\":{},\"tid\":{}",
+                    phase,
+                    format_us(entry.start().0),
+                    pid,
+                    tid));
+        match entry.kind() {
+            TraceKind::Complete => {
+                try!(write!(writer, ",\"dur\":{}", format_us(entry.duration().unwrap_or(0))))
+            }
+            TraceKind::Event | TraceKind::Park | TraceKind::Unpark => {
+                try!(write!(writer, ",\"s\":\"t\""))
+            }
+            _ => {}
+        }
+
+        try!(write!(writer, ",\"args\":{{"));
+        if let Some(value) = entry.counter_value() {
+            try!(write_str(writer, entry.label()));
+            try!(write!(writer, ":{}}}}}", value));
+            continue;
+        }
+        try!(write!(writer, "\"id\":{}", entry.id()));
+        if let Some((_, why)) = entry.why() {
+            try!(write!(writer, ",\"why\":{}", why));
+        }
+        if let Some(name) = entry.name().and_then(|id| names.get(id as usize)) {
+            try!(write!(writer, ",\"name\":"));
+            try!(write_str(writer, name));
+        }
+        match entry.kind() {
+            TraceKind::Cancel => try!(write!(writer, ",\"cancelled\":true")),
+            TraceKind::Park => try!(write!(writer, ",\"park\":true")),
+            TraceKind::Unpark => try!(write!(writer, ",\"unpark\":true")),
+            _ => {}
+        }
+        try!(write!(writer, "}}}}"));
+    }
+    writeln!(writer, "\n]}}")
+}
+
+// Get the `tid` to write for the given thread. Tracks' thread IDs are too
+// large for JSON numbers to hold exactly, so they count down from the largest
+// integer that they can instead, well above any OS thread's.
+fn tid_of(thread: ThreadId) -> u64 {
+    match Track::from_thread(thread) {
+        Some(track) => MAX_SAFE_INTEGER - track.index() as u64,
+        None => thread.0 as u64,
+    }
+}
+
+// Format nanoseconds as the microseconds the format expects, with nanosecond
+// precision.
+fn format_us(ns: u64) -> String {
+    format!("{}.{:03}", ns / 1_000, ns % 1_000)
+}
+
+fn write_str<W>(writer: &mut W, s: &str) -> io::Result<()>
+    where W: io::Write
+{
+    try!(write!(writer, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(writer, "\\\"")),
+            '\\' => try!(write!(writer, "\\\\")),
+            c if (c as u32) < 0x20 => try!(write!(writer, "\\u{:04x}", c as u32)),
+            c => try!(write!(writer, "{}", c)),
+        }
+    }
+    write!(writer, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use ring_buffer::NsSinceEpoch;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use testing::TraceBuilder;
+    use traits::{ThreadId, TraceSink};
+
+    fn events<I>(entries: I) -> Vec<serde_json::Value>
+        where I: IntoIterator<Item = Entry<SimpleTrace>>
+    {
+        let mut out = vec![];
+        write_json(entries, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        json.find("traceEvents").unwrap().as_array().unwrap().clone()
+    }
+
+    fn field<'a>(event: &'a serde_json::Value, path: &[&str]) -> &'a serde_json::Value {
+        event.find_path(path).unwrap()
+    }
+
+    #[test]
+    fn spans_and_completes() {
+        let buffer = TraceBuilder::new()
+            .starting_at(NsSinceEpoch(1_000_000))
+            .span(SimpleTrace::OperationThing, 0..10_500, |b| {
+                b.complete(SimpleTrace::OperationAnother, 1_000..2_000)
+            })
+            .build();
+        let events = events(buffer.iter());
+
+        let phases: Vec<_> = events.iter().map(|e| field(e, &["ph"]).as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["B", "X", "E"]);
+        assert_eq!(field(&events[0], &["name"]).as_str(), Some("Thing"));
+        assert_eq!(field(&events[0], &["ts"]).as_f64(), Some(1_000.0));
+        assert_eq!(field(&events[1], &["dur"]).as_f64(), Some(1.0));
+        assert_eq!(field(&events[2], &["ts"]).as_f64(), Some(1_010.5));
+    }
+
+    #[test]
+    fn why_counters_and_cancels() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        buffer.trace_event(SimpleTrace::FooEvent, Some(parent));
+        buffer.trace_cancel(parent, SimpleTrace::OperationThing);
+        let events = events(buffer.iter());
+
+        let phases: Vec<_> = events.iter().map(|e| field(e, &["ph"]).as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["B", "C", "i", "E"]);
+        assert_eq!(field(&events[1], &["args", "Foo"]).as_u64(), Some(42));
+        assert_eq!(field(&events[2], &["args", "why"]).as_u64(), Some(parent.0 as u64));
+        assert_eq!(field(&events[3], &["args", "cancelled"]).as_bool(), Some(true));
+    }
+
+    #[test]
+    fn names() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let mut out = vec![];
+        write_json_with_names(buffer.iter(), buffer.names(), &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let named = json.find("traceEvents").unwrap().as_array().unwrap();
+        assert_eq!(field(&named[0], &["name"]).as_str(), Some("Foo"));
+        assert_eq!(field(&named[0], &["args", "name"]).as_str(), Some("/etc/hosts"));
+        assert!(named[1].find_path(&["args", "name"]).is_none());
+
+        // Without the names, named events are written like any other.
+        assert!(events(buffer.iter())[0].find_path(&["args", "name"]).is_none());
+    }
+
+    #[test]
+    fn one_row_per_track() {
+        let tracks = track::declare("chrome-{n}", 2);
+        let mut buffer = SimpleTraceBuffer::default();
+        let id = buffer.on_track(tracks.get(1)).trace_start(SimpleTrace::OperationThing, None);
+        buffer.on_track(tracks.get(1)).trace_stop(id, SimpleTrace::OperationThing);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let events = events(buffer.iter());
+
+        // The track's row is named once, and its events are on it.
+        let phases: Vec<_> = events.iter().map(|e| field(e, &["ph"]).as_str().unwrap()).collect();
+        assert_eq!(phases, vec!["M", "B", "E", "i"]);
+        assert_eq!(field(&events[0], &["name"]).as_str(), Some("thread_name"));
+        assert_eq!(field(&events[0], &["args", "name"]).as_str(), Some("chrome-1"));
+
+        let tid = field(&events[0], &["tid"]).as_u64().unwrap();
+        assert!(tid <= MAX_SAFE_INTEGER);
+        assert_eq!(field(&events[1], &["tid"]).as_u64(), Some(tid));
+        assert_eq!(field(&events[2], &["tid"]).as_u64(), Some(tid));
+        assert!(field(&events[3], &["tid"]).as_u64() != Some(tid));
+    }
+
+    #[test]
+    fn groups_by_source() {
+        let source = |thread, sink| {
+            Source {
+                process: 7,
+                thread: ThreadId(thread),
+                sink: sink,
+            }
+        };
+        let mut buffer = TraceBuilder::new()
+            .event(SimpleTrace::FooEvent, 0)
+            .event(SimpleTrace::FooEvent, 10)
+            .event(SimpleTrace::FooEvent, 20)
+            .build();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let entries: Vec<_> = buffer.iter().collect();
+        let merged = vec![(source(1, "main"), entries[0]),
+                          (source(2, "worker"), entries[1]),
+                          (source(1, "main"), entries[2]),
+                          (source(2, "worker"), entries[3])];
+
+        let mut out = vec![];
+        write_json_with_sources(merged, buffer.names(), &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let events = json.find("traceEvents").unwrap().as_array().unwrap();
+
+        // Each source's row is named once, before its first event.
+        let rows: Vec<_> = events.iter()
+            .map(|e| {
+                (field(e, &["ph"]).as_str().unwrap(),
+                 field(e, &["pid"]).as_u64().unwrap(),
+                 field(e, &["tid"]).as_u64().unwrap())
+            })
+            .collect();
+        assert_eq!(rows,
+                   vec![("M", 7, 1),
+                        ("i", 7, 1),
+                        ("M", 7, 2),
+                        ("i", 7, 2),
+                        ("i", 7, 1),
+                        ("i", 7, 2)]);
+        assert_eq!(field(&events[0], &["args", "name"]).as_str(), Some("main"));
+        assert_eq!(field(&events[2], &["args", "name"]).as_str(), Some("worker"));
+        assert_eq!(field(&events[5], &["args", "name"]).as_str(), Some("/etc/hosts"));
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let mut out = vec![];
+        write_str(&mut out, "a \"quoted\"\\\n label").unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(parsed.as_str(), Some("a \"quoted\"\\\n label"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Export entries as a Common Trace Format (CTF) 1.8 trace, which can be
+//! opened in Trace Compass and read with babeltrace.
+//!
+//! A CTF trace is a directory holding a `metadata` file, which describes the
+//! trace's layout in TSDL, and a binary `stream` file holding every entry as
+//! an event. Each tag that appears in the entries gets its own event class,
+//! named with `Trace::label`, whose ID is the tag. Every event has these
+//! fields:
+//!
+//! * `kind`: the entry's `TraceKind`, as an enumeration.
+//!
+//! * `id`: the entry's ID.
+//!
+//! * `has_thread` and `thread`: whether the entry has a thread, and if so,
+//!   which.
+//!
+//! * `has_why` and `why`: whether the entry has a `why`, and if so, its ID.
+//!
+//! * `value`: a complete's duration in nanoseconds, a counter's value, or `0`.
+//!   A complete's `timestamp` is its end.
+//!
+//! Timestamps are nanoseconds since the epoch, on a clock named `eep`.
+//!
+//! ```no_run
+//! use eep::export::ctf;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! buffer.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! ctf::write_trace(buffer.iter(), "my-trace").unwrap();
+//! // Then: babeltrace2 my-trace
+//! ```
+
+use ring_buffer::{Entry, TraceKind};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use traits::Trace;
+
+/// The magic number that begins every CTF packet.
+const MAGIC: u32 = 0xc1fc_1fc1;
+
+/// Write the given entries, which should be in timestamp order, as a CTF
+/// trace in the directory `dir`, creating it if need be.
+///
+/// Clock jumps are left out.
+pub fn write_trace<I, T, P>(entries: I, dir: P) -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          P: AsRef<Path>
+{
+    let dir = dir.as_ref();
+    try!(fs::create_dir_all(dir));
+
+    let mut tags = BTreeSet::new();
+    let mut stream = BufWriter::new(try!(File::create(dir.join("stream"))));
+    try!(write_stream(entries.into_iter().inspect(|entry| {
+                          tags.insert(entry.tag());
+                      }),
+                      &mut stream));
+    try!(stream.flush());
+
+    let mut metadata = BufWriter::new(try!(File::create(dir.join("metadata"))));
+    try!(write_metadata::<T, _>(&tags, &mut metadata));
+    metadata.flush()
+}
+
+/// Write the TSDL metadata describing a trace of the given tags.
+pub fn write_metadata<T, W>(tags: &BTreeSet<u32>, writer: &mut W) -> io::Result<()>
+    where T: Trace,
+          W: io::Write
+{
+    try!(writer.write_all(METADATA_PREAMBLE.as_bytes()));
+    for &tag in tags {
+        try!(writeln!(writer,
+                      "\nevent {{\n\
+                       \tname = \"{}\";\n\
+                       \tid = {};\n\
+                       \tstream_id = 0;\n\
+                       \tfields := struct entry_fields;\n\
+                       }};",
+                      escape(T::label(tag)),
+                      tag));
+    }
+    Ok(())
+}
+
+/// Write the binary stream of events for the given entries, which should be
+/// in timestamp order.
+///
+/// Clock jumps are left out.
+pub fn write_stream<I, T, W>(entries: I, writer: &mut W) -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          W: io::Write
+{
+    // The packet header: the magic number, and the stream ID.
+    try!(writer.write_all(&MAGIC.to_le_bytes()));
+    try!(writer.write_all(&0u32.to_le_bytes()));
+
+    let mut event = Vec::with_capacity(40);
+    for entry in entries {
+        if entry.kind() == TraceKind::ClockJump {
+            continue;
+        }
+
+        event.clear();
+        event.extend_from_slice(&entry.tag().to_le_bytes());
+        event.extend_from_slice(&entry.timestamp().0.to_le_bytes());
+        event.push(entry.kind() as u8);
+        event.extend_from_slice(&entry.id().to_le_bytes());
+        let thread = entry.thread();
+        event.push(thread.is_some() as u8);
+        event.extend_from_slice(&thread.map_or(0, |thread| thread.0 as u64).to_le_bytes());
+        let why = entry.why();
+        event.push(why.is_some() as u8);
+        event.extend_from_slice(&why.map_or(0, |(_, why)| why).to_le_bytes());
+        let value = entry.duration().or_else(|| entry.counter_value()).unwrap_or(0);
+        event.extend_from_slice(&value.to_le_bytes());
+        try!(writer.write_all(&event));
+    }
+    Ok(())
+}
+
+// Escape a string for a TSDL string literal.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Everything in the metadata but the event classes. Without a packet context,
+// the stream file is a single packet.
+const METADATA_PREAMBLE: &str = "/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+
+trace {
+\tmajor = 1;
+\tminor = 8;
+\tbyte_order = le;
+\tpacket.header := struct {
+\t\tuint32_t magic;
+\t\tuint32_t stream_id;
+\t};
+};
+
+env {
+\ttracer_name = \"eep\";
+};
+
+clock {
+\tname = eep;
+\tdescription = \"Nanoseconds since the epoch\";
+\tfreq = 1000000000;
+\toffset = 0;
+};
+
+typealias integer {
+\tsize = 64; align = 8; signed = false;
+\tmap = clock.eep.value;
+} := uint64_clock_eep_t;
+
+enum trace_kind : uint8_t {
+\tevent = 0,
+\tstart = 1,
+\tstop = 2,
+\tclock_jump = 3,
+\tcancel = 4,
+\tpark = 5,
+\tunpark = 6,
+\tcomplete = 7,
+\tcounter = 8,
+};
+
+struct entry_fields {
+\tenum trace_kind kind;
+\tuint32_t id;
+\tuint8_t has_thread;
+\tuint64_t thread;
+\tuint8_t has_why;
+\tuint32_t why;
+\tuint64_t value;
+};
+
+stream {
+\tid = 0;
+\tevent.header := struct {
+\t\tuint32_t id;
+\t\tuint64_clock_eep_t timestamp;
+\t};
+};
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec;
+    use ring_buffer::NsSinceEpoch;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use std::env;
+    use std::process;
+    use traits::TraceSink;
+
+    #[test]
+    fn writes_metadata_and_stream() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        buffer.trace_stop(parent, SimpleTrace::OperationThing);
+        let entries: Vec<_> = buffer.iter().collect();
+
+        let dir = env::temp_dir().join(format!("eep-ctf-{}", process::id()));
+        write_trace(buffer.iter(), &dir).unwrap();
+        let metadata = fs::read_to_string(dir.join("metadata")).unwrap();
+        let stream = fs::read(dir.join("stream")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(metadata.starts_with("/* CTF 1.8 */"));
+        assert!(metadata.contains("\tname = \"Thing\";\n\tid = 1;\n"));
+        assert!(metadata.contains("\tname = \"Foo\";\n\tid = 0;\n"));
+        assert!(!metadata.contains("\"Another\""));
+
+        // The packet header, then 39 bytes per event.
+        assert_eq!(codec::read_u32(&stream[0..]), MAGIC);
+        assert_eq!(stream.len(), 8 + 3 * 39);
+        let counter = &stream[8 + 39..];
+        assert_eq!(codec::read_u32(&counter[0..]), SimpleTrace::FooEvent.tag());
+        assert_eq!(NsSinceEpoch(codec::read_u64(&counter[4..])), entries[1].timestamp());
+        assert_eq!(counter[12], TraceKind::Counter as u8);
+        assert_eq!(codec::read_u32(&counter[13..]), entries[1].id());
+        assert_eq!(codec::read_u64(&counter[31..]), 42);
+    }
+
+    #[test]
+    fn escapes_labels() {
+        assert_eq!(escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+    }
+}
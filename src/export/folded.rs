@@ -0,0 +1,166 @@
+//! Export nested spans in Brendan Gregg's folded-stacks format, which is
+//! accepted by `flamegraph.pl`, `inferno`, and speedscope.
+//!
+//! Each line is a `;`-separated stack of span labels, outermost first,
+//! followed by a space and the total self time, in nanoseconds, spent with
+//! exactly that stack open. Identical stacks are merged, and lines are sorted
+//! by stack. With `write_folded_with_sources`, entries merged from several
+//! sources are grouped by source, and each source's stacks are rooted at a
+//! frame naming its sink, process and thread.
+//!
+//! ```
+//! use eep::export::folded;
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::testing::TraceBuilder;
+//!
+//! let buffer = TraceBuilder::new()
+//!     .span(SimpleTrace::OperationThing, 0..10, |b| {
+//!         b.leaf_span(SimpleTrace::OperationAnother, 2..5)
+//!     })
+//!     .build();
+//!
+//! let mut out = vec![];
+//! folded::write_folded(buffer.iter(), &mut out).unwrap();
+//! assert_eq!(String::from_utf8(out).unwrap(), "Thing 7\nThing;Another 3\n");
+//! ```
+
+use analysis::{self, Interval, Intervals};
+use ring_buffer::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use thread_local_trace::Source;
+use traits::{ThreadId, Trace};
+
+/// Write the spans in the given entries, which should be in the order they
+/// were traced, as folded stacks.
+///
+/// Spans whose start or stop is missing are skipped, and a span whose parent
+/// is missing is treated as outermost.
+pub fn write_folded<I, T, W>(entries: I, writer: &mut W) -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          W: io::Write
+{
+    let mut stacks = BTreeMap::new();
+    add_stacks(entries, None, &mut stacks);
+    write_stacks(stacks, writer)
+}
+
+/// Like `write_folded`, but for entries merged from several sources, as by
+/// `thread_local_trace::merge_with_sources`. Each source's spans are matched
+/// up separately, and its stacks are rooted at a frame such as
+/// `worker 1234:5`, naming its sink, process and thread.
+pub fn write_folded_with_sources<I, T, W>(entries: I, writer: &mut W) -> io::Result<()>
+    where I: IntoIterator<Item = (Source, Entry<T>)>,
+          T: Trace,
+          W: io::Write
+{
+    let mut stacks = BTreeMap::new();
+    for (source, entries) in analysis::group_by_source(entries) {
+        let root = format!("{} {}:{}", source.sink, source.process, source.thread.0);
+        add_stacks(entries, Some(root.replace(';', ":")), &mut stacks);
+    }
+    write_stacks(stacks, writer)
+}
+
+// Add the self time of each stack of spans in the given entries to `stacks`,
+// below `root` if it's given.
+fn add_stacks<I, T>(entries: I, root: Option<String>, stacks: &mut BTreeMap<String, u64>)
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace
+{
+    let intervals: HashMap<(Option<ThreadId>, u32), Interval> = Intervals::new(entries)
+        .map(|interval| ((interval.thread, interval.id), interval))
+        .collect();
+
+    let mut self_times: HashMap<(Option<ThreadId>, u32), u64> = intervals.iter()
+        .map(|(&key, interval)| (key, interval.duration_ns))
+        .collect();
+    for interval in intervals.values() {
+        if let Some(parent) = interval.parent {
+            if let Some(self_time) = self_times.get_mut(&(interval.thread, parent)) {
+                *self_time = self_time.saturating_sub(interval.duration_ns);
+            }
+        }
+    }
+
+    for (key, self_time) in self_times {
+        if self_time == 0 {
+            continue;
+        }
+
+        let mut frames = vec![];
+        let mut interval = intervals.get(&key);
+        while let Some(i) = interval {
+            frames.push(i.label.replace(';', ":"));
+            interval = i.parent.and_then(|parent| intervals.get(&(i.thread, parent)));
+        }
+        frames.extend(root.clone());
+        frames.reverse();
+
+        *stacks.entry(frames.join(";")).or_insert(0) += self_time;
+    }
+}
+
+fn write_stacks<W>(stacks: BTreeMap<String, u64>, writer: &mut W) -> io::Result<()>
+    where W: io::Write
+{
+    for (stack, self_time) in stacks {
+        try!(writeln!(writer, "{} {}", stack, self_time));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use testing::TraceBuilder;
+    use traits::ThreadId;
+
+    #[test]
+    fn merges_identical_stacks() {
+        let buffer = TraceBuilder::new()
+            .span(SimpleTrace::OperationThing, 0..10, |b| {
+                b.leaf_span(SimpleTrace::OperationAnother, 1..3)
+                    .leaf_span(SimpleTrace::OperationAnother, 4..10)
+            })
+            .span(SimpleTrace::OperationAnother, 20..30, |b| {
+                b.leaf_span(SimpleTrace::OperationThing, 20..30)
+            })
+            .build();
+
+        let mut out = vec![];
+        write_folded(buffer.iter(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "Another;Thing 10\nThing 2\nThing;Another 8\n");
+    }
+
+    #[test]
+    fn roots_stacks_at_their_source() {
+        let source = |thread, sink| {
+            Source {
+                process: 7,
+                thread: ThreadId(thread),
+                sink: sink,
+            }
+        };
+        // Both sources use span ID 0, which must not be matched up across them.
+        let main = TraceBuilder::new()
+            .leaf_span(SimpleTrace::OperationThing, 0..10)
+            .build();
+        let worker = TraceBuilder::new()
+            .leaf_span(SimpleTrace::OperationAnother, 5..8)
+            .build();
+        let mut merged: Vec<_> = main.iter()
+            .map(|entry| (source(1, "main"), entry))
+            .chain(worker.iter().map(|entry| (source(2, "worker"), entry)))
+            .collect();
+        merged.sort_by_key(|&(_, ref entry)| entry.timestamp().0);
+
+        let mut out = vec![];
+        write_folded_with_sources(merged, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "main 7:1;Thing 10\nworker 7:2;Another 3\n");
+    }
+}
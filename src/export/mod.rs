@@ -0,0 +1,11 @@
+//! Exporters that turn captured traces into formats consumed by other tools.
+
+pub mod chrome;
+
+pub mod ctf;
+
+pub mod folded;
+
+pub mod time_series;
+
+pub mod trace_file;
@@ -0,0 +1,284 @@
+//! Summarize many capture sessions (for example, nightly benchmark runs) into a
+//! per-label time series suitable for plotting performance over time.
+//!
+//! Each run contributes a count, a cancelled span count, and the median and
+//! 99th percentile span duration for every label that appears in it. The
+//! resulting `TimeSeries` can be written as CSV with `write_csv`, or serialized
+//! with any `serde` serializer, such as `serde_json`.
+
+extern crate serde;
+
+use analysis::percentile;
+use ring_buffer::{Entry, RingBuffer, TraceKind};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use traits::Trace;
+
+/// Summary statistics for a single label within a single run.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RunSummary {
+    /// The number of one-off events plus completed spans.
+    pub count: usize,
+
+    /// The number of spans that were cancelled rather than completed. These
+    /// are not included in `count` or the percentiles.
+    pub cancelled: usize,
+
+    /// The median duration of completed spans, in nanoseconds, or `None` if
+    /// there were no completed spans.
+    pub p50: Option<u64>,
+
+    /// The 99th percentile duration of completed spans, in nanoseconds, or
+    /// `None` if there were no completed spans.
+    pub p99: Option<u64>,
+}
+
+impl serde::Serialize for RunSummary {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("RunSummary", 4));
+        try!(serializer.serialize_struct_elt(&mut state, "count", self.count));
+        try!(serializer.serialize_struct_elt(&mut state, "cancelled", self.cancelled));
+        try!(serializer.serialize_struct_elt(&mut state, "p50", self.p50));
+        try!(serializer.serialize_struct_elt(&mut state, "p99", self.p99));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+/// A per-label time series of `RunSummary`s across many runs.
+#[derive(Clone, Debug, Default)]
+pub struct TimeSeries {
+    runs: Vec<String>,
+
+    // For every label, one summary per run, or `None` if the label did not
+    // appear in that run.
+    labels: BTreeMap<&'static str, Vec<Option<RunSummary>>>,
+}
+
+impl TimeSeries {
+    /// Construct a new, empty `TimeSeries`.
+    pub fn new() -> TimeSeries {
+        Default::default()
+    }
+
+    /// Summarize the given `buffer` and append it as a new run with the given
+    /// `name`.
+    pub fn add_run<T>(&mut self, name: &str, buffer: &RingBuffer<T>)
+        where T: Trace
+    {
+        self.add_run_entries(name, buffer.iter())
+    }
+
+    /// Summarize the given entries, which should be in the order they were
+    /// traced, and append them as a new run with the given `name`.
+    ///
+    /// This allows summarizing a subset of a capture, for example only the
+    /// entries from one `thread_local_trace::Source`.
+    pub fn add_run_entries<T, I>(&mut self, name: &str, entries: I)
+        where T: Trace,
+              I: IntoIterator<Item = Entry<T>>
+    {
+        let mut counts: HashMap<&'static str, usize> = HashMap::new();
+        let mut cancelled: HashMap<&'static str, usize> = HashMap::new();
+        let mut durations: HashMap<&'static str, Vec<u64>> = HashMap::new();
+        let mut starts = HashMap::new();
+
+        for entry in entries {
+            let label = entry.label();
+            match entry.kind() {
+                TraceKind::Event => {
+                    *counts.entry(label).or_insert(0) += 1;
+                }
+                TraceKind::Start => {
+                    starts.insert((entry.thread(), entry.id()), entry.timestamp().0);
+                }
+                TraceKind::Stop => {
+                    // Stops whose start was already overwritten are skipped.
+                    if let Some(start) = starts.remove(&(entry.thread(), entry.id())) {
+                        *counts.entry(label).or_insert(0) += 1;
+                        durations.entry(label)
+                            .or_insert_with(Vec::new)
+                            .push(entry.timestamp().0.saturating_sub(start));
+                    }
+                }
+                TraceKind::Complete => {
+                    *counts.entry(label).or_insert(0) += 1;
+                    durations.entry(label)
+                        .or_insert_with(Vec::new)
+                        .push(entry.duration().unwrap());
+                }
+                TraceKind::Cancel => {
+                    if starts.remove(&(entry.thread(), entry.id())).is_some() {
+                        counts.entry(label).or_insert(0);
+                        *cancelled.entry(label).or_insert(0) += 1;
+                    }
+                }
+                TraceKind::ClockJump | TraceKind::Park | TraceKind::Unpark |
+                TraceKind::Counter => {}
+            }
+        }
+
+        let previous_runs = self.runs.len();
+        for (label, count) in counts {
+            let (p50, p99) = match durations.get_mut(label) {
+                Some(durations) => {
+                    durations.sort();
+                    (Some(percentile(durations, 50)), Some(percentile(durations, 99)))
+                }
+                None => (None, None),
+            };
+
+            let series = self.labels.entry(label).or_insert_with(|| vec![None; previous_runs]);
+            series.push(Some(RunSummary {
+                count: count,
+                cancelled: cancelled.get(label).cloned().unwrap_or(0),
+                p50: p50,
+                p99: p99,
+            }));
+        }
+
+        // Labels that did not appear in this run still need an entry for it.
+        for series in self.labels.values_mut() {
+            if series.len() == previous_runs {
+                series.push(None);
+            }
+        }
+
+        self.runs.push(name.to_string());
+    }
+
+    /// Get the names of the runs in this time series, in the order they were
+    /// added.
+    pub fn runs(&self) -> &[String] {
+        &self.runs
+    }
+
+    /// Get the per-run summaries for the given label, or `None` if the label
+    /// never appeared in any run.
+    pub fn series(&self, label: &str) -> Option<&[Option<RunSummary>]> {
+        self.labels.get(label).map(|series| &series[..])
+    }
+
+    /// Write this time series as CSV, with one row per run and label.
+    ///
+    /// The columns are `run,label,count,cancelled,p50_ns,p99_ns`. Percentile
+    /// columns are left empty for labels without any completed spans.
+    pub fn write_csv<W>(&self, writer: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        try!(writeln!(writer, "run,label,count,cancelled,p50_ns,p99_ns"));
+        for (i, run) in self.runs.iter().enumerate() {
+            for (label, series) in &self.labels {
+                if let Some(summary) = series[i] {
+                    try!(writeln!(writer,
+                                  "{},{},{},{},{},{}",
+                                  csv_field(run),
+                                  csv_field(label),
+                                  summary.count,
+                                  summary.cancelled,
+                                  summary.p50.map_or(String::new(), |p| p.to_string()),
+                                  summary.p99.map_or(String::new(), |p| p.to_string())));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for TimeSeries {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("TimeSeries", 2));
+        try!(serializer.serialize_struct_elt(&mut state, "runs", &self.runs));
+        try!(serializer.serialize_struct_elt(&mut state, "labels", &self.labels));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use testing::TraceBuilder;
+    use traits::TraceSink;
+
+    #[test]
+    fn runs_and_missing_labels() {
+        let mut first = SimpleTraceBuffer::default();
+        first.trace_event(SimpleTrace::FooEvent, None);
+        first.trace_event(SimpleTrace::FooEvent, None);
+
+        let mut second = SimpleTraceBuffer::default();
+        let id = second.trace_start(SimpleTrace::OperationThing, None);
+        second.trace_stop(id, SimpleTrace::OperationThing);
+
+        let mut series = TimeSeries::new();
+        series.add_run("first", &first);
+        series.add_run("second", &second);
+
+        assert_eq!(series.runs(), &["first".to_string(), "second".to_string()]);
+
+        let foo = series.series("Foo").unwrap();
+        assert_eq!(foo,
+                   &[Some(RunSummary {
+                         count: 2,
+                         cancelled: 0,
+                         p50: None,
+                         p99: None,
+                     }),
+                     None]);
+
+        let thing = series.series("Thing").unwrap();
+        assert_eq!(thing[0], None);
+        let thing = thing[1].unwrap();
+        assert_eq!(thing.count, 1);
+        assert!(thing.p50.is_some());
+        assert_eq!(thing.p50, thing.p99);
+
+        assert_eq!(series.series("Another"), None);
+    }
+
+    #[test]
+    fn span_durations() {
+        let buffer = TraceBuilder::new()
+            .leaf_span(SimpleTrace::OperationThing, 0..10)
+            .leaf_span(SimpleTrace::OperationThing, 20..40)
+            .leaf_span(SimpleTrace::OperationThing, 50..80)
+            .build();
+
+        let mut series = TimeSeries::new();
+        series.add_run("synthetic", &buffer);
+
+        assert_eq!(series.series("Thing").unwrap(),
+                   &[Some(RunSummary {
+                         count: 3,
+                         cancelled: 0,
+                         p50: Some(20),
+                         p99: Some(30),
+                     })]);
+    }
+
+    #[test]
+    fn csv() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let mut series = TimeSeries::new();
+        series.add_run("nightly, 1", &buffer);
+
+        let mut csv = vec![];
+        series.write_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(),
+                   "run,label,count,cancelled,p50_ns,p99_ns\n\"nightly, 1\",Foo,1,0,,\n");
+    }
+}
@@ -0,0 +1,301 @@
+//! A compact, streaming trace file format, with pluggable codecs for entries'
+//! timestamps and tags.
+//!
+//! A trace file is a magic number followed by one record per entry: the entry's
+//! flags byte, its tag and timestamp as encoded by the file's codecs, and then
+//! its remaining fields as varints. Files don't record which codecs wrote them,
+//! so they must be read with the same ones.
+//!
+//! The names of events traced with `trace_event_named` are written in names
+//! records, interleaved with the entries: `NAMES`, the number of names as a
+//! varint, and then each name's length in bytes as a varint followed by its
+//! UTF-8. Each names record appends to the file's table of names, which named
+//! entries refer to by ID, and which `read_with_names` returns.
+//!
+//! ```
+//! use eep::codec::{DoubleDelta, Leb128};
+//! use eep::export::trace_file::{self, TraceFileWriter};
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! buffer.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! let mut writer = TraceFileWriter::new(vec![], DoubleDelta::default(), Leb128).unwrap();
+//! writer.write_entries(buffer.iter()).unwrap();
+//! let file = writer.into_inner().unwrap();
+//!
+//! let entries: Vec<_> =
+//!     trace_file::read::<_, SimpleTrace, _, _>(&mut &file[..], DoubleDelta::default(), Leb128)
+//!         .unwrap();
+//! assert_eq!(entries, buffer.iter().collect::<Vec<_>>());
+//! ```
+
+use codec::{self, Codec};
+use ring_buffer::{Entry, NsSinceEpoch, MAX_VARINT_FIELDS_SIZE};
+use std::io;
+use traits::Trace;
+
+/// The bytes every trace file begins with, the last of which is the format's
+/// version.
+pub const MAGIC: &[u8; 8] = b"EEPTRAC\x02";
+
+/// The first byte of a names record, which is never an entry's flags byte.
+pub const NAMES: u8 = 0x0f;
+
+/// Writes entries to a trace file, encoding their timestamps with `C` and
+/// their tags with `D`.
+#[derive(Debug)]
+pub struct TraceFileWriter<W, C, D> {
+    writer: W,
+    timestamps: C,
+    tags: D,
+    record: Vec<u8>,
+    // The number of names already written in names records.
+    names_written: usize,
+}
+
+impl<W, C, D> TraceFileWriter<W, C, D>
+    where W: io::Write,
+          C: Codec,
+          D: Codec
+{
+    /// Start a trace file in `writer` whose timestamps are encoded with
+    /// `timestamps` and whose tags are encoded with `tags`.
+    pub fn new(mut writer: W, timestamps: C, tags: D) -> io::Result<TraceFileWriter<W, C, D>> {
+        try!(writer.write_all(MAGIC));
+        Ok(TraceFileWriter {
+            writer: writer,
+            timestamps: timestamps,
+            tags: tags,
+            record: vec![],
+            names_written: 0,
+        })
+    }
+
+    /// Append `entry` to the file.
+    pub fn write_entry<T>(&mut self, entry: &Entry<T>) -> io::Result<()>
+        where T: Trace
+    {
+        self.record.clear();
+        self.record.push(entry.flags());
+        self.tags.encode(entry.tag() as u64, &mut self.record);
+        self.timestamps.encode(entry.timestamp().0, &mut self.record);
+        let mut fields = [0; MAX_VARINT_FIELDS_SIZE];
+        let len = entry.encode_varint_fields(&mut fields);
+        self.record.extend_from_slice(&fields[..len]);
+        self.writer.write_all(&self.record)
+    }
+
+    /// Append every entry in `entries` to the file, in order.
+    pub fn write_entries<I, T>(&mut self, entries: I) -> io::Result<()>
+        where I: IntoIterator<Item = Entry<T>>,
+              T: Trace
+    {
+        for entry in entries {
+            try!(self.write_entry(&entry));
+        }
+        Ok(())
+    }
+
+    /// Append the names in `names`, indexed by ID, as returned by
+    /// `RingBuffer::names`, that haven't already been written, so that the
+    /// file can be written while the buffer is still interning names.
+    pub fn write_names(&mut self, names: &[String]) -> io::Result<()> {
+        if names.len() <= self.names_written {
+            return Ok(());
+        }
+        let new = &names[self.names_written..];
+        let mut varint = [0; codec::MAX_VARINT_LEN];
+        self.record.clear();
+        self.record.push(NAMES);
+        let len = codec::write_varint(&mut varint, new.len() as u64);
+        self.record.extend_from_slice(&varint[..len]);
+        for name in new {
+            let len = codec::write_varint(&mut varint, name.len() as u64);
+            self.record.extend_from_slice(&varint[..len]);
+            self.record.extend_from_slice(name.as_bytes());
+        }
+        try!(self.writer.write_all(&self.record));
+        self.names_written = names.len();
+        Ok(())
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+}
+
+/// Read every entry in the trace file in `reader`, which was written with
+/// codecs in the same initial states as `timestamps` and `tags`.
+///
+/// Returns an error of kind `InvalidData` if the file is not a trace file, or
+/// is truncated or corrupt.
+pub fn read<R, T, C, D>(reader: &mut R, timestamps: C, tags: D) -> io::Result<Vec<Entry<T>>>
+    where R: io::Read,
+          T: Trace,
+          C: Codec,
+          D: Codec
+{
+    read_with_names(reader, timestamps, tags).map(|(entries, _)| entries)
+}
+
+/// Like `read`, but also return the file's names, indexed by ID, which named
+/// entries refer to.
+pub fn read_with_names<R, T, C, D>(reader: &mut R,
+                                   mut timestamps: C,
+                                   mut tags: D)
+                                   -> io::Result<(Vec<Entry<T>>, Vec<String>)>
+    where R: io::Read,
+          T: Trace,
+          C: Codec,
+          D: Codec
+{
+    let mut file = vec![];
+    try!(reader.read_to_end(&mut file));
+    if !file.starts_with(MAGIC) {
+        return Err(invalid_data("not an eep trace file"));
+    }
+
+    let mut bytes = file[MAGIC.len()..].iter().cloned();
+    let mut entries = vec![];
+    let mut names = vec![];
+    while let Some(flags) = bytes.next() {
+        if flags == NAMES {
+            match read_names(&mut bytes) {
+                Some(new) => names.extend(new),
+                None => return Err(invalid_data("truncated or corrupt trace file names")),
+            }
+            continue;
+        }
+        let entry = tags.decode(&mut bytes)
+            .and_then(|tag| if tag <= u32::MAX as u64 { Some(tag as u32) } else { None })
+            .and_then(|tag| {
+                timestamps.decode(&mut bytes).and_then(|timestamp| {
+                    Entry::decode_varint_fields(flags, tag, NsSinceEpoch(timestamp), &mut bytes)
+                })
+            });
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => return Err(invalid_data("truncated or corrupt trace file entry")),
+        }
+    }
+    Ok((entries, names))
+}
+
+fn read_names<I>(bytes: &mut I) -> Option<Vec<String>>
+    where I: Iterator<Item = u8>
+{
+    let count = codec::read_varint(bytes)?;
+    let mut names = vec![];
+    for _ in 0..count {
+        let len = codec::read_varint(bytes)? as usize;
+        let name: Vec<u8> = bytes.take(len).collect();
+        if name.len() != len {
+            return None;
+        }
+        names.push(String::from_utf8(name).ok()?);
+    }
+    Some(names)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::{DeltaLeb128, DoubleDelta, Leb128, Raw};
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::TraceSink;
+
+    type SimpleEntry = Entry<SimpleTrace>;
+
+    fn buffer() -> SimpleTraceBuffer {
+        let mut buffer = SimpleTraceBuffer::new(4096);
+        let parent = buffer.trace_start(SimpleTrace::OperationThing, None);
+        for i in 0..10 {
+            buffer.trace_counter(SimpleTrace::FooEvent, i << 30);
+            buffer.trace_event(SimpleTrace::FooEvent, Some(parent));
+        }
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        buffer.trace_complete(SimpleTrace::OperationAnother,
+                              NsSinceEpoch(5),
+                              NsSinceEpoch(1_000_000_005),
+                              Some(parent));
+        buffer.trace_stop(parent, SimpleTrace::OperationThing);
+        buffer
+    }
+
+    fn round_trip<C, D>(timestamps: C, tags: D) -> usize
+        where C: Clone + Codec,
+              D: Clone + Codec
+    {
+        let buffer = buffer();
+        let entries: Vec<SimpleEntry> = buffer.iter().collect();
+        let mut writer = TraceFileWriter::new(vec![], timestamps.clone(), tags.clone()).unwrap();
+        writer.write_names(buffer.names()).unwrap();
+        writer.write_entries(entries.iter().cloned()).unwrap();
+        let file = writer.into_inner().unwrap();
+
+        let (decoded, names): (Vec<SimpleEntry>, _) =
+            read_with_names(&mut &file[..], timestamps.clone(), tags.clone()).unwrap();
+        assert_eq!(decoded, entries);
+        assert_eq!(names, buffer.names());
+
+        // A truncated file is either an error, or, if cut between records,
+        // the entries before the cut.
+        for len in MAGIC.len()..file.len() {
+            let truncated = read::<_, SimpleTrace, _, _>(&mut &file[..len],
+                                                         timestamps.clone(),
+                                                         tags.clone());
+            match truncated {
+                Ok(truncated) => assert!(entries[..entries.len() - 1].starts_with(&truncated)),
+                Err(error) => assert_eq!(error.kind(), io::ErrorKind::InvalidData),
+            }
+        }
+        file.len()
+    }
+
+    #[test]
+    fn round_trips_with_every_codec() {
+        let raw = round_trip(Raw, Raw);
+        let leb128 = round_trip(Leb128, Leb128);
+        let delta = round_trip(DeltaLeb128::default(), Leb128);
+        let double_delta = round_trip(DoubleDelta::default(), DeltaLeb128::default());
+        assert!(leb128 < raw);
+        assert!(delta < leb128);
+        assert!(double_delta < raw);
+    }
+
+    #[test]
+    fn appends_new_names() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let mut writer = TraceFileWriter::new(vec![], Leb128, Leb128).unwrap();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        writer.write_names(buffer.names()).unwrap();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/passwd");
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        writer.write_names(buffer.names()).unwrap();
+        writer.write_names(buffer.names()).unwrap();
+        writer.write_entries(buffer.iter()).unwrap();
+        let file = writer.into_inner().unwrap();
+
+        let (entries, names) =
+            read_with_names::<_, SimpleTrace, _, _>(&mut &file[..], Leb128, Leb128).unwrap();
+        assert_eq!(names, ["/etc/hosts", "/etc/passwd"]);
+        let named: Vec<_> = entries.iter()
+            .map(|entry| &names[entry.name().unwrap() as usize][..])
+            .collect();
+        assert_eq!(named, ["/etc/hosts", "/etc/passwd", "/etc/hosts"]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let error = read::<_, SimpleTrace, _, _>(&mut &b"not a trace"[..], Raw, Raw).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,181 @@
+//! Human-readable text dumps of trace buffers, for debugging.
+//!
+//! `dump` writes one line per entry, in the order they were traced: the time
+//! since the first entry, then the entry's kind, label, ID, and name, if it
+//! has one, and what caused it, if anything. Entries are indented by how many
+//! spans were open on their thread when they were traced. Completes are listed
+//! when they ended, with their duration.
+//!
+//! ```
+//! use eep::fmt;
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::testing::TraceBuilder;
+//!
+//! let buffer = TraceBuilder::new()
+//!     .span(SimpleTrace::OperationThing, 0..5_000_000, |b| {
+//!         b.event(SimpleTrace::FooEvent, 2_000_000)
+//!     })
+//!     .build();
+//!
+//! let mut out = vec![];
+//! fmt::dump(&buffer, &mut out).unwrap();
+//! print!("{}", String::from_utf8(out).unwrap());
+//! ```
+//!
+//! prints something like:
+//!
+//! ```text
+//!    +0.000000ms Start Thing #1
+//!    +2.000000ms   Event Foo #2
+//!    +5.000000ms Stop Thing #1
+//! ```
+
+use ring_buffer::{Entry, RingBuffer, TraceKind};
+use std::collections::HashMap;
+use std::io;
+use traits::{ThreadId, Trace};
+
+/// Write a chronological, indented listing of every entry in `buffer`.
+pub fn dump<T, W>(buffer: &RingBuffer<T>, writer: &mut W) -> io::Result<()>
+    where T: Trace,
+          W: io::Write
+{
+    write_entries_with_names(buffer.iter(), buffer.names(), writer)
+}
+
+/// Like `dump`, but for any entries, which should be in the order they were
+/// traced. Events traced with `trace_event_named` are listed without their
+/// names.
+pub fn write_entries<I, T, W>(entries: I, writer: &mut W) -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          W: io::Write
+{
+    write_entries_with_names(entries, &[], writer)
+}
+
+/// Like `write_entries`, but look up the names of events traced with
+/// `trace_event_named` in `names`, as returned by `RingBuffer::names` or
+/// `TraceSnapshot::names`, and list them too.
+pub fn write_entries_with_names<I, T, W>(entries: I,
+                                         names: &[String],
+                                         writer: &mut W)
+                                         -> io::Result<()>
+    where I: IntoIterator<Item = Entry<T>>,
+          T: Trace,
+          W: io::Write
+{
+    // The IDs of each thread's open spans, outermost first.
+    let mut open: HashMap<Option<ThreadId>, Vec<u32>> = HashMap::new();
+    let mut first = None;
+
+    for entry in entries {
+        let first = *first.get_or_insert(entry.timestamp().0);
+        let stack = open.entry(entry.thread()).or_default();
+
+        let depth = match entry.kind() {
+            TraceKind::Stop | TraceKind::Cancel => {
+                match stack.iter().rposition(|&id| id == entry.id()) {
+                    Some(position) => {
+                        stack.truncate(position);
+                        position
+                    }
+                    None => stack.len(),
+                }
+            }
+            _ => stack.len(),
+        };
+        if entry.kind() == TraceKind::Start {
+            stack.push(entry.id());
+        }
+
+        try!(write!(writer,
+                    "{:>14} {:indent$}{:?} {} #{}",
+                    format_ms(entry.timestamp().0.wrapping_sub(first) as i64, "+"),
+                    "",
+                    entry.kind(),
+                    entry.label(),
+                    entry.id(),
+                    indent = 2 * depth));
+        if let Some(name) = entry.name().and_then(|id| names.get(id as usize)) {
+            try!(write!(writer, " {:?}", name));
+        }
+        if let Some(duration) = entry.duration() {
+            try!(write!(writer, " for {}", format_ms(duration as i64, "")));
+        }
+        if let Some(value) = entry.counter_value() {
+            try!(write!(writer, " = {}", value));
+        }
+        if let Some((_, why)) = entry.why() {
+            try!(write!(writer, " why #{}", why));
+        }
+        try!(writeln!(writer));
+    }
+    Ok(())
+}
+
+// Format nanoseconds as milliseconds, with nanosecond precision.
+fn format_ms(ns: i64, plus: &str) -> String {
+    let sign = if ns < 0 { "-" } else { plus };
+    let ns = ns.unsigned_abs();
+    format!("{}{}.{:06}ms", sign, ns / 1_000_000, ns % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::NsSinceEpoch;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use testing::TraceBuilder;
+    use traits::TraceSink;
+
+    #[test]
+    fn indents_nested_spans() {
+        let buffer = TraceBuilder::new()
+            .starting_at(NsSinceEpoch(1_000_000_000))
+            .span(SimpleTrace::OperationThing, 0..10_000_000, |b| {
+                b.leaf_span(SimpleTrace::OperationAnother, 1_500..2_000_000)
+                    .event(SimpleTrace::FooEvent, 3_000_000)
+            })
+            .complete(SimpleTrace::OperationAnother, 12_000_000..12_250_000)
+            .build();
+        let ids: Vec<_> = buffer.iter().map(|e| e.id()).collect();
+
+        let mut out = vec![];
+        dump(&buffer, &mut out).unwrap();
+        let expected = format!("   +0.000000ms Start Thing #{}\n\
+                                \x20  +0.001500ms   Start Another #{}\n\
+                                \x20  +2.000000ms   Stop Another #{}\n\
+                                \x20  +3.000000ms   Event Foo #{}\n\
+                                \x20 +10.000000ms Stop Thing #{}\n\
+                                \x20 +12.250000ms Complete Another #{} for 0.250000ms\n",
+                               ids[0],
+                               ids[1],
+                               ids[1],
+                               ids[3],
+                               ids[0],
+                               ids[5]);
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn why_counters_and_cancels() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        let child = buffer.trace_event(SimpleTrace::FooEvent, Some(parent));
+        buffer.trace_cancel(parent, SimpleTrace::OperationThing);
+        let named = buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+
+        let mut out = vec![];
+        dump(&buffer, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().map(|line| line.split_at(15).1).collect();
+        assert_eq!(lines,
+                   vec![format!("Start Thing #{}", parent.0),
+                        format!("  Counter Foo #{} = 42", buffer.iter().nth(1).unwrap().id()),
+                        format!("  Event Foo #{} why #{}", child.0, parent.0),
+                        format!("Cancel Thing #{}", parent.0),
+                        format!("Event Foo #{} \"/etc/hosts\"", named.0)]);
+    }
+}
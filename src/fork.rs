@@ -0,0 +1,135 @@
+//! Keep the `global` sinks and `thread_local_trace` buffers consistent across
+//! `fork()`, for daemons and pre-forking servers that trace before forking.
+//!
+//! `fork()` only copies the calling thread into the child, so any lock another
+//! thread held at that moment stays locked in the child forever. Once
+//! `install_handlers` has been called, every registry lock is taken just
+//! before forking and released just after, in both processes.
+//!
+//! After a fork:
+//!
+//! * The parent carries on exactly as before.
+//!
+//! * The child starts over with no global sinks installed, and no thread-local
+//!   buffers. The next time the forking thread traces into a
+//!   `ThreadLocalSink`, it gets a new, empty buffer, registered with the
+//!   child's process ID. Nothing traced before the fork is merged or exported
+//!   a second time from the child.
+//!
+//! Sinks that aren't registered with either module, such as a `RingBuffer`
+//! owned by the forking thread, are copied into the child as they are.
+//!
+//! ```
+//! use eep::fork;
+//!
+//! // At startup, before forking.
+//! fork::install_handlers();
+//! ```
+
+extern crate libc;
+
+use global;
+use std::cell::RefCell;
+use std::sync::Once;
+use thread_local_trace;
+
+static INSTALL: Once = Once::new();
+
+// The locks taken before forking, on the forking thread, which is the only
+// thread that continues in the child.
+thread_local!(static HELD: RefCell<Option<(global::ForkGuard, thread_local_trace::ForkGuard)>> =
+                  const { RefCell::new(None) });
+
+/// Register `pthread_atfork` handlers that make forking safe while other
+/// threads trace. Calling this more than once is harmless.
+///
+/// # Panics
+///
+/// Panics if the handlers can't be registered.
+pub fn install_handlers() {
+    INSTALL.call_once(|| {
+        let ret = unsafe { libc::pthread_atfork(Some(prepare), Some(parent), Some(child)) };
+        assert_eq!(ret, 0, "pthread_atfork failed");
+    });
+}
+
+extern "C" fn prepare() {
+    // Same order as any thread that traces into a `ThreadLocalSink` installed
+    // as a global sink: the global slots, then the thread-local registry and
+    // clock.
+    let held = (global::prepare_fork(), thread_local_trace::prepare_fork());
+    let _ = HELD.try_with(|h| *h.borrow_mut() = Some(held));
+}
+
+extern "C" fn parent() {
+    let _ = HELD.try_with(|h| h.borrow_mut().take());
+}
+
+extern "C" fn child() {
+    let _ = HELD.try_with(|h| {
+        if let Some((global, thread_local)) = h.borrow_mut().take() {
+            global::after_fork_child(global);
+            thread_local_trace::after_fork_child(thread_local);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTraceId;
+    use std::panic;
+    use thread_local_trace::ThreadLocalSink;
+    use traits::{Trace, TraceSink};
+
+    // A trace type only used by this test, so that other tests' threads don't
+    // add to its buffers.
+    #[derive(Copy, Clone, Debug)]
+    struct ForkTrace;
+
+    impl Trace for ForkTrace {
+        type Id = SimpleTraceId;
+
+        fn label(_tag: u32) -> &'static str {
+            "Fork"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    fn in_child() -> bool {
+        // Nothing traced before the fork is visible.
+        if thread_local_trace::merge::<ForkTrace>().count() != 0 {
+            return false;
+        }
+
+        ThreadLocalSink::get().trace_event(ForkTrace, None);
+        let sources: Vec<_> = thread_local_trace::merge_with_sources::<ForkTrace>()
+            .map(|(source, _)| source.process)
+            .collect();
+        sources == vec![unsafe { libc::getpid() } as u32]
+    }
+
+    #[test]
+    fn child_starts_over() {
+        install_handlers();
+        ThreadLocalSink::get().trace_event(ForkTrace, None);
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Never return into the test harness from the child.
+            let ok = panic::catch_unwind(in_child).unwrap_or(false);
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        assert_eq!(thread_local_trace::merge::<ForkTrace>().count(), 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! A process-wide sink per `Trace` type, so that library code can be
+//! instrumented without threading a `TraceSink` through every call, and the
+//! application chooses where traces go at startup.
+//!
+//! Until a sink is installed, tracing through `global()` does nothing.
+//!
+//! ```
+//! use eep::global;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! // In a library:
+//! fn do_foo() {
+//!     global::global().trace_event(SimpleTrace::FooEvent, None);
+//! }
+//!
+//! // In the application:
+//! global::set_global_sink(SimpleTraceBuffer::default());
+//! do_foo();
+//! global::clear_global_sink::<SimpleTrace>();
+//! ```
+//!
+//! Every trace locks the installed sink for its `Trace` type, so sinks must
+//! not trace into the global sink themselves, or they will deadlock.
+
+use ring_buffer::NsSinceEpoch;
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+#[cfg(all(feature = "fork", unix))]
+use std::mem;
+use std::sync::{Mutex, PoisonError};
+#[cfg(all(feature = "fork", unix))]
+use std::sync::MutexGuard;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use traits::{BatchKind, Trace, TraceId, TraceSink};
+
+/// A boxed sink, as installed with `set_global_sink`.
+pub type BoxedSink<T> = Box<dyn TraceSink<T> + Send>;
+
+// Each slot holds a `BoxedSink<T>` for the trace type it is keyed by. Slots are
+// type-erased so that they can all be locked together around a `fork`.
+type Slot = Mutex<Option<Box<dyn Any + Send>>>;
+
+// Each trace type's slot, leaked so that it can be handed out without holding
+// the registry's lock.
+static SLOTS: Mutex<Vec<(TypeId, &'static Slot)>> = Mutex::new(Vec::new());
+
+// The number of installed sinks, so that tracing can skip all locking when
+// there are none.
+static INSTALLED: AtomicUsize = AtomicUsize::new(0);
+
+// The slots this thread has already looked up, so that tracing doesn't contend
+// on the registry's lock. Slots are never removed, so these never go stale.
+thread_local!(static CACHED_SLOTS: RefCell<Vec<(TypeId, &'static Slot)>> =
+                  RefCell::new(Vec::new()));
+
+fn slot<T>() -> &'static Slot
+    where T: 'static + Trace
+{
+    let cached = CACHED_SLOTS.try_with(|cached| {
+        cached.borrow().iter().find(|&&(t, _)| t == TypeId::of::<T>()).map(|&(_, slot)| slot)
+    });
+    if let Ok(Some(slot)) = cached {
+        return slot;
+    }
+
+    let slot = registered_slot::<T>();
+    // This thread's cache is gone if it is exiting, but the slot can still be
+    // looked up in the registry.
+    let _ = CACHED_SLOTS.try_with(|cached| cached.borrow_mut().push((TypeId::of::<T>(), slot)));
+    slot
+}
+
+fn registered_slot<T>() -> &'static Slot
+    where T: 'static + Trace
+{
+    let mut slots = SLOTS.lock().unwrap();
+    if let Some(&(_, slot)) = slots.iter().find(|&&(t, _)| t == TypeId::of::<T>()) {
+        return slot;
+    }
+    let slot: &'static Slot = Box::leak(Box::new(Mutex::new(None)));
+    slots.push((TypeId::of::<T>(), slot));
+    slot
+}
+
+fn downcast<T>(sink: Box<dyn Any + Send>) -> BoxedSink<T>
+    where T: 'static + Trace
+{
+    *sink.downcast().expect("slots are keyed by their trace type")
+}
+
+/// Install `sink` as the global sink for `T`, returning the previously
+/// installed sink, if any.
+pub fn set_global_sink<S, T>(sink: S) -> Option<BoxedSink<T>>
+    where S: 'static + Send + TraceSink<T>,
+          T: 'static + Trace
+{
+    let sink: BoxedSink<T> = Box::new(sink);
+    let previous = slot::<T>()
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .replace(Box::new(sink));
+    if previous.is_none() {
+        INSTALLED.fetch_add(1, Ordering::AcqRel);
+    }
+    previous.map(downcast)
+}
+
+/// Uninstall and return the global sink for `T`, if any. Tracing through
+/// `global()` does nothing again afterwards.
+pub fn clear_global_sink<T>() -> Option<BoxedSink<T>>
+    where T: 'static + Trace
+{
+    let previous = slot::<T>().lock().unwrap_or_else(PoisonError::into_inner).take();
+    if previous.is_some() {
+        INSTALLED.fetch_sub(1, Ordering::AcqRel);
+    }
+    previous.map(downcast)
+}
+
+/// Get a handle to the global sink for `T`.
+pub fn global<T>() -> GlobalSink<T> {
+    GlobalSink(PhantomData)
+}
+
+fn with_global_sink<T, R, F>(f: F) -> Option<R>
+    where T: 'static + Trace,
+          F: FnOnce(&mut (dyn TraceSink<T> + Send)) -> R
+{
+    if INSTALLED.load(Ordering::Acquire) == 0 {
+        return None;
+    }
+    // A sink that panicked mid-trace is still usable, and tracing shouldn't
+    // turn one panic into a panic on every thread.
+    let mut installed = slot::<T>().lock().unwrap_or_else(PoisonError::into_inner);
+    installed.as_mut().map(|sink| {
+        let sink = sink.downcast_mut::<BoxedSink<T>>()
+            .expect("slots are keyed by their trace type");
+        f(&mut **sink)
+    })
+}
+
+/// Every lock in the registry, held from just before a `fork` until just
+/// after it, so that no other thread holds one at the moment of forking.
+#[cfg(all(feature = "fork", unix))]
+pub(crate) struct ForkGuard {
+    _slots: MutexGuard<'static, Vec<(TypeId, &'static Slot)>>,
+    locked: Vec<MutexGuard<'static, Option<Box<dyn Any + Send>>>>,
+}
+
+#[cfg(all(feature = "fork", unix))]
+pub(crate) fn prepare_fork() -> ForkGuard {
+    let slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+    let locked = slots.iter()
+        .map(|&(_, slot)| slot.lock().unwrap_or_else(|e| e.into_inner()))
+        .collect();
+    ForkGuard {
+        _slots: slots,
+        locked: locked,
+    }
+}
+
+/// Uninstall every sink in the child, so that it doesn't trace on top of the
+/// parent's entries.
+#[cfg(all(feature = "fork", unix))]
+pub(crate) fn after_fork_child(mut guard: ForkGuard) {
+    for slot in &mut guard.locked {
+        // Forget the parent's sinks rather than dropping them, so that their
+        // destructors don't flush the parent's entries a second time.
+        if let Some(sink) = slot.take() {
+            mem::forget(sink);
+        }
+    }
+    INSTALLED.store(0, Ordering::Release);
+}
+
+/// A `TraceSink` that writes into the global sink for `T`, if one is
+/// installed, and otherwise does nothing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GlobalSink<T>(PhantomData<T>);
+
+impl<T> TraceSink<T> for GlobalSink<T>
+    where T: 'static + Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        with_global_sink(|sink| sink.trace_event(trace, why)).unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        with_global_sink(|sink| sink.trace_start(trace, why)).unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        with_global_sink(|sink| sink.trace_stop(id, trace));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        with_global_sink(|sink| sink.trace_cancel(id, trace));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        with_global_sink(|sink| sink.trace_complete(trace, start, end, why))
+            .unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        with_global_sink(|sink| sink.trace_park(trace));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        with_global_sink(|sink| sink.trace_unpark(trace));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        with_global_sink(|sink| sink.trace_counter(trace, value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        with_global_sink(|sink| sink.trace_event_named(trace, name))
+            .unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        with_global_sink(|sink| sink.trace_batch(traces));
+    }
+
+    fn max_depth(&self) -> usize {
+        with_global_sink::<T, _, _>(|sink| sink.max_depth()).unwrap_or(usize::MAX)
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        with_global_sink(|sink| sink.trace_depth_exceeded(trace, depth));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTraceId;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    // A trace type only used by this test, so other tests can't install
+    // global sinks for it concurrently.
+    #[derive(Copy, Clone, Debug)]
+    struct GlobalTrace;
+
+    impl Trace for GlobalTrace {
+        type Id = SimpleTraceId;
+
+        fn label(_tag: u32) -> &'static str {
+            "Global"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl TraceSink<GlobalTrace> for CountingSink {
+        fn trace_event(&mut self, _: GlobalTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            SimpleTraceId::new_id()
+        }
+
+        fn trace_start(&mut self, _: GlobalTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            SimpleTraceId::new_id()
+        }
+
+        fn trace_stop(&mut self, _: SimpleTraceId, _: GlobalTrace) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn install_and_uninstall() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        // Nothing is installed yet, so this goes nowhere.
+        global().trace_event(GlobalTrace, None);
+
+        assert!(set_global_sink(CountingSink(count.clone())).is_none());
+        global().trace_event(GlobalTrace, None);
+        let id = global().trace_start(GlobalTrace, None);
+        global().trace_stop(id, GlobalTrace);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        assert!(set_global_sink(CountingSink(count.clone())).is_some());
+        assert!(clear_global_sink::<GlobalTrace>().is_some());
+        assert!(clear_global_sink::<GlobalTrace>().is_none());
+
+        global().trace_event(GlobalTrace, None);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // This thread has already looked up the slot, and sees the new sink,
+        // as do others.
+        assert!(set_global_sink(CountingSink(count.clone())).is_none());
+        global().trace_event(GlobalTrace, None);
+        thread::spawn(|| global().trace_event(GlobalTrace, None)).join().unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 5);
+        assert!(clear_global_sink::<GlobalTrace>().is_some());
+    }
+
+    // A trace type only used by `survives_a_panicking_sink`.
+    #[derive(Copy, Clone, Debug)]
+    struct PanickyTrace;
+
+    impl Trace for PanickyTrace {
+        type Id = SimpleTraceId;
+
+        fn label(_tag: u32) -> &'static str {
+            "Panicky"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    // Panics on every start, while the global sink's lock is held.
+    struct PanickingSink(Arc<AtomicUsize>);
+
+    impl TraceSink<PanickyTrace> for PanickingSink {
+        fn trace_event(&mut self, _: PanickyTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            SimpleTraceId::new_id()
+        }
+
+        fn trace_start(&mut self, _: PanickyTrace, _: Option<SimpleTraceId>) -> SimpleTraceId {
+            panic!("start")
+        }
+
+        fn trace_stop(&mut self, _: SimpleTraceId, _: PanickyTrace) {}
+    }
+
+    #[test]
+    fn survives_a_panicking_sink() {
+        let count = Arc::new(AtomicUsize::new(0));
+        set_global_sink(PanickingSink(count.clone()));
+
+        assert!(thread::spawn(|| global().trace_start(PanickyTrace, None)).join().is_err());
+        global().trace_event(PanickyTrace, None);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(clear_global_sink::<PanickyTrace>().is_some());
+    }
+}
@@ -0,0 +1,390 @@
+//! Trace the whole lifetime of a future as a single span, even as it hops
+//! between threads across `.await`s.
+//!
+//! `Instrumented` starts its span when the future is first polled, and stops it
+//! when the future completes. A future that is dropped before completing has
+//! its span cancelled. While the future is being polled, on whichever thread
+//! polls it, `current` returns the span's ID, so that traces made from inside
+//! the future can name it as their `why`.
+//!
+//! ```edition2018
+//! use eep::instrument::{self, Instrumented};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::thread_local_trace::ThreadLocalSink;
+//! use eep::traits::TraceSink;
+//!
+//! async fn handle_request() {
+//!     let why = instrument::current::<SimpleTrace>();
+//!     ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, why);
+//! }
+//!
+//! let future = Instrumented::new(handle_request(),
+//!                                SimpleTrace::OperationThing,
+//!                                None,
+//!                                ThreadLocalSink::get());
+//! # drop(future);
+//! ```
+//!
+//! The sink is carried along with the future, so it should be one that can be
+//! used from any thread, such as a `ThreadLocalSink`, a `GlobalSink`, or a
+//! `WeakSinkHandle`.
+//!
+//! Polling an `Instrumented` future inside another's poll nests their spans.
+//! To keep instrumented recursive code from growing the stack of current spans
+//! without bound, a sink's `TraceSink::max_depth` limits how deeply spans
+//! traced into it nest. A future polled beyond that depth is still traced, but
+//! is not made `current`, and the sink is told with `trace_depth_exceeded`,
+//! once, which a `RingBuffer` counts in its stats.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use traits::{Trace, TraceSink};
+
+// For each trace type, a `Vec<T::Id>` of the IDs of the spans of the
+// `Instrumented` futures being polled on this thread, innermost last.
+thread_local!(static CURRENT: RefCell<Vec<(TypeId, Box<dyn Any>)>> =
+                  RefCell::new(Vec::new()));
+
+// Get the stack of current spans for `T` out of `CURRENT`, adding it if it
+// isn't there yet.
+fn stack<T>(current: &mut Vec<(TypeId, Box<dyn Any>)>) -> &mut Vec<T::Id>
+    where T: 'static + Trace
+{
+    let index = match current.iter().position(|&(ty, _)| ty == TypeId::of::<T>()) {
+        Some(index) => index,
+        None => {
+            current.push((TypeId::of::<T>(), Box::new(Vec::<T::Id>::new())));
+            current.len() - 1
+        }
+    };
+    current[index].1.downcast_mut().unwrap()
+}
+
+/// Get the ID of the span of the innermost `Instrumented` future with trace
+/// type `T` that is being polled on this thread, if any.
+pub fn current<T>() -> Option<T::Id>
+    where T: 'static + Trace
+{
+    CURRENT.with(|current| {
+        current.borrow()
+            .iter()
+            .find(|&&(ty, _)| ty == TypeId::of::<T>())
+            .and_then(|&(_, ref stack)| stack.downcast_ref::<Vec<T::Id>>())
+            .and_then(|stack| stack.last().cloned())
+    })
+}
+
+// Make `id` the current span for `T` on this thread until the returned guard
+// is dropped, or return the depth if that would nest spans beyond
+// `max_depth`.
+pub(crate) fn enter<T>(id: T::Id, max_depth: usize) -> Result<Entered<T>, usize>
+    where T: 'static + Trace
+{
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let stack = stack::<T>(&mut current);
+        if stack.len() < max_depth {
+            stack.push(id);
+            Ok(Entered(PhantomData))
+        } else {
+            Err(stack.len())
+        }
+    })
+}
+
+// Pops the span pushed by `enter`, even if the code that was entered panics.
+pub(crate) struct Entered<T>(PhantomData<T>) where T: 'static + Trace;
+
+impl<T> Drop for Entered<T>
+    where T: 'static + Trace
+{
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| stack::<T>(&mut current.borrow_mut()).pop());
+    }
+}
+
+/// A wrapper around a future that traces its lifetime as a span.
+#[derive(Debug)]
+pub struct Instrumented<F, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    future: F,
+    trace: T,
+    why: Option<T::Id>,
+    sink: S,
+    id: Option<T::Id>,
+    done: bool,
+    poll_events: bool,
+    max_depth: usize,
+    depth_exceeded: bool,
+}
+
+impl<F, S, T> Instrumented<F, S, T>
+    where F: Future,
+          S: TraceSink<T>,
+          T: 'static + Trace
+{
+    /// Wrap `future` such that its lifetime is traced as `trace` into `sink`,
+    /// caused by `why`.
+    pub fn new(future: F, trace: T, why: Option<T::Id>, sink: S) -> Instrumented<F, S, T> {
+        Instrumented {
+            future: future,
+            trace: trace,
+            why: why,
+            sink: sink,
+            id: None,
+            done: false,
+            poll_events: false,
+            max_depth: usize::MAX,
+            depth_exceeded: false,
+        }
+    }
+
+    /// Also trace an event, caused by the span, every time the future is
+    /// polled.
+    pub fn with_poll_events(mut self) -> Instrumented<F, S, T> {
+        self.poll_events = true;
+        self
+    }
+
+    /// Get the ID of this future's span, once it has been polled.
+    pub fn id(&self) -> Option<T::Id> {
+        self.id
+    }
+}
+
+impl<F, S, T> Future for Instrumented<F, S, T>
+    where F: Future,
+          S: TraceSink<T>,
+          T: 'static + Trace
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // Safe because `future` is never moved out of `self`, and no other
+        // field is pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let id = match this.id {
+            Some(id) => id,
+            None => {
+                let id = this.sink.trace_start(this.trace, this.why);
+                this.id = Some(id);
+                this.max_depth = this.sink.max_depth();
+                id
+            }
+        };
+        if this.poll_events {
+            this.sink.trace_event(this.trace, Some(id));
+        }
+
+        let poll = {
+            let _entered = match enter::<T>(id, this.max_depth) {
+                Ok(entered) => Some(entered),
+                Err(depth) => {
+                    if !this.depth_exceeded {
+                        this.depth_exceeded = true;
+                        this.sink.trace_depth_exceeded(this.trace, depth);
+                    }
+                    None
+                }
+            };
+            unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx)
+        };
+
+        if poll.is_ready() {
+            this.done = true;
+            this.sink.trace_stop(id, this.trace);
+        }
+        poll
+    }
+}
+
+impl<F, S, T> Drop for Instrumented<F, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.id, self.done) {
+            self.sink.trace_cancel(id, self.trace);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring_buffer::TraceKind;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer, SimpleTraceId};
+    use std::sync::{Arc, Mutex};
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    use std::thread;
+    use sink_combinators::WeakSinkHandle;
+
+    // Completes on its second poll, tracing an event caused by the current
+    // span on each one.
+    struct TwoPolls {
+        polls: usize,
+        sink: WeakSinkHandle<SimpleTraceBuffer>,
+    }
+
+    impl Future for TwoPolls {
+        type Output = usize;
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<usize> {
+            let why = current::<SimpleTrace>();
+            assert!(why.is_some());
+            self.sink.trace_event(SimpleTrace::FooEvent, why);
+            self.polls += 1;
+            if self.polls == 2 {
+                Poll::Ready(self.polls)
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(clone(std::ptr::null())) }
+    }
+
+    fn poll<F>(future: &mut Pin<Box<F>>) -> Poll<F::Output>
+        where F: Future
+    {
+        let waker = noop_waker();
+        future.as_mut().poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn span_across_threads() {
+        let buffer = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        let sink = WeakSinkHandle::new(&buffer);
+        let future = TwoPolls {
+            polls: 0,
+            sink: sink.clone(),
+        };
+        let mut future =
+            Box::pin(Instrumented::new(future, SimpleTrace::OperationThing, None, sink));
+
+        assert!(poll(&mut future).is_pending());
+        assert_eq!(current::<SimpleTrace>(), None);
+        let future = thread::spawn(move || {
+                assert_eq!(poll(&mut future), Poll::Ready(2));
+                future
+            })
+            .join()
+            .unwrap();
+        let id = future.id().unwrap();
+        drop(future);
+
+        let entries: Vec<_> = buffer.lock().unwrap().iter().collect();
+        let kinds: Vec<_> = entries.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds,
+                   vec![TraceKind::Start, TraceKind::Event, TraceKind::Event, TraceKind::Stop]);
+        assert!(entries.iter().all(|e| e.id() == id.0 || e.why() == Some((None, id.0))));
+    }
+
+    #[test]
+    fn cancelled_when_dropped_early() {
+        let buffer = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        let sink = WeakSinkHandle::new(&buffer);
+        let future = TwoPolls {
+            polls: 0,
+            sink: sink.clone(),
+        };
+        let mut future = Box::pin(Instrumented::new(future, SimpleTrace::OperationThing, None, sink)
+            .with_poll_events());
+
+        assert!(poll(&mut future).is_pending());
+        drop(future);
+
+        let kinds: Vec<_> = buffer.lock().unwrap().iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds,
+                   vec![TraceKind::Start, TraceKind::Event, TraceKind::Event, TraceKind::Cancel]);
+    }
+
+    // Completes immediately, remembering which span was current.
+    struct Leaf(Arc<Mutex<Option<Option<SimpleTraceId>>>>);
+
+    impl Future for Leaf {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            *self.0.lock().unwrap() = Some(current::<SimpleTrace>());
+            Poll::Ready(())
+        }
+    }
+
+    #[test]
+    fn max_depth_bounds_nesting() {
+        fn instrument<F>(future: F,
+                         sink: &WeakSinkHandle<SimpleTraceBuffer>)
+                         -> Instrumented<F, WeakSinkHandle<SimpleTraceBuffer>, SimpleTrace>
+            where F: Future
+        {
+            Instrumented::new(future, SimpleTrace::OperationThing, None, sink.clone())
+        }
+
+        let buffer = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        buffer.lock().unwrap().set_max_depth(2);
+        let sink = WeakSinkHandle::new(&buffer);
+        let seen = Arc::new(Mutex::new(None));
+        let nested = instrument(instrument(Leaf(seen.clone()), &sink), &sink);
+        let mut future = Box::pin(instrument(nested, &sink));
+        assert!(poll(&mut future).is_ready());
+        assert_eq!(buffer.lock().unwrap().stats().depth_exceeded, 1);
+
+        // The innermost span never became current, so the leaf saw the middle
+        // one's.
+        let entries: Vec<_> = buffer.lock().unwrap().iter().collect();
+        let kinds: Vec<_> = entries.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds,
+                   vec![TraceKind::Start,
+                        TraceKind::Start,
+                        TraceKind::Start,
+                        TraceKind::Counter,
+                        TraceKind::Stop,
+                        TraceKind::Stop,
+                        TraceKind::Stop]);
+        assert_eq!(entries[3].counter_value(), Some(2));
+        assert_eq!(*seen.lock().unwrap(), Some(Some(SimpleTraceId(entries[1].id()))));
+    }
+
+    #[test]
+    fn time_makes_its_span_current() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let mut events = SimpleTraceBuffer::default();
+        let (inner, elapsed) = buffer.time(SimpleTrace::OperationThing, || {
+            events.trace_event(SimpleTrace::FooEvent, current::<SimpleTrace>());
+            let mut nested = SimpleTraceBuffer::default();
+            let (inner, _) = nested.time(SimpleTrace::OperationAnother, || {
+                thread::sleep(std::time::Duration::from_millis(1));
+                current::<SimpleTrace>()
+            });
+            (inner, nested.iter().next().unwrap())
+        });
+        assert!(elapsed.as_millis() >= 1);
+        assert_eq!(current::<SimpleTrace>(), None);
+
+        let entries: Vec<_> = buffer.iter().collect();
+        let kinds: Vec<_> = entries.iter().map(|entry| entry.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Start, TraceKind::Stop]);
+        let id = entries[0].id();
+        assert_eq!(events.iter().next().unwrap().why().map(|(_, why)| why), Some(id));
+
+        // Nested calls are caused by, and become current inside, each other.
+        let (current_inside, nested_start) = inner;
+        assert_eq!(nested_start.why().map(|(_, why)| why), Some(id));
+        assert_eq!(current_inside, Some(SimpleTraceId(nested_start.id())));
+    }
+}
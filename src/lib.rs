@@ -4,19 +4,116 @@
 
 #![deny(missing_debug_implementations)]
 #![deny(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-// extern crate leb128;
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+
+// Let `core` stand in for `std` so that modules can use `std::` paths for
+// anything that lives in `core`.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+#[cfg(feature = "derive")]
+extern crate eep_derive;
+
+#[macro_use]
+mod macros;
+
+/// Derive `traits::Trace` for a C-like enum, using each variant's discriminant
+/// as its tag and its name, or `#[trace(label = "...")]`, as its label.
+#[cfg(feature = "derive")]
+pub use eep_derive::Trace;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+
+#[cfg(all(feature = "atrace", any(target_os = "android", target_os = "linux")))]
+pub mod atrace;
+
+pub mod codec;
+
+#[cfg(feature = "std")]
+pub mod crash;
+#[cfg(feature = "std")]
+pub use crash::install_panic_hook;
+
+#[cfg(all(feature = "etw", windows))]
+pub mod etw;
+
+#[cfg(feature = "std")]
+pub mod export;
+
+#[cfg(feature = "std")]
+pub mod fmt;
+
+#[cfg(all(feature = "fork", unix))]
+pub mod fork;
+
+#[cfg(feature = "std")]
+pub mod global;
+
+#[cfg(feature = "std")]
+pub mod instrument;
+
+#[cfg(feature = "log-compat")]
+pub mod log_compat;
+
+#[cfg(all(feature = "mmap", unix))]
+pub mod mmap_ring_buffer;
+
+#[cfg(feature = "std")]
+pub mod overhead;
+
+#[cfg(feature = "std")]
+pub mod owned;
+
+pub mod query;
+
+#[cfg(feature = "std")]
+pub mod quickstart;
+#[cfg(feature = "std")]
+pub use quickstart::quickstart;
 
 pub mod ring_buffer;
+pub use ring_buffer::merge;
+
+#[cfg(all(any(feature = "mmap", feature = "shmem"), unix))]
+mod shared_names;
+
+#[cfg(all(feature = "shmem", unix))]
+pub mod shmem;
 
-#[cfg(feature = "signpost")]
+#[cfg(all(feature = "signpost", feature = "std"))]
 pub mod signpost;
 
 pub mod simple_trace;
 
 pub mod sink_combinators;
 
+#[cfg(feature = "std")]
+pub mod testing;
+
+#[cfg(feature = "std")]
+pub mod thread_local_trace;
+
+#[cfg(feature = "std")]
 mod threaded_trace_id;
-pub use threaded_trace_id::ThreadedTraceId;
+#[cfg(feature = "std")]
+pub use threaded_trace_id::{LinkedTraceId, ThreadedTraceId};
+
+#[cfg(all(feature = "trace-marker", target_os = "linux"))]
+pub mod trace_marker;
+
+pub mod traced_drop;
+
+#[cfg(feature = "tracing-compat")]
+pub mod tracing_compat;
+
+pub mod track;
 
 pub mod traits;
+
+#[cfg(all(feature = "web-performance", target_arch = "wasm32"))]
+pub mod web_performance;
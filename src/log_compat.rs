@@ -0,0 +1,114 @@
+//! Forward records from the `log` crate into any `TraceSink`, so that crates
+//! which already log don't need to be instrumented twice.
+//!
+//! Records are classified into `Trace`s by a plain function; records it
+//! returns `None` for are dropped.
+//!
+//! ```
+//! extern crate eep;
+//! extern crate log;
+//!
+//! use eep::log_compat::LogSink;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//!
+//! fn classify(record: &log::Record) -> Option<SimpleTrace> {
+//!     if record.target().starts_with("my_crate::foo") {
+//!         Some(SimpleTrace::FooEvent)
+//!     } else {
+//!         None
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let sink = LogSink::new(SimpleTraceBuffer::default(), classify);
+//! log::set_boxed_logger(Box::new(sink)).unwrap();
+//! log::set_max_level(log::LevelFilter::Trace);
+//! # }
+//! ```
+
+extern crate log;
+
+use std::sync::{Mutex, MutexGuard};
+use traits::{Trace, TraceSink};
+
+/// Classify a `log` record as a `Trace`, or `None` to drop it.
+pub type Classify<T> = fn(&log::Record) -> Option<T>;
+
+/// A `log::Log` implementation that traces a one-off event for every
+/// classified record.
+#[derive(Debug)]
+pub struct LogSink<S, T> {
+    sink: Mutex<S>,
+    classify: Classify<T>,
+}
+
+impl<S, T> LogSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    /// Construct a new `LogSink` that traces records classified by `classify`
+    /// into `sink`.
+    pub fn new(sink: S, classify: Classify<T>) -> LogSink<S, T> {
+        LogSink {
+            sink: Mutex::new(sink),
+            classify: classify,
+        }
+    }
+
+    /// Lock and get the underlying sink.
+    pub fn sink(&self) -> MutexGuard<'_, S> {
+        self.sink.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the underlying sink back.
+    pub fn into_inner(self) -> S {
+        self.sink.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S, T> log::Log for LogSink<S, T>
+    where S: TraceSink<T> + Send,
+          T: Trace
+{
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        // Classification needs the whole record.
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(trace) = (self.classify)(record) {
+            self.sink().trace_event(trace, None);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::log::Log;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+
+    fn classify(record: &log::Record) -> Option<SimpleTrace> {
+        match record.target() {
+            "foo" => Some(SimpleTrace::FooEvent),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn traces_classified_records() {
+        let sink = LogSink::new(SimpleTraceBuffer::default(), classify);
+        for target in &["foo", "bar", "foo"] {
+            sink.log(&log::Record::builder()
+                .args(format_args!("hello"))
+                .target(target)
+                .build());
+        }
+
+        let buffer = sink.into_inner();
+        let labels: Vec<_> = buffer.iter().map(|e| e.label()).collect();
+        assert_eq!(labels, vec!["Foo", "Foo"]);
+    }
+}
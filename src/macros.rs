@@ -0,0 +1,170 @@
+//! Macros for tracing that can be compiled out entirely.
+//!
+//! With the `disable-tracing` feature enabled, every macro invocation expands
+//! to `()`: there is no timestamp read, no ID allocation, and no sink dispatch.
+//! Arguments are still type checked, but never evaluated. For this to work,
+//! IDs returned by `trace_event!` and `trace_start!` should only ever be passed
+//! to other tracing macros.
+
+/// Trace a one-off event with `TraceSink::trace_event`, optionally giving the
+/// ID of the trace that caused it, and evaluate to the new trace's ID.
+///
+/// ```
+/// # #[macro_use] extern crate eep;
+/// # fn main() {
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+///
+/// let mut buffer = SimpleTraceBuffer::default();
+/// let id = trace_event!(buffer, SimpleTrace::FooEvent);
+/// trace_event!(buffer, SimpleTrace::FooEvent, Some(id));
+/// # }
+/// ```
+#[cfg(not(feature = "disable-tracing"))]
+#[macro_export]
+macro_rules! trace_event {
+    ($sink:expr, $trace:expr) => {
+        $crate::trace_event!($sink, $trace, None)
+    };
+    ($sink:expr, $trace:expr, $why:expr) => {
+        {
+            use $crate::traits::TraceSink;
+            $sink.trace_event($trace, $why)
+        }
+    };
+}
+
+/// Trace the start of an operation with `TraceSink::trace_start`, optionally
+/// giving the ID of the trace that caused it, and evaluate to the ID to pass
+/// to `trace_stop!`.
+///
+/// ```
+/// # #[macro_use] extern crate eep;
+/// # fn main() {
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+///
+/// let mut buffer = SimpleTraceBuffer::default();
+/// let id = trace_start!(buffer, SimpleTrace::OperationThing);
+/// trace_stop!(buffer, id, SimpleTrace::OperationThing);
+/// # }
+/// ```
+#[cfg(not(feature = "disable-tracing"))]
+#[macro_export]
+macro_rules! trace_start {
+    ($sink:expr, $trace:expr) => {
+        $crate::trace_start!($sink, $trace, None)
+    };
+    ($sink:expr, $trace:expr, $why:expr) => {
+        {
+            use $crate::traits::TraceSink;
+            $sink.trace_start($trace, $why)
+        }
+    };
+}
+
+/// Trace the end of the operation with the given ID, as returned by
+/// `trace_start!`, with `TraceSink::trace_stop`.
+#[cfg(not(feature = "disable-tracing"))]
+#[macro_export]
+macro_rules! trace_stop {
+    ($sink:expr, $id:expr, $trace:expr) => {
+        {
+            use $crate::traits::TraceSink;
+            $sink.trace_stop($id, $trace)
+        }
+    };
+}
+
+/// Trace a one-off event with `TraceSink::trace_event`, optionally giving the
+/// ID of the trace that caused it, and evaluate to the new trace's ID.
+///
+/// Tracing is disabled, so this expands to `()`.
+#[cfg(feature = "disable-tracing")]
+#[macro_export]
+macro_rules! trace_event {
+    ($sink:expr, $trace:expr) => {
+        {
+            if false {
+                let _ = (&$sink, &$trace);
+            }
+        }
+    };
+    ($sink:expr, $trace:expr, $why:expr) => {
+        {
+            if false {
+                let _ = (&$sink, &$trace, &$why);
+            }
+        }
+    };
+}
+
+/// Trace the start of an operation with `TraceSink::trace_start`, optionally
+/// giving the ID of the trace that caused it, and evaluate to the ID to pass
+/// to `trace_stop!`.
+///
+/// Tracing is disabled, so this expands to `()`.
+#[cfg(feature = "disable-tracing")]
+#[macro_export]
+macro_rules! trace_start {
+    ($sink:expr, $trace:expr) => {
+        {
+            if false {
+                let _ = (&$sink, &$trace);
+            }
+        }
+    };
+    ($sink:expr, $trace:expr, $why:expr) => {
+        {
+            if false {
+                let _ = (&$sink, &$trace, &$why);
+            }
+        }
+    };
+}
+
+/// Trace the end of the operation with the given ID, as returned by
+/// `trace_start!`, with `TraceSink::trace_stop`.
+///
+/// Tracing is disabled, so this expands to `()`.
+#[cfg(feature = "disable-tracing")]
+#[macro_export]
+macro_rules! trace_stop {
+    ($sink:expr, $id:expr, $trace:expr) => {
+        {
+            if false {
+                let _ = (&$sink, &$id, &$trace);
+            }
+        }
+    };
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+
+    #[cfg(not(feature = "disable-tracing"))]
+    #[test]
+    fn macros_trace() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let event = trace_event!(buffer, SimpleTrace::FooEvent);
+        let id = trace_start!(buffer, SimpleTrace::OperationThing, Some(event));
+        trace_stop!(buffer, id, SimpleTrace::OperationThing);
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].why(), Some((None, entries[0].id())));
+    }
+
+    // Bind the IDs the way callers do, even though they are `()` here. Clippy
+    // only flags this within the crate defining the macros.
+    #[cfg(feature = "disable-tracing")]
+    #[test]
+    #[allow(clippy::let_unit_value)]
+    fn macros_are_compiled_out() {
+        let buffer = SimpleTraceBuffer::default();
+        let event = trace_event!(buffer, SimpleTrace::FooEvent);
+        let id = trace_start!(buffer, SimpleTrace::OperationThing, Some(event));
+        trace_stop!(buffer, id, SimpleTrace::OperationThing);
+
+        assert_eq!(buffer.iter().count(), 0);
+    }
+}
@@ -0,0 +1,622 @@
+//! A ring buffer sink backed by a memory-mapped file, so that the most recent
+//! entries can be recovered after the traced process crashes or is killed.
+//!
+//! The file starts with a small header, followed by the ring itself, and then
+//! by an area holding the names interned by `trace_event_named`:
+//!
+//! | Offset | Field                                        |
+//! |--------|----------------------------------------------|
+//! | 0      | Magic number, `b"EEPRING\0"`                 |
+//! | 8      | Format version                               |
+//! | 16     | Size of each entry, in bytes                 |
+//! | 24     | Capacity of the ring, in bytes               |
+//! | 32     | Where valid data begins within the ring      |
+//! | 40     | The number of valid bytes in the ring        |
+//! | 48     | Capacity of the names area, in bytes         |
+//! | 56     | The number of bytes of names in use          |
+//!
+//! Every header field is a native-endian `u64`, so the file must be recovered
+//! on a machine with the same endianness and `Trace` type that wrote it. The
+//! entries in the ring are encoded the same way on every platform. The names
+//! area holds each name, in order of ID, as its length in bytes as a
+//! little-endian `u32` followed by its UTF-8. Names are never evicted, so once
+//! the area is full, events with new names are traced without them.
+//!
+//! ```no_run
+//! use eep::mmap_ring_buffer::{self, MmapRingBuffer};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//!
+//! // In the traced process:
+//! let mut sink = MmapRingBuffer::<SimpleTrace>::create("trace.eep", 4096).unwrap();
+//! sink.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! // Later, possibly in another process after the traced one crashed:
+//! let buffer = mmap_ring_buffer::recover::<SimpleTrace, _>("trace.eep").unwrap();
+//! for entry in buffer.iter() {
+//!     println!("{}", entry.label());
+//! }
+//! ```
+//!
+//! On Linux, the ring buffer can also live in an anonymous `memfd` rather than
+//! a file, whose descriptor is inherited across `exec`. This lets a launcher
+//! collect the whole trace of a short-lived child, such as a CLI tool, after
+//! it exits:
+//!
+//! ```no_run
+//! # #[cfg(target_os = "linux")]
+//! # fn main() {
+//! use eep::mmap_ring_buffer::{self, MmapRingBuffer};
+//! use eep::simple_trace::SimpleTrace;
+//! use std::process::Command;
+//!
+//! // In the launcher:
+//! let mut buffer = MmapRingBuffer::<SimpleTrace>::create_memfd(1 << 20).unwrap();
+//! Command::new("my-tool")
+//!     .env(mmap_ring_buffer::FD_ENV_VAR, buffer.inheritable_fd().to_string())
+//!     .status()
+//!     .unwrap();
+//! buffer.reload().unwrap();
+//! for entry in buffer.snapshot().iter() {
+//!     println!("{}", entry.label());
+//! }
+//!
+//! // In `my-tool`, trace into `MmapRingBuffer::from_env()`, if it is set.
+//! # }
+//! # #[cfg(not(target_os = "linux"))]
+//! # fn main() {}
+//! ```
+
+extern crate libc;
+
+use ring_buffer::{self, Entry, NsSinceEpoch, RingBuffer, TraceKind};
+use shared_names::{self, SharedNames};
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use traits::{Trace, TraceId, TraceSink};
+
+const MAGIC: [u8; 8] = *b"EEPRING\0";
+const VERSION: u64 = 3;
+
+const HEADER_SIZE: usize = 64;
+const ENTRY_SIZE_OFFSET: usize = 16;
+const CAPACITY_OFFSET: usize = 24;
+const BEGIN_OFFSET: usize = 32;
+const LENGTH_OFFSET: usize = 40;
+const NAMES_CAPACITY_OFFSET: usize = 48;
+const NAMES_LENGTH_OFFSET: usize = 56;
+
+/// The environment variable through which a launcher tells the processes it
+/// spawns which inherited file descriptor to trace into, as read by
+/// `MmapRingBuffer::from_env`.
+pub const FD_ENV_VAR: &'static str = "EEP_TRACE_FD";
+
+/// A `TraceSink` that writes into a ring buffer living in a memory-mapped file.
+///
+/// The header is updated around every write such that, if the process dies
+/// mid-write, at worst the oldest entry or the entry being written is lost.
+#[derive(Debug)]
+pub struct MmapRingBuffer<T> {
+    // The start of the mapping, which is the start of the header.
+    map: *mut u8,
+    capacity: usize,
+    begin: usize,
+    length: usize,
+    names_capacity: usize,
+    names: SharedNames,
+    _file: File,
+    phantom: PhantomData<T>,
+}
+
+unsafe impl<T> Send for MmapRingBuffer<T> {}
+
+impl<T> MmapRingBuffer<T>
+    where T: Trace
+{
+    /// Create (or truncate) the file at `path` and map a ring buffer with the
+    /// given capacity, in bytes, into it, with room for a quarter as many
+    /// bytes of names.
+    pub fn create<P>(path: P, capacity: usize) -> io::Result<MmapRingBuffer<T>>
+        where P: AsRef<Path>
+    {
+        assert!(capacity > Entry::<T>::size());
+        let names_capacity = capacity / 4;
+
+        let file = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path));
+        try!(file.set_len((HEADER_SIZE + capacity + names_capacity) as u64));
+
+        Self::init(file, capacity, names_capacity)
+    }
+
+    /// Create a ring buffer with the given capacity, in bytes, and room for a
+    /// quarter as many bytes of names, in a new anonymous `memfd`.
+    ///
+    /// Unlike most descriptors, the `memfd`'s isn't closed on `exec`, so that
+    /// processes spawned from this one inherit it. Pass `inheritable_fd` to
+    /// them, for example through `FD_ENV_VAR`.
+    #[cfg(target_os = "linux")]
+    pub fn create_memfd(capacity: usize) -> io::Result<MmapRingBuffer<T>> {
+        assert!(capacity > Entry::<T>::size());
+        let names_capacity = capacity / 4;
+
+        let fd = unsafe { libc::memfd_create(b"eep\0".as_ptr() as *const libc::c_char, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        try!(file.set_len((HEADER_SIZE + capacity + names_capacity) as u64));
+
+        Self::init(file, capacity, names_capacity)
+    }
+
+    /// Map the ring buffer in the file open as `fd`, for example one inherited
+    /// from a launcher, and continue tracing after its existing entries.
+    ///
+    /// # Safety
+    ///
+    /// Takes ownership of `fd`, which must be open and not owned by anything
+    /// else. It is closed when the returned buffer is dropped.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<MmapRingBuffer<T>> {
+        let file = File::from_raw_fd(fd);
+        let (capacity, names_capacity) = {
+            let mut header = [0; HEADER_SIZE];
+            // Read at an explicit offset, since every holder of an inherited
+            // descriptor shares its file offset.
+            try!(file.read_exact_at(&mut header, 0));
+            let header_field = |offset: usize| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&header[offset..offset + 8]);
+                u64::from_ne_bytes(bytes) as usize
+            };
+            try!(check_header::<T>(&header[..MAGIC.len()],
+                                   header_field(MAGIC.len()),
+                                   header_field(ENTRY_SIZE_OFFSET)));
+            (header_field(CAPACITY_OFFSET), header_field(NAMES_CAPACITY_OFFSET))
+        };
+        let size = try!(file.metadata()).len();
+        if size != HEADER_SIZE as u64 + capacity as u64 + names_capacity as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "corrupt eep ring buffer file header"));
+        }
+
+        let mut buffer = MmapRingBuffer {
+            map: try!(map(&file, HEADER_SIZE + capacity + names_capacity)),
+            capacity: capacity,
+            begin: 0,
+            length: 0,
+            names_capacity: names_capacity,
+            names: SharedNames::new(),
+            _file: file,
+            phantom: PhantomData,
+        };
+        try!(buffer.reload());
+        Ok(buffer)
+    }
+
+    /// Map the ring buffer in the file descriptor named by the `FD_ENV_VAR`
+    /// environment variable, as set by a launcher, if it is set.
+    ///
+    /// Each process should only call this once, since the returned buffer
+    /// takes ownership of the descriptor.
+    pub fn from_env() -> io::Result<Option<MmapRingBuffer<T>>> {
+        let fd = match env::var(FD_ENV_VAR) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        match fd.parse() {
+            Ok(fd) => unsafe { Self::from_fd(fd).map(Some) },
+            Err(_) => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "invalid file descriptor in EEP_TRACE_FD"))
+            }
+        }
+    }
+
+    fn init(file: File, capacity: usize, names_capacity: usize) -> io::Result<MmapRingBuffer<T>> {
+        let buffer = MmapRingBuffer {
+            map: try!(map(&file, HEADER_SIZE + capacity + names_capacity)),
+            capacity: capacity,
+            begin: 0,
+            length: 0,
+            names_capacity: names_capacity,
+            names: SharedNames::new(),
+            _file: file,
+            phantom: PhantomData,
+        };
+
+        unsafe {
+            ptr::copy_nonoverlapping(MAGIC.as_ptr(), buffer.map, MAGIC.len());
+        }
+        buffer.set_header(MAGIC.len(), VERSION);
+        buffer.set_header(ENTRY_SIZE_OFFSET, Entry::<T>::size() as u64);
+        buffer.set_header(CAPACITY_OFFSET, capacity as u64);
+        buffer.set_header(BEGIN_OFFSET, 0);
+        buffer.set_header(LENGTH_OFFSET, 0);
+        buffer.set_header(NAMES_CAPACITY_OFFSET, names_capacity as u64);
+        buffer.set_header(NAMES_LENGTH_OFFSET, 0);
+
+        Ok(buffer)
+    }
+
+    /// Get the file descriptor of this buffer's file, which processes spawned
+    /// from this one inherit if it was made with `create_memfd`.
+    pub fn inheritable_fd(&self) -> RawFd {
+        self._file.as_raw_fd()
+    }
+
+    /// Re-read where the valid entries are, and the names they refer to, from
+    /// the header, after another process sharing this buffer's file traced
+    /// into it.
+    pub fn reload(&mut self) -> io::Result<()> {
+        let begin = self.header(BEGIN_OFFSET) as usize;
+        let length = self.header(LENGTH_OFFSET) as usize;
+        if begin >= self.capacity || length > self.capacity ||
+           length % Entry::<T>::size() != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "corrupt eep ring buffer file header"));
+        }
+        let names = match self.names_area() {
+            Some(names) => names.to_vec(),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "corrupt eep ring buffer file header"))
+            }
+        };
+        if !self.names.sync(&names) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "corrupt eep ring buffer file names"));
+        }
+        self.begin = begin;
+        self.length = length;
+        Ok(())
+    }
+
+    /// Copy this buffer's current contents, and the names they refer to, into
+    /// an in-memory `RingBuffer`.
+    pub fn snapshot(&self) -> RingBuffer<T> {
+        let mut buffer = RingBuffer::from_raw_parts(self.ring().to_vec(), self.begin, self.length);
+        if let Some(names) = self.names_area().and_then(shared_names::read) {
+            buffer.set_names(names);
+        }
+        buffer
+    }
+
+    fn header(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile(self.map.offset(offset as isize) as *const u64) }
+    }
+
+    fn set_header(&self, offset: usize, value: u64) {
+        unsafe {
+            ptr::write_volatile(self.map.offset(offset as isize) as *mut u64, value);
+        }
+    }
+
+    fn ring(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.map.offset(HEADER_SIZE as isize), self.capacity) }
+    }
+
+    fn ring_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.map.offset(HEADER_SIZE as isize), self.capacity)
+        }
+    }
+
+    // The part of the names area in use, or `None` if the header is corrupt.
+    fn names_area(&self) -> Option<&[u8]> {
+        let length = self.header(NAMES_LENGTH_OFFSET) as usize;
+        if length > self.names_capacity {
+            return None;
+        }
+        unsafe {
+            let names = self.map.offset((HEADER_SIZE + self.capacity) as isize);
+            Some(slice::from_raw_parts(names, length))
+        }
+    }
+
+    // Get the ID of `name`, appending it to the names area and publishing it
+    // if it isn't there yet, or `None` if there's no room for it.
+    fn intern(&mut self, name: &str) -> Option<u32> {
+        let area = unsafe {
+            slice::from_raw_parts_mut(self.map.offset((HEADER_SIZE + self.capacity) as isize),
+                                      self.names_capacity)
+        };
+        let id = self.names.intern(area, name);
+        self.set_header(NAMES_LENGTH_OFFSET, self.names.used() as u64);
+        id
+    }
+
+    fn write_entry(&mut self, entry: Entry<T>) {
+        let size = Entry::<T>::size();
+
+        // Evict the oldest entry, and publish that, before overwriting it.
+        if self.capacity - self.length < size {
+            self.begin = (self.begin + size) % self.capacity;
+            self.length -= size;
+            self.set_header(BEGIN_OFFSET, self.begin as u64);
+            self.set_header(LENGTH_OFFSET, self.length as u64);
+        }
+
+        let end = (self.begin + self.length) % self.capacity;
+        ring_buffer::copy_wrapping(self.ring_mut(), end, &entry.to_bytes());
+
+        self.length += size;
+        self.set_header(LENGTH_OFFSET, self.length as u64);
+    }
+}
+
+fn map(file: &File, size: usize) -> io::Result<*mut u8> {
+    let map = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   size,
+                   libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_SHARED,
+                   file.as_raw_fd(),
+                   0)
+    };
+    if map == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(map as *mut u8)
+}
+
+fn check_header<T>(magic: &[u8], version: usize, entry_size: usize) -> io::Result<()> {
+    let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    if magic != MAGIC {
+        return invalid("not an eep ring buffer file");
+    }
+    if version != VERSION as usize {
+        return invalid("unsupported eep ring buffer file version");
+    }
+    if entry_size != Entry::<T>::size() {
+        return invalid("eep ring buffer file was written with a different entry size");
+    }
+    Ok(())
+}
+
+impl<T> Drop for MmapRingBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let size = HEADER_SIZE + self.capacity + self.names_capacity;
+            libc::munmap(self.map as *mut libc::c_void, size);
+        }
+    }
+}
+
+impl<T> TraceSink<T> for MmapRingBuffer<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, why, NsSinceEpoch::now()));
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        self.write_entry(Entry::new(TraceKind::Start, trace.tag(), id, why, NsSinceEpoch::now()));
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.write_entry(Entry::new(TraceKind::Stop, trace.tag(), id, None, NsSinceEpoch::now()));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Cancel, trace.tag(), id, None, now));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let duration = end.0.saturating_sub(start.0);
+        self.write_entry(Entry::new(TraceKind::Complete, trace.tag(), id, why, end)
+            .with_value(duration));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        let id = T::Id::new_id();
+        self.write_entry(Entry::new(TraceKind::Park, trace.tag(), id, None, NsSinceEpoch::now()));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        let id = T::Id::new_id();
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Unpark, trace.tag(), id, None, now));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let id = T::Id::new_id();
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Counter, trace.tag(), id, None, now)
+            .with_value(value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = T::Id::new_id();
+        let name = self.intern(name).map_or(0, |name| name as u64 + 1);
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, None, now)
+            .with_value(name));
+        id
+    }
+}
+
+/// Read the ring buffer file at `path`, written by a `MmapRingBuffer<T>` in
+/// this or another process, into an in-memory `RingBuffer<T>`.
+pub fn recover<T, P>(path: P) -> io::Result<RingBuffer<T>>
+    where T: Trace,
+          P: AsRef<Path>
+{
+    let mut contents = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut contents));
+
+    let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+    if contents.len() < HEADER_SIZE || contents[..MAGIC.len()] != MAGIC {
+        return invalid("not an eep ring buffer file");
+    }
+
+    let header = |offset: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&contents[offset..offset + 8]);
+        u64::from_ne_bytes(bytes) as usize
+    };
+
+    try!(check_header::<T>(&contents[..MAGIC.len()],
+                           header(MAGIC.len()),
+                           header(ENTRY_SIZE_OFFSET)));
+
+    let capacity = header(CAPACITY_OFFSET);
+    let begin = header(BEGIN_OFFSET);
+    let length = header(LENGTH_OFFSET);
+    let names_capacity = header(NAMES_CAPACITY_OFFSET);
+    let names_length = header(NAMES_LENGTH_OFFSET);
+    if contents.len() as u64 != HEADER_SIZE as u64 + capacity as u64 + names_capacity as u64 ||
+       begin >= capacity || length > capacity || length % Entry::<T>::size() != 0 ||
+       names_length > names_capacity {
+        return invalid("corrupt eep ring buffer file header");
+    }
+
+    let names_start = HEADER_SIZE + capacity;
+    let names = match shared_names::read(&contents[names_start..names_start + names_length]) {
+        Some(names) => names,
+        None => return invalid("corrupt eep ring buffer file names"),
+    };
+    let mut data = contents.split_off(HEADER_SIZE);
+    data.truncate(capacity);
+    let mut buffer = RingBuffer::from_raw_parts(data, begin, length);
+    buffer.set_names(names);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use traits::TraceSink;
+
+    // A path unique to this test, process, and call, so that tests running in
+    // parallel, or several runs at once, don't share files.
+    fn temp_path(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        env::temp_dir().join(format!("eep-mmap-ring-buffer-{}-{}-{}", name, process::id(), n))
+    }
+
+    #[test]
+    fn recover_after_roll_over() {
+        let path = temp_path("recover-after-roll-over");
+        {
+            let mut sink = MmapRingBuffer::create(&path, 3 * Entry::<SimpleTrace>::size() + 1)
+                .unwrap();
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+            assert_eq!(sink.snapshot().iter().count(), 3);
+        }
+
+        let buffer = recover::<SimpleTrace, _>(&path).unwrap();
+        let kinds: Vec<_> = buffer.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Start, TraceKind::Event, TraceKind::Stop]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_names() {
+        let path = temp_path("recover-names");
+        {
+            let mut sink = MmapRingBuffer::create(&path, 4 * Entry::<SimpleTrace>::size() + 1)
+                .unwrap();
+            sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            // The names area, a quarter of the ring, has no room for this.
+            sink.trace_event_named(SimpleTrace::FooEvent, &"x".repeat(64));
+            assert_eq!(sink.snapshot().names(), ["/etc/hosts"]);
+        }
+
+        let buffer = recover::<SimpleTrace, _>(&path).unwrap();
+        let names: Vec<_> = buffer.iter().map(|e| e.name()).collect();
+        assert_eq!(names, vec![Some(0), Some(0), None]);
+        assert_eq!(buffer.names(), ["/etc/hosts"]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_rejects_other_files() {
+        let path = temp_path("recover-rejects-other-files");
+        fs::write(&path, b"definitely not a ring buffer, but long enough for a header").unwrap();
+
+        let err = recover::<SimpleTrace, _>(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn hand_off_memfd() {
+        let mut launcher = MmapRingBuffer::<SimpleTrace>::create_memfd(4096).unwrap();
+        launcher.trace_event(SimpleTrace::FooEvent, None);
+
+        // Stand in for a spawned process, which would get its own copy of the
+        // inherited descriptor.
+        {
+            let fd = unsafe { libc::dup(launcher.inheritable_fd()) };
+            assert!(fd >= 0);
+            let mut child = unsafe { MmapRingBuffer::<SimpleTrace>::from_fd(fd).unwrap() };
+            let id = child.trace_start(SimpleTrace::OperationThing, None);
+            child.trace_stop(id, SimpleTrace::OperationThing);
+            child.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        }
+
+        // Descriptors duplicated from the same one share a file offset, which
+        // mapping one mustn't move.
+        {
+            let fd = unsafe { libc::dup(launcher.inheritable_fd()) };
+            assert!(fd >= 0);
+            let second = unsafe { MmapRingBuffer::<SimpleTrace>::from_fd(fd).unwrap() };
+            assert_eq!(second.snapshot().iter().count(), 4);
+        }
+
+        assert_eq!(launcher.snapshot().iter().count(), 1);
+        launcher.reload().unwrap();
+        let kinds: Vec<_> = launcher.snapshot().iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds,
+                   vec![TraceKind::Event, TraceKind::Start, TraceKind::Stop, TraceKind::Event]);
+
+        // The launcher interns on after the names the child interned.
+        launcher.trace_event_named(SimpleTrace::FooEvent, "/tmp");
+        launcher.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let snapshot = launcher.snapshot();
+        let names: Vec<_> = snapshot.iter().filter_map(|e| e.name()).collect();
+        assert_eq!(names, vec![0, 1, 0]);
+        assert_eq!(snapshot.names(), ["/etc/hosts", "/tmp"]);
+    }
+}
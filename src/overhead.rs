@@ -0,0 +1,170 @@
+//! Measure the overhead of clock sources and write paths on this machine, to
+//! help pick a configuration that meets an overhead budget.
+//!
+//! Results vary a lot across hardware and operating systems, so run this
+//! where the traced program will run, for example from a `--bench-tracing`
+//! flag in your own binary.
+//!
+//! ```
+//! use eep::overhead;
+//! use std::io;
+//!
+//! let measurements = overhead::run(10_000);
+//! overhead::write_report(&measurements, &mut io::stdout()).unwrap();
+//! ```
+
+use ring_buffer::{Clock, Entry, NsSinceEpoch, RingBuffer};
+use simple_trace::SimpleTraceId;
+use sink_combinators::{FilteredSink, ToggleSink};
+use std::cmp;
+use std::hint;
+use std::io;
+use std::time::Instant;
+use thread_local_trace::ThreadLocalSink;
+use traits::{Trace, TraceSink};
+
+/// The average cost of a single operation.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Measurement {
+    /// What was measured.
+    pub name: &'static str,
+
+    /// The mean time per operation, in nanoseconds.
+    pub ns_per_iter: f64,
+}
+
+/// Measure the cost of reading the given clock.
+pub fn measure_clock(name: &'static str, clock: Clock, iterations: usize) -> Measurement {
+    measure(name, iterations, || {
+        hint::black_box(clock());
+    })
+}
+
+/// Measure the cost of tracing a one-off event into the given sink.
+pub fn measure_sink<S, T>(name: &'static str,
+                          sink: &mut S,
+                          trace: T,
+                          iterations: usize)
+                          -> Measurement
+    where S: TraceSink<T>,
+          T: Trace
+{
+    measure(name, iterations, || {
+        hint::black_box(sink.trace_event(trace, None));
+    })
+}
+
+fn measure<F>(name: &'static str, iterations: usize, mut f: F) -> Measurement
+    where F: FnMut()
+{
+    assert!(iterations > 0);
+
+    // Warm up caches, and lazily initialized state such as thread-local
+    // buffers.
+    for _ in 0..iterations / 10 + 1 {
+        f();
+    }
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed = start.elapsed();
+
+    Measurement {
+        name: name,
+        ns_per_iter: elapsed.as_nanos() as f64 / iterations as f64,
+    }
+}
+
+// A trace type of our own, so that measuring doesn't leave entries behind in
+// the thread-local buffers of any trace type used elsewhere.
+#[derive(Copy, Clone, Debug)]
+struct Overhead;
+
+impl Trace for Overhead {
+    type Id = SimpleTraceId;
+
+    fn label(_tag: u32) -> &'static str {
+        "Overhead"
+    }
+
+    fn tag(&self) -> u32 {
+        0
+    }
+}
+
+fn fixed_clock() -> NsSinceEpoch {
+    NsSinceEpoch(0)
+}
+
+/// Run the built-in suite of measurements, with the given number of iterations
+/// each.
+///
+/// The suite compares reading the system clock against a fixed clock, writing
+/// to a `RingBuffer` with and without evicting old entries, and the sinks and
+/// combinators in this crate.
+pub fn run(iterations: usize) -> Vec<Measurement> {
+    let large = 2 * 1024 * 1024;
+    let small = 100;
+    let trace = Overhead;
+
+    let mut measurements = vec![measure_clock("clock: system", NsSinceEpoch::now, iterations),
+                                measure_clock("clock: fixed", fixed_clock, iterations)];
+
+    // Make sure the large buffer never has to evict, so only the fast path is
+    // measured.
+    let capacity = cmp::max(large, 2 * iterations * Entry::<Overhead>::size());
+    let mut buffer = RingBuffer::with_clock(capacity, fixed_clock);
+    measurements.push(measure_sink("write: ring buffer", &mut buffer, trace, iterations));
+
+    let mut buffer = RingBuffer::with_clock(small, fixed_clock);
+    measurements.push(measure_sink("write: ring buffer, evicting", &mut buffer, trace, iterations));
+
+    let mut buffer = RingBuffer::new(large);
+    measurements.push(measure_sink("sink: ring buffer", &mut buffer, trace, iterations));
+
+    measurements.push(measure_sink("sink: thread local",
+                                   &mut ThreadLocalSink::get(),
+                                   trace,
+                                   iterations));
+
+    let mut sink = ToggleSink::new_disabled(RingBuffer::new(small));
+    measurements.push(measure_sink("sink: toggle, disabled", &mut sink, trace, iterations));
+
+    let mut sink = FilteredSink::new_disabled(RingBuffer::new(small), 1);
+    measurements.push(measure_sink("sink: filtered, disabled", &mut sink, trace, iterations));
+
+    measurements
+}
+
+/// Write a human-readable table of the given measurements.
+pub fn write_report<W>(measurements: &[Measurement], writer: &mut W) -> io::Result<()>
+    where W: io::Write
+{
+    for measurement in measurements {
+        try!(writeln!(writer,
+                      "{:<32} {:>10.1} ns/iter",
+                      measurement.name,
+                      measurement.ns_per_iter));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_suite() {
+        let measurements = run(100);
+        assert!(measurements.len() > 2);
+        assert!(measurements.iter().all(|m| m.ns_per_iter >= 0.0));
+
+        let mut report = vec![];
+        write_report(&measurements, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(report.lines().count(), measurements.len());
+        assert!(report.contains("clock: system"));
+    }
+}
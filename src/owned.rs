@@ -0,0 +1,289 @@
+//! Owned mirrors of captured trace types, for pushing traces through serde
+//! into existing telemetry pipelines, and reading them back out again.
+//!
+//! `Entry<T>` and `Interval` borrow their labels from `T`, so they can be
+//! serialized, but not deserialized. `OwnedEntry` and `OwnedInterval` own
+//! their labels instead, and so implement both `Serialize` and `Deserialize`.
+//! A `TraceSnapshot<T>` serializes as a sequence of `OwnedEntry`s, with the
+//! names of events traced with `trace_event_named` filled in, and an
+//! `Interval` the same way as an `OwnedInterval`.
+//!
+//! ```
+//! extern crate eep;
+//! extern crate serde_json;
+//!
+//! use eep::owned::OwnedEntry;
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traits::TraceSink;
+//!
+//! # fn main() {
+//! let mut buffer = SimpleTraceBuffer::default();
+//! buffer.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! let json = serde_json::to_string(&buffer.snapshot()).unwrap();
+//! let entries: Vec<OwnedEntry> = serde_json::from_str(&json).unwrap();
+//! assert_eq!(entries[0].label, "Foo");
+//! # }
+//! ```
+
+extern crate serde;
+
+use analysis::Interval;
+use ring_buffer::{Entry, NsSinceEpoch, TraceKind};
+use traits::{ThreadId, Trace};
+
+/// An `Entry<T>`, with its label, that doesn't depend on `T`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnedEntry {
+    /// When the entry was traced.
+    pub timestamp: NsSinceEpoch,
+
+    /// The tag of the trace.
+    pub tag: u32,
+
+    /// The label of the trace.
+    pub label: String,
+
+    /// The name of an event traced with `trace_event_named`, if known.
+    pub name: Option<String>,
+
+    /// The kind of trace.
+    pub kind: TraceKind,
+
+    /// The entry's ID.
+    pub id: u32,
+
+    /// The thread that traced the entry, if available.
+    pub thread: Option<ThreadId>,
+
+    /// The thread and ID of what caused the entry, if anything.
+    pub why: Option<(Option<ThreadId>, u32)>,
+
+    /// A complete's duration in nanoseconds, a counter's value, or `0`.
+    pub value: u64,
+}
+
+// An `Entry<T>` doesn't know its name, only the name's ID, so the `name` of an
+// `OwnedEntry` converted from one is `None`, unless it is filled in from the
+// buffer or snapshot the entry came from.
+impl<T> From<Entry<T>> for OwnedEntry
+    where T: Trace
+{
+    fn from(entry: Entry<T>) -> OwnedEntry {
+        OwnedEntry {
+            timestamp: entry.timestamp(),
+            tag: entry.tag(),
+            label: entry.label().to_string(),
+            name: None,
+            kind: entry.kind(),
+            id: entry.id(),
+            thread: entry.thread(),
+            why: entry.why(),
+            value: entry.duration().or_else(|| entry.counter_value()).unwrap_or(0),
+        }
+    }
+}
+
+/// An `Interval`, with its label, that doesn't depend on the `Trace` type.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnedInterval {
+    /// The tag of the traced operation.
+    pub tag: u32,
+
+    /// The label of the traced operation.
+    pub label: String,
+
+    /// The thread that traced this span, if available.
+    pub thread: Option<ThreadId>,
+
+    /// The ID shared by this span's start and stop entries.
+    pub id: u32,
+
+    /// When this span started, in nanoseconds since the epoch.
+    pub start_ns: u64,
+
+    /// How long this span lasted, in nanoseconds.
+    pub duration_ns: u64,
+
+    /// The ID of the innermost span on the same thread that was still open
+    /// when this span started, if any.
+    pub parent: Option<u32>,
+
+    /// Whether the clock jumped while this span was open, in which case
+    /// `duration_ns` is unreliable.
+    pub clock_jumped: bool,
+
+    /// Whether this span was cancelled rather than stopped.
+    pub cancelled: bool,
+}
+
+impl From<Interval> for OwnedInterval {
+    fn from(interval: Interval) -> OwnedInterval {
+        OwnedInterval {
+            tag: interval.tag,
+            label: interval.label.to_string(),
+            thread: interval.thread,
+            id: interval.id,
+            start_ns: interval.start_ns,
+            duration_ns: interval.duration_ns,
+            parent: interval.parent,
+            clock_jumped: interval.clock_jumped,
+            cancelled: interval.cancelled,
+        }
+    }
+}
+
+// Implement `Serialize` and `Deserialize` for a struct of the given fields.
+// Deserializing accepts the fields by name, in any order, as self-describing
+// formats like JSON write them, or in order, as other formats like bincode do.
+macro_rules! impl_serde {
+    ($name:ident { $($field:ident),* }) => {
+        impl serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+                where S: serde::Serializer
+            {
+                let len = [$(stringify!($field)),*].len();
+                let mut state = try!(serializer.serialize_struct(stringify!($name), len));
+                $(
+                    try!(serializer.serialize_struct_elt(&mut state,
+                                                         stringify!($field),
+                                                         &self.$field));
+                )*
+                serializer.serialize_struct_end(state)
+            }
+        }
+
+        impl serde::Deserialize for $name {
+            fn deserialize<D>(deserializer: &mut D) -> Result<$name, D::Error>
+                where D: serde::Deserializer
+            {
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+
+                struct FieldsVisitor;
+
+                impl serde::de::Visitor for FieldsVisitor {
+                    type Value = $name;
+
+                    fn visit_map<V>(&mut self, mut visitor: V) -> Result<$name, V::Error>
+                        where V: serde::de::MapVisitor
+                    {
+                        $(let mut $field = None;)*
+                        while let Some(key) = try!(visitor.visit_key::<String>()) {
+                            match &key[..] {
+                                $(
+                                    stringify!($field) => {
+                                        $field = Some(try!(visitor.visit_value()));
+                                    }
+                                )*
+                                _ => {
+                                    try!(visitor.visit_value::<serde::de::impls::IgnoredAny>());
+                                }
+                            }
+                        }
+                        try!(visitor.end());
+                        Ok($name {
+                            $($field: match $field {
+                                Some($field) => $field,
+                                None => try!(visitor.missing_field(stringify!($field))),
+                            },)*
+                        })
+                    }
+
+                    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<$name, V::Error>
+                        where V: serde::de::SeqVisitor
+                    {
+                        $(
+                            let $field = match try!(visitor.visit()) {
+                                Some($field) => $field,
+                                None => {
+                                    return Err(serde::de::Error::invalid_length(FIELDS.len()))
+                                }
+                            };
+                        )*
+                        try!(visitor.end());
+                        Ok($name { $($field: $field),* })
+                    }
+                }
+
+                deserializer.deserialize_struct(stringify!($name), FIELDS, FieldsVisitor)
+            }
+        }
+    }
+}
+
+impl_serde!(OwnedEntry { timestamp, tag, label, name, kind, id, thread, why, value });
+
+impl_serde!(OwnedInterval {
+    tag,
+    label,
+    thread,
+    id,
+    start_ns,
+    duration_ns,
+    parent,
+    clock_jumped,
+    cancelled
+});
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use analysis::Intervals;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::TraceSink;
+
+    #[test]
+    fn entries_round_trip() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let parent = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        buffer.trace_event(SimpleTrace::FooEvent, Some(parent));
+        buffer.trace_cancel(parent, SimpleTrace::OperationThing);
+        let owned: Vec<OwnedEntry> = buffer.iter().map(OwnedEntry::from).collect();
+
+        let json = serde_json::to_string(&buffer.snapshot()).unwrap();
+        let decoded: Vec<OwnedEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, owned);
+        assert_eq!(decoded[1].kind, TraceKind::Counter);
+        assert_eq!(decoded[1].value, 42);
+        assert_eq!(decoded[2].why.map(|(_, id)| id), Some(parent.0));
+        assert_eq!(decoded[2].name, None);
+
+        // Fields can be in any order, and unknown fields are ignored.
+        let json = r#"{"tag": 0, "label": "Foo", "kind": "Event", "extra": [1, 2],
+                       "id": 7, "thread": null, "why": null, "value": 0, "timestamp": 5}"#;
+        let entry: OwnedEntry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry.timestamp, NsSinceEpoch(5));
+        assert_eq!(entry.id, 7);
+
+        let missing = serde_json::from_str::<OwnedEntry>(r#"{"tag": 0}"#);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+
+        let json = serde_json::to_string(&buffer.snapshot()).unwrap();
+        let decoded: Vec<OwnedEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded[0].label, "Foo");
+        assert_eq!(decoded[0].name, Some("/etc/hosts".to_string()));
+        assert_eq!(decoded[0].value, 0);
+    }
+
+    #[test]
+    fn intervals_round_trip() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        let interval = Intervals::new(buffer.iter()).next().unwrap();
+
+        let json = serde_json::to_string(&interval).unwrap();
+        let decoded: OwnedInterval = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, OwnedInterval::from(interval));
+        assert_eq!(decoded.label, "Thing");
+    }
+}
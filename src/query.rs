@@ -0,0 +1,157 @@
+//! Composable filters over trace entries, so that analyses don't have to
+//! re-filter full scans by hand.
+//!
+//! ```
+//! use eep::ring_buffer::TraceKind;
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::testing::TraceBuilder;
+//! use eep::traits::Trace;
+//!
+//! let buffer = TraceBuilder::new()
+//!     .leaf_span(SimpleTrace::OperationThing, 0..10)
+//!     .leaf_span(SimpleTrace::OperationAnother, 20..30)
+//!     .leaf_span(SimpleTrace::OperationThing, 40..50)
+//!     .build();
+//!
+//! let starts = buffer.query()
+//!     .between(5, 45)
+//!     .with_tag(SimpleTrace::OperationThing.tag())
+//!     .of_kind(TraceKind::Start)
+//!     .count();
+//! assert_eq!(starts, 1);
+//! ```
+
+use ring_buffer::{Entry, TraceKind};
+
+/// An iterator over the entries of another iterator that match every filter
+/// added to it.
+///
+/// Construct one with `RingBuffer::query`, `TraceSnapshot::query`, or
+/// `TraceQuery::new`.
+#[derive(Clone, Debug)]
+pub struct TraceQuery<I> {
+    entries: I,
+    filters: Filters,
+    // Set when two filters contradict each other, so nothing can match.
+    empty: bool,
+}
+
+// The filters an entry must match, kept apart from the entries so that they
+// can be checked while the entries are borrowed.
+#[derive(Clone, Debug)]
+struct Filters {
+    start_ns: Option<u64>,
+    end_ns: Option<u64>,
+    tag: Option<u32>,
+    kind: Option<TraceKind>,
+}
+
+impl Filters {
+    fn matches<T>(&self, entry: &Entry<T>) -> bool {
+        let timestamp = entry.timestamp().0;
+        self.start_ns.map_or(true, |start| timestamp >= start) &&
+        self.end_ns.map_or(true, |end| timestamp < end) &&
+        self.tag.map_or(true, |tag| entry.tag() == tag) &&
+        self.kind.map_or(true, |kind| entry.kind() == kind)
+    }
+}
+
+impl<I> TraceQuery<I> {
+    /// Construct a new query over the given entries that matches every entry.
+    pub fn new<J>(entries: J) -> TraceQuery<I>
+        where J: IntoIterator<IntoIter = I>
+    {
+        TraceQuery {
+            entries: entries.into_iter(),
+            filters: Filters {
+                start_ns: None,
+                end_ns: None,
+                tag: None,
+                kind: None,
+            },
+            empty: false,
+        }
+    }
+
+    /// Only match entries whose timestamp, in nanoseconds since the epoch, is
+    /// at least `start_ns` and less than `end_ns`.
+    pub fn between(mut self, start_ns: u64, end_ns: u64) -> TraceQuery<I> {
+        self.filters.start_ns = Some(self.filters.start_ns.map_or(start_ns, |s| s.max(start_ns)));
+        self.filters.end_ns = Some(self.filters.end_ns.map_or(end_ns, |e| e.min(end_ns)));
+        self
+    }
+
+    /// Only match entries with the given tag.
+    pub fn with_tag(mut self, tag: u32) -> TraceQuery<I> {
+        if self.filters.tag.map_or(false, |t| t != tag) {
+            self.empty = true;
+        }
+        self.filters.tag = Some(tag);
+        self
+    }
+
+    /// Only match entries of the given kind.
+    pub fn of_kind(mut self, kind: TraceKind) -> TraceQuery<I> {
+        if self.filters.kind.map_or(false, |k| k != kind) {
+            self.empty = true;
+        }
+        self.filters.kind = Some(kind);
+        self
+    }
+}
+
+impl<I, T> Iterator for TraceQuery<I>
+    where I: Iterator<Item = Entry<T>>
+{
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Entry<T>> {
+        if self.empty {
+            return None;
+        }
+        let filters = &self.filters;
+        self.entries.find(|entry| filters.matches(entry))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use testing::TraceBuilder;
+    use traits::Trace;
+
+    fn buffer() -> SimpleTraceBuffer {
+        let mut builder = TraceBuilder::new();
+        for i in 0..5 {
+            builder = builder.event(SimpleTrace::FooEvent, i * 10)
+                .leaf_span(SimpleTrace::OperationThing, i * 10..i * 10 + 5);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn filters_compose() {
+        let buffer = buffer();
+        assert_eq!(buffer.query().count(), 15);
+        assert_eq!(buffer.query().between(10, 30).count(), 6);
+        assert_eq!(buffer.query().with_tag(SimpleTrace::FooEvent.tag()).count(), 5);
+        assert_eq!(buffer.query().of_kind(TraceKind::Stop).count(), 5);
+
+        let timestamps: Vec<_> = buffer.query()
+            .between(0, 40)
+            .between(10, 50)
+            .of_kind(TraceKind::Start)
+            .map(|e| e.timestamp().0)
+            .collect();
+        assert_eq!(timestamps, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn contradictory_filters_match_nothing() {
+        let buffer = buffer();
+        assert_eq!(buffer.query().of_kind(TraceKind::Start).of_kind(TraceKind::Stop).count(),
+                   0);
+        assert_eq!(buffer.query().with_tag(0).with_tag(1).count(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! A default tracing pipeline, set up in one call, for trying the crate out.
+//!
+//! `quickstart` timestamps the per-thread buffers of `thread_local_trace` with
+//! the monotonic clock, dumps them to `CRASH_PATH` if any thread panics, and
+//! returns a guard that exports them to `TRACE_PATH` when dropped. Both files
+//! are in the Chrome trace event format; see `export::chrome`.
+//!
+//! ```no_run
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::thread_local_trace::ThreadLocalSink;
+//! use eep::traits::TraceSink;
+//!
+//! let _trace = eep::quickstart::<SimpleTrace>();
+//!
+//! let mut sink = ThreadLocalSink::get();
+//! let id = sink.trace_start(SimpleTrace::OperationThing, None);
+//! sink.trace_stop(id, SimpleTrace::OperationThing);
+//!
+//! // `hydra-trace.json` is written here, when `_trace` is dropped.
+//! ```
+//!
+//! The panic hook is installed by the first call, and calls the previously
+//! installed hook after dumping. Later calls reuse it, and it dumps for the
+//! most recently set up pipeline whose guard is still alive.
+
+use export::chrome;
+use ring_buffer::NsSinceEpoch;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use thread_local_trace;
+use traits::Trace;
+
+/// Where `quickstart` exports traces to when its guard is dropped.
+pub const TRACE_PATH: &str = "hydra-trace.json";
+
+/// Where `quickstart` dumps traces to when a thread panics.
+pub const CRASH_PATH: &str = "hydra-crash.json";
+
+/// Set up the default pipeline for `T`, exporting to `TRACE_PATH` and dumping
+/// crashes to `CRASH_PATH`, in the current directory.
+pub fn quickstart<T>() -> Quickstart<T>
+    where T: 'static + Send + Trace
+{
+    Quickstart::new(TRACE_PATH, CRASH_PATH)
+}
+
+// What the panic hook dumps, if anything, along with the generation of the
+// `Quickstart` that set it.
+static CRASH_DUMP: Mutex<Option<(u64, Box<dyn Fn() + Send>)>> = Mutex::new(None);
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+static INSTALL_HOOK: Once = Once::new();
+
+/// A guard that exports every thread's traces when it is dropped. See
+/// `quickstart`.
+#[derive(Debug)]
+pub struct Quickstart<T>
+    where T: 'static + Send + Trace
+{
+    trace_path: PathBuf,
+    // Identifies this guard's entry in `CRASH_DUMP`, which it clears once it's
+    // gone.
+    generation: u64,
+    finished: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Quickstart<T>
+    where T: 'static + Send + Trace
+{
+    /// Set up the default pipeline for `T`, like `quickstart`, but exporting
+    /// to and dumping crashes to the given paths.
+    pub fn new<P, Q>(trace_path: P, crash_path: Q) -> Quickstart<T>
+        where P: Into<PathBuf>,
+              Q: Into<PathBuf>
+    {
+        thread_local_trace::set_clock(NsSinceEpoch::monotonic);
+
+        INSTALL_HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                // Don't wait on, or deadlock with, a dump that panicked.
+                if let Ok(dump) = CRASH_DUMP.try_lock() {
+                    if let Some((_, ref dump)) = *dump {
+                        dump();
+                    }
+                }
+                previous(info);
+            }));
+        });
+
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        let crash_path = crash_path.into();
+        let dump = move || {
+            let _ = export::<T>(&crash_path);
+        };
+        let mut crash_dump = CRASH_DUMP.lock().unwrap_or_else(PoisonError::into_inner);
+        *crash_dump = Some((generation, Box::new(dump)));
+        drop(crash_dump);
+
+        Quickstart {
+            trace_path: trace_path.into(),
+            generation: generation,
+            finished: false,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Export every thread's traces now, rather than when dropped, so that
+    /// errors can be handled.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.disarm();
+        export::<T>(&self.trace_path)
+    }
+
+    // Stop the panic hook from dumping for this guard, unless a later one has
+    // already taken over.
+    fn disarm(&self) {
+        let mut dump = CRASH_DUMP.lock().unwrap_or_else(PoisonError::into_inner);
+        if dump.as_ref().map_or(false, |&(generation, _)| generation == self.generation) {
+            *dump = None;
+        }
+    }
+}
+
+impl<T> Drop for Quickstart<T>
+    where T: 'static + Send + Trace
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            self.disarm();
+            let _ = export::<T>(&self.trace_path);
+        }
+    }
+}
+
+// Write every thread's traces for `T` to `path`.
+fn export<T>(path: &Path) -> io::Result<()>
+    where T: 'static + Send + Trace
+{
+    let mut writer = BufWriter::new(try!(File::create(path)));
+    try!(chrome::write_json(thread_local_trace::merge::<T>().map(|(_, entry)| entry),
+                            &mut writer));
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use std::thread;
+    use thread_local_trace::ThreadLocalSink;
+    use traits::TraceSink;
+
+    fn labels(path: &Path) -> Vec<String> {
+        let json: serde_json::Value = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        json.find("traceEvents")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.find("name").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn exports_on_drop_and_dumps_on_panic() {
+        let dir = env::temp_dir();
+        let trace_path = dir.join(format!("eep-quickstart-trace-{}.json", std::process::id()));
+        let crash_path = dir.join(format!("eep-quickstart-crash-{}.json", std::process::id()));
+
+        let guard = Quickstart::<SimpleTrace>::new(trace_path.clone(), crash_path.clone());
+        thread::spawn(|| {
+                ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+                panic!("crash");
+            })
+            .join()
+            .unwrap_err();
+        assert!(labels(&crash_path).contains(&"Foo".to_string()));
+
+        let mut sink = ThreadLocalSink::get();
+        let id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+        drop(guard);
+        assert!(labels(&trace_path).contains(&"Thing".to_string()));
+
+        // Once the guard is gone, panics no longer dump.
+        fs::remove_file(&crash_path).unwrap();
+        thread::spawn(|| panic!("crash")).join().unwrap_err();
+        assert!(!crash_path.exists());
+
+        let _ = fs::remove_file(trace_path);
+        let _ = fs::remove_file(crash_path);
+    }
+}
@@ -1,19 +1,56 @@
 //! TODO FITZGEN
 
 extern crate serde;
+#[cfg(feature = "native-ids")]
+extern crate thread_id;
+#[cfg(feature = "std")]
 extern crate time;
 
-use std::cell::UnsafeCell;
-use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::collections::{BTreeMap, BinaryHeap};
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+#[cfg(not(feature = "std"))]
+use alloc::sync::Arc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use codec;
+#[cfg(feature = "std")]
+use owned::OwnedEntry;
+use query::TraceQuery;
+use std::cmp::{self, Reverse};
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::marker::PhantomData;
 use std::mem;
-use traits::{ThreadId, Trace, TraceId, TraceSink};
+#[cfg(feature = "native-ids")]
+use std::process;
+use std::slice;
+#[cfg(feature = "std")]
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::time::Instant;
+use track::Track;
+use traits::{BatchKind, ThreadId, Trace, TraceId, TraceSink};
 
 /// TODO FITZGEN
-#[derive(Clone, Debug)]
-pub struct RingBuffer<T> {
+///
+/// The entries are stored in `B`, which is a heap-allocated `Vec<u8>` unless
+/// the buffer is constructed with `from_slice` or `from_array`, neither of
+/// which allocates. Recording into a buffer never allocates, except to intern
+/// a name passed to `trace_event_named` for the first time, so a buffer
+/// backed by an array in a `static` can be traced into from signal handlers,
+/// panic hooks, and other contexts where allocating isn't safe.
+///
+/// A buffer constructed with `with_shared_storage` instead stores its entries
+/// in `SharedBlocks`, so that it can be cloned, for example to hand a capture
+/// to another thread, without copying them. The first write after a clone
+/// copies them instead.
+#[derive(Debug)]
+pub struct RingBuffer<T, B = Vec<u8>> {
     // The data itself.
-    data: Vec<u8>,
+    data: B,
 
     // Where valid data begins.
     begin: usize,
@@ -21,358 +58,2370 @@ pub struct RingBuffer<T> {
     // The number of bytes in the ring buffer that are valid.
     length: usize,
 
+    // Whether writes are currently being rejected, and how many writes were
+    // rejected since the last time we were frozen.
+    frozen: AtomicBool,
+    frozen_writes: AtomicUsize,
+
+    // Recorded timestamps are truncated to a multiple of this many
+    // nanoseconds.
+    timestamp_precision: u64,
+
+    // Where timestamps come from, what to do when they go backwards, and the
+    // last timestamp we handed out to compare against.
+    clock: Clock,
+    clock_regression: ClockRegression,
+    last_timestamp: NsSinceEpoch,
+
+    // How entries are encoded, and with `Encoding::Compact`, the timestamp
+    // that the oldest entry's delta is relative to, and that of the newest
+    // entry. With `Encoding::Relative`, the bases that the oldest and newest
+    // entries' offsets are relative to.
+    encoding: Encoding,
+    compact_base: NsSinceEpoch,
+    compact_last: NsSinceEpoch,
+
+    // How many entries have been written and evicted, and so on.
+    stats: RingBufferStats,
+
+    // How many spans traced into this buffer can be current at once.
+    max_depth: usize,
+
+    // The names traced with `trace_event_named`, which entries refer to by ID.
+    // Names are never evicted, so that every entry's name can be found, but
+    // there are at most `set_name_capacity` of them.
+    names: Names,
+
     phantom: PhantomData<T>,
 }
 
+impl<T, B> Clone for RingBuffer<T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]> + Clone
+{
+    fn clone(&self) -> RingBuffer<T, B> {
+        RingBuffer {
+            data: self.data.clone(),
+            begin: self.begin,
+            length: self.length,
+            frozen: AtomicBool::new(self.is_frozen()),
+            frozen_writes: AtomicUsize::new(self.frozen_writes()),
+            timestamp_precision: self.timestamp_precision,
+            clock: self.clock,
+            clock_regression: self.clock_regression,
+            last_timestamp: self.last_timestamp,
+            encoding: self.encoding,
+            compact_base: self.compact_base,
+            compact_last: self.compact_last,
+            stats: self.stats,
+            max_depth: self.max_depth,
+            names: self.names.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl<T> Default for RingBuffer<T> {
     fn default() -> RingBuffer<T> {
         Self::new(4096)
     }
 }
 
+/// A source of timestamps for a `RingBuffer`.
+pub type Clock = fn() -> NsSinceEpoch;
+
+/// What a `RingBuffer` does when its clock goes backwards, for example because
+/// the wall clock was adjusted.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClockRegression {
+    /// Record the regressed timestamp as-is. Durations spanning the
+    /// regression may be negative. This is the default.
+    Record,
+    /// Record the last timestamp again instead, so that timestamps never
+    /// decrease.
+    Clamp,
+    /// Record the regressed timestamp as-is, but first record a
+    /// `TraceKind::ClockJump` entry so that analyses can tell which durations
+    /// are unreliable.
+    Mark,
+}
+
+/// Counters describing a `RingBuffer`'s health, for judging whether its
+/// capacity is adequate, and how much history was lost. See
+/// `RingBuffer::stats`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RingBufferStats {
+    /// The number of entries written, including those since evicted, but not
+    /// those rejected while frozen.
+    pub written: u64,
+
+    /// The number of entries evicted to make room for newer ones.
+    pub evicted: u64,
+
+    /// The most bytes of entries the buffer has held at once. A buffer whose
+    /// high water is its capacity has been full.
+    pub high_water: usize,
+
+    /// The number of times writing has wrapped around from the end of the
+    /// buffer back to its front.
+    pub wraps: u64,
+
+    /// The number of events traced with `trace_event_named` whose names were
+    /// left out, and so traced as plain events, because the buffer had
+    /// already interned as many names as it can hold.
+    pub unnamed: u64,
+
+    /// The number of spans that were nested beyond `max_depth`, and so
+    /// weren't made current. See `TraceSink::max_depth`.
+    pub depth_exceeded: u64,
+}
+
+impl serde::Serialize for RingBufferStats {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("RingBufferStats", 6));
+        try!(serializer.serialize_struct_elt(&mut state, "written", self.written));
+        try!(serializer.serialize_struct_elt(&mut state, "evicted", self.evicted));
+        try!(serializer.serialize_struct_elt(&mut state, "high_water", self.high_water));
+        try!(serializer.serialize_struct_elt(&mut state, "wraps", self.wraps));
+        try!(serializer.serialize_struct_elt(&mut state, "unnamed", self.unnamed));
+        try!(serializer.serialize_struct_elt(&mut state,
+                                             "depth_exceeded",
+                                             self.depth_exceeded));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+/// How a `RingBuffer` encodes its entries.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Every entry takes the same, fixed number of bytes, so that entries can
+    /// be found without decoding the ones before them. This is the default.
+    Fixed,
+    /// Timestamps are encoded as the delta from the previous entry's, and
+    /// every other field as a LEB128 varint, omitting fields the entry's kind
+    /// doesn't use. Typical entries take a third of the space or less, so a
+    /// buffer holds that much more history, at the cost of decoding entries
+    /// in order, and re-encoding them for `snapshot`.
+    Compact,
+    /// Like `Fixed`, but every entry's timestamp is a 32-bit offset, counted
+    /// in `unit` nanoseconds, from a 64-bit base, saving four bytes an entry.
+    /// The base is recorded once, and again in a re-sync record whenever an
+    /// offset wouldn't fit, which with nanosecond units is at least every four
+    /// seconds, and with microsecond units every seventy minutes. Timestamps
+    /// are rounded down to a whole number of units after their base.
+    ///
+    /// Re-sync records take the space of an entry, and count as entries in
+    /// `stats`. Entries must be decoded in order to find their base.
+    Relative {
+        /// How many nanoseconds each unit of an offset is.
+        unit: u64,
+    },
+}
+
 impl<T> RingBuffer<T> {
-    /// Construct a new `RingBuffer` with the given capacity.
+    /// Construct a new `RingBuffer` with the given capacity, timestamped by the
+    /// system clock.
+    #[cfg(feature = "std")]
     pub fn new(capacity: usize) -> RingBuffer<T> {
+        Self::with_clock(capacity, NsSinceEpoch::now)
+    }
+
+    /// Construct a new `RingBuffer` with the given capacity, timestamped by the
+    /// given `clock`.
+    ///
+    /// This is the only way to construct a `RingBuffer` without the `std`
+    /// feature, since there is no system clock to fall back on.
+    pub fn with_clock(capacity: usize, clock: Clock) -> RingBuffer<T> {
+        assert!(capacity > Entry::<T>::size());
+        Self::from_storage(vec![0; capacity], clock)
+    }
+
+    /// Construct a `RingBuffer` containing the entries encoded in the given
+    /// blocks, and their names, as returned by `as_blocks` (possibly in
+    /// another process).
+    ///
+    /// Returns `None` if the blocks were encoded with a different entry size,
+    /// for example by a different version of this crate or for a different
+    /// `Trace` type, don't contain a whole number of entries, or contain an
+    /// entry of an unknown kind.
+    #[cfg(feature = "std")]
+    pub fn from_blocks(blocks: Blocks) -> Option<RingBuffer<T>> {
+        let Blocks { entry_size, first, second, names } = blocks;
+        let length = first.len() + second.len();
+        if entry_size != Entry::<T>::size() || length % entry_size != 0 {
+            return None;
+        }
+
+        // Leave room for one more entry, so the buffer isn't considered empty
+        // when it is full and can still be traced into.
+        let mut data = Vec::with_capacity(length + entry_size);
+        data.extend_from_slice(first);
+        data.extend_from_slice(second);
+        // Entries are decoded lazily, and panic if they are corrupt, so check
+        // them all up front.
+        let valid = data.chunks(entry_size)
+            .all(|entry| TraceKind::from_u8(entry[0] & KIND_MASK).is_some());
+        if !valid {
+            return None;
+        }
+        data.resize(length + entry_size, 0);
+        let mut buffer = Self::from_raw_parts(data, 0, length);
+        buffer.set_names(names.to_vec());
+        Some(buffer)
+    }
+
+    /// Construct a `RingBuffer` around existing data, for example data
+    /// recovered from a file, timestamped by the system clock.
+    #[cfg(feature = "std")]
+    pub(crate) fn from_raw_parts(data: Vec<u8>, begin: usize, length: usize) -> RingBuffer<T> {
+        let mut buffer = Self::from_storage(data, NsSinceEpoch::now);
+        buffer.begin = begin;
+        buffer.length = length;
+        buffer
+    }
+}
+
+impl<T> RingBuffer<T, SharedBlocks> {
+    /// Construct a new `RingBuffer` with the given capacity, timestamped by the
+    /// given `clock`, whose entries are shared with its clones until either is
+    /// written to.
+    ///
+    /// ```
+    /// use eep::ring_buffer::{NsSinceEpoch, RingBuffer};
+    /// use eep::simple_trace::SimpleTrace;
+    /// use eep::traits::TraceSink;
+    /// use std::thread;
+    ///
+    /// let mut buffer: RingBuffer<SimpleTrace, _> =
+    ///     RingBuffer::with_shared_storage(1 << 20, NsSinceEpoch::now);
+    /// buffer.trace_event(SimpleTrace::FooEvent, None);
+    ///
+    /// // Hand a capture to another thread without copying the megabyte.
+    /// let capture = buffer.clone();
+    /// let exporter = thread::spawn(move || capture.iter().count());
+    /// buffer.trace_event(SimpleTrace::FooEvent, None);
+    /// assert_eq!(exporter.join().unwrap(), 1);
+    /// ```
+    pub fn with_shared_storage(capacity: usize, clock: Clock) -> RingBuffer<T, SharedBlocks> {
         assert!(capacity > Entry::<T>::size());
+        Self::from_storage(SharedBlocks(Arc::new(vec![0; capacity])), clock)
+    }
+}
+
+/// Copy-on-write storage for a `RingBuffer`, as constructed by
+/// `RingBuffer::with_shared_storage`.
+///
+/// Clones share their bytes, and a clone that is written to while shared
+/// copies them first.
+#[derive(Clone, Debug)]
+pub struct SharedBlocks(Arc<Vec<u8>>);
+
+impl SharedBlocks {
+    /// Whether these bytes are shared with a clone, so that the next write
+    /// copies them.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+}
+
+impl AsRef<[u8]> for SharedBlocks {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsMut<[u8]> for SharedBlocks {
+    fn as_mut(&mut self) -> &mut [u8] {
+        let data: &mut Vec<u8> = Arc::make_mut(&mut self.0);
+        data
+    }
+}
+
+impl<'a, T> RingBuffer<T, &'a mut [u8]> {
+    /// Construct a new `RingBuffer` that stores its entries in `data`, without
+    /// allocating, timestamped by the given `clock`.
+    ///
+    /// Panics if `data` can't hold an entry.
+    pub fn from_slice(data: &'a mut [u8], clock: Clock) -> RingBuffer<T, &'a mut [u8]> {
+        assert!(data.len() > Entry::<T>::size());
+        Self::from_storage(data, clock)
+    }
+}
+
+impl<T, const N: usize> RingBuffer<T, [u8; N]> {
+    /// Construct a new `RingBuffer` that stores its entries inline in `data`,
+    /// without allocating, timestamped by the given `clock`.
+    ///
+    /// This is a `const fn`, so the buffer can be a `static`, for example
+    /// behind a lock:
+    ///
+    /// ```
+    /// use eep::ring_buffer::{NsSinceEpoch, RingBuffer};
+    /// use eep::simple_trace::SimpleTrace;
+    /// use eep::traits::TraceSink;
+    /// use std::sync::Mutex;
+    ///
+    /// static FLIGHT_RECORDER: Mutex<RingBuffer<SimpleTrace, [u8; 4096]>> =
+    ///     Mutex::new(RingBuffer::from_array([0; 4096], NsSinceEpoch::now));
+    ///
+    /// // For example, in a panic hook, skipping the trace rather than
+    /// // blocking if the recorder is busy.
+    /// if let Ok(mut recorder) = FLIGHT_RECORDER.try_lock() {
+    ///     recorder.trace_event(SimpleTrace::FooEvent, None);
+    /// }
+    /// ```
+    ///
+    /// Panics, or fails to compile in a `const` context, if `N` can't hold an
+    /// entry.
+    pub const fn from_array(data: [u8; N], clock: Clock) -> RingBuffer<T, [u8; N]> {
+        assert!(N > ENTRY_SIZE);
+        Self::from_storage(data, clock)
+    }
+}
+
+impl<T, B> RingBuffer<T, B> {
+    const fn from_storage(data: B, clock: Clock) -> RingBuffer<T, B> {
         RingBuffer {
-            data: vec![0; capacity],
+            data: data,
             begin: 0,
             length: 0,
+            frozen: AtomicBool::new(false),
+            frozen_writes: AtomicUsize::new(0),
+            timestamp_precision: 1,
+            clock: clock,
+            clock_regression: ClockRegression::Record,
+            last_timestamp: NsSinceEpoch(0),
+            encoding: Encoding::Fixed,
+            compact_base: NsSinceEpoch(0),
+            compact_last: NsSinceEpoch(0),
+            stats: RingBufferStats {
+                written: 0,
+                evicted: 0,
+                high_water: 0,
+                wraps: 0,
+                unnamed: 0,
+                depth_exceeded: 0,
+            },
+            max_depth: usize::MAX,
+            names: Names::new(DEFAULT_NAME_CAPACITY),
             phantom: PhantomData,
         }
     }
+}
 
-    /// Iterate over the `Entry<T>` in this `RingBuffer<T>`.
-    pub fn iter(&self) -> RingBufferIter<T> {
-        RingBufferIter(if self.length == 0 {
-            RingBufferIterState::Empty
-        } else {
-            RingBufferIterState::NonEmpty {
-                buffer: self,
-                idx: self.begin,
-            }
-        })
+impl<T, B> RingBuffer<T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]>
+{
+    /// Timestamp entries recorded from now on with the given `clock`.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
     }
 
-    #[inline(always)]
-    fn end(&self) -> usize {
-        (self.begin + self.length) % self.data.len()
+    /// Set what to do when the clock goes backwards from now on.
+    pub fn set_clock_regression(&mut self, policy: ClockRegression) {
+        self.clock_regression = policy;
     }
 
-    fn write(&mut self, data: &[u8]) {
-        let end = self.end();
-        let new_data_len = data.len();
-        let capacity = self.data.len();
+    /// Get what this `RingBuffer` does when the clock goes backwards.
+    pub fn clock_regression(&self) -> ClockRegression {
+        self.clock_regression
+    }
 
-        if capacity - self.length < Entry::<T>::size() {
-            self.begin = (self.begin + Entry::<T>::size()) % capacity;
-            self.length -= Entry::<T>::size();
+    /// Encode entries with the given `encoding` from now on, discarding any
+    /// entries already in this `RingBuffer`.
+    ///
+    /// Panics if this `RingBuffer`'s capacity can't hold the largest compact
+    /// entry, or if a relative encoding's unit is `0`.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        match encoding {
+            Encoding::Fixed => {}
+            Encoding::Compact => assert!(self.ring().len() >= MAX_COMPACT_SIZE),
+            Encoding::Relative { unit } => assert!(unit > 0),
         }
+        self.encoding = encoding;
+        self.begin = 0;
+        self.length = 0;
+        self.compact_base = NsSinceEpoch(0);
+        self.compact_last = NsSinceEpoch(0);
+    }
 
-        if end + new_data_len > capacity {
-            let middle = capacity - end;
-            self.data[end..capacity].copy_from_slice(&data[..middle]);
-            self.data[0..new_data_len - middle].copy_from_slice(&data[middle..]);
-        } else {
-            self.data[end..end + new_data_len].copy_from_slice(data);
+    /// Get how this `RingBuffer` encodes its entries.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Get counters of how many entries this `RingBuffer` has written and
+    /// evicted, and how full it has been.
+    pub fn stats(&self) -> RingBufferStats {
+        self.stats
+    }
+
+    // Replace this buffer's stats with `stats`, for example those of entries
+    // recovered from elsewhere.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_stats(&mut self, stats: RingBufferStats) {
+        self.stats = stats;
+    }
+
+    /// Get the name interned with the given ID by `trace_event_named`, as
+    /// returned by `Entry::name`.
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(id)
+    }
+
+    /// Get every name interned by `trace_event_named`, indexed by ID.
+    pub fn names(&self) -> &[String] {
+        &self.names.by_id
+    }
+
+    // Replace the interned names with `names`, indexed by ID, for example
+    // those of entries recovered from elsewhere. The table holds at least as
+    // many names as it would have otherwise.
+    #[cfg(feature = "std")]
+    pub(crate) fn set_names(&mut self, names: Vec<String>) {
+        let capacity = cmp::max(self.names.capacity, names.len());
+        self.names = Names::new(capacity);
+        for name in names {
+            let id = self.names.by_id.len() as u32;
+            self.names.ids.entry(name.clone()).or_insert(id);
+            self.names.by_id.push(name);
         }
+    }
 
-        self.length += Entry::<T>::size();
-        debug_assert!(self.length <= capacity);
+    /// Get a `TraceSink` that records into this `RingBuffer` onto the given
+    /// virtual `track`, rather than onto the current thread.
+    pub fn on_track(&mut self, track: Track) -> OnTrack<'_, T, B> {
+        OnTrack {
+            buffer: self,
+            thread: track.thread(),
+        }
     }
-}
 
-impl<T> TraceSink<T> for RingBuffer<T>
-    where T: Trace
-{
-    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
-        let id = T::Id::new_id();
+    /// Truncate the timestamps of entries recorded from now on to a multiple of
+    /// `precision` nanoseconds.
+    ///
+    /// Coarser timestamps compress better, and some environments consider
+    /// high-resolution timing data sensitive. A precision of `0` or `1` records
+    /// full-resolution timestamps, which is the default.
+    pub fn set_timestamp_precision(&mut self, precision: u64) {
+        self.timestamp_precision = precision;
+    }
 
-        let entry: Entry<T> = Entry {
-            why: why.map(|id| (id.thread(), id.u32())),
-            thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
-            id: id.u32(),
-            tag: trace.tag(),
-            kind: TraceKind::Event,
-            phantom: PhantomData,
-        };
+    /// Get the precision, in nanoseconds, that recorded timestamps are
+    /// truncated to.
+    pub fn timestamp_precision(&self) -> u64 {
+        self.timestamp_precision
+    }
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
-        self.write(&entry);
+    /// Set the most distinct names that `trace_event_named` interns, which is
+    /// 1024 by default.
+    ///
+    /// Interned names are kept for as long as the buffer is, so that every
+    /// entry's name can be found, so this bounds their memory. Once the buffer
+    /// holds `capacity` names, events with new names are traced without them,
+    /// and counted in `RingBufferStats::unnamed`. Names already interned stay,
+    /// even if there are more than `capacity` of them.
+    pub fn set_name_capacity(&mut self, capacity: usize) {
+        self.names.capacity = capacity;
+    }
 
-        id
+    /// Set the most spans traced into this `RingBuffer` that can be current
+    /// at once on a thread, which is unbounded by default. See
+    /// `TraceSink::max_depth`.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
     }
 
-    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
-        let id = T::Id::new_id();
+    // Intern `name`, and get the value of a named event's entry that refers
+    // to it, or `0`, for no name, if the names table is full.
+    fn name_value(&mut self, name: &str) -> u64 {
+        match self.names.intern(name) {
+            Some(id) => id as u64 + 1,
+            None => {
+                self.stats.unnamed += 1;
+                0
+            }
+        }
+    }
 
-        let entry: Entry<T> = Entry {
-            why: why.map(|id| (id.thread(), id.u32())),
-            thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
-            id: id.u32(),
-            tag: trace.tag(),
-            kind: TraceKind::Start,
-            phantom: PhantomData,
-        };
+    /// Freeze this `RingBuffer` so that it stops accepting new writes.
+    ///
+    /// While frozen, traces are not recorded, but are counted and reported by
+    /// `frozen_writes`. This guarantees that the entries already in the buffer
+    /// are not overwritten while they are being dumped. Because it only takes
+    /// `&self`, it may be called from within trigger callbacks.
+    pub fn freeze(&self) {
+        self.frozen_writes.store(0, Ordering::Release);
+        self.frozen.store(true, Ordering::Release);
+    }
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
-        self.write(&entry);
+    /// Thaw this `RingBuffer` so that it accepts new writes again, and return
+    /// the number of writes that were rejected while it was frozen.
+    pub fn thaw(&self) -> usize {
+        self.frozen.store(false, Ordering::Release);
+        self.frozen_writes()
+    }
 
-        id
+    /// Return `true` if this `RingBuffer` is frozen, `false` otherwise.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
     }
 
-    fn trace_stop(&mut self, id: T::Id, trace: T) {
-        let entry: Entry<T> = Entry {
-            why: None,
-            thread: id.thread(),
-            timestamp: NsSinceEpoch::now(),
-            id: id.u32(),
-            tag: trace.tag(),
-            kind: TraceKind::Stop,
-            phantom: PhantomData,
-        };
+    /// Get the number of writes that were rejected during the most recent
+    /// freeze.
+    pub fn frozen_writes(&self) -> usize {
+        self.frozen_writes.load(Ordering::Acquire)
+    }
+
+    /// Iterate over the `Entry<T>` in this `RingBuffer<T>`.
+    pub fn iter(&self) -> RingBufferIter<'_, T, B> {
+        RingBufferIter {
+            buffer: self,
+            offset: 0,
+            timestamp: self.compact_base,
+        }
+    }
 
-        let entry: [u8; 65] = unsafe { mem::transmute(entry) };
-        self.write(&entry);
+    /// Iterate over the `Entry<T>`s in this `RingBuffer<T>`, newest first.
+    ///
+    /// With `Encoding::Compact` or `Encoding::Relative`, every entry is decoded
+    /// up front.
+    pub fn iter_rev(&self) -> RingBufferRevIter<'_, T, B> {
+        self.last_n(usize::MAX)
     }
-}
 
-impl<T> serde::Serialize for RingBuffer<T>
-    where T: Trace
-{
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer
-    {
-        // Build up all the entries' labels in a map keyed by T's tag, serialize
-        // that, and then serialize the individual entries.
+    /// Iterate over at most the `n` newest `Entry<T>`s in this
+    /// `RingBuffer<T>`, newest first.
+    ///
+    /// With `Encoding::Compact` or `Encoding::Relative`, every entry is decoded
+    /// up front.
+    pub fn last_n(&self, n: usize) -> RingBufferRevIter<'_, T, B> {
+        let mut decoded = Vec::new();
+        if self.encoding != Encoding::Fixed {
+            for entry in self.iter() {
+                decoded.extend_from_slice(&entry.to_bytes());
+            }
+        }
+        let len = match self.encoding {
+            Encoding::Fixed => self.length / Entry::<T>::size(),
+            Encoding::Compact |
+            Encoding::Relative { .. } => decoded.len() / Entry::<T>::size(),
+        };
+        RingBufferRevIter {
+            buffer: self,
+            yielded: 0,
+            remaining: cmp::min(n, len),
+            decoded: decoded,
+        }
+    }
+
+    /// Query the `Entry<T>`s in this `RingBuffer<T>`, for example only those
+    /// of one kind, or in a time range.
+    pub fn query(&self) -> TraceQuery<RingBufferIter<'_, T, B>> {
+        TraceQuery::new(self.iter())
+    }
 
-        struct Entries<'a, T>(&'a RingBuffer<T>) where T: 'a + Trace;
+    /// Get the raw encoded bytes of the entries in this `RingBuffer`, for
+    /// shipping over a custom transport verbatim.
+    ///
+    /// Decode the bytes on the other side with `RingBuffer::from_blocks`.
+    ///
+    /// Panics if this `RingBuffer` uses `Encoding::Compact` or
+    /// `Encoding::Relative`, whose entries can't be decoded without the ones
+    /// evicted before them.
+    pub fn as_blocks(&self) -> Blocks<'_> {
+        assert_eq!(self.encoding, Encoding::Fixed);
+        let ring = self.ring();
+        let first_len = cmp::min(self.length, ring.len() - self.begin);
+        Blocks {
+            entry_size: Entry::<T>::size(),
+            first: &ring[self.begin..self.begin + first_len],
+            second: &ring[..self.length - first_len],
+            names: self.names(),
+        }
+    }
 
-        impl<'a, T> serde::Serialize for Entries<'a, T>
-            where T: Trace
-        {
-            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-                where S: serde::Serializer
-            {
-                let mut state = try!(serializer.serialize_seq(None));
-                for entry in self.0.iter() {
-                    try!(serializer.serialize_seq_elt(&mut state, entry));
+    /// Copy the entries in this `RingBuffer`, and its interned names, out into
+    /// an owned snapshot, in O(length) time, so they can be iterated while
+    /// tracing continues.
+    ///
+    /// With `Encoding::Compact` or `Encoding::Relative`, the entries are
+    /// decoded into the snapshot.
+    pub fn snapshot(&self) -> TraceSnapshot<T> {
+        let mut data = Vec::with_capacity(self.length);
+        match self.encoding {
+            Encoding::Fixed => {
+                let blocks = self.as_blocks();
+                data.extend_from_slice(blocks.first);
+                data.extend_from_slice(blocks.second);
+            }
+            Encoding::Compact | Encoding::Relative { .. } => {
+                for entry in self.iter() {
+                    data.extend_from_slice(&entry.to_bytes());
                 }
-                serializer.serialize_seq_end(state)
             }
         }
+        TraceSnapshot {
+            data: Arc::new(data),
+            names: Arc::new(self.names.by_id.clone()),
+            stats: self.stats,
+            phantom: PhantomData,
+        }
+    }
 
-        let mut labels = HashMap::new();
-        for entry in self.iter() {
-            let tag = entry.tag();
-            // Turn the key into a string to support JSON.
-            labels.insert(format!("{}", tag), T::label(tag));
+    #[inline(always)]
+    fn now(&mut self) -> NsSinceEpoch {
+        let now = (self.clock)().truncate(self.timestamp_precision);
+        if now.0 < self.last_timestamp.0 {
+            return self.regressed(now);
         }
+        self.last_timestamp = now;
+        now
+    }
 
-        let mut state = try!(serializer.serialize_struct("RingBuffer", 2));
-        try!(serializer.serialize_struct_elt(&mut state, "labels", labels));
-        try!(serializer.serialize_struct_elt(&mut state, "entries", Entries(self)));
-        serializer.serialize_struct_end(state)
+    #[cold]
+    fn regressed(&mut self, now: NsSinceEpoch) -> NsSinceEpoch {
+        match self.clock_regression {
+            ClockRegression::Record => {}
+            ClockRegression::Clamp => return self.last_timestamp,
+            ClockRegression::Mark => {
+                self.write_entry(Entry {
+                    why: None,
+                    thread: None,
+                    id: 0,
+                    tag: 0,
+                    timestamp: now,
+                    kind: TraceKind::ClockJump,
+                    value: 0,
+                    #[cfg(feature = "native-ids")]
+                    process: process::id(),
+                    #[cfg(feature = "native-ids")]
+                    native_thread: thread_id::get() as u64,
+                    phantom: PhantomData,
+                });
+            }
+        }
+        self.last_timestamp = now;
+        now
     }
-}
 
-/// Nanoseconds since the epoch.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct NsSinceEpoch(pub u64);
+    #[inline(always)]
+    fn ring(&self) -> &[u8] {
+        self.data.as_ref()
+    }
 
-impl NsSinceEpoch {
-    /// Get the current nanoseconds since the epoch.
     #[inline(always)]
-    pub fn now() -> NsSinceEpoch {
-        let timespec = time::get_time();
-        let sec = timespec.sec as u64;
-        let nsec = timespec.nsec as u64;
-        NsSinceEpoch(sec * 1_000_000_000 + nsec)
+    fn ring_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut()
     }
-}
 
-impl serde::Serialize for NsSinceEpoch {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer
-    {
-        serializer.serialize_newtype_struct("NsSinceEpoch", self.0)
+    #[inline(always)]
+    fn end(&self) -> usize {
+        (self.begin + self.length) % self.ring().len()
+    }
+
+    #[inline(always)]
+    fn write(&mut self, data: &[u8]) {
+        // The common case: there's room without evicting anything, and the
+        // entry doesn't reach the end of the buffer.
+        let end = self.begin + self.length;
+        if end + data.len() < self.ring().len() && !self.is_frozen() {
+            self.ring_mut()[end..end + data.len()].copy_from_slice(data);
+            self.length += data.len();
+            self.stats.written += 1;
+            if self.length > self.stats.high_water {
+                self.stats.high_water = self.length;
+            }
+            return;
+        }
+
+        self.write_slow(data);
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn write_slow(&mut self, data: &[u8]) {
+        if self.is_frozen() {
+            self.frozen_writes.fetch_add(1, Ordering::AcqRel);
+            return;
+        }
+
+        let end = self.end();
+        let capacity = self.ring().len();
+
+        // Every record is the same size, so evicting one makes room.
+        if capacity - self.length < data.len() {
+            if self.encoding != Encoding::Fixed && self.ring()[self.begin] == RESYNC {
+                // The next oldest entry's offset is relative to the evicted
+                // re-sync record's base.
+                let mut record = [0; RELATIVE_SIZE];
+                self.read_wrapping(self.begin, &mut record);
+                self.compact_base = NsSinceEpoch(codec::read_u64(&record[1..]));
+            }
+            self.begin = (self.begin + data.len()) % capacity;
+            self.length -= data.len();
+            self.stats.evicted += 1;
+        }
+
+        copy_wrapping(self.ring_mut(), end, data);
+
+        self.length += data.len();
+        debug_assert!(self.length <= capacity);
+        self.wrote(end, data.len());
+    }
+
+    // Count an entry of `len` bytes just written at index `end`.
+    fn wrote(&mut self, end: usize, len: usize) {
+        self.stats.written += 1;
+        self.stats.high_water = cmp::max(self.stats.high_water, self.length);
+        if end + len >= self.ring().len() {
+            self.stats.wraps += 1;
+        }
+    }
+
+    /// Append an already-constructed entry, for example one with a synthetic
+    /// timestamp.
+    pub(crate) fn write_entry(&mut self, entry: Entry<T>) {
+        match self.encoding {
+            Encoding::Fixed => self.write(&entry.to_bytes()),
+            Encoding::Compact => self.write_compact(entry),
+            Encoding::Relative { unit } => self.write_relative(entry, unit),
+        }
+    }
+
+    fn write_compact(&mut self, entry: Entry<T>) {
+        if self.is_frozen() {
+            self.frozen_writes.fetch_add(1, Ordering::AcqRel);
+            return;
+        }
+
+        let mut record = [0; MAX_COMPACT_SIZE];
+        let len = entry.encode_compact(self.compact_last, &mut record);
+        self.compact_last = entry.timestamp;
+
+        let capacity = self.ring().len();
+        while capacity - self.length < len {
+            // Evict the oldest entry, which the next oldest's delta is now
+            // relative to.
+            let (evicted, evicted_len) = Entry::<T>::decode_compact(self.ring(),
+                                                                    self.begin,
+                                                                    self.compact_base);
+            self.compact_base = evicted.timestamp;
+            self.begin = (self.begin + evicted_len) % capacity;
+            self.length -= evicted_len;
+            self.stats.evicted += 1;
+        }
+
+        let end = self.end();
+        copy_wrapping(self.ring_mut(), end, &record[..len]);
+        self.length += len;
+        self.wrote(end, len);
+    }
+
+    fn write_relative(&mut self, entry: Entry<T>, unit: u64) {
+        if self.is_frozen() {
+            self.frozen_writes.fetch_add(1, Ordering::AcqRel);
+            return;
+        }
+
+        let offset = entry.timestamp.0.checked_sub(self.compact_last.0).map(|delta| delta / unit);
+        let offset = match offset {
+            Some(offset) if offset <= u32::MAX as u64 && self.length > 0 => offset as u32,
+            _ => {
+                // The buffer is empty, so nothing has recorded the base yet,
+                // or the offset doesn't fit, so re-sync to this entry's
+                // timestamp.
+                let mut record = [0; RELATIVE_SIZE];
+                record[0] = RESYNC;
+                codec::write_u64(&mut record[1..], entry.timestamp.0);
+                self.write(&record);
+                self.compact_last = entry.timestamp;
+                0
+            }
+        };
+        self.write(&entry.to_relative_bytes(offset));
+    }
+
+    fn fixed_entry_at(&self, idx: usize) -> Entry<T> {
+        let mut bytes = [0; ENTRY_SIZE];
+        self.read_wrapping(idx, &mut bytes);
+        Entry::from_bytes(&bytes)
+    }
+
+    // Fill `out` with the bytes starting at index `idx`, wrapping around to
+    // the front of the buffer if they are split across its end.
+    fn read_wrapping(&self, idx: usize, out: &mut [u8]) {
+        let ring = self.ring();
+        let capacity = ring.len();
+        if idx + out.len() > capacity {
+            let middle = capacity - idx;
+            out[..middle].copy_from_slice(&ring[idx..]);
+            let len = out.len();
+            out[middle..].copy_from_slice(&ring[..len - middle]);
+        } else {
+            let len = out.len();
+            out.copy_from_slice(&ring[idx..idx + len]);
+        }
     }
 }
 
-/// The kind of trace an entry represents.
-#[repr(u8)]
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub enum TraceKind {
-    /// A one off event.
-    Event = 0x0,
-    /// The start of some operation.
-    Start = 0x1,
-    /// The end of some operation.
-    Stop = 0x2,
+// Every encoding starts entries with their kind in the low bits of a byte, and
+// which of their optional fields are present in the high bits.
+const KIND_MASK: u8 = 0x0f;
+const HAS_THREAD: u8 = 0x10;
+const HAS_WHY: u8 = 0x20;
+const WHY_HAS_THREAD: u8 = 0x40;
+// An event traced with `trace_event_named`, whose value is its name's ID plus
+// one.
+const NAMED: u8 = 0x80;
+
+// At most five varints: the ID, thread, why's ID and thread, and value, plus
+// the process and native thread with `native-ids`.
+#[cfg(not(feature = "native-ids"))]
+pub(crate) const MAX_VARINT_FIELDS_SIZE: usize = 5 * codec::MAX_VARINT_LEN;
+#[cfg(feature = "native-ids")]
+pub(crate) const MAX_VARINT_FIELDS_SIZE: usize = 7 * codec::MAX_VARINT_LEN;
+
+// The flags byte, the tag and timestamp delta as varints, and the rest.
+const MAX_COMPACT_SIZE: usize = 1 + 2 * codec::MAX_VARINT_LEN + MAX_VARINT_FIELDS_SIZE;
+
+// With `Encoding::Relative`, entries are laid out like fixed ones, but with a
+// 32-bit offset in place of their timestamp.
+const RELATIVE_SIZE: usize = ENTRY_SIZE - 4;
+
+// With `Encoding::Relative`, the flags byte of a re-sync record, whose base
+// follows it. No kind has this value.
+const RESYNC: u8 = KIND_MASK;
+
+// Reads bytes out of a ring, starting at an index and wrapping around its end.
+struct RingReader<'a> {
+    ring: &'a [u8],
+    at: usize,
+    read: usize,
 }
 
-impl serde::Serialize for TraceKind {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer
-    {
-        match *self {
-            TraceKind::Event => serializer.serialize_unit_variant("TraceKind", 0, "Event"),
-            TraceKind::Start => serializer.serialize_unit_variant("TraceKind", 1, "Start"),
-            TraceKind::Stop => serializer.serialize_unit_variant("TraceKind", 2, "Stop"),
-        }
+impl<'a> Iterator for RingReader<'a> {
+    type Item = u8;
+
+    #[inline]
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.ring[(self.at + self.read) % self.ring.len()];
+        self.read += 1;
+        Some(byte)
     }
 }
 
-/// An `Entry<T>` is a single trace, why it happened, on which thread, and when.
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub struct Entry<T> {
+/// Copy `bytes` into `ring` starting at index `at`, wrapping around to the
+/// front of `ring` if they don't fit before its end.
+pub(crate) fn copy_wrapping(ring: &mut [u8], at: usize, bytes: &[u8]) {
+    let capacity = ring.len();
+    if at + bytes.len() > capacity {
+        let middle = capacity - at;
+        ring[at..capacity].copy_from_slice(&bytes[..middle]);
+        ring[0..bytes.len() - middle].copy_from_slice(&bytes[middle..]);
+    } else {
+        ring[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+impl<T, B> TraceSink<T> for RingBuffer<T, B>
+    where T: Trace,
+          B: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let timestamp = self.now();
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, why, timestamp));
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let timestamp = self.now();
+        self.write_entry(Entry::new(TraceKind::Start, trace.tag(), id, why, timestamp));
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        let timestamp = self.now();
+        self.write_entry(Entry::new(TraceKind::Stop, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        let timestamp = self.now();
+        self.write_entry(Entry::new(TraceKind::Cancel, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let duration = end.0.saturating_sub(start.0);
+        let end = end.truncate(self.timestamp_precision);
+        self.write_entry(Entry::new(TraceKind::Complete, trace.tag(), id, why, end)
+            .with_value(duration));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        let timestamp = self.now();
+        let id = T::Id::new_id();
+        self.write_entry(Entry::new(TraceKind::Park, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        let timestamp = self.now();
+        let id = T::Id::new_id();
+        self.write_entry(Entry::new(TraceKind::Unpark, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let timestamp = self.now();
+        let id = T::Id::new_id();
+        self.write_entry(Entry::new(TraceKind::Counter, trace.tag(), id, None, timestamp)
+            .with_value(value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let timestamp = self.now();
+        let id = T::Id::new_id();
+        let name = self.name_value(name);
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, None, timestamp)
+            .with_value(name));
+        id
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        // Every trace in the batch shares one timestamp.
+        let timestamp = self.now();
+
+        // The common case: there's room for the whole batch without evicting
+        // anything or reaching the end of the buffer, so encode the entries
+        // straight into it, one after another.
+        let size = Entry::<T>::size();
+        let end = self.begin + self.length;
+        if self.encoding == Encoding::Fixed && !self.is_frozen() &&
+           end + traces.len() * size < self.ring().len() {
+            let mut at = end;
+            for &(trace, kind) in traces {
+                let entry = batched(trace, kind, timestamp);
+                self.ring_mut()[at..at + size].copy_from_slice(&entry.to_bytes());
+                at += size;
+                self.stats.written += 1;
+            }
+            self.length = at - self.begin;
+            self.stats.high_water = cmp::max(self.stats.high_water, self.length);
+            return;
+        }
+
+        for &(trace, kind) in traces {
+            self.write_entry(batched(trace, kind, timestamp));
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.stats.depth_exceeded += 1;
+        self.trace_counter(trace, depth as u64);
+    }
+}
+
+// The entry for a trace in a `trace_batch`.
+fn batched<T>(trace: T, kind: BatchKind, timestamp: NsSinceEpoch) -> Entry<T>
+    where T: Trace
+{
+    Entry::new(kind.into(), trace.tag(), T::Id::new_id(), None, timestamp)
+}
+
+const DEFAULT_NAME_CAPACITY: usize = 1024;
+
+// The table of names interned by `trace_event_named`. Each name is stored
+// once, and an ID is its index in `by_id`.
+#[derive(Clone, Debug)]
+struct Names {
+    by_id: Vec<String>,
+    ids: BTreeMap<String, u32>,
+    // The most names to intern.
+    capacity: usize,
+}
+
+impl Names {
+    const fn new(capacity: usize) -> Names {
+        Names {
+            by_id: Vec::new(),
+            ids: BTreeMap::new(),
+            capacity: capacity,
+        }
+    }
+
+    // Get the ID of `name`, adding it to the table if it isn't there yet, or
+    // return `None` if it isn't and the table is full.
+    fn intern(&mut self, name: &str) -> Option<u32> {
+        if let Some(&id) = self.ids.get(name) {
+            return Some(id);
+        }
+        if self.by_id.len() >= self.capacity {
+            return None;
+        }
+        let id = self.by_id.len() as u32;
+        self.by_id.push(name.into());
+        self.ids.insert(name.into(), id);
+        Some(id)
+    }
+
+    fn get(&self, id: u32) -> Option<&str> {
+        self.by_id.get(id as usize).map(|name| &name[..])
+    }
+}
+
+/// A `TraceSink` that records into a `RingBuffer` onto a virtual track. See
+/// `RingBuffer::on_track`.
+#[derive(Debug)]
+pub struct OnTrack<'a, T, B = Vec<u8>>
+    where T: 'a,
+          B: 'a
+{
+    buffer: &'a mut RingBuffer<T, B>,
+    thread: ThreadId,
+}
+
+impl<'a, T, B> OnTrack<'a, T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn write(&mut self, mut entry: Entry<T>) {
+        entry.thread = Some(self.thread);
+        self.buffer.write_entry(entry);
+    }
+}
+
+impl<'a, T, B> TraceSink<T> for OnTrack<'a, T, B>
+    where T: Trace,
+          B: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let timestamp = self.buffer.now();
+        self.write(Entry::new(TraceKind::Event, trace.tag(), id, why, timestamp));
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let timestamp = self.buffer.now();
+        self.write(Entry::new(TraceKind::Start, trace.tag(), id, why, timestamp));
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        let timestamp = self.buffer.now();
+        self.write(Entry::new(TraceKind::Stop, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        let timestamp = self.buffer.now();
+        self.write(Entry::new(TraceKind::Cancel, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let duration = end.0.saturating_sub(start.0);
+        let end = end.truncate(self.buffer.timestamp_precision);
+        self.write(Entry::new(TraceKind::Complete, trace.tag(), id, why, end)
+            .with_value(duration));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        let timestamp = self.buffer.now();
+        let id = T::Id::new_id();
+        self.write(Entry::new(TraceKind::Park, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        let timestamp = self.buffer.now();
+        let id = T::Id::new_id();
+        self.write(Entry::new(TraceKind::Unpark, trace.tag(), id, None, timestamp));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let timestamp = self.buffer.now();
+        let id = T::Id::new_id();
+        self.write(Entry::new(TraceKind::Counter, trace.tag(), id, None, timestamp)
+            .with_value(value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let timestamp = self.buffer.now();
+        let id = T::Id::new_id();
+        let name = self.buffer.name_value(name);
+        self.write(Entry::new(TraceKind::Event, trace.tag(), id, None, timestamp)
+            .with_value(name));
+        id
+    }
+
+    fn max_depth(&self) -> usize {
+        self.buffer.max_depth
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.buffer.stats.depth_exceeded += 1;
+        self.trace_counter(trace, depth as u64);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, B> serde::Serialize for RingBuffer<T, B>
+    where T: Trace,
+          B: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        // Build up all the entries' labels in a map keyed by T's tag, serialize
+        // that, and then serialize the individual entries.
+
+        struct Entries<'a, T, B>(&'a RingBuffer<T, B>) where T: 'a + Trace, B: 'a;
+
+        impl<'a, T, B> serde::Serialize for Entries<'a, T, B>
+            where T: Trace,
+                  B: AsRef<[u8]> + AsMut<[u8]>
+        {
+            fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+                where S: serde::Serializer
+            {
+                let mut state = try!(serializer.serialize_seq(None));
+                for entry in self.0.iter() {
+                    try!(serializer.serialize_seq_elt(&mut state, entry));
+                }
+                serializer.serialize_seq_end(state)
+            }
+        }
+
+        let mut labels = HashMap::new();
+        let mut categories = HashMap::new();
+        let mut colors = HashMap::new();
+        for entry in self.iter().filter(|e| e.kind() != TraceKind::ClockJump) {
+            let tag = entry.tag();
+            // Turn the key into a string to support JSON.
+            let key = format!("{}", tag);
+            if let Some(category) = T::category(tag) {
+                categories.insert(key.clone(), category);
+            }
+            if let Some(color) = T::color(tag) {
+                colors.insert(key.clone(), color);
+            }
+            labels.insert(key, T::label(tag));
+        }
+
+        let mut state = try!(serializer.serialize_struct("RingBuffer", 6));
+        try!(serializer.serialize_struct_elt(&mut state, "labels", labels));
+        try!(serializer.serialize_struct_elt(&mut state, "categories", categories));
+        try!(serializer.serialize_struct_elt(&mut state, "colors", colors));
+        try!(serializer.serialize_struct_elt(&mut state, "names", self.names()));
+        try!(serializer.serialize_struct_elt(&mut state, "stats", self.stats));
+        try!(serializer.serialize_struct_elt(&mut state, "entries", Entries(self)));
+        serializer.serialize_struct_end(state)
+    }
+}
+
+/// Nanoseconds since the epoch.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct NsSinceEpoch(pub u64);
+
+impl NsSinceEpoch {
+    /// Get the current nanoseconds since the epoch.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub fn now() -> NsSinceEpoch {
+        let timespec = time::get_time();
+        let sec = timespec.sec as u64;
+        let nsec = timespec.nsec as u64;
+        NsSinceEpoch(sec * 1_000_000_000 + nsec)
+    }
+
+    /// Get the current nanoseconds since the epoch from a monotonic clock,
+    /// which, unlike `now`, never goes backwards when the wall clock is
+    /// adjusted. It is anchored to the wall clock the first time it is called.
+    #[cfg(feature = "std")]
+    pub fn monotonic() -> NsSinceEpoch {
+        static ANCHOR: OnceLock<(Instant, NsSinceEpoch)> = OnceLock::new();
+        let &(instant, epoch) = ANCHOR.get_or_init(|| (Instant::now(), NsSinceEpoch::now()));
+        NsSinceEpoch(epoch.0 + instant.elapsed().as_nanos() as u64)
+    }
+
+    /// Truncate this timestamp to a multiple of `precision` nanoseconds. A
+    /// precision of `0` or `1` leaves the timestamp as-is.
+    #[inline(always)]
+    pub fn truncate(self, precision: u64) -> NsSinceEpoch {
+        if precision <= 1 {
+            self
+        } else {
+            NsSinceEpoch(self.0 - self.0 % precision)
+        }
+    }
+}
+
+impl serde::Serialize for NsSinceEpoch {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        serializer.serialize_newtype_struct("NsSinceEpoch", self.0)
+    }
+}
+
+impl serde::Deserialize for NsSinceEpoch {
+    fn deserialize<D>(deserializer: &mut D) -> Result<NsSinceEpoch, D::Error>
+        where D: serde::Deserializer
+    {
+        struct NsVisitor;
+
+        impl serde::de::Visitor for NsVisitor {
+            type Value = NsSinceEpoch;
+
+            fn visit_u64<E>(&mut self, ns: u64) -> Result<NsSinceEpoch, E>
+                where E: serde::de::Error
+            {
+                Ok(NsSinceEpoch(ns))
+            }
+
+            fn visit_newtype_struct<D>(&mut self,
+                                       deserializer: &mut D)
+                                       -> Result<NsSinceEpoch, D::Error>
+                where D: serde::Deserializer
+            {
+                serde::Deserialize::deserialize(deserializer).map(NsSinceEpoch)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct("NsSinceEpoch", NsVisitor)
+    }
+}
+
+/// The kind of trace an entry represents.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceKind {
+    /// A one off event.
+    Event = 0x0,
+    /// The start of some operation.
+    Start = 0x1,
+    /// The end of some operation.
+    Stop = 0x2,
+    /// Not a trace, but a marker that the clock went backwards just before
+    /// this entry's timestamp. Its tag, ID, and label are meaningless.
+    ClockJump = 0x3,
+    /// The abnormal end of some operation, which was aborted before it
+    /// completed.
+    Cancel = 0x4,
+    /// The thread parked to wait for more work.
+    Park = 0x5,
+    /// The thread was unparked after parking.
+    Unpark = 0x6,
+    /// A whole operation, recorded when it ended. The entry's timestamp is
+    /// the operation's end, and its value is the operation's duration in
+    /// nanoseconds, available as `Entry::duration`, so that its start is
+    /// `Entry::start`. Exported to Chrome as a complete (`X`) event.
+    Complete = 0x7,
+    /// A sampled numeric value, available as `Entry::counter_value`.
+    Counter = 0x8,
+}
+
+impl TraceKind {
+    fn from_u8(kind: u8) -> Option<TraceKind> {
+        Some(match kind {
+            0x0 => TraceKind::Event,
+            0x1 => TraceKind::Start,
+            0x2 => TraceKind::Stop,
+            0x3 => TraceKind::ClockJump,
+            0x4 => TraceKind::Cancel,
+            0x5 => TraceKind::Park,
+            0x6 => TraceKind::Unpark,
+            0x7 => TraceKind::Complete,
+            0x8 => TraceKind::Counter,
+            _ => return None,
+        })
+    }
+}
+
+impl serde::Serialize for TraceKind {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        match *self {
+            TraceKind::Event => serializer.serialize_unit_variant("TraceKind", 0, "Event"),
+            TraceKind::Start => serializer.serialize_unit_variant("TraceKind", 1, "Start"),
+            TraceKind::Stop => serializer.serialize_unit_variant("TraceKind", 2, "Stop"),
+            TraceKind::ClockJump => {
+                serializer.serialize_unit_variant("TraceKind", 3, "ClockJump")
+            }
+            TraceKind::Cancel => serializer.serialize_unit_variant("TraceKind", 4, "Cancel"),
+            TraceKind::Park => serializer.serialize_unit_variant("TraceKind", 5, "Park"),
+            TraceKind::Unpark => serializer.serialize_unit_variant("TraceKind", 6, "Unpark"),
+            TraceKind::Complete => serializer.serialize_unit_variant("TraceKind", 7, "Complete"),
+            TraceKind::Counter => serializer.serialize_unit_variant("TraceKind", 8, "Counter"),
+        }
+    }
+}
+
+// The names of `TraceKind`'s variants, indexed by their values.
+const TRACE_KIND_NAMES: &[&str] = &["Event",
+                                    "Start",
+                                    "Stop",
+                                    "ClockJump",
+                                    "Cancel",
+                                    "Park",
+                                    "Unpark",
+                                    "Complete",
+                                    "Counter"];
+
+impl serde::Deserialize for TraceKind {
+    fn deserialize<D>(deserializer: &mut D) -> Result<TraceKind, D::Error>
+        where D: serde::Deserializer
+    {
+        // A variant, identified by its name in self-describing formats like
+        // JSON, and by its index in others.
+        struct Variant(TraceKind);
+
+        impl serde::Deserialize for Variant {
+            fn deserialize<D>(deserializer: &mut D) -> Result<Variant, D::Error>
+                where D: serde::Deserializer
+            {
+                deserializer.deserialize_struct_field(VariantVisitor)
+            }
+        }
+
+        struct VariantVisitor;
+
+        impl serde::de::Visitor for VariantVisitor {
+            type Value = Variant;
+
+            fn visit_u64<E>(&mut self, index: u64) -> Result<Variant, E>
+                where E: serde::de::Error
+            {
+                if index < TRACE_KIND_NAMES.len() as u64 {
+                    Ok(Variant(TraceKind::from_u8(index as u8).unwrap()))
+                } else {
+                    Err(E::invalid_value("unknown TraceKind index"))
+                }
+            }
+
+            fn visit_str<E>(&mut self, name: &str) -> Result<Variant, E>
+                where E: serde::de::Error
+            {
+                match TRACE_KIND_NAMES.iter().position(|&n| n == name) {
+                    Some(index) => self.visit_u64(index as u64),
+                    None => Err(E::unknown_variant(name)),
+                }
+            }
+        }
+
+        struct KindVisitor;
+
+        impl serde::de::EnumVisitor for KindVisitor {
+            type Value = TraceKind;
+
+            fn visit<V>(&mut self, mut visitor: V) -> Result<TraceKind, V::Error>
+                where V: serde::de::VariantVisitor
+            {
+                let Variant(kind) = try!(visitor.visit_variant());
+                try!(visitor.visit_unit());
+                Ok(kind)
+            }
+        }
+
+        deserializer.deserialize_enum("TraceKind", TRACE_KIND_NAMES, KindVisitor)
+    }
+}
+
+/// An `Entry<T>` is a single trace, why it happened, on which thread, and when.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Entry<T> {
     why: Option<(Option<ThreadId>, u32)>,
     thread: Option<ThreadId>,
     id: u32,
     tag: u32,
     timestamp: NsSinceEpoch,
     kind: TraceKind,
+    // Extra data whose meaning depends on `kind`.
+    value: u64,
+    #[cfg(feature = "native-ids")]
+    process: u32,
+    #[cfg(feature = "native-ids")]
+    native_thread: u64,
     phantom: PhantomData<T>,
 }
 
+// In the fixed encoding, every entry is laid out the same way, regardless of
+// platform, with every integer little-endian:
+//
+// | Offset | Size | Field                                                     |
+// |--------|------|-----------------------------------------------------------|
+// | 0      | 1    | Kind, and which of thread and why are present             |
+// | 1      | 4    | Tag                                                       |
+// | 5      | 4    | ID                                                        |
+// | 9      | 8    | Thread, or zero                                           |
+// | 17     | 4    | Why's ID, or zero                                         |
+// | 21     | 8    | Why's thread, or zero                                     |
+// | 29     | 8    | Timestamp                                                 |
+// | 37     | 8    | Value                                                     |
+// | 45     | 4    | Process (`native-ids` only)                               |
+// | 49     | 8    | Native thread (`native-ids` only)                         |
+const TAG_OFFSET: usize = 1;
+const ID_OFFSET: usize = 5;
+const THREAD_OFFSET: usize = 9;
+const WHY_ID_OFFSET: usize = 17;
+const WHY_THREAD_OFFSET: usize = 21;
+const TIMESTAMP_OFFSET: usize = 29;
+const VALUE_OFFSET: usize = 37;
+#[cfg(feature = "native-ids")]
+const PROCESS_OFFSET: usize = 45;
+#[cfg(feature = "native-ids")]
+const NATIVE_THREAD_OFFSET: usize = 49;
+
+#[cfg(not(feature = "native-ids"))]
+const ENTRY_SIZE: usize = 45;
+#[cfg(feature = "native-ids")]
+const ENTRY_SIZE: usize = 57;
+
 impl<T> Entry<T>
     where T: Trace
 {
+    pub(crate) fn new(kind: TraceKind,
+                      tag: u32,
+                      id: T::Id,
+                      why: Option<T::Id>,
+                      timestamp: NsSinceEpoch)
+                      -> Entry<T> {
+        Entry {
+            why: why.map(|id| (id.thread(), id.u32())),
+            thread: id.thread(),
+            timestamp: timestamp,
+            id: id.u32(),
+            tag: tag,
+            kind: kind,
+            value: 0,
+            #[cfg(feature = "native-ids")]
+            process: process::id(),
+            #[cfg(feature = "native-ids")]
+            native_thread: thread_id::get() as u64,
+            phantom: PhantomData,
+        }
+    }
+
     /// Get the label of this trace entry.
     pub fn label(&self) -> &'static str {
         T::label(self.tag)
     }
 }
 
-impl<T> Entry<T> {
-    /// Get the tag for this trace entry.
-    pub fn tag(&self) -> u32 {
-        self.tag
-    }
-
-    /// Get the kind of trace this entry represents.
-    pub fn kind(&self) -> TraceKind {
-        self.kind
-    }
+impl<T> Entry<T> {
+    /// Get the tag for this trace entry.
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    /// Get the kind of trace this entry represents.
+    pub fn kind(&self) -> TraceKind {
+        self.kind
+    }
+
+    /// Get the timestamp when this trace ocurred.
+    ///
+    /// Complete entries are recorded when their operation ended, so that
+    /// entries stay in timestamp order, and this is their end. Use `start` to
+    /// get when they started.
+    pub fn timestamp(&self) -> NsSinceEpoch {
+        self.timestamp
+    }
+
+    /// Get the timestamp when the operation this entry represents started,
+    /// if it is a `TraceKind::Complete` entry, or else when it ocurred.
+    pub fn start(&self) -> NsSinceEpoch {
+        match self.duration() {
+            Some(duration) => NsSinceEpoch(self.timestamp.0.saturating_sub(duration)),
+            None => self.timestamp,
+        }
+    }
+
+    /// Get the duration, in nanoseconds, of the operation this entry
+    /// represents, if it is a `TraceKind::Complete` entry.
+    pub fn duration(&self) -> Option<u64> {
+        if self.kind == TraceKind::Complete {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    /// Get the sampled value, if this is a `TraceKind::Counter` entry.
+    pub fn counter_value(&self) -> Option<u64> {
+        if self.kind == TraceKind::Counter {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    /// Get the ID of this event's name, if it was traced with
+    /// `trace_event_named`. Look the name up with `RingBuffer::name` or
+    /// `TraceSnapshot::name`.
+    pub fn name(&self) -> Option<u32> {
+        if self.kind == TraceKind::Event && self.value != 0 {
+            Some((self.value - 1) as u32)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn with_value(mut self, value: u64) -> Entry<T> {
+        self.value = value;
+        self
+    }
+
+    /// Get a copy of this entry with its timestamp truncated to a multiple of
+    /// `precision` nanoseconds, for truncating timestamps at export time.
+    pub fn with_timestamp_precision(mut self, precision: u64) -> Entry<T> {
+        self.timestamp = self.timestamp.truncate(precision);
+        self
+    }
+
+    /// Get the thread that traced this entry, if available.
+    pub fn thread(&self) -> Option<ThreadId> {
+        self.thread
+    }
+
+    /// Get the ID of this entry.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Get the thread ID and trace ID of the trace that triggered this entry's
+    /// trace, if available.
+    pub fn why(&self) -> Option<(Option<ThreadId>, u32)> {
+        self.why
+    }
+
+    /// Get the ID of the process that wrote this entry, if the `native-ids`
+    /// feature is enabled.
+    #[cfg(feature = "native-ids")]
+    pub fn process_id(&self) -> Option<u32> {
+        Some(self.process)
+    }
+
+    /// Get the ID of the process that wrote this entry, if the `native-ids`
+    /// feature is enabled.
+    #[cfg(not(feature = "native-ids"))]
+    pub fn process_id(&self) -> Option<u32> {
+        None
+    }
+
+    /// Get the native ID of the OS thread that wrote this entry, regardless of
+    /// the `TraceId` type, if the `native-ids` feature is enabled.
+    #[cfg(feature = "native-ids")]
+    pub fn native_thread_id(&self) -> Option<u64> {
+        Some(self.native_thread)
+    }
+
+    /// Get the native ID of the OS thread that wrote this entry, regardless of
+    /// the `TraceId` type, if the `native-ids` feature is enabled.
+    #[cfg(not(feature = "native-ids"))]
+    pub fn native_thread_id(&self) -> Option<u64> {
+        None
+    }
+
+    pub(crate) fn size() -> usize {
+        ENTRY_SIZE
+    }
+
+    pub(crate) fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[0] = self.flags();
+        codec::write_u32(&mut bytes[TAG_OFFSET..], self.tag);
+        codec::write_u32(&mut bytes[ID_OFFSET..], self.id);
+        if let Some(thread) = self.thread {
+            codec::write_u64(&mut bytes[THREAD_OFFSET..], thread.0 as u64);
+        }
+        if let Some((thread, id)) = self.why {
+            codec::write_u32(&mut bytes[WHY_ID_OFFSET..], id);
+            if let Some(thread) = thread {
+                codec::write_u64(&mut bytes[WHY_THREAD_OFFSET..], thread.0 as u64);
+            }
+        }
+        codec::write_u64(&mut bytes[TIMESTAMP_OFFSET..], self.timestamp.0);
+        codec::write_u64(&mut bytes[VALUE_OFFSET..], self.value);
+        #[cfg(feature = "native-ids")]
+        {
+            codec::write_u32(&mut bytes[PROCESS_OFFSET..], self.process);
+            codec::write_u64(&mut bytes[NATIVE_THREAD_OFFSET..], self.native_thread);
+        }
+        bytes
+    }
+
+    /// Panics if `bytes` isn't a valid entry.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Entry<T> {
+        assert_eq!(bytes.len(), Self::size());
+        let flags = bytes[0];
+        let thread = |offset| ThreadId(codec::read_u64(&bytes[offset..]) as usize);
+        Entry {
+            why: if flags & HAS_WHY != 0 {
+                let id = codec::read_u32(&bytes[WHY_ID_OFFSET..]);
+                if flags & WHY_HAS_THREAD != 0 {
+                    Some((Some(thread(WHY_THREAD_OFFSET)), id))
+                } else {
+                    Some((None, id))
+                }
+            } else {
+                None
+            },
+            thread: if flags & HAS_THREAD != 0 {
+                Some(thread(THREAD_OFFSET))
+            } else {
+                None
+            },
+            id: codec::read_u32(&bytes[ID_OFFSET..]),
+            tag: codec::read_u32(&bytes[TAG_OFFSET..]),
+            timestamp: NsSinceEpoch(codec::read_u64(&bytes[TIMESTAMP_OFFSET..])),
+            kind: TraceKind::from_u8(flags & KIND_MASK).expect("corrupt entry"),
+            value: codec::read_u64(&bytes[VALUE_OFFSET..]),
+            #[cfg(feature = "native-ids")]
+            process: codec::read_u32(&bytes[PROCESS_OFFSET..]),
+            #[cfg(feature = "native-ids")]
+            native_thread: codec::read_u64(&bytes[NATIVE_THREAD_OFFSET..]),
+            phantom: PhantomData,
+        }
+    }
+
+    // Encode this entry with its timestamp replaced by `offset`, for
+    // `Encoding::Relative`.
+    fn to_relative_bytes(&self, offset: u32) -> [u8; RELATIVE_SIZE] {
+        let bytes = self.to_bytes();
+        let mut relative = [0; RELATIVE_SIZE];
+        relative[..TIMESTAMP_OFFSET].copy_from_slice(&bytes[..TIMESTAMP_OFFSET]);
+        codec::write_u32(&mut relative[TIMESTAMP_OFFSET..], offset);
+        relative[TIMESTAMP_OFFSET + 4..].copy_from_slice(&bytes[VALUE_OFFSET..]);
+        relative
+    }
+
+    // Decode an entry encoded by `to_relative_bytes` whose offset is relative
+    // to `base` in `unit` nanoseconds.
+    fn from_relative_bytes(relative: &[u8; RELATIVE_SIZE],
+                           base: NsSinceEpoch,
+                           unit: u64)
+                           -> Entry<T> {
+        let offset = codec::read_u32(&relative[TIMESTAMP_OFFSET..]) as u64;
+        let mut bytes = [0; ENTRY_SIZE];
+        bytes[..TIMESTAMP_OFFSET].copy_from_slice(&relative[..TIMESTAMP_OFFSET]);
+        codec::write_u64(&mut bytes[TIMESTAMP_OFFSET..], base.0 + offset * unit);
+        bytes[VALUE_OFFSET..].copy_from_slice(&relative[TIMESTAMP_OFFSET + 4..]);
+        Entry::from_bytes(&bytes)
+    }
+
+    // Encode this entry, with its timestamp relative to `last`, into the front
+    // of `out`, and return the number of bytes written.
+    fn encode_compact(&self, last: NsSinceEpoch, out: &mut [u8; MAX_COMPACT_SIZE]) -> usize {
+        out[0] = self.flags();
+        let mut len = 1;
+        len += codec::write_varint(&mut out[len..], self.tag as u64);
+        len += codec::write_varint(&mut out[len..],
+                                   codec::zigzag(self.timestamp.0.wrapping_sub(last.0) as i64));
+        len + self.encode_varint_fields(&mut out[len..])
+    }
+
+    // Decode the entry at index `at` of `ring`, whose timestamp is relative to
+    // `last`, and return it along with the number of bytes it took.
+    fn decode_compact(ring: &[u8], at: usize, last: NsSinceEpoch) -> (Entry<T>, usize) {
+        let mut reader = RingReader {
+            ring: ring,
+            at: at,
+            read: 0,
+        };
+        let entry = {
+            let flags = reader.next().unwrap();
+            let tag = codec::read_varint(&mut reader).map(|tag| tag as u32);
+            let delta = codec::read_varint(&mut reader).map(codec::unzigzag);
+            tag.and_then(|tag| {
+                    delta.and_then(|delta| {
+                        let timestamp = NsSinceEpoch(last.0.wrapping_add(delta as u64));
+                        Self::decode_varint_fields(flags, tag, timestamp, &mut reader)
+                    })
+                })
+                .expect("corrupt compact entry")
+        };
+        (entry, reader.read)
+    }
+
+    /// The first byte of this entry's encoding, which holds its kind, and
+    /// which of its optional fields are present.
+    pub(crate) fn flags(&self) -> u8 {
+        let mut flags = self.kind as u8;
+        if self.name().is_some() {
+            flags |= NAMED;
+        }
+        if self.thread.is_some() {
+            flags |= HAS_THREAD;
+        }
+        if let Some((thread, _)) = self.why {
+            flags |= HAS_WHY;
+            if thread.is_some() {
+                flags |= WHY_HAS_THREAD;
+            }
+        }
+        flags
+    }
+
+    /// Encode every field but the flags, tag, and timestamp as varints, leaving
+    /// out those the entry doesn't have, into the front of `out`, and return
+    /// the number of bytes written.
+    pub(crate) fn encode_varint_fields(&self, out: &mut [u8]) -> usize {
+        let mut len = 0;
+        {
+            let mut varint = |value: u64| len += codec::write_varint(&mut out[len..], value);
+
+            varint(self.id as u64);
+            if let Some(thread) = self.thread {
+                varint(thread.0 as u64);
+            }
+            if let Some((thread, id)) = self.why {
+                varint(id as u64);
+                if let Some(thread) = thread {
+                    varint(thread.0 as u64);
+                }
+            }
+            if self.kind == TraceKind::Complete || self.kind == TraceKind::Counter ||
+               self.name().is_some() {
+                varint(self.value);
+            }
+            #[cfg(feature = "native-ids")]
+            {
+                varint(self.process as u64);
+                varint(self.native_thread);
+            }
+        }
+        len
+    }
+
+    /// Decode the entry with the given `flags`, `tag`, and `timestamp` whose
+    /// other fields were encoded by `encode_varint_fields` at the front of
+    /// `bytes`, or return `None` if they are corrupt.
+    pub(crate) fn decode_varint_fields<I>(flags: u8,
+                                          tag: u32,
+                                          timestamp: NsSinceEpoch,
+                                          bytes: &mut I)
+                                          -> Option<Entry<T>>
+        where I: Iterator<Item = u8>
+    {
+        let kind = TraceKind::from_u8(flags & KIND_MASK)?;
+        let id = codec::read_varint(bytes)? as u32;
+        let thread = if flags & HAS_THREAD != 0 {
+            Some(ThreadId(codec::read_varint(bytes)? as usize))
+        } else {
+            None
+        };
+        let why = if flags & HAS_WHY != 0 {
+            let id = codec::read_varint(bytes)? as u32;
+            if flags & WHY_HAS_THREAD != 0 {
+                Some((Some(ThreadId(codec::read_varint(bytes)? as usize)), id))
+            } else {
+                Some((None, id))
+            }
+        } else {
+            None
+        };
+        let value = if kind == TraceKind::Complete || kind == TraceKind::Counter ||
+                       flags & NAMED != 0 {
+            codec::read_varint(bytes)?
+        } else {
+            0
+        };
+
+        Some(Entry {
+            why: why,
+            thread: thread,
+            id: id,
+            tag: tag,
+            timestamp: timestamp,
+            kind: kind,
+            value: value,
+            #[cfg(feature = "native-ids")]
+            process: codec::read_varint(bytes)? as u32,
+            #[cfg(feature = "native-ids")]
+            native_thread: codec::read_varint(bytes)?,
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<T> serde::Serialize for Entry<T> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let fields = if cfg!(feature = "native-ids") { 9 } else { 7 };
+        let mut state = try!(serializer.serialize_struct("Entry", fields));
+        try!(serializer.serialize_struct_elt(&mut state, "why", &self.why));
+        try!(serializer.serialize_struct_elt(&mut state, "thread", &self.thread));
+        try!(serializer.serialize_struct_elt(&mut state, "id", self.id));
+        try!(serializer.serialize_struct_elt(&mut state, "tag", self.tag));
+        try!(serializer.serialize_struct_elt(&mut state, "timestamp", self.timestamp));
+        try!(serializer.serialize_struct_elt(&mut state, "kind", self.kind));
+        try!(serializer.serialize_struct_elt(&mut state, "value", self.value));
+        #[cfg(feature = "native-ids")]
+        {
+            try!(serializer.serialize_struct_elt(&mut state, "process", self.process));
+            try!(serializer.serialize_struct_elt(&mut state, "native_thread", self.native_thread));
+        }
+        serializer.serialize_struct_end(state)
+    }
+}
+
+/// The raw encoded bytes of a `RingBuffer`'s entries, as returned by
+/// `RingBuffer::as_blocks`.
+///
+/// The entries are the bytes of `first` followed by the bytes of `second`,
+/// each `entry_size` bytes long and stored oldest first.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Blocks<'a> {
+    /// The size of each encoded entry, in bytes.
+    pub entry_size: usize,
+
+    /// The oldest entries.
+    pub first: &'a [u8],
+
+    /// The newest entries, if the valid data wraps around the end of the
+    /// buffer, and otherwise empty.
+    pub second: &'a [u8],
+
+    /// The names interned by `trace_event_named`, indexed by ID, which the
+    /// entries refer to.
+    pub names: &'a [String],
+}
+
+/// An owned copy of the entries in a `RingBuffer`, as returned by
+/// `RingBuffer::snapshot`.
+///
+/// Clones share the entries and names, so a snapshot can be handed to any
+/// number of export or analysis threads without copying them again.
+#[derive(Debug)]
+pub struct TraceSnapshot<T> {
+    // The encoded entries, oldest first.
+    data: Arc<Vec<u8>>,
+    // The buffer's interned names, indexed by ID.
+    names: Arc<Vec<String>>,
+    // The buffer's stats when the snapshot was taken.
+    stats: RingBufferStats,
+    phantom: PhantomData<T>,
+}
+
+impl<T> Clone for TraceSnapshot<T> {
+    fn clone(&self) -> TraceSnapshot<T> {
+        TraceSnapshot {
+            data: self.data.clone(),
+            names: self.names.clone(),
+            stats: self.stats,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> TraceSnapshot<T> {
+    /// Get the number of entries in this snapshot.
+    pub fn len(&self) -> usize {
+        self.data.len() / Entry::<T>::size()
+    }
+
+    /// Whether this snapshot has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Iterate over the `Entry<T>`s in this snapshot, oldest first.
+    pub fn iter(&self) -> TraceSnapshotIter<'_, T> {
+        TraceSnapshotIter {
+            chunks: self.data.chunks(Entry::<T>::size()),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Query the `Entry<T>`s in this snapshot, for example only those of one
+    /// kind, or in a time range.
+    pub fn query(&self) -> TraceQuery<TraceSnapshotIter<'_, T>> {
+        TraceQuery::new(self.iter())
+    }
+
+    /// Get the name interned with the given ID, as returned by `Entry::name`.
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(id as usize).map(|name| &name[..])
+    }
+
+    /// Get every name interned by the buffer this snapshot was taken of,
+    /// indexed by ID.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Get the stats of the buffer this snapshot was taken of, as of when it
+    /// was taken.
+    pub fn stats(&self) -> RingBufferStats {
+        self.stats
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> serde::Serialize for TraceSnapshot<T>
+    where T: Trace
+{
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        // Serialize owned entries, so that they carry their labels and names,
+        // and can be deserialized again.
+        let mut state = try!(serializer.serialize_seq(Some(self.len())));
+        for entry in self.iter() {
+            let mut owned = OwnedEntry::from(entry);
+            owned.name = entry.name().and_then(|id| self.name(id)).map(String::from);
+            try!(serializer.serialize_seq_elt(&mut state, owned));
+        }
+        serializer.serialize_seq_end(state)
+    }
+}
+
+impl<'a, T> IntoIterator for &'a TraceSnapshot<T> {
+    type Item = Entry<T>;
+    type IntoIter = TraceSnapshotIter<'a, T>;
+
+    fn into_iter(self) -> TraceSnapshotIter<'a, T> {
+        self.iter()
+    }
+}
+
+/// An iterator over the `Entry<T>`s in a `TraceSnapshot<T>`.
+#[derive(Clone, Debug)]
+pub struct TraceSnapshotIter<'a, T> {
+    chunks: slice::Chunks<'a, u8>,
+    phantom: PhantomData<T>,
+}
+
+impl<'a, T> Iterator for TraceSnapshotIter<'a, T> {
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Entry<T>> {
+        self.chunks.next().map(Entry::from_bytes)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+/// An iterator over `Entry<T>`s in a `RingBuffer<T>`.
+#[derive(Clone, Debug)]
+pub struct RingBufferIter<'a, T, B = Vec<u8>>
+    where T: 'a,
+          B: 'a
+{
+    buffer: &'a RingBuffer<T, B>,
+    // How many bytes past the buffer's beginning the next entry starts.
+    offset: usize,
+    // With `Encoding::Compact`, the timestamp of the previous entry, and with
+    // `Encoding::Relative`, the base of the next.
+    timestamp: NsSinceEpoch,
+}
+
+impl<'a, T, B> Iterator for RingBufferIter<'a, T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]>
+{
+    type Item = Entry<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let buffer = self.buffer;
+        loop {
+            if self.offset >= buffer.length {
+                return None;
+            }
+
+            let idx = (buffer.begin + self.offset) % buffer.ring().len();
+            match buffer.encoding {
+                Encoding::Fixed => {
+                    self.offset += Entry::<T>::size();
+                    return Some(buffer.fixed_entry_at(idx));
+                }
+                Encoding::Compact => {
+                    let (entry, len) = Entry::decode_compact(buffer.ring(), idx, self.timestamp);
+                    self.offset += len;
+                    self.timestamp = entry.timestamp;
+                    return Some(entry);
+                }
+                Encoding::Relative { unit } => {
+                    self.offset += RELATIVE_SIZE;
+                    let mut record = [0; RELATIVE_SIZE];
+                    buffer.read_wrapping(idx, &mut record);
+                    if record[0] == RESYNC {
+                        self.timestamp = NsSinceEpoch(codec::read_u64(&record[1..]));
+                        continue;
+                    }
+                    return Some(Entry::from_relative_bytes(&record, self.timestamp, unit));
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over `Entry<T>`s in a `RingBuffer<T>`, newest first.
+#[derive(Clone, Debug)]
+pub struct RingBufferRevIter<'a, T, B = Vec<u8>>
+    where T: 'a,
+          B: 'a
+{
+    buffer: &'a RingBuffer<T, B>,
+    yielded: usize,
+    remaining: usize,
+    // With `Encoding::Compact` or `Encoding::Relative`, every entry re-encoded
+    // in the fixed format, oldest first, since such entries can only be
+    // decoded in that order.
+    decoded: Vec<u8>,
+}
 
-    /// Get the timestamp when this trace ocurred.
-    pub fn timestamp(&self) -> NsSinceEpoch {
-        self.timestamp
-    }
+impl<'a, T, B> Iterator for RingBufferRevIter<'a, T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]>
+{
+    type Item = Entry<T>;
 
-    /// Get the thread that traced this entry, if available.
-    pub fn thread(&self) -> Option<ThreadId> {
-        self.thread
-    }
+    fn next(&mut self) -> Option<Entry<T>> {
+        if self.remaining == 0 {
+            return None;
+        }
 
-    /// Get the ID of this entry.
-    pub fn id(&self) -> u32 {
-        self.id
-    }
+        let size = Entry::<T>::size();
+        self.remaining -= 1;
+        if self.buffer.encoding != Encoding::Fixed {
+            let len = self.decoded.len() - size;
+            let entry = Entry::from_bytes(&self.decoded[len..]);
+            self.decoded.truncate(len);
+            return Some(entry);
+        }
 
-    /// Get the thread ID and trace ID of the trace that triggered this entry's
-    /// trace, if available.
-    pub fn why(&self) -> Option<(Option<ThreadId>, u32)> {
-        self.why
+        let buffer = self.buffer;
+        let capacity = buffer.ring().len();
+        let idx = (buffer.begin + buffer.length - (self.yielded + 1) * size) % capacity;
+        self.yielded += 1;
+        Some(buffer.fixed_entry_at(idx))
     }
 
-    fn size() -> usize {
-        mem::size_of::<Self>()
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
     }
 }
 
-impl<T> serde::Serialize for Entry<T> {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
-        where S: serde::Serializer
-    {
-        let mut state = try!(serializer.serialize_struct("Entry", 6));
-        try!(serializer.serialize_struct_elt(&mut state, "why", &self.why));
-        try!(serializer.serialize_struct_elt(&mut state, "thread", &self.thread));
-        try!(serializer.serialize_struct_elt(&mut state, "id", self.id));
-        try!(serializer.serialize_struct_elt(&mut state, "tag", self.tag));
-        try!(serializer.serialize_struct_elt(&mut state, "timestamp", self.timestamp));
-        try!(serializer.serialize_struct_elt(&mut state, "kind", self.kind));
-        serializer.serialize_struct_end(state)
-    }
+impl<'a, T, B> ExactSizeIterator for RingBufferRevIter<'a, T, B>
+    where B: AsRef<[u8]> + AsMut<[u8]>
+{
 }
 
-#[derive(Clone, Debug)]
-enum RingBufferIterState<'a, T>
-    where T: 'a
-{
-    Empty,
-    NonEmpty {
-        buffer: &'a RingBuffer<T>,
-        idx: usize,
-    },
+/// Merge the entries in each of the given `buffers` into a single timeline,
+/// ordered by timestamp, for example to build a unified view of per-thread or
+/// per-subsystem buffers.
+///
+/// Each entry is yielded along with the index in `buffers` of the buffer it
+/// came from. Entries with equal timestamps are yielded in the order of their
+/// buffers' indices, and entries from the same buffer always keep their
+/// relative order.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::traits::TraceSink;
+///
+/// let mut network = SimpleTraceBuffer::default();
+/// let mut storage = SimpleTraceBuffer::default();
+/// network.trace_event(SimpleTrace::FooEvent, None);
+/// storage.trace_event(SimpleTrace::FooEvent, None);
+///
+/// for (source, entry) in eep::merge(&[&network, &storage]) {
+///     println!("{} from buffer {}", entry.label(), source);
+/// }
+/// ```
+pub fn merge<'a, T>(buffers: &[&'a RingBuffer<T>]) -> Merge<'a, T> {
+    Merge(MergeIters::new(buffers.iter().map(|buffer| buffer.iter()).collect()))
 }
 
-/// An iterator over `Entry<T>`s in a `RingBuffer<T>`.
-#[derive(Clone, Debug)]
-pub struct RingBufferIter<'a, T>(RingBufferIterState<'a, T>) where T: 'a;
+/// An iterator over the entries of several `RingBuffer<T>`s in timestamp
+/// order, along with the index of the buffer each came from. See `merge`.
+#[derive(Debug)]
+pub struct Merge<'a, T>(MergeIters<RingBufferIter<'a, T>, T>) where T: 'a;
 
-impl<'a, T> Iterator for RingBufferIter<'a, T> {
-    type Item = Entry<T>;
+impl<'a, T> Iterator for Merge<'a, T> {
+    type Item = (usize, Entry<T>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (next_state, result) = match self.0 {
-            RingBufferIterState::Empty => return None,
-            RingBufferIterState::NonEmpty { ref buffer, idx } => {
-                let entry: Entry<T> = unsafe {
-                    if idx + Entry::<T>::size() > buffer.data.len() {
-                        // The entry is split across the end of the buffer and
-                        // wraps around to the front of it again.
-                        let entry: UnsafeCell<Entry<T>> = mem::uninitialized();
-                        let mut entry: [u8; 65] = mem::transmute(entry);
-                        let middle = buffer.data.len() - idx;
-                        entry[..middle].copy_from_slice(&buffer.data[idx..]);
-                        entry[middle..]
-                            .copy_from_slice(&buffer.data[..Entry::<T>::size() - middle]);
-                        mem::transmute(entry)
-                    } else {
-                        // The entry is in one contiguous block in the buffer.
-                        let entry: UnsafeCell<Entry<T>> = mem::uninitialized();
-                        let mut entry: [u8; 65] = mem::transmute(entry);
-                        entry.copy_from_slice(&buffer.data[idx..idx + Entry::<T>::size()]);
-                        mem::transmute(entry)
-                    }
-                };
+        self.0.next()
+    }
+}
 
-                let next_idx = (idx + Entry::<T>::size()) % buffer.data.len();
-                let next_state = if next_idx == buffer.end() {
-                    RingBufferIterState::Empty
-                } else {
-                    RingBufferIterState::NonEmpty {
-                        buffer: buffer,
-                        idx: next_idx,
-                    }
-                };
+// A k-way merge of iterators over entries in timestamp order, such as those of
+// `RingBuffer`s or `TraceSnapshot`s, yielding each entry along with the index
+// of the iterator it came from, with the same ordering as `merge`.
+#[derive(Debug)]
+pub(crate) struct MergeIters<I, T> {
+    iters: Vec<I>,
+    // The next entry from each iterator, if it has any left.
+    heads: Vec<Option<Entry<T>>>,
+    // The timestamp and iterator index of every head, earliest first.
+    order: BinaryHeap<Reverse<(u64, usize)>>,
+}
 
-                (next_state, Some(entry))
-            }
+impl<I, T> MergeIters<I, T>
+    where I: Iterator<Item = Entry<T>>
+{
+    pub(crate) fn new(iters: Vec<I>) -> MergeIters<I, T> {
+        let mut merge = MergeIters {
+            heads: Vec::with_capacity(iters.len()),
+            order: BinaryHeap::with_capacity(iters.len()),
+            iters: iters,
         };
+        for source in 0..merge.iters.len() {
+            let head = merge.iters[source].next();
+            merge.heads.push(head);
+            merge.push_head(source);
+        }
+        merge
+    }
+
+    fn push_head(&mut self, source: usize) {
+        if let Some(ref head) = self.heads[source] {
+            self.order.push(Reverse((head.timestamp.0, source)));
+        }
+    }
+}
+
+impl<I, T> Iterator for MergeIters<I, T>
+    where I: Iterator<Item = Entry<T>>
+{
+    type Item = (usize, Entry<T>);
 
-        mem::replace(&mut self.0, next_state);
-        result
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, source)) = self.order.pop()?;
+        let next = self.iters[source].next();
+        let entry = mem::replace(&mut self.heads[source], next)
+            .expect("every ordered iterator has a head");
+        self.push_head(source);
+        Some((source, entry))
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     extern crate serde_json;
 
     use super::*;
-    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
-    use traits::{Trace, TraceSink};
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer, SimpleTraceId};
+    use traits::{BatchKind, Trace, TraceId, TraceSink};
 
     type SimpleEntry = Entry<SimpleTrace>;
 
     #[test]
     fn trace_entry_has_right_size() {
-        assert_eq!(SimpleEntry::size(), 65);
+        assert_eq!(SimpleEntry::size(), ENTRY_SIZE);
+        if cfg!(feature = "native-ids") {
+            assert_eq!(SimpleEntry::size(), 57);
+        } else {
+            assert_eq!(SimpleEntry::size(), 45);
+        }
+    }
+
+    #[test]
+    fn entry_encoding_is_stable() {
+        // Entries must decode the same way on every platform, and in every
+        // version of this crate, so compare against the exact bytes.
+        let entry = SimpleEntry {
+            why: Some((Some(ThreadId(0x0102)), 0x0304)),
+            thread: Some(ThreadId(0x0506)),
+            id: 0x0708,
+            tag: 0x090a,
+            timestamp: NsSinceEpoch(0x0b0c_0d0e_0f10_1112),
+            kind: TraceKind::Complete,
+            value: 0x1314,
+            #[cfg(feature = "native-ids")]
+            process: 0x1516,
+            #[cfg(feature = "native-ids")]
+            native_thread: 0x1718,
+            phantom: PhantomData,
+        };
+
+        let mut expected = vec![
+            // Kind, and that there's a thread, why, and why thread.
+            0x77,
+            // Tag.
+            0x0a, 0x09, 0, 0,
+            // ID.
+            0x08, 0x07, 0, 0,
+            // Thread.
+            0x06, 0x05, 0, 0, 0, 0, 0, 0,
+            // Why's ID.
+            0x04, 0x03, 0, 0,
+            // Why's thread.
+            0x02, 0x01, 0, 0, 0, 0, 0, 0,
+            // Timestamp.
+            0x12, 0x11, 0x10, 0x0f, 0x0e, 0x0d, 0x0c, 0x0b,
+            // Value.
+            0x14, 0x13, 0, 0, 0, 0, 0, 0,
+        ];
+        if cfg!(feature = "native-ids") {
+            expected.extend_from_slice(&[0x16, 0x15, 0, 0, 0x18, 0x17, 0, 0, 0, 0, 0, 0]);
+        }
+        assert_eq!(&entry.to_bytes()[..], &expected[..]);
+        assert_eq!(SimpleEntry::from_bytes(&expected), entry);
+
+        // Absent threads and whys are zeroed, and flagged as absent.
+        let entry = SimpleEntry {
+            why: None,
+            thread: None,
+            kind: TraceKind::Event,
+            value: 0,
+            ..entry
+        };
+        let bytes = entry.to_bytes();
+        assert_eq!(bytes[0], 0x00);
+        assert!(bytes[THREAD_OFFSET..TIMESTAMP_OFFSET].iter().all(|&b| b == 0));
+        assert_eq!(SimpleEntry::from_bytes(&bytes), entry);
+    }
+
+    #[test]
+    fn counter() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_counter(SimpleTrace::FooEvent, 42);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries[0].kind(), TraceKind::Counter);
+        assert_eq!(entries[0].counter_value(), Some(42));
+        assert_eq!(entries[0].duration(), None);
+        assert_eq!(entries[1].counter_value(), None);
+    }
+
+    #[test]
+    fn named_events() {
+        for &encoding in &[Encoding::Fixed, Encoding::Compact, Encoding::Relative { unit: 1 }] {
+            let mut buffer = SimpleTraceBuffer::default();
+            buffer.set_encoding(encoding);
+            buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            buffer.trace_event_named(SimpleTrace::FooEvent, "https://example.com");
+            buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            buffer.trace_event(SimpleTrace::FooEvent, None);
+
+            let names: Vec<_> = buffer.iter().map(|e| e.name()).collect();
+            assert_eq!(names, vec![Some(0), Some(1), Some(0), None]);
+            assert_eq!(buffer.name(0), Some("/etc/hosts"));
+            assert_eq!(buffer.name(1), Some("https://example.com"));
+            assert_eq!(buffer.name(2), None);
+            assert_eq!(buffer.iter().next().unwrap().label(), "Foo");
+            assert_eq!(buffer.iter().next().unwrap().counter_value(), None);
+
+            let snapshot = buffer.snapshot();
+            assert_eq!(snapshot.iter().map(|e| e.name()).collect::<Vec<_>>(), names);
+            assert_eq!(snapshot.names(), buffer.names());
+            assert_eq!(snapshot.name(1), Some("https://example.com"));
+        }
+
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let json = serde_json::to_string(&buffer).unwrap();
+        assert!(json.contains(r#""names":["/etc/hosts"]"#));
+
+        // Once the table is full, only names already in it are kept.
+        buffer.set_name_capacity(2);
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/passwd");
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/group");
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let names: Vec<_> = buffer.iter().map(|e| e.name()).collect();
+        assert_eq!(names, vec![Some(0), Some(1), None, Some(0)]);
+        assert_eq!(buffer.names().len(), 2);
+        assert_eq!(buffer.stats().unnamed, 1);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut buffer = SimpleTraceBuffer::new(3 * SimpleEntry::size() + 1);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(id, SimpleTrace::OperationThing);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let snapshot = buffer.snapshot();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        assert_eq!(snapshot.len(), 3);
+        let kinds: Vec<_> = snapshot.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Start, TraceKind::Stop, TraceKind::Event]);
+        assert!(SimpleTraceBuffer::default().snapshot().is_empty());
+    }
+
+    #[test]
+    fn captures_are_send_and_sync() {
+        // Captures are handed off to export and analysis threads by moving
+        // or cloning them, which doesn't copy their entries.
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SimpleTraceBuffer>();
+        assert_send_sync::<SimpleEntry>();
+        assert_send_sync::<RingBufferIter<SimpleTrace>>();
+        assert_send_sync::<Blocks>();
+        assert_send_sync::<TraceSnapshot<SimpleTrace>>();
+        assert_send_sync::<RingBuffer<SimpleTrace, SharedBlocks>>();
+    }
+
+    #[test]
+    fn snapshot_clones_share_entries() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let snapshot = buffer.snapshot();
+        let clone = snapshot.clone();
+        assert!(Arc::ptr_eq(&snapshot.data, &clone.data));
+        assert!(Arc::ptr_eq(&snapshot.names, &clone.names));
+        assert_eq!(clone.iter().collect::<Vec<_>>(), snapshot.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shared_storage_copies_on_write() {
+        let mut buffer: RingBuffer<SimpleTrace, _> =
+            RingBuffer::with_shared_storage(4096, NsSinceEpoch::now);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let capture = buffer.clone();
+        assert!(buffer.data.is_shared());
+        assert_eq!(capture.ring().as_ptr(), buffer.ring().as_ptr());
+
+        // Writing copies the entries, leaving the capture as it was.
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        assert!(!buffer.data.is_shared());
+        assert!(!capture.data.is_shared());
+        assert_eq!(capture.iter().count(), 1);
+        assert_eq!(buffer.iter().count(), 2);
+
+        // Once the capture is gone, writing doesn't copy.
+        drop(capture);
+        let ring = buffer.ring().as_ptr();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(buffer.ring().as_ptr(), ring);
+    }
+
+    #[cfg(feature = "native-ids")]
+    #[test]
+    fn native_ids() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let entry = buffer.iter().next().unwrap();
+        assert_eq!(entry.process_id(), Some(::std::process::id()));
+        assert_eq!(entry.native_thread_id(), Some(ThreadId::get().0 as u64));
     }
 
     #[test]
@@ -502,6 +2551,225 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn iter_rev_and_last_n() {
+        // Every rotation of a buffer that doesn't divide evenly, so that each
+        // entry gets split across the end of it at some point.
+        for traced in 0..10 {
+            let mut buffer = SimpleTraceBuffer::new(3 * SimpleEntry::size() + 5);
+            for i in 0..traced {
+                buffer.trace_counter(SimpleTrace::FooEvent, i);
+            }
+
+            let mut forward: Vec<_> = buffer.iter().collect();
+            forward.reverse();
+            let backward: Vec<_> = buffer.iter_rev().collect();
+            assert_eq!(backward, forward);
+            assert_eq!(buffer.iter_rev().len(), forward.len());
+
+            let last: Vec<_> = buffer.last_n(2).map(|e| e.counter_value().unwrap()).collect();
+            let expected: Vec<_> = (0..traced).rev().take(2).collect();
+            assert_eq!(last, expected);
+        }
+    }
+
+    // Trace a little of everything into a fixed-encoding buffer.
+    fn varied_entries() -> Vec<SimpleEntry> {
+        let mut buffer = SimpleTraceBuffer::new(4096);
+        let parent = buffer.trace_event(SimpleTrace::FooEvent, None);
+        let child = buffer.trace_start(SimpleTrace::OperationThing, Some(parent));
+        buffer.trace_counter(SimpleTrace::FooEvent, 1 << 40);
+        buffer.trace_park(SimpleTrace::OperationThing);
+        buffer.trace_unpark(SimpleTrace::OperationThing);
+        buffer.trace_stop(child, SimpleTrace::OperationThing);
+        let cancelled = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_cancel(cancelled, SimpleTrace::OperationThing);
+        buffer.trace_complete(SimpleTrace::OperationThing,
+                              NsSinceEpoch(5),
+                              NsSinceEpoch(1_000_000_005),
+                              Some(parent));
+        buffer.iter().collect()
+    }
+
+    #[test]
+    fn compact_encoding() {
+        let entries = varied_entries();
+
+        let mut buffer = SimpleTraceBuffer::new(4096);
+        buffer.set_encoding(Encoding::Compact);
+        for &entry in &entries {
+            buffer.write_entry(entry);
+        }
+        assert_eq!(buffer.iter().collect::<Vec<_>>(), entries);
+
+        let mut newest_first = entries.clone();
+        newest_first.reverse();
+        assert_eq!(buffer.iter_rev().collect::<Vec<_>>(), newest_first);
+        assert_eq!(buffer.last_n(2).collect::<Vec<_>>(), &newest_first[..2]);
+        assert_eq!(buffer.snapshot().iter().collect::<Vec<_>>(), entries);
+
+        buffer.set_encoding(Encoding::Fixed);
+        assert_eq!(buffer.iter().count(), 0);
+    }
+
+    #[test]
+    fn compact_encoding_with_roll_over() {
+        let entries = varied_entries();
+        let mut buffer = SimpleTraceBuffer::new(100);
+        buffer.set_encoding(Encoding::Compact);
+
+        let mut written = vec![];
+        for i in 0..100 {
+            let entry = entries[i % entries.len()];
+            buffer.write_entry(entry);
+            written.push(entry);
+
+            // Whatever fits is always the newest entries, in order.
+            let held: Vec<_> = buffer.iter().collect();
+            assert!(!held.is_empty());
+            assert_eq!(&held[..], &written[written.len() - held.len()..]);
+        }
+    }
+
+    #[test]
+    fn compact_encoding_holds_more() {
+        let capacity = 1024;
+        let count = |encoding| {
+            let mut buffer = SimpleTraceBuffer::new(capacity);
+            buffer.set_encoding(encoding);
+            for i in 0..capacity {
+                let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+                buffer.trace_stop(id, SimpleTrace::OperationThing);
+                buffer.trace_counter(SimpleTrace::FooEvent, i as u64);
+            }
+            buffer.iter().count()
+        };
+        assert!(count(Encoding::Compact) >= 3 * count(Encoding::Fixed));
+    }
+
+    #[test]
+    fn trace_batch() {
+        let batch = [(SimpleTrace::FooEvent, BatchKind::Event),
+                     (SimpleTrace::OperationThing, BatchKind::Park),
+                     (SimpleTrace::OperationThing, BatchKind::Unpark)];
+
+        // Fits without wrapping, fills the buffer, and then wraps around.
+        let mut buffer = SimpleTraceBuffer::new(4 * ENTRY_SIZE + ENTRY_SIZE / 2);
+        for expected in [3, 4, 4] {
+            buffer.trace_batch(&batch);
+            let entries: Vec<_> = buffer.iter().collect();
+            assert_eq!(entries.len(), expected);
+            assert_eq!(entries[expected - 1].kind(), TraceKind::Unpark);
+            assert_eq!(entries[expected - 2].timestamp(), entries[expected - 1].timestamp());
+        }
+        assert_eq!(buffer.stats().written, 9);
+
+        let mut compact = SimpleTraceBuffer::new(4096);
+        compact.set_encoding(Encoding::Compact);
+        compact.trace_batch(&batch);
+        let kinds: Vec<_> = compact.iter().map(|entry| entry.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Event, TraceKind::Park, TraceKind::Unpark]);
+    }
+
+    #[test]
+    fn relative_encoding() {
+        // Includes timestamps decades apart, and going backwards, which need
+        // re-syncing.
+        let entries = varied_entries();
+
+        let mut buffer = SimpleTraceBuffer::new(4096);
+        buffer.set_encoding(Encoding::Relative { unit: 1 });
+        for &entry in &entries {
+            buffer.write_entry(entry);
+        }
+        assert_eq!(buffer.iter().collect::<Vec<_>>(), entries);
+
+        let mut newest_first = entries.clone();
+        newest_first.reverse();
+        assert_eq!(buffer.iter_rev().collect::<Vec<_>>(), newest_first);
+        assert_eq!(buffer.last_n(2).collect::<Vec<_>>(), &newest_first[..2]);
+        assert_eq!(buffer.snapshot().iter().collect::<Vec<_>>(), entries);
+
+        // Microsecond units round down from the base.
+        let at = |timestamp| {
+            Entry::new(TraceKind::Event,
+                       SimpleTrace::FooEvent.tag(),
+                       SimpleTraceId::new_id(),
+                       None,
+                       NsSinceEpoch(timestamp))
+        };
+        buffer.set_encoding(Encoding::Relative { unit: 1_000 });
+        buffer.write_entry(at(1_000_000_123));
+        buffer.write_entry(at(1_000_005_999));
+        buffer.write_entry(at(1_000_000_123 + 5_000_000_000_000));
+        let timestamps: Vec<_> = buffer.iter().map(|entry| entry.timestamp().0).collect();
+        assert_eq!(timestamps,
+                   vec![1_000_000_123, 1_000_005_123, 1_000_000_123 + 5_000_000_000_000]);
+    }
+
+    #[test]
+    fn relative_encoding_with_roll_over() {
+        let entries = varied_entries();
+        let mut buffer = SimpleTraceBuffer::new(3 * RELATIVE_SIZE + RELATIVE_SIZE / 2);
+        buffer.set_encoding(Encoding::Relative { unit: 1 });
+
+        let mut written = vec![];
+        for i in 0..100 {
+            let entry = entries[i % entries.len()];
+            buffer.write_entry(entry);
+            written.push(entry);
+
+            // Even once the re-sync records are evicted, whatever fits is
+            // always the newest entries, in order.
+            let held: Vec<_> = buffer.iter().collect();
+            assert!(!held.is_empty());
+            assert_eq!(&held[..], &written[written.len() - held.len()..]);
+        }
+    }
+
+    #[test]
+    fn relative_encoding_holds_more() {
+        let capacity = 100 * ENTRY_SIZE;
+        let count = |encoding| {
+            let mut buffer = SimpleTraceBuffer::new(capacity);
+            buffer.set_encoding(encoding);
+            for _ in 0..capacity {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+            buffer.iter().count()
+        };
+        assert!(count(Encoding::Relative { unit: 1 }) > count(Encoding::Fixed));
+    }
+
+    #[test]
+    fn merge_in_timestamp_order() {
+        let at = |timestamp| {
+            Entry::new(TraceKind::Event,
+                       SimpleTrace::FooEvent.tag(),
+                       SimpleTraceId::new_id(),
+                       None,
+                       NsSinceEpoch(timestamp))
+        };
+
+        let mut a = SimpleTraceBuffer::default();
+        let mut b = SimpleTraceBuffer::default();
+        let empty = SimpleTraceBuffer::default();
+        for &timestamp in &[1, 4, 4, 9] {
+            a.write_entry(at(timestamp));
+        }
+        for &timestamp in &[2, 4, 5] {
+            b.write_entry(at(timestamp));
+        }
+
+        let merged: Vec<_> = merge(&[&a, &empty, &b])
+            .map(|(source, entry)| (source, entry.timestamp().0))
+            .collect();
+        assert_eq!(merged,
+                   vec![(0, 1), (2, 2), (0, 4), (0, 4), (2, 4), (2, 5), (0, 9)]);
+
+        assert_eq!(merge::<SimpleTrace>(&[]).count(), 0);
+    }
+
     #[test]
     fn why() {
         let mut buffer = SimpleTraceBuffer::default();
@@ -550,6 +2818,179 @@ mod tests {
         assert_eq!(entry.why(), None);
     }
 
+    #[test]
+    fn cancel() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_cancel(id, SimpleTrace::OperationThing);
+
+        let entry = buffer.iter().nth(1).unwrap();
+        assert_eq!(entry.kind(), TraceKind::Cancel);
+        assert_eq!(entry.id(), id.u32());
+        assert_eq!(entry.label(), "Thing");
+    }
+
+    #[test]
+    fn complete() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let id = buffer.trace_complete(SimpleTrace::OperationThing,
+                                       NsSinceEpoch(100),
+                                       NsSinceEpoch(250),
+                                       None);
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind(), TraceKind::Complete);
+        assert_eq!(entries[0].id(), id.u32());
+        assert_eq!(entries[0].timestamp(), NsSinceEpoch(250));
+        assert_eq!(entries[0].start(), NsSinceEpoch(100));
+        assert_eq!(entries[0].duration(), Some(150));
+    }
+
+    #[test]
+    fn frozen() {
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        buffer.freeze();
+        assert!(buffer.is_frozen());
+
+        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
+        assert_eq!(buffer.frozen_writes(), 2);
+        assert_eq!(buffer.iter().count(), 1);
+
+        assert_eq!(buffer.thaw(), 2);
+        assert!(!buffer.is_frozen());
+
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(buffer.iter().count(), 2);
+    }
+
+    #[test]
+    fn truncate_timestamps() {
+        assert_eq!(NsSinceEpoch(1_234_567).truncate(0), NsSinceEpoch(1_234_567));
+        assert_eq!(NsSinceEpoch(1_234_567).truncate(1), NsSinceEpoch(1_234_567));
+        assert_eq!(NsSinceEpoch(1_234_567).truncate(1_000), NsSinceEpoch(1_234_000));
+
+        let mut buffer = SimpleTraceBuffer::default();
+        buffer.set_timestamp_precision(1_000);
+        assert_eq!(buffer.timestamp_precision(), 1_000);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let entry = buffer.iter().next().unwrap();
+        assert_eq!(entry.timestamp().0 % 1_000, 0);
+
+        let entry = entry.with_timestamp_precision(1_000_000);
+        assert_eq!(entry.timestamp().0 % 1_000_000, 0);
+    }
+
+    #[test]
+    fn custom_clock() {
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(42)
+        }
+
+        let mut buffer = SimpleTraceBuffer::with_clock(4096, clock);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(buffer.iter().next().unwrap().timestamp(), NsSinceEpoch(42));
+    }
+
+    #[test]
+    fn monotonic_clock() {
+        let first = NsSinceEpoch::monotonic();
+        let second = NsSinceEpoch::monotonic();
+        assert!(first.0 <= second.0);
+        assert!(first.0.abs_diff(NsSinceEpoch::now().0) < 60 * 1_000_000_000);
+    }
+
+    #[test]
+    fn slice_and_array_storage() {
+        // Holds two whole entries, so that the third wraps around the end.
+        const CAPACITY: usize = 2 * ENTRY_SIZE + ENTRY_SIZE / 2;
+        static mut STATIC: RingBuffer<SimpleTrace, [u8; CAPACITY]> =
+            RingBuffer::from_array([0; CAPACITY], NsSinceEpoch::now);
+
+        let entries = varied_entries();
+        let mut data = [0; CAPACITY];
+        let mut slice = RingBuffer::from_slice(&mut data, NsSinceEpoch::now);
+        let mut array = RingBuffer::from_array([0; CAPACITY], NsSinceEpoch::now);
+        #[allow(static_mut_refs)]
+        let inline = unsafe { &mut STATIC };
+        for (i, &entry) in entries.iter().enumerate() {
+            slice.write_entry(entry);
+            array.write_entry(entry);
+            inline.write_entry(entry);
+
+            let newest = &entries[i.saturating_sub(1)..i + 1];
+            assert_eq!(slice.iter().collect::<Vec<_>>(), newest);
+            assert_eq!(array.iter().collect::<Vec<_>>(), newest);
+            assert_eq!(inline.iter().collect::<Vec<_>>(), newest);
+        }
+
+        let id = slice.trace_start(SimpleTrace::OperationThing, None);
+        slice.trace_stop(id, SimpleTrace::OperationThing);
+        let kinds: Vec<_> = slice.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds, vec![TraceKind::Start, TraceKind::Stop]);
+    }
+
+    #[test]
+    fn clock_regression() {
+        use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+        static TICKS: [u64; 4] = [10, 20, 15, 30];
+        static NEXT_TICK: AtomicUsize = ATOMIC_USIZE_INIT;
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(TICKS[NEXT_TICK.fetch_add(1, Ordering::AcqRel) % TICKS.len()])
+        }
+
+        let timestamps = |policy| {
+            let mut buffer = SimpleTraceBuffer::with_clock(4096, clock);
+            buffer.set_clock_regression(policy);
+            NEXT_TICK.store(0, Ordering::Release);
+            for _ in 0..TICKS.len() {
+                buffer.trace_event(SimpleTrace::FooEvent, None);
+            }
+            buffer.iter().map(|e| (e.kind(), e.timestamp().0)).collect::<Vec<_>>()
+        };
+
+        let event = TraceKind::Event;
+        assert_eq!(timestamps(ClockRegression::Record),
+                   vec![(event, 10), (event, 20), (event, 15), (event, 30)]);
+        assert_eq!(timestamps(ClockRegression::Clamp),
+                   vec![(event, 10), (event, 20), (event, 20), (event, 30)]);
+        assert_eq!(timestamps(ClockRegression::Mark),
+                   vec![(event, 10),
+                        (event, 20),
+                        (TraceKind::ClockJump, 15),
+                        (event, 15),
+                        (event, 30)]);
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut buffer = SimpleTraceBuffer::new(3 * SimpleEntry::size() + 1);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        let thing_id = buffer.trace_start(SimpleTrace::OperationThing, None);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        buffer.trace_stop(thing_id, SimpleTrace::OperationThing);
+
+        let blocks = buffer.as_blocks();
+        assert_eq!(blocks.entry_size, SimpleEntry::size());
+        assert_eq!(blocks.first.len() + blocks.second.len(), 3 * SimpleEntry::size());
+        assert!(!blocks.second.is_empty());
+
+        let decoded = SimpleTraceBuffer::from_blocks(blocks).unwrap();
+        assert_eq!(decoded.iter().collect::<Vec<_>>(), buffer.iter().collect::<Vec<_>>());
+
+        let torn = Blocks { first: &blocks.first[1..], ..blocks };
+        assert!(SimpleTraceBuffer::from_blocks(torn).is_none());
+
+        buffer.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        let decoded = SimpleTraceBuffer::from_blocks(buffer.as_blocks()).unwrap();
+        assert_eq!(decoded.iter().last().unwrap().name(), Some(0));
+        assert_eq!(decoded.names(), ["/etc/hosts"]);
+    }
+
     #[test]
     fn serialize_entry() {
         let mut buffer = SimpleTraceBuffer::new(2 * SimpleEntry::size());
@@ -562,6 +3003,42 @@ mod tests {
         println!("serialized = {}", serialized);
     }
 
+    #[test]
+    fn stats() {
+        let size = SimpleEntry::size();
+        let mut buffer = SimpleTraceBuffer::new(4 * size);
+        assert_eq!(buffer.stats(), RingBufferStats::default());
+
+        for _ in 0..10 {
+            buffer.trace_event(SimpleTrace::FooEvent, None);
+        }
+        buffer.freeze();
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+        buffer.thaw();
+        assert_eq!(buffer.stats(),
+                   RingBufferStats {
+                       written: 10,
+                       evicted: 6,
+                       high_water: 4 * size,
+                       wraps: 2,
+                       unnamed: 0,
+                       depth_exceeded: 0,
+                   });
+
+        let mut compact = SimpleTraceBuffer::new(256);
+        compact.set_encoding(Encoding::Compact);
+        for i in 0..100 {
+            compact.trace_counter(SimpleTrace::FooEvent, i);
+        }
+        let stats = compact.stats();
+        assert_eq!(stats.written, 100);
+        assert_eq!(stats.evicted, 100 - compact.iter().count() as u64);
+        assert!(stats.high_water <= 256 && stats.wraps > 0);
+
+        let serialized = serde_json::to_string(&buffer).unwrap();
+        assert!(serialized.contains(r#""stats":{"written":10,"evicted":6,"#));
+    }
+
     #[test]
     fn serialize_ring_buffer() {
         let mut buffer = SimpleTraceBuffer::new(10 * SimpleEntry::size());
@@ -0,0 +1,146 @@
+//! Names interned by `trace_event_named` into a fixed-size area of memory
+//! shared between processes, alongside a shared ring buffer whose entries
+//! refer to them.
+//!
+//! The area holds a sequence of records, each a name's length in bytes as a
+//! little-endian `u32` followed by its UTF-8, and a name's ID is the index of
+//! its record. Names are only ever appended. The writer publishes how many
+//! bytes of the area are in use after writing each record, and before writing
+//! any entry that refers to it, so a reader that reads the area up to the
+//! published length after the entries sees every name they refer to.
+
+use std::collections::BTreeMap;
+use std::str;
+
+/// The state of the process writing names into an area.
+#[derive(Clone, Debug, Default)]
+pub struct SharedNames {
+    ids: BTreeMap<String, u32>,
+    // How many names, and how many bytes of records, are in the area.
+    count: u32,
+    used: usize,
+}
+
+impl SharedNames {
+    /// Construct a new `SharedNames` for an empty area.
+    pub fn new() -> SharedNames {
+        SharedNames::default()
+    }
+
+    /// Catch up with the records that other processes appended to the area,
+    /// given its published length, since this one last wrote to it.
+    ///
+    /// Returns `false` if the new records are corrupt.
+    #[cfg(feature = "mmap")]
+    pub fn sync(&mut self, area: &[u8]) -> bool {
+        if area.len() < self.used {
+            return false;
+        }
+        let mut rest = &area[self.used..];
+        while !rest.is_empty() {
+            match next(&mut rest) {
+                Some(name) => {
+                    self.ids.entry(name.into()).or_insert(self.count);
+                    self.count += 1;
+                }
+                None => return false,
+            }
+        }
+        self.used = area.len();
+        true
+    }
+
+    /// Get the ID of `name`, appending it to `area` if it isn't there yet, or
+    /// return `None` if it isn't and there's no room for it.
+    ///
+    /// Publish `used` as the area's new length afterwards.
+    pub fn intern(&mut self, area: &mut [u8], name: &str) -> Option<u32> {
+        if let Some(&id) = self.ids.get(name) {
+            return Some(id);
+        }
+        let end = self.used + 4 + name.len();
+        if name.len() > u32::MAX as usize || end > area.len() {
+            return None;
+        }
+
+        area[self.used..self.used + 4].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        area[self.used + 4..end].copy_from_slice(name.as_bytes());
+        self.used = end;
+
+        let id = self.count;
+        self.ids.insert(name.into(), id);
+        self.count += 1;
+        Some(id)
+    }
+
+    /// Get how many bytes of the area are in use.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+/// Read every name in the area, given its published length, indexed by ID, or
+/// return `None` if it is corrupt.
+pub fn read(mut area: &[u8]) -> Option<Vec<String>> {
+    let mut names = vec![];
+    while !area.is_empty() {
+        names.push(next(&mut area)?.into());
+    }
+    Some(names)
+}
+
+// Read the name at the front of `area`, and advance past it.
+fn next<'a>(area: &mut &'a [u8]) -> Option<&'a str> {
+    if area.len() < 4 {
+        return None;
+    }
+    let length = u32::from_le_bytes([area[0], area[1], area[2], area[3]]) as usize;
+    if area.len() - 4 < length {
+        return None;
+    }
+    let name = str::from_utf8(&area[4..4 + length]).ok()?;
+    *area = &area[4 + length..];
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_into_the_area() {
+        let mut area = [0; 32];
+        let mut names = SharedNames::new();
+        assert_eq!(names.intern(&mut area, "/etc/hosts"), Some(0));
+        assert_eq!(names.intern(&mut area, "/tmp"), Some(1));
+        assert_eq!(names.intern(&mut area, "/etc/hosts"), Some(0));
+        assert_eq!(names.used(), 22);
+
+        // Full.
+        assert_eq!(names.intern(&mut area, "/var/log"), None);
+        assert_eq!(read(&area[..names.used()]), Some(vec!["/etc/hosts".into(), "/tmp".into()]));
+
+        // Torn or corrupt records.
+        assert_eq!(read(&area[..names.used() - 1]), None);
+        assert_eq!(read(&[1, 0, 0, 0, 0xff]), None);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn syncs_with_other_writers() {
+        let mut area = [0; 32];
+        let mut names = SharedNames::new();
+        names.intern(&mut area, "/etc/hosts");
+
+        // Another process catches up, and carries on, and then this one does.
+        let mut other = SharedNames::new();
+        assert!(other.sync(&area[..names.used()]));
+        assert_eq!(other.intern(&mut area, "/etc/hosts"), Some(0));
+        assert_eq!(other.intern(&mut area, "/"), Some(1));
+        assert!(names.sync(&area[..other.used()]));
+        assert_eq!(names.intern(&mut area, "/"), Some(1));
+        assert_eq!(names.intern(&mut area, "/tmp"), Some(2));
+
+        assert!(!names.sync(&area[..other.used()]));
+    }
+}
@@ -0,0 +1,694 @@
+//! A flight recorder shared between processes, so that a parent process can
+//! merge the traces of all of its children, along with its own.
+//!
+//! The parent `create`s a shared-memory file with a fixed number of regions.
+//! Each producer, in any process, claims a region of its own with
+//! `Producer::open`, and is the only one to ever write into it. The parent
+//! can `merge` every region at any time, without stopping the producers.
+//!
+//! The file starts with a header, followed by the regions:
+//!
+//! | Offset | Field                                        |
+//! |--------|----------------------------------------------|
+//! | 0      | Magic number, `b"EEPSHMEM"`                  |
+//! | 8      | Format version                               |
+//! | 16     | Size of each entry, in bytes                 |
+//! | 24     | Number of regions                            |
+//! | 32     | Capacity of each region's ring, in bytes     |
+//! | 40     | Capacity of each region's names, in bytes    |
+//!
+//! Each region starts with its own header, followed by its ring and then by
+//! an area holding the names its producer interned with `trace_event_named`,
+//! each padded to a multiple of eight bytes:
+//!
+//! | Offset | Field                                        |
+//! |--------|----------------------------------------------|
+//! | 0      | ID of the owning process, or 0 if unclaimed  |
+//! | 8      | Sequence number, odd while being written     |
+//! | 16     | Where valid data begins within the ring      |
+//! | 24     | The number of valid bytes in the ring        |
+//! | 32     | The number of bytes of names in use          |
+//!
+//! Every field is a native-endian `u64`, so every process must run on the same
+//! machine and share the same `Trace` type. The names area holds each name, in
+//! order of ID, as its length in bytes as a little-endian `u32` followed by its
+//! UTF-8. Names are never evicted, so once a region's names area is full,
+//! events with new names are traced without them.
+//!
+//! ```no_run
+//! use eep::shmem::{Producer, SharedTrace};
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//!
+//! // In the parent process, before spawning children:
+//! let shared = SharedTrace::<SimpleTrace>::create("/dev/shm/my-app.eep", 16, 4096).unwrap();
+//!
+//! // In each child process:
+//! let mut sink = Producer::<SimpleTrace>::open("/dev/shm/my-app.eep").unwrap();
+//! sink.trace_event(SimpleTrace::FooEvent, None);
+//!
+//! // Back in the parent:
+//! for (pid, entry) in shared.merge() {
+//!     println!("{}: {}", pid, entry.label());
+//! }
+//! ```
+//!
+//! A producer whose process dies in the middle of a write leaves its region
+//! torn. `merge` gives up on copying a torn region after a bounded number of
+//! attempts, rather than wait for a write that will never finish, and reports
+//! it in `Merged::torn`.
+
+extern crate libc;
+
+use ring_buffer::{self, Entry, NsSinceEpoch, RingBuffer, TraceKind};
+use shared_names::{self, SharedNames};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::hint;
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{self, AtomicU64, Ordering};
+use traits::{Trace, TraceId, TraceSink};
+
+const MAGIC: [u8; 8] = *b"EEPSHMEM";
+const VERSION: u64 = 3;
+
+const HEADER_SIZE: usize = 48;
+const ENTRY_SIZE_OFFSET: usize = 16;
+const REGIONS_OFFSET: usize = 24;
+const REGION_CAPACITY_OFFSET: usize = 32;
+const NAMES_CAPACITY_OFFSET: usize = 40;
+
+const REGION_HEADER_SIZE: usize = 40;
+const PID_OFFSET: usize = 0;
+const SEQUENCE_OFFSET: usize = 8;
+const BEGIN_OFFSET: usize = 16;
+const LENGTH_OFFSET: usize = 24;
+const NAMES_LENGTH_OFFSET: usize = 32;
+
+// How many times `merge` tries to copy a region consistently before giving up
+// on it as torn. A live producer finishes a write within microseconds.
+const COPY_ATTEMPTS: usize = 10_000;
+
+/// A shared-memory file divided into per-producer regions.
+#[derive(Debug)]
+pub struct SharedTrace<T> {
+    // The start of the mapping, which is the start of the header.
+    map: *mut u8,
+    size: usize,
+    regions: usize,
+    region_capacity: usize,
+    names_capacity: usize,
+    _file: File,
+    phantom: PhantomData<T>,
+}
+
+unsafe impl<T> Send for SharedTrace<T> {}
+unsafe impl<T> Sync for SharedTrace<T> {}
+
+// The size of each region, or `None` if it overflows, for example because a
+// corrupt header claims huge capacities.
+fn region_stride(region_capacity: usize, names_capacity: usize) -> Option<usize> {
+    match (region_capacity.checked_add(7), names_capacity.checked_add(7)) {
+        (Some(ring), Some(names)) => {
+            REGION_HEADER_SIZE.checked_add(ring / 8 * 8)
+                .and_then(|size| size.checked_add(names / 8 * 8))
+        }
+        _ => None,
+    }
+}
+
+// The size of a whole file, or `None` if it overflows.
+fn file_size(regions: usize, region_capacity: usize, names_capacity: usize) -> Option<usize> {
+    region_stride(region_capacity, names_capacity)
+        .and_then(|stride| stride.checked_mul(regions))
+        .and_then(|size| size.checked_add(HEADER_SIZE))
+}
+
+fn map(file: &File, size: usize) -> io::Result<*mut u8> {
+    let map = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   size,
+                   libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_SHARED,
+                   file.as_raw_fd(),
+                   0)
+    };
+    if map == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(map as *mut u8)
+}
+
+impl<T> SharedTrace<T>
+    where T: Trace
+{
+    /// Create (or truncate) the file at `path`, and map the given number of
+    /// regions, each with a ring of the given capacity in bytes, and room for
+    /// a quarter as many bytes of names, into it.
+    ///
+    /// For the file to live in memory rather than on disk, put it on a
+    /// `tmpfs`, such as `/dev/shm` on Linux.
+    pub fn create<P>(path: P, regions: usize, region_capacity: usize) -> io::Result<SharedTrace<T>>
+        where P: AsRef<Path>
+    {
+        assert!(regions > 0);
+        assert!(region_capacity > Entry::<T>::size());
+
+        let names_capacity = region_capacity / 4;
+        let size = try!(file_size(regions, region_capacity, names_capacity).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "eep shared memory file is too large")
+        }));
+        let file = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path));
+        try!(file.set_len(size as u64));
+
+        let shared = SharedTrace {
+            map: try!(map(&file, size)),
+            size: size,
+            regions: regions,
+            region_capacity: region_capacity,
+            names_capacity: names_capacity,
+            _file: file,
+            phantom: PhantomData,
+        };
+
+        // The file was just truncated, so every region header is already
+        // zeroed, and so unclaimed. Write the magic number last, so that
+        // `open` never sees a half-written header.
+        shared.header(MAGIC.len()).store(VERSION, Ordering::Relaxed);
+        shared.header(ENTRY_SIZE_OFFSET).store(Entry::<T>::size() as u64, Ordering::Relaxed);
+        shared.header(REGIONS_OFFSET).store(regions as u64, Ordering::Relaxed);
+        shared.header(REGION_CAPACITY_OFFSET).store(region_capacity as u64, Ordering::Relaxed);
+        shared.header(NAMES_CAPACITY_OFFSET).store(names_capacity as u64, Ordering::Relaxed);
+        shared.header(0).store(u64::from_ne_bytes(MAGIC), Ordering::Release);
+
+        Ok(shared)
+    }
+
+    /// Map the file at `path`, created by `SharedTrace::create` in this or
+    /// another process.
+    pub fn open<P>(path: P) -> io::Result<SharedTrace<T>>
+        where P: AsRef<Path>
+    {
+        let invalid = |msg| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+        let file = try!(OpenOptions::new().read(true).write(true).open(path));
+        let size = try!(file.metadata()).len() as usize;
+        if size < HEADER_SIZE {
+            return invalid("not an eep shared memory file");
+        }
+
+        let mut shared = SharedTrace {
+            map: try!(map(&file, size)),
+            size: size,
+            regions: 0,
+            region_capacity: 0,
+            names_capacity: 0,
+            _file: file,
+            phantom: PhantomData,
+        };
+
+        if shared.header(0).load(Ordering::Acquire) != u64::from_ne_bytes(MAGIC) {
+            return invalid("not an eep shared memory file");
+        }
+        if shared.header(MAGIC.len()).load(Ordering::Relaxed) != VERSION {
+            return invalid("unsupported eep shared memory file version");
+        }
+        if shared.header(ENTRY_SIZE_OFFSET).load(Ordering::Relaxed) != Entry::<T>::size() as u64 {
+            return invalid("eep shared memory file was written with a different entry size");
+        }
+
+        shared.regions = shared.header(REGIONS_OFFSET).load(Ordering::Relaxed) as usize;
+        shared.region_capacity = shared.header(REGION_CAPACITY_OFFSET).load(Ordering::Relaxed) as
+                                 usize;
+        shared.names_capacity = shared.header(NAMES_CAPACITY_OFFSET).load(Ordering::Relaxed) as
+                                usize;
+        let expected = file_size(shared.regions, shared.region_capacity, shared.names_capacity);
+        if shared.region_capacity <= Entry::<T>::size() || expected != Some(size) {
+            return invalid("corrupt eep shared memory file header");
+        }
+
+        Ok(shared)
+    }
+
+    /// Collect the entries from every claimed region, interleaved by
+    /// timestamp, along with the ID of the process that traced each entry, and
+    /// the names each process interned.
+    ///
+    /// Each region is copied consistently, retrying if its producer wrote to
+    /// it mid-copy, but producers keep tracing while other regions are copied.
+    /// Regions that can't be copied consistently are left out, and reported as
+    /// torn.
+    pub fn merge(&self) -> Merged<T> {
+        let mut buffers = vec![];
+        let mut names = BTreeMap::new();
+        let mut torn = vec![];
+        for region in 0..self.regions {
+            let pid = self.region_header(region, PID_OFFSET).load(Ordering::Acquire) as u32;
+            if pid == 0 {
+                continue;
+            }
+            match self.copy_region(region) {
+                Some(buffer) => {
+                    if !buffer.names().is_empty() {
+                        names.insert(pid, buffer.names().to_vec());
+                    }
+                    buffers.push((pid, buffer));
+                }
+                None => torn.push(pid),
+            }
+        }
+
+        let entries = {
+            let regions: Vec<_> = buffers.iter().map(|&(_, ref buffer)| buffer).collect();
+            ring_buffer::merge(&regions).map(|(index, entry)| (buffers[index].0, entry)).collect()
+        };
+        Merged {
+            entries: entries,
+            names: names,
+            torn: torn,
+        }
+    }
+
+    // Copy the given region, and its names, or return `None` if it is torn: it
+    // is still being written after `COPY_ATTEMPTS` tries, or its header or
+    // names are corrupt.
+    fn copy_region(&self, region: usize) -> Option<RingBuffer<T>> {
+        let sequence = self.region_header(region, SEQUENCE_OFFSET);
+        for _ in 0..COPY_ATTEMPTS {
+            let before = sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+
+            let begin = self.region_header(region, BEGIN_OFFSET).load(Ordering::Relaxed);
+            let length = self.region_header(region, LENGTH_OFFSET).load(Ordering::Relaxed);
+            let data = self.ring(region).to_vec();
+
+            atomic::fence(Ordering::Acquire);
+            if sequence.load(Ordering::Relaxed) != before {
+                continue;
+            }
+
+            let (begin, length) = (begin as usize, length as usize);
+            if begin >= self.region_capacity || length > self.region_capacity ||
+               length % Entry::<T>::size() != 0 {
+                return None;
+            }
+
+            // Names are only appended, and published before the entries that
+            // refer to them, so reading them after the entries finds them all.
+            let names_length = self.region_header(region, NAMES_LENGTH_OFFSET)
+                .load(Ordering::Acquire) as usize;
+            if names_length > self.names_capacity {
+                return None;
+            }
+            let names = match shared_names::read(&self.names_area(region)[..names_length]) {
+                Some(names) => names,
+                None => return None,
+            };
+
+            let mut buffer = RingBuffer::from_raw_parts(data, begin, length);
+            buffer.set_names(names);
+            return Some(buffer);
+        }
+        None
+    }
+
+    fn header(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.map.offset(offset as isize) as *const AtomicU64) }
+    }
+
+    fn region_start(&self, region: usize) -> *mut u8 {
+        assert!(region < self.regions);
+        let stride = region_stride(self.region_capacity, self.names_capacity)
+            .expect("the file size was checked when it was mapped");
+        let offset = HEADER_SIZE + region * stride;
+        unsafe { self.map.offset(offset as isize) }
+    }
+
+    fn region_header(&self, region: usize, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.region_start(region).offset(offset as isize) as *const AtomicU64) }
+    }
+
+    fn ring(&self, region: usize) -> &[u8] {
+        unsafe {
+            let ring = self.region_start(region).offset(REGION_HEADER_SIZE as isize);
+            slice::from_raw_parts(ring, self.region_capacity)
+        }
+    }
+
+    fn ring_mut(&mut self, region: usize) -> &mut [u8] {
+        unsafe {
+            let ring = self.region_start(region).offset(REGION_HEADER_SIZE as isize);
+            slice::from_raw_parts_mut(ring, self.region_capacity)
+        }
+    }
+
+    fn names_offset(&self) -> usize {
+        REGION_HEADER_SIZE + self.region_capacity.div_ceil(8) * 8
+    }
+
+    fn names_area(&self, region: usize) -> &[u8] {
+        unsafe {
+            let names = self.region_start(region).offset(self.names_offset() as isize);
+            slice::from_raw_parts(names, self.names_capacity)
+        }
+    }
+
+    fn names_area_mut(&mut self, region: usize) -> &mut [u8] {
+        unsafe {
+            let names = self.region_start(region).offset(self.names_offset() as isize);
+            slice::from_raw_parts_mut(names, self.names_capacity)
+        }
+    }
+}
+
+impl<T> Drop for SharedTrace<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+/// The entries of every region of a `SharedTrace`, as returned by
+/// `SharedTrace::merge`.
+///
+/// Iterating over a `Merged` iterates over its entries.
+#[derive(Clone, Debug)]
+pub struct Merged<T> {
+    /// The entries from every region that was copied, interleaved by
+    /// timestamp, along with the ID of the process that traced each entry.
+    pub entries: Vec<(u32, Entry<T>)>,
+
+    /// The names interned by `trace_event_named` in each process that interned
+    /// any, indexed by ID, which that process's entries refer to.
+    pub names: BTreeMap<u32, Vec<String>>,
+
+    /// The IDs of the processes whose regions were torn, for example because
+    /// the process died in the middle of a write, and so were left out.
+    pub torn: Vec<u32>,
+}
+
+impl<T> Merged<T> {
+    /// Get the name interned with the given ID by the process `pid`, as
+    /// returned by `Entry::name` for one of its entries.
+    pub fn name(&self, pid: u32, id: u32) -> Option<&str> {
+        self.names.get(&pid).and_then(|names| names.get(id as usize)).map(|name| &name[..])
+    }
+}
+
+impl<T> IntoIterator for Merged<T> {
+    type Item = (u32, Entry<T>);
+    type IntoIter = ::std::vec::IntoIter<(u32, Entry<T>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+/// A `TraceSink` that writes into its own region of a `SharedTrace`.
+///
+/// A region stays claimed by its producer's process even after the producer is
+/// dropped or its process exits, so that its entries can still be merged.
+#[derive(Debug)]
+pub struct Producer<T> {
+    shared: SharedTrace<T>,
+    region: usize,
+    begin: usize,
+    length: usize,
+    names: SharedNames,
+}
+
+impl<T> Producer<T>
+    where T: Trace
+{
+    /// Map the file at `path`, created by `SharedTrace::create`, and claim an
+    /// unclaimed region in it for this producer.
+    ///
+    /// Returns an error if every region has already been claimed.
+    pub fn open<P>(path: P) -> io::Result<Producer<T>>
+        where P: AsRef<Path>
+    {
+        let shared = try!(SharedTrace::open(path));
+        let pid = process::id() as u64;
+        let region = (0..shared.regions).find(|&region| {
+            shared.region_header(region, PID_OFFSET)
+                .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        });
+
+        match region {
+            Some(region) => {
+                Ok(Producer {
+                    shared: shared,
+                    region: region,
+                    begin: 0,
+                    length: 0,
+                    names: SharedNames::new(),
+                })
+            }
+            None => {
+                Err(io::Error::new(io::ErrorKind::Other,
+                                   "every region of the eep shared memory file is claimed"))
+            }
+        }
+    }
+
+    // Get the ID of `name`, appending it to the region's names and publishing
+    // it if it isn't there yet, or `None` if there's no room for it.
+    fn intern(&mut self, name: &str) -> Option<u32> {
+        let region = self.region;
+        let id = self.names.intern(self.shared.names_area_mut(region), name);
+        self.shared
+            .region_header(region, NAMES_LENGTH_OFFSET)
+            .store(self.names.used() as u64, Ordering::Release);
+        id
+    }
+
+    fn write_entry(&mut self, entry: Entry<T>) {
+        let size = Entry::<T>::size();
+        let capacity = self.shared.region_capacity;
+        let sequence = self.shared.region_header(self.region, SEQUENCE_OFFSET);
+
+        // Mark the region as being written, so that `merge` retries any copy
+        // that overlaps this write.
+        sequence.fetch_add(1, Ordering::AcqRel);
+
+        if capacity - self.length < size {
+            self.begin = (self.begin + size) % capacity;
+            self.length -= size;
+        }
+        let end = (self.begin + self.length) % capacity;
+        let region = self.region;
+        ring_buffer::copy_wrapping(self.shared.ring_mut(region), end, &entry.to_bytes());
+        self.length += size;
+
+        let shared = &self.shared;
+        shared.region_header(region, BEGIN_OFFSET).store(self.begin as u64, Ordering::Relaxed);
+        shared.region_header(region, LENGTH_OFFSET).store(self.length as u64, Ordering::Relaxed);
+        shared.region_header(region, SEQUENCE_OFFSET).fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T> TraceSink<T> for Producer<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, why, NsSinceEpoch::now()));
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        self.write_entry(Entry::new(TraceKind::Start, trace.tag(), id, why, NsSinceEpoch::now()));
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.write_entry(Entry::new(TraceKind::Stop, trace.tag(), id, None, NsSinceEpoch::now()));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Cancel, trace.tag(), id, None, now));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = why.as_ref().map_or_else(T::Id::new_id, T::Id::new_child_id);
+        let duration = end.0.saturating_sub(start.0);
+        self.write_entry(Entry::new(TraceKind::Complete, trace.tag(), id, why, end)
+            .with_value(duration));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        let id = T::Id::new_id();
+        self.write_entry(Entry::new(TraceKind::Park, trace.tag(), id, None, NsSinceEpoch::now()));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        let id = T::Id::new_id();
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Unpark, trace.tag(), id, None, now));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let id = T::Id::new_id();
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Counter, trace.tag(), id, None, now)
+            .with_value(value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = T::Id::new_id();
+        let name = self.intern(name).map_or(0, |name| name as u64 + 1);
+        let now = NsSinceEpoch::now();
+        self.write_entry(Entry::new(TraceKind::Event, trace.tag(), id, None, now)
+            .with_value(name));
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use std::panic;
+    use std::path::PathBuf;
+
+    // A path unique to this test and process, so that tests running in
+    // parallel, or several runs at once, don't share files.
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("eep-shmem-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn merge_across_processes() {
+        let path = temp_path("merge-across-processes");
+        let shared = SharedTrace::<SimpleTrace>::create(&path, 4, 4096).unwrap();
+
+        let mut parent = Producer::open(&path).unwrap();
+        parent.trace_event(SimpleTrace::FooEvent, None);
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Never return into the test harness from the child.
+            let ok = panic::catch_unwind(|| {
+                let mut child = Producer::open(&path).unwrap();
+                let id = child.trace_start(SimpleTrace::OperationThing, None);
+                child.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+                child.trace_stop(id, SimpleTrace::OperationThing);
+            });
+            unsafe { libc::_exit(if ok.is_ok() { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+        parent.trace_event(SimpleTrace::FooEvent, None);
+
+        let merged = shared.merge();
+        let parent_pid = process::id();
+        let child_pid = pid as u32;
+        assert_eq!(merged.name(child_pid, 0), Some("/etc/hosts"));
+        assert_eq!(merged.name(parent_pid, 0), None);
+
+        let merged: Vec<_> = merged.into_iter()
+            .map(|(pid, entry)| (pid, entry.kind(), entry.name()))
+            .collect();
+        assert_eq!(merged,
+                   vec![(parent_pid, TraceKind::Event, None),
+                        (child_pid, TraceKind::Start, None),
+                        (child_pid, TraceKind::Event, Some(0)),
+                        (child_pid, TraceKind::Stop, None),
+                        (parent_pid, TraceKind::Event, None)]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn regions_run_out() {
+        let path = temp_path("regions-run-out");
+        let size = Entry::<SimpleTrace>::size();
+        let shared = SharedTrace::<SimpleTrace>::create(&path, 2, 2 * size + 1).unwrap();
+
+        let mut first = Producer::open(&path).unwrap();
+        let _second = Producer::<SimpleTrace>::open(&path).unwrap();
+        assert!(Producer::<SimpleTrace>::open(&path).is_err());
+
+        // The first region rolls over, and only keeps the latest two entries.
+        for _ in 0..3 {
+            first.trace_event(SimpleTrace::FooEvent, None);
+        }
+        first.trace_counter(SimpleTrace::FooEvent, 42);
+        let merged = shared.merge();
+        assert_eq!(merged.entries.len(), 2);
+        assert_eq!(merged.entries[1].1.counter_value(), Some(42));
+        assert!(merged.torn.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_regions_are_reported() {
+        let path = temp_path("torn-regions-are-reported");
+        let shared = SharedTrace::<SimpleTrace>::create(&path, 3, 4096).unwrap();
+        let mut intact = Producer::open(&path).unwrap();
+        let mut died = Producer::<SimpleTrace>::open(&path).unwrap();
+        let mut corrupt = Producer::<SimpleTrace>::open(&path).unwrap();
+        intact.trace_event(SimpleTrace::FooEvent, None);
+        died.trace_event(SimpleTrace::FooEvent, None);
+        corrupt.trace_event(SimpleTrace::FooEvent, None);
+
+        // As if the producer's process died mid-write, leaving the sequence
+        // odd, and as if another's header were scribbled over.
+        shared.region_header(died.region, SEQUENCE_OFFSET).fetch_add(1, Ordering::AcqRel);
+        shared.region_header(corrupt.region, LENGTH_OFFSET).store(1 << 40, Ordering::Relaxed);
+
+        let merged = shared.merge();
+        assert_eq!(merged.entries.len(), 1);
+        assert_eq!(merged.torn, vec![process::id(), process::id()]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overflowing_headers_are_rejected() {
+        let path = temp_path("overflowing-headers-are-rejected");
+        let shared = SharedTrace::<SimpleTrace>::create(&path, 1, 4096).unwrap();
+        for &(offset, value) in &[(REGIONS_OFFSET, u64::MAX / 2),
+                                  (REGION_CAPACITY_OFFSET, u64::MAX),
+                                  (NAMES_CAPACITY_OFFSET, u64::MAX)] {
+            let original = shared.header(offset).swap(value, Ordering::Relaxed);
+            let error = SharedTrace::<SimpleTrace>::open(&path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            shared.header(offset).store(original, Ordering::Relaxed);
+        }
+        assert!(SharedTrace::<SimpleTrace>::open(&path).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -34,6 +34,13 @@ impl Trace for SimpleTrace {
             SimpleTrace::OperationAnother => 2,
         }
     }
+
+    fn category(tag: u32) -> Option<&'static str> {
+        match tag {
+            1 | 2 => Some("operation"),
+            _ => None,
+        }
+    }
 }
 
 /// A global, monotonically increasing (and eventually wrapping) counter.
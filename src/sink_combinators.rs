@@ -1,8 +1,22 @@
 //! Combinators for building up complex `TraceSink` implementations from simple
 //! parts.
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use traits::{Trace, TraceId, TraceSink};
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use std::cmp;
+use std::fmt;
+use std::mem;
+#[cfg(feature = "std")]
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, TryLockError, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use ring_buffer::{Clock, Entry, NsSinceEpoch, TraceKind};
+#[cfg(feature = "std")]
+use ring_buffer::{RingBuffer, TraceSnapshot};
+use traits::{BatchKind, ThreadId, Trace, TraceId, TraceSink};
 
 /// A wrapper around another `TraceSink` that adds dynamically enabling or
 /// disabling tracing.
@@ -88,13 +102,1415 @@ impl<S, T> TraceSink<T> for ToggleSink<S>
             self.sink.trace_stop(id, trace);
         }
     }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        if self.is_enabled() {
+            self.sink.trace_cancel(id, trace);
+        }
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        if self.is_enabled() {
+            self.sink.trace_complete(trace, start, end, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        if self.is_enabled() {
+            self.sink.trace_park(trace);
+        }
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        if self.is_enabled() {
+            self.sink.trace_unpark(trace);
+        }
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        if self.is_enabled() {
+            self.sink.trace_counter(trace, value);
+        }
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        if self.is_enabled() {
+            self.sink.trace_event_named(trace, name)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        if self.is_enabled() {
+            self.sink.trace_depth_exceeded(trace, depth);
+        }
+    }
+}
+
+/// A wrapper around another `TraceSink` that dynamically enables or disables
+/// tracing on a per-tag basis.
+///
+/// A `FilteredSink` keeps one bit per `Trace::tag()` value in
+/// `0..num_tags`. Traces whose tag is disabled cost a single atomic load and
+/// are never passed to the underlying sink. Traces whose tag is outside that
+/// range are always passed through.
+#[derive(Debug)]
+pub struct FilteredSink<S> {
+    enabled: Vec<AtomicUsize>,
+    num_tags: u32,
+    sink: S,
+}
+
+const BITS_PER_WORD: u32 = 8 * mem::size_of::<usize>() as u32;
+
+impl<S> FilteredSink<S> {
+    /// Construct a new `FilteredSink` with the given `sink` where every tag in
+    /// `0..num_tags` is initially enabled.
+    pub fn new_enabled(sink: S, num_tags: u32) -> FilteredSink<S> {
+        Self::with_initial(sink, num_tags, !0)
+    }
+
+    /// Construct a new `FilteredSink` with the given `sink` where every tag in
+    /// `0..num_tags` is initially disabled.
+    pub fn new_disabled(sink: S, num_tags: u32) -> FilteredSink<S> {
+        Self::with_initial(sink, num_tags, 0)
+    }
+
+    fn with_initial(sink: S, num_tags: u32, word: usize) -> FilteredSink<S> {
+        let num_words = num_tags.div_ceil(BITS_PER_WORD);
+        FilteredSink {
+            enabled: (0..num_words).map(|_| AtomicUsize::new(word)).collect(),
+            num_tags: num_tags,
+            sink: sink,
+        }
+    }
+
+    /// Enable tracing for the given `tag`.
+    ///
+    /// Panics if `tag` is not less than the `num_tags` this `FilteredSink` was
+    /// constructed with.
+    pub fn enable(&self, tag: u32) {
+        assert!(tag < self.num_tags);
+        let (word, bit) = Self::bit(tag);
+        self.enabled[word].fetch_or(bit, Ordering::AcqRel);
+    }
+
+    /// Disable tracing for the given `tag`.
+    ///
+    /// Panics if `tag` is not less than the `num_tags` this `FilteredSink` was
+    /// constructed with.
+    pub fn disable(&self, tag: u32) {
+        assert!(tag < self.num_tags);
+        let (word, bit) = Self::bit(tag);
+        self.enabled[word].fetch_and(!bit, Ordering::AcqRel);
+    }
+
+    /// Return `true` if tracing is enabled for the given `tag`, `false`
+    /// otherwise.
+    #[inline(always)]
+    pub fn is_enabled(&self, tag: u32) -> bool {
+        if tag >= self.num_tags {
+            return true;
+        }
+        let (word, bit) = Self::bit(tag);
+        self.enabled[word].load(Ordering::Acquire) & bit != 0
+    }
+
+    #[inline(always)]
+    fn bit(tag: u32) -> (usize, usize) {
+        ((tag / BITS_PER_WORD) as usize, 1 << (tag % BITS_PER_WORD))
+    }
+}
+
+impl<S> AsRef<S> for FilteredSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for FilteredSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> TraceSink<T> for FilteredSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_event(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_start(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_stop(id, trace);
+        }
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_cancel(id, trace);
+        }
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_complete(trace, start, end, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_park(trace);
+        }
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_unpark(trace);
+        }
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_counter(trace, value);
+        }
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_event_named(trace, name)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        if self.is_enabled(trace.tag()) {
+            self.sink.trace_depth_exceeded(trace, depth);
+        }
+    }
+}
+
+/// A `TraceSink` that forwards every trace to two underlying sinks, for example
+/// to both an in-memory `RingBuffer` and OS signposts.
+///
+/// Nest `TeeSink`s to fan out to more than two sinks.
+///
+/// The ID returned by the first sink is the one propagated to the caller, and
+/// so it is also the ID that both sinks receive in `trace_stop` and as the
+/// `why` of later traces. The second sink's own IDs are discarded. Therefore,
+/// the first sink should be the one whose recorded IDs matter, such as a
+/// `RingBuffer`, and the second one that ignores IDs, such as `Signpost`.
+#[derive(Debug)]
+pub struct TeeSink<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> TeeSink<A, B> {
+    /// Construct a new `TeeSink` that forwards traces to `first` and then to
+    /// `second`.
+    pub fn new(first: A, second: B) -> TeeSink<A, B> {
+        TeeSink {
+            first: first,
+            second: second,
+        }
+    }
+
+    /// Get a reference to the first underlying sink.
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    /// Get a mutable reference to the first underlying sink.
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    /// Get a reference to the second underlying sink.
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    /// Get a mutable reference to the second underlying sink.
+    pub fn second_mut(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    /// Consume this `TeeSink` and return its underlying sinks.
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B, T> TraceSink<T> for TeeSink<A, B>
+    where A: TraceSink<T>,
+          B: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.first.trace_event(trace, why);
+        self.second.trace_event(trace, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.first.trace_start(trace, why);
+        self.second.trace_start(trace, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.first.trace_stop(id, trace);
+        self.second.trace_stop(id, trace);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.first.trace_cancel(id, trace);
+        self.second.trace_cancel(id, trace);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = self.first.trace_complete(trace, start, end, why);
+        self.second.trace_complete(trace, start, end, why);
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.first.trace_park(trace);
+        self.second.trace_park(trace);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.first.trace_unpark(trace);
+        self.second.trace_unpark(trace);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.first.trace_counter(trace, value);
+        self.second.trace_counter(trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = self.first.trace_event_named(trace, name);
+        self.second.trace_event_named(trace, name);
+        id
+    }
+
+    fn max_depth(&self) -> usize {
+        cmp::min(self.first.max_depth(), self.second.max_depth())
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.first.trace_depth_exceeded(trace, depth);
+        self.second.trace_depth_exceeded(trace, depth);
+    }
+}
+
+/// How a `SamplingSink` decides which one-off traces of a tag to keep.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SamplePolicy {
+    /// Keep every trace. This is the default.
+    All,
+    /// Keep the first of every `n` traces.
+    OneIn(u32),
+    /// Keep at most `n` traces per millisecond, dropping the rest.
+    PerMillisecond(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TagSampling {
+    policy: SamplePolicy,
+    seen: u64,
+    kept: u64,
+    window_ms: u64,
+    kept_in_window: u32,
+}
+
+impl TagSampling {
+    fn new(policy: SamplePolicy) -> TagSampling {
+        TagSampling {
+            policy: policy,
+            seen: 0,
+            kept: 0,
+            window_ms: 0,
+            kept_in_window: 0,
+        }
+    }
+}
+
+/// A wrapper around another `TraceSink` that only passes through a sample of
+/// very hot traces, according to a per-tag `SamplePolicy`.
+///
+/// Only self-contained traces are sampled: one-off events, complete spans, and
+/// counters. Starts, stops, and everything else are always passed through, so
+/// that spans stay balanced.
+///
+/// To rescale counts during analysis, `kept` and `dropped` report how many
+/// sampled traces of each tag were passed through or sampled away. Tags that
+/// were never given a policy aren't counted.
+#[derive(Debug)]
+pub struct SamplingSink<S> {
+    tags: BTreeMap<u32, TagSampling>,
+    clock: Clock,
+    sink: S,
+}
+
+impl<S> SamplingSink<S> {
+    /// Construct a new `SamplingSink` with the given `sink` that initially
+    /// keeps every trace, and measures rates with the system clock.
+    #[cfg(feature = "std")]
+    pub fn new(sink: S) -> SamplingSink<S> {
+        Self::with_clock(sink, NsSinceEpoch::now)
+    }
+
+    /// Construct a new `SamplingSink` with the given `sink` that initially
+    /// keeps every trace, and measures rates with the given `clock`.
+    pub fn with_clock(sink: S, clock: Clock) -> SamplingSink<S> {
+        SamplingSink {
+            tags: BTreeMap::new(),
+            clock: clock,
+            sink: sink,
+        }
+    }
+
+    /// Sample traces with the given `tag` according to `policy` from now on,
+    /// and reset its counts.
+    ///
+    /// Panics if `policy` is `SamplePolicy::OneIn(0)`, which has no sample to
+    /// keep.
+    pub fn set_policy(&mut self, tag: u32, policy: SamplePolicy) {
+        assert!(policy != SamplePolicy::OneIn(0), "can't sample one in zero traces");
+        self.tags.insert(tag, TagSampling::new(policy));
+    }
+
+    /// Get the policy for the given `tag`.
+    pub fn policy(&self, tag: u32) -> SamplePolicy {
+        self.tags.get(&tag).map_or(SamplePolicy::All, |t| t.policy)
+    }
+
+    /// Get how many sampled traces with the given `tag` were passed through
+    /// since its policy was set.
+    pub fn kept(&self, tag: u32) -> u64 {
+        self.tags.get(&tag).map_or(0, |t| t.kept)
+    }
+
+    /// Get how many sampled traces with the given `tag` were sampled away
+    /// since its policy was set.
+    pub fn dropped(&self, tag: u32) -> u64 {
+        self.tags.get(&tag).map_or(0, |t| t.seen - t.kept)
+    }
+
+    fn sample(&mut self, tag: u32) -> bool {
+        let clock = self.clock;
+        let sampling = match self.tags.get_mut(&tag) {
+            Some(sampling) => sampling,
+            None => return true,
+        };
+
+        let keep = match sampling.policy {
+            SamplePolicy::All => true,
+            // `set_policy` rejects zero, but never divide by it regardless.
+            SamplePolicy::OneIn(n) => sampling.seen.checked_rem(n as u64) == Some(0),
+            SamplePolicy::PerMillisecond(n) => {
+                let now_ms = clock().0 / 1_000_000;
+                if now_ms != sampling.window_ms {
+                    sampling.window_ms = now_ms;
+                    sampling.kept_in_window = 0;
+                }
+                if sampling.kept_in_window < n {
+                    sampling.kept_in_window += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+
+        // Saturate rather than overflow, so that `dropped` never underflows.
+        sampling.seen = sampling.seen.saturating_add(1);
+        if keep {
+            sampling.kept = sampling.kept.saturating_add(1);
+        }
+        keep
+    }
+}
+
+impl<S> AsRef<S> for SamplingSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for SamplingSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> TraceSink<T> for SamplingSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        if self.sample(trace.tag()) {
+            self.sink.trace_event(trace, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.sink.trace_start(trace, why)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_stop(id, trace);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_cancel(id, trace);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        if self.sample(trace.tag()) {
+            self.sink.trace_complete(trace, start, end, why)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.sink.trace_park(trace);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.sink.trace_unpark(trace);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        if self.sample(trace.tag()) {
+            self.sink.trace_counter(trace, value);
+        }
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        if self.sample(trace.tag()) {
+            self.sink.trace_event_named(trace, name)
+        } else {
+            T::Id::new_id()
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.sink.trace_depth_exceeded(trace, depth);
+    }
+}
+
+// What a `DedupSink` last recorded for a tag, and when.
+#[derive(Copy, Clone, Debug, Default)]
+struct LastRecorded {
+    event: Option<(NsSinceEpoch, Option<(Option<ThreadId>, u32)>)>,
+    counter: Option<(NsSinceEpoch, u64)>,
+    suppressed: u64,
+}
+
+/// A wrapper around another `TraceSink` that suppresses duplicate one-off
+/// traces arriving in bursts, such as redundant notifications, so that they
+/// don't drown out the rest of the buffer.
+///
+/// An event is a duplicate if it has the same tag and `why` as the last
+/// recorded event with that tag, and arrives within the window after it. A
+/// counter is a duplicate if it has the same tag and value as the last
+/// recorded counter with that tag, and arrives within the window after it. So
+/// a continuous burst is still recorded once per window.
+///
+/// Everything else, including events traced with `trace_event_named`, is
+/// always passed through. How many traces of each tag were suppressed is
+/// reported by `suppressed`.
+#[derive(Debug)]
+pub struct DedupSink<S> {
+    window_ns: u64,
+    tags: BTreeMap<u32, LastRecorded>,
+    clock: Clock,
+    sink: S,
+}
+
+impl<S> DedupSink<S> {
+    /// Construct a new `DedupSink` with the given `sink` that suppresses
+    /// duplicates within `window_ns` nanoseconds, as measured by the system
+    /// clock.
+    #[cfg(feature = "std")]
+    pub fn new(sink: S, window_ns: u64) -> DedupSink<S> {
+        Self::with_clock(sink, window_ns, NsSinceEpoch::now)
+    }
+
+    /// Construct a new `DedupSink` with the given `sink` that suppresses
+    /// duplicates within `window_ns` nanoseconds, as measured by the given
+    /// `clock`.
+    pub fn with_clock(sink: S, window_ns: u64, clock: Clock) -> DedupSink<S> {
+        DedupSink {
+            window_ns: window_ns,
+            tags: BTreeMap::new(),
+            clock: clock,
+            sink: sink,
+        }
+    }
+
+    /// Get how many traces with the given `tag` were suppressed as
+    /// duplicates.
+    pub fn suppressed(&self, tag: u32) -> u64 {
+        self.tags.get(&tag).map_or(0, |t| t.suppressed)
+    }
+
+    fn last_recorded(&mut self, tag: u32) -> &mut LastRecorded {
+        self.tags.entry(tag).or_insert_with(LastRecorded::default)
+    }
+
+    // Whether `last`, recorded at `then`, is a duplicate of `current` at `now`.
+    fn is_duplicate<K>(&self,
+                       last: Option<(NsSinceEpoch, K)>,
+                       current: K,
+                       now: NsSinceEpoch)
+                       -> bool
+        where K: PartialEq
+    {
+        match last {
+            Some((then, last)) => last == current && now.0.wrapping_sub(then.0) < self.window_ns,
+            None => false,
+        }
+    }
+}
+
+impl<S> AsRef<S> for DedupSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for DedupSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> TraceSink<T> for DedupSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let now = (self.clock)();
+        let key = why.map(|why| (why.thread(), why.u32()));
+        let last = self.last_recorded(trace.tag()).event;
+        if self.is_duplicate(last, key, now) {
+            self.last_recorded(trace.tag()).suppressed += 1;
+            return T::Id::new_id();
+        }
+
+        self.last_recorded(trace.tag()).event = Some((now, key));
+        self.sink.trace_event(trace, why)
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.sink.trace_start(trace, why)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_stop(id, trace);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_cancel(id, trace);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        self.sink.trace_complete(trace, start, end, why)
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.sink.trace_park(trace);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.sink.trace_unpark(trace);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let now = (self.clock)();
+        let last = self.last_recorded(trace.tag()).counter;
+        if self.is_duplicate(last, value, now) {
+            self.last_recorded(trace.tag()).suppressed += 1;
+            return;
+        }
+
+        self.last_recorded(trace.tag()).counter = Some((now, value));
+        self.sink.trace_counter(trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.sink.trace_event_named(trace, name)
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.sink.trace_depth_exceeded(trace, depth);
+    }
+}
+
+/// A wrapper around another `TraceSink` that also hands every trace, as an
+/// `Entry<T>`, to a callback, for example to stream traces live to a socket or
+/// UI instead of polling a buffer.
+///
+/// The callback is invoked synchronously, on the traced thread, after the
+/// trace has been passed through to the underlying sink, so it adds directly
+/// to the cost of every trace. It should do a bounded, small amount of work
+/// that never blocks, such as a `try_send` on a bounded channel that a
+/// background thread drains, and drop the entry rather than wait when the
+/// consumer falls behind. It must not trace into this sink, or any sink that
+/// leads back to it, and should not panic.
+///
+/// ```
+/// use eep::ring_buffer::Entry;
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::SubscriberSink;
+/// use eep::traits::TraceSink;
+/// use std::sync::mpsc;
+///
+/// let (sender, receiver) = mpsc::sync_channel(1024);
+/// let subscriber = move |entry: &Entry<SimpleTrace>| {
+///     let _ = sender.try_send(*entry);
+/// };
+/// let mut sink = SubscriberSink::new(SimpleTraceBuffer::default(), subscriber);
+/// sink.trace_event(SimpleTrace::FooEvent, None);
+///
+/// assert_eq!(receiver.recv().unwrap().label(), "Foo");
+/// ```
+///
+/// The entries' timestamps come from the `SubscriberSink`'s own clock, and so
+/// can differ slightly from those the underlying sink records.
+pub struct SubscriberSink<S, F> {
+    clock: Clock,
+    subscriber: F,
+    sink: S,
+}
+
+impl<S, F> SubscriberSink<S, F> {
+    /// Construct a new `SubscriberSink` with the given `sink` that calls
+    /// `subscriber` with every trace, timestamped by the system clock.
+    #[cfg(feature = "std")]
+    pub fn new(sink: S, subscriber: F) -> SubscriberSink<S, F> {
+        Self::with_clock(sink, subscriber, NsSinceEpoch::now)
+    }
+
+    /// Construct a new `SubscriberSink` with the given `sink` that calls
+    /// `subscriber` with every trace, timestamped by the given `clock`.
+    pub fn with_clock(sink: S, subscriber: F, clock: Clock) -> SubscriberSink<S, F> {
+        SubscriberSink {
+            clock: clock,
+            subscriber: subscriber,
+            sink: sink,
+        }
+    }
+
+    /// Unwrap this `SubscriberSink`, returning the underlying sink.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    fn notify<T>(&mut self, kind: TraceKind, trace: T, id: T::Id, why: Option<T::Id>)
+        where F: FnMut(&Entry<T>),
+              T: Trace
+    {
+        let entry = Entry::new(kind, trace.tag(), id, why, (self.clock)());
+        (self.subscriber)(&entry);
+    }
+}
+
+impl<S, F> fmt::Debug for SubscriberSink<S, F>
+    where S: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriberSink")
+            .field("sink", &self.sink)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, F> AsRef<S> for SubscriberSink<S, F> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S, F> AsMut<S> for SubscriberSink<S, F> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, F, T> TraceSink<T> for SubscriberSink<S, F>
+    where S: TraceSink<T>,
+          F: FnMut(&Entry<T>),
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.sink.trace_event(trace, why);
+        self.notify(TraceKind::Event, trace, id, why);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.sink.trace_start(trace, why);
+        self.notify(TraceKind::Start, trace, id, why);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_stop(id, trace);
+        self.notify(TraceKind::Stop, trace, id, None);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_cancel(id, trace);
+        self.notify(TraceKind::Cancel, trace, id, None);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = self.sink.trace_complete(trace, start, end, why);
+        let entry = Entry::new(TraceKind::Complete, trace.tag(), id, why, end)
+            .with_value(end.0.saturating_sub(start.0));
+        (self.subscriber)(&entry);
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.sink.trace_park(trace);
+        self.notify(TraceKind::Park, trace, T::Id::new_id(), None);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.sink.trace_unpark(trace);
+        self.notify(TraceKind::Unpark, trace, T::Id::new_id(), None);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.sink.trace_counter(trace, value);
+        let id = T::Id::new_id();
+        let entry = Entry::new(TraceKind::Counter, trace.tag(), id, None, (self.clock)())
+            .with_value(value);
+        (self.subscriber)(&entry);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        // The subscriber can't look names up, so it gets a plain event.
+        let id = self.sink.trace_event_named(trace, name);
+        self.notify(TraceKind::Event, trace, id, None);
+        id
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.sink.trace_depth_exceeded(trace, depth);
+        let id = T::Id::new_id();
+        let entry = Entry::new(TraceKind::Counter, trace.tag(), id, None, (self.clock)())
+            .with_value(depth as u64);
+        (self.subscriber)(&entry);
+    }
+}
+
+/// A wrapper around another `TraceSink`, typically one shared between threads,
+/// that buffers one-off traces locally and flushes them to it in batches with
+/// `TraceSink::trace_batch`, amortizing locking and timestamping over many
+/// traces.
+///
+/// Events without a `why`, parks, and unparks are buffered, and flushed once
+/// `capacity` of them are, when any other trace is passed through, on
+/// `flush`, and when the `BatchingSink` is dropped. Buffered traces are
+/// timestamped when they are flushed, so the larger the batch, the coarser
+/// their timestamps.
+///
+/// ```
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::BatchingSink;
+/// use eep::traits::TraceSink;
+/// use std::sync::{Arc, Mutex};
+///
+/// let shared = Arc::new(Mutex::new(SimpleTraceBuffer::new(1 << 20)));
+/// {
+///     let mut buffer = shared.lock().unwrap();
+///     let mut sink = BatchingSink::new(&mut *buffer, 64);
+///     for _ in 0..1000 {
+///         sink.trace_event(SimpleTrace::FooEvent, None);
+///     }
+/// }
+/// assert_eq!(shared.lock().unwrap().iter().count(), 1000);
+/// ```
+///
+/// The IDs returned for buffered events aren't the ones that are recorded, so
+/// they can't be used as the `why` of later traces.
+pub struct BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    batch: Vec<(T, BatchKind)>,
+    capacity: usize,
+    sink: S,
+}
+
+impl<S, T> BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    /// Construct a new `BatchingSink` with the given `sink` that flushes
+    /// every `capacity` buffered traces.
+    ///
+    /// Panics if `capacity` is `0`.
+    pub fn new(sink: S, capacity: usize) -> BatchingSink<S, T> {
+        assert!(capacity > 0);
+        BatchingSink {
+            batch: Vec::with_capacity(capacity),
+            capacity: capacity,
+            sink: sink,
+        }
+    }
+
+    /// Flush every buffered trace to the underlying sink now.
+    pub fn flush(&mut self) {
+        if !self.batch.is_empty() {
+            self.sink.trace_batch(&self.batch);
+            self.batch.clear();
+        }
+    }
+
+    fn push(&mut self, trace: T, kind: BatchKind) {
+        self.batch.push((trace, kind));
+        if self.batch.len() >= self.capacity {
+            self.flush();
+        }
+    }
+}
+
+impl<S, T> fmt::Debug for BatchingSink<S, T>
+    where S: TraceSink<T> + fmt::Debug,
+          T: Trace + fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchingSink")
+            .field("batch", &self.batch)
+            .field("capacity", &self.capacity)
+            .field("sink", &self.sink)
+            .finish()
+    }
+}
+
+impl<S, T> AsRef<S> for BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S, T> AsMut<S> for BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S, T> Drop for BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<S, T> TraceSink<T> for BatchingSink<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        // Batched traces have no `why`, so pass events that have one through.
+        if why.is_some() {
+            self.flush();
+            return self.sink.trace_event(trace, why);
+        }
+        self.push(trace, BatchKind::Event);
+        T::Id::new_id()
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.flush();
+        self.sink.trace_start(trace, why)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.flush();
+        self.sink.trace_stop(id, trace);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.flush();
+        self.sink.trace_cancel(id, trace);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        self.flush();
+        self.sink.trace_complete(trace, start, end, why)
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.push(trace, BatchKind::Park);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.push(trace, BatchKind::Unpark);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.flush();
+        self.sink.trace_counter(trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.flush();
+        self.sink.trace_event_named(trace, name)
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        self.flush();
+        self.sink.trace_batch(traces);
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.flush();
+        self.sink.trace_depth_exceeded(trace, depth);
+    }
+}
+
+/// A wrapper around a `RingBuffer` flight recorder that watches for spans that
+/// take too long, and hands a snapshot of the buffer to a dump handler each
+/// time one does, for example to catch and diagnose jank.
+///
+/// Each tag can be given a duration threshold with `set_threshold`. When a
+/// span of a tag with a threshold is stopped or cancelled more than that long
+/// after it was started, or a complete span of that tag lasting longer is
+/// traced, the span is recorded, and then the handler is called with a
+/// `TraceSnapshot` of everything in the buffer, the slow span included.
+///
+/// ```
+/// use eep::ring_buffer::TraceSnapshot;
+/// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+/// use eep::sink_combinators::WatchdogSink;
+/// use eep::traits::{Trace, TraceSink};
+///
+/// let dump = |snapshot: TraceSnapshot<SimpleTrace>| {
+///     eprintln!("jank! {} entries leading up to it", snapshot.len());
+/// };
+/// let mut sink = WatchdogSink::new(SimpleTraceBuffer::default(), dump);
+/// // Dump whenever a "Thing" span takes longer than 16ms.
+/// sink.set_threshold(SimpleTrace::OperationThing.tag(), Some(16_000_000));
+///
+/// let id = sink.trace_start(SimpleTrace::OperationThing, None);
+/// sink.trace_stop(id, SimpleTrace::OperationThing);
+/// ```
+///
+/// The handler is called synchronously, on the traced thread, and snapshotting
+/// takes time proportional to the buffer's length, so a slow span is made
+/// slower still. Spans' durations are measured with the `WatchdogSink`'s own
+/// clock, and only spans of tags with a threshold are tracked, until they are
+/// stopped or cancelled.
+#[cfg(feature = "std")]
+pub struct WatchdogSink<T, F, B = Vec<u8>> {
+    thresholds: BTreeMap<u32, u64>,
+    // When each open span of a tag with a threshold started, by its thread and
+    // ID.
+    open: HashMap<(Option<ThreadId>, u32), NsSinceEpoch>,
+    clock: Clock,
+    dump: F,
+    buffer: RingBuffer<T, B>,
+}
+
+#[cfg(feature = "std")]
+impl<T, F, B> WatchdogSink<T, F, B>
+    where T: Trace,
+          F: FnMut(TraceSnapshot<T>),
+          B: AsRef<[u8]> + AsMut<[u8]>
+{
+    /// Construct a new `WatchdogSink` around the given `buffer` that calls
+    /// `dump` with a snapshot of it whenever a span takes too long, measured
+    /// by the system clock.
+    pub fn new(buffer: RingBuffer<T, B>, dump: F) -> WatchdogSink<T, F, B> {
+        Self::with_clock(buffer, dump, NsSinceEpoch::now)
+    }
+
+    /// Construct a new `WatchdogSink` around the given `buffer` that calls
+    /// `dump` with a snapshot of it whenever a span takes too long, measured
+    /// by the given `clock`.
+    pub fn with_clock(buffer: RingBuffer<T, B>, dump: F, clock: Clock) -> WatchdogSink<T, F, B> {
+        WatchdogSink {
+            thresholds: BTreeMap::new(),
+            open: HashMap::new(),
+            clock: clock,
+            dump: dump,
+            buffer: buffer,
+        }
+    }
+
+    /// Dump whenever a span with the given `tag` lasts longer than
+    /// `threshold_ns` nanoseconds from now on, or never, if `None`.
+    pub fn set_threshold(&mut self, tag: u32, threshold_ns: Option<u64>) {
+        match threshold_ns {
+            Some(threshold_ns) => self.thresholds.insert(tag, threshold_ns),
+            None => self.thresholds.remove(&tag),
+        };
+    }
+
+    /// Get the threshold, in nanoseconds, for spans with the given `tag`.
+    pub fn threshold(&self, tag: u32) -> Option<u64> {
+        self.thresholds.get(&tag).cloned()
+    }
+
+    /// Unwrap this `WatchdogSink`, returning the underlying buffer.
+    pub fn into_inner(self) -> RingBuffer<T, B> {
+        self.buffer
+    }
+
+    // Dump if a span of `trace` that lasted `duration_ns` took too long.
+    fn check(&mut self, trace: T, duration_ns: u64) {
+        if self.threshold(trace.tag()).map_or(false, |threshold| duration_ns > threshold) {
+            (self.dump)(self.buffer.snapshot());
+        }
+    }
+
+    fn stopped(&mut self, id: T::Id, trace: T) {
+        if let Some(start) = self.open.remove(&(id.thread(), id.u32())) {
+            let duration_ns = (self.clock)().0.saturating_sub(start.0);
+            self.check(trace, duration_ns);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, B> fmt::Debug for WatchdogSink<T, F, B>
+    where T: fmt::Debug,
+          B: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchdogSink")
+            .field("thresholds", &self.thresholds)
+            .field("buffer", &self.buffer)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, B> AsRef<RingBuffer<T, B>> for WatchdogSink<T, F, B> {
+    fn as_ref(&self) -> &RingBuffer<T, B> {
+        &self.buffer
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, B> AsMut<RingBuffer<T, B>> for WatchdogSink<T, F, B> {
+    fn as_mut(&mut self) -> &mut RingBuffer<T, B> {
+        &mut self.buffer
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, B> TraceSink<T> for WatchdogSink<T, F, B>
+    where T: Trace,
+          F: FnMut(TraceSnapshot<T>),
+          B: AsRef<[u8]> + AsMut<[u8]>
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.buffer.trace_event(trace, why)
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.buffer.trace_start(trace, why);
+        if self.threshold(trace.tag()).is_some() {
+            self.open.insert((id.thread(), id.u32()), (self.clock)());
+        }
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.buffer.trace_stop(id, trace);
+        self.stopped(id, trace);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.buffer.trace_cancel(id, trace);
+        self.stopped(id, trace);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = self.buffer.trace_complete(trace, start, end, why);
+        self.check(trace, end.0.saturating_sub(start.0));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.buffer.trace_park(trace);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.buffer.trace_unpark(trace);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.buffer.trace_counter(trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.buffer.trace_event_named(trace, name)
+    }
+
+    fn max_depth(&self) -> usize {
+        self.buffer.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.buffer.trace_depth_exceeded(trace, depth);
+    }
+}
+
+/// A handle to a `TraceSink` shared through an `Arc<Mutex<S>>` that doesn't
+/// keep it alive.
+///
+/// Long-lived components can hold a `WeakSinkHandle` without extending the
+/// lifetime of the buffer it points to. Once every `Arc` to the sink has been
+/// dropped, tracing through the handle is a no-op, so components don't need
+/// to be shut down before the buffer.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct WeakSinkHandle<S> {
+    sink: Weak<Mutex<S>>,
+}
+
+#[cfg(feature = "std")]
+impl<S> WeakSinkHandle<S> {
+    /// Construct a new `WeakSinkHandle` to the given shared `sink`.
+    pub fn new(sink: &Arc<Mutex<S>>) -> WeakSinkHandle<S> {
+        WeakSinkHandle { sink: Arc::downgrade(sink) }
+    }
+
+    /// Construct a new `WeakSinkHandle` that was never attached to a sink,
+    /// and so is always a no-op.
+    pub fn dead() -> WeakSinkHandle<S> {
+        WeakSinkHandle { sink: Weak::new() }
+    }
+
+    /// Return `true` if the sink this handle points to is still alive, `false`
+    /// otherwise.
+    pub fn is_alive(&self) -> bool {
+        self.sink.upgrade().is_some()
+    }
+
+    fn with_sink<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut S) -> R
+    {
+        self.sink.upgrade().map(|sink| {
+            let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut sink)
+        })
+    }
+
+    // Like `with_sink`, but give up rather than wait if the sink is locked,
+    // for example from a panic hook on a thread that was tracing into it.
+    pub(crate) fn try_with_sink<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut S) -> R
+    {
+        self.sink.upgrade().and_then(|sink| {
+            let mut sink = match sink.try_lock() {
+                Ok(sink) => sink,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => return None,
+            };
+            Some(f(&mut sink))
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S> Clone for WeakSinkHandle<S> {
+    fn clone(&self) -> WeakSinkHandle<S> {
+        WeakSinkHandle { sink: self.sink.clone() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S, T> TraceSink<T> for WeakSinkHandle<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.with_sink(|sink| sink.trace_event(trace, why)).unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        self.with_sink(|sink| sink.trace_start(trace, why)).unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.with_sink(|sink| sink.trace_stop(id, trace));
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.with_sink(|sink| sink.trace_cancel(id, trace));
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        self.with_sink(|sink| sink.trace_complete(trace, start, end, why))
+            .unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.with_sink(|sink| sink.trace_park(trace));
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.with_sink(|sink| sink.trace_unpark(trace));
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.with_sink(|sink| sink.trace_counter(trace, value));
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.with_sink(|sink| sink.trace_event_named(trace, name))
+            .unwrap_or_else(T::Id::new_id)
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        self.with_sink(|sink| sink.trace_batch(traces));
+    }
+
+    fn max_depth(&self) -> usize {
+        self.with_sink(|sink| sink.max_depth()).unwrap_or(usize::MAX)
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.with_sink(|sink| sink.trace_depth_exceeded(trace, depth));
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use ring_buffer::{NsSinceEpoch, TraceKind};
     use simple_trace::{SimpleTrace, SimpleTraceBuffer};
-    use traits::TraceSink;
+    use std::cell::Cell;
+    use traits::{Trace, TraceId, TraceSink};
+
+    // A fake clock for sinks that read the time, which each test sets on its
+    // own thread.
+    thread_local!(static NOW: Cell<u64> = Cell::new(0));
+
+    fn now() -> NsSinceEpoch {
+        NsSinceEpoch(NOW.with(|now| now.get()))
+    }
+
+    fn set_now(ns: u64) {
+        NOW.with(|now| now.set(ns));
+    }
+
+    fn advance_now(ns: u64) {
+        NOW.with(|now| now.set(now.get() + ns));
+    }
 
     #[test]
     fn does_not_trace_when_disabled() {
@@ -121,4 +1537,312 @@ mod tests {
 
         assert!(sink.as_ref().iter().next().is_some());
     }
+
+    #[test]
+    fn filters_disabled_tags() {
+        let mut sink = FilteredSink::new_enabled(SimpleTraceBuffer::default(), 3);
+        assert!(sink.is_enabled(SimpleTrace::FooEvent.tag()));
+
+        sink.disable(SimpleTrace::FooEvent.tag());
+        assert!(!sink.is_enabled(SimpleTrace::FooEvent.tag()));
+        assert!(sink.is_enabled(SimpleTrace::OperationThing.tag()));
+
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(sink.as_ref().iter().next(), None);
+
+        let id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+        assert_eq!(sink.as_ref().iter().count(), 2);
+    }
+
+    #[test]
+    fn traces_enabled_tags() {
+        let mut sink = FilteredSink::new_disabled(SimpleTraceBuffer::default(), 3);
+        assert!(!sink.is_enabled(SimpleTrace::FooEvent.tag()));
+        assert!(sink.is_enabled(100));
+
+        sink.enable(SimpleTrace::FooEvent.tag());
+        assert!(sink.is_enabled(SimpleTrace::FooEvent.tag()));
+
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        sink.trace_event(SimpleTrace::OperationThing, None);
+
+        let entries: Vec<_> = sink.as_ref().iter().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tag(), SimpleTrace::FooEvent.tag());
+    }
+
+    #[test]
+    fn tee_writes_to_both_sinks() {
+        let mut sink = TeeSink::new(SimpleTraceBuffer::default(),
+                                    ToggleSink::new_enabled(SimpleTraceBuffer::default()));
+
+        let event = sink.trace_event(SimpleTrace::FooEvent, None);
+        let id = sink.trace_start(SimpleTrace::OperationThing, Some(event));
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        let first: Vec<_> = sink.first().iter().collect();
+        let second: Vec<_> = sink.second().as_ref().iter().collect();
+        assert_eq!(first.len(), 3);
+        assert_eq!(second.len(), 3);
+
+        // The first sink's IDs are the ones propagated.
+        assert_eq!(first[0].id(), event.u32());
+        assert_eq!(first[1].id(), id.u32());
+        assert_eq!(first[2].id(), id.u32());
+        assert_eq!(second[2].id(), id.u32());
+
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.tag(), b.tag());
+            assert_eq!(a.kind(), b.kind());
+        }
+    }
+
+    #[test]
+    fn wrappers_keep_names() {
+        let inner = DedupSink::new(SimpleTraceBuffer::default(), 1_000_000);
+        let inner = FilteredSink::new_enabled(SamplingSink::new(inner), 3);
+        let mut sink = TeeSink::new(ToggleSink::new_enabled(inner),
+                                    WatchdogSink::new(SimpleTraceBuffer::default(), |_| {}));
+        sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+
+        let first: &SimpleTraceBuffer = sink.first().as_ref().as_ref().as_ref().as_ref();
+        let second: &SimpleTraceBuffer = sink.second().as_ref();
+        for buffer in &[first, second] {
+            assert_eq!(buffer.iter().next().unwrap().name(), Some(0));
+            assert_eq!(buffer.name(0), Some("/etc/hosts"));
+        }
+    }
+
+    #[test]
+    fn samples_one_in_n() {
+        let mut sink = SamplingSink::new(SimpleTraceBuffer::default());
+        sink.set_policy(SimpleTrace::FooEvent.tag(), SamplePolicy::OneIn(3));
+
+        for _ in 0..10 {
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+        let id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_stop(id, SimpleTrace::OperationThing);
+
+        assert_eq!(sink.as_ref().iter().count(), 4 + 2);
+        assert_eq!(sink.kept(SimpleTrace::FooEvent.tag()), 4);
+        assert_eq!(sink.dropped(SimpleTrace::FooEvent.tag()), 6);
+        assert_eq!(sink.dropped(SimpleTrace::OperationThing.tag()), 0);
+
+        // Giving a large tag a policy doesn't count the tags below it.
+        sink.set_policy(u32::MAX, SamplePolicy::OneIn(2));
+        sink.trace_event(SimpleTrace::OperationThing, None);
+        assert_eq!(sink.kept(SimpleTrace::OperationThing.tag()), 0);
+        assert_eq!(sink.policy(u32::MAX), SamplePolicy::OneIn(2));
+    }
+
+    #[test]
+    fn samples_extreme_rates() {
+        let tag = SimpleTrace::FooEvent.tag();
+        let mut sink = SamplingSink::new(SimpleTraceBuffer::default());
+
+        sink.set_policy(tag, SamplePolicy::OneIn(u32::MAX));
+        for _ in 0..10 {
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+        assert_eq!(sink.kept(tag), 1);
+        assert_eq!(sink.dropped(tag), 9);
+
+        // Counts saturate instead of overflowing.
+        sink.set_policy(tag, SamplePolicy::All);
+        sink.tags.get_mut(&tag).unwrap().seen = u64::MAX;
+        sink.tags.get_mut(&tag).unwrap().kept = u64::MAX;
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        assert_eq!(sink.kept(tag), u64::MAX);
+        assert_eq!(sink.dropped(tag), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't sample one in zero traces")]
+    fn rejects_sampling_one_in_zero() {
+        let mut sink = SamplingSink::new(SimpleTraceBuffer::default());
+        sink.set_policy(SimpleTrace::FooEvent.tag(), SamplePolicy::OneIn(0));
+    }
+
+    #[test]
+    fn samples_per_millisecond() {
+        let mut sink = SamplingSink::with_clock(SimpleTraceBuffer::default(), now);
+        sink.set_policy(SimpleTrace::FooEvent.tag(), SamplePolicy::PerMillisecond(2));
+
+        for ms in 0..3 {
+            set_now(ms * 1_000_000 + 500);
+            for _ in 0..5 {
+                sink.trace_event(SimpleTrace::FooEvent, None);
+            }
+        }
+
+        assert_eq!(sink.as_ref().iter().count(), 6);
+        assert_eq!(sink.kept(SimpleTrace::FooEvent.tag()), 6);
+        assert_eq!(sink.dropped(SimpleTrace::FooEvent.tag()), 9);
+    }
+
+    #[test]
+    fn batching_sink_flushes_in_order() {
+        let mut buffer = SimpleTraceBuffer::default();
+        {
+            let mut sink = BatchingSink::new(&mut buffer, 3);
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            sink.trace_park(SimpleTrace::FooEvent);
+            assert_eq!(sink.as_ref().iter().count(), 0);
+
+            // A full batch is flushed.
+            sink.trace_unpark(SimpleTrace::FooEvent);
+            assert_eq!(sink.as_ref().iter().count(), 3);
+
+            // Other traces flush the batch first, so order is kept.
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            // Events with a `why` are passed through, keeping it.
+            sink.trace_event(SimpleTrace::FooEvent, Some(id));
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+            sink.trace_event(SimpleTrace::FooEvent, None);
+        }
+
+        let kinds: Vec<_> = buffer.iter().map(|entry| entry.kind()).collect();
+        assert_eq!(kinds,
+                   vec![TraceKind::Event,
+                        TraceKind::Park,
+                        TraceKind::Unpark,
+                        TraceKind::Event,
+                        TraceKind::Start,
+                        TraceKind::Event,
+                        TraceKind::Event,
+                        TraceKind::Stop,
+                        TraceKind::Event]);
+        let whys: Vec<_> = buffer.iter().map(|entry| entry.why().is_some()).collect();
+        assert_eq!(whys, vec![false, false, false, false, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn watchdog_dumps_slow_spans() {
+        let mut dumps = vec![];
+        {
+            let dump = |snapshot: TraceSnapshot<SimpleTrace>| {
+                dumps.push(snapshot.iter().map(|entry| entry.kind()).collect::<Vec<_>>());
+            };
+            let mut sink = WatchdogSink::with_clock(SimpleTraceBuffer::default(), dump, now);
+            sink.set_threshold(SimpleTrace::OperationThing.tag(), Some(100));
+            assert_eq!(sink.threshold(SimpleTrace::OperationThing.tag()), Some(100));
+            assert_eq!(sink.threshold(SimpleTrace::OperationAnother.tag()), None);
+
+            // Fast enough.
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            advance_now(100);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+
+            // No threshold.
+            let id = sink.trace_start(SimpleTrace::OperationAnother, None);
+            advance_now(1_000);
+            sink.trace_stop(id, SimpleTrace::OperationAnother);
+
+            // Too slow, whether stopped, cancelled, or complete.
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            advance_now(101);
+            sink.trace_cancel(id, SimpleTrace::OperationThing);
+            sink.trace_complete(SimpleTrace::OperationThing,
+                                NsSinceEpoch(0),
+                                NsSinceEpoch(1_000),
+                                None);
+
+            // Spans started before a threshold was set aren't tracked.
+            sink.set_threshold(SimpleTrace::OperationThing.tag(), None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.set_threshold(SimpleTrace::OperationThing.tag(), Some(0));
+            advance_now(1_000);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        assert_eq!(dumps.len(), 2);
+        assert_eq!(dumps[0].len(), 6);
+        assert_eq!(dumps[0].last(), Some(&TraceKind::Cancel));
+        assert_eq!(dumps[1].last(), Some(&TraceKind::Complete));
+    }
+
+    #[test]
+    fn subscriber_sees_every_trace() {
+        fn clock() -> NsSinceEpoch {
+            NsSinceEpoch(42)
+        }
+
+        let mut seen: Vec<Entry<SimpleTrace>> = vec![];
+        let (parent, child, recorded) = {
+            let subscriber = |entry: &Entry<SimpleTrace>| seen.push(*entry);
+            let mut sink =
+                SubscriberSink::with_clock(SimpleTraceBuffer::default(), subscriber, clock);
+            let parent = sink.trace_start(SimpleTrace::OperationThing, None);
+            let child = sink.trace_event(SimpleTrace::FooEvent, Some(parent));
+            sink.trace_counter(SimpleTrace::FooEvent, 7);
+            sink.trace_complete(SimpleTrace::OperationAnother,
+                                NsSinceEpoch(10),
+                                NsSinceEpoch(25),
+                                Some(parent));
+            sink.trace_stop(parent, SimpleTrace::OperationThing);
+            let recorded: Vec<_> = sink.as_ref().iter().map(|e| e.kind()).collect();
+            (parent, child, recorded)
+        };
+
+        let kinds: Vec<_> = seen.iter().map(|e| e.kind()).collect();
+        assert_eq!(kinds, recorded);
+        assert_eq!(seen[0].id(), parent.0);
+        assert_eq!(seen[1].id(), child.0);
+        assert_eq!(seen[1].why(), Some((None, parent.0)));
+        assert_eq!(seen[1].timestamp(), NsSinceEpoch(42));
+        assert_eq!(seen[2].counter_value(), Some(7));
+        assert_eq!(seen[3].timestamp(), NsSinceEpoch(25));
+        assert_eq!(seen[3].duration(), Some(15));
+        assert_eq!(seen[4].id(), parent.0);
+    }
+
+    #[test]
+    fn suppresses_duplicates_within_window() {
+        let mut sink = DedupSink::with_clock(SimpleTraceBuffer::default(), 100, now);
+        let cause = sink.trace_event(SimpleTrace::OperationThing, None);
+
+        for t in 0..30 {
+            set_now(1_000 + t * 10);
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            sink.trace_counter(SimpleTrace::FooEvent, 7);
+        }
+        // A different cause or value isn't a duplicate.
+        sink.trace_event(SimpleTrace::FooEvent, Some(cause));
+        sink.trace_counter(SimpleTrace::FooEvent, 8);
+
+        // One of each per 100ns window, and the two distinct traces.
+        let entries: Vec<_> = sink.as_ref().iter().collect();
+        let events = entries.iter().filter(|e| e.kind() == TraceKind::Event).count();
+        let counters = entries.iter().filter(|e| e.kind() == TraceKind::Counter).count();
+        assert_eq!(events, 1 + 3 + 1);
+        assert_eq!(counters, 3 + 1);
+        assert_eq!(sink.suppressed(SimpleTrace::FooEvent.tag()), 2 * (30 - 3));
+        assert_eq!(sink.suppressed(SimpleTrace::OperationThing.tag()), 0);
+    }
+
+    #[test]
+    fn weak_handle_does_not_keep_sink_alive() {
+        let buffer = Arc::new(Mutex::new(SimpleTraceBuffer::default()));
+        let mut handle = WeakSinkHandle::new(&buffer);
+        let mut clone = handle.clone();
+
+        handle.trace_event(SimpleTrace::FooEvent, None);
+        clone.trace_counter(SimpleTrace::FooEvent, 1);
+        assert!(handle.is_alive());
+        assert_eq!(buffer.lock().unwrap().iter().count(), 2);
+
+        drop(buffer);
+        assert!(!handle.is_alive());
+        handle.trace_event(SimpleTrace::FooEvent, None);
+        let id = clone.trace_start(SimpleTrace::OperationThing, None);
+        clone.trace_stop(id, SimpleTrace::OperationThing);
+
+        let mut dead = WeakSinkHandle::<SimpleTraceBuffer>::dead();
+        assert!(!dead.is_alive());
+        dead.trace_event(SimpleTrace::FooEvent, None);
+    }
 }
@@ -0,0 +1,152 @@
+//! Construct synthetic trace sessions with exact, deterministic timestamps.
+//!
+//! This is useful for testing code that consumes traces, such as exporters and
+//! analyses, without depending on how long real operations take.
+//!
+//! ```
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::testing::TraceBuilder;
+//!
+//! // Timestamps are in nanoseconds relative to the start of the session.
+//! let buffer = TraceBuilder::new()
+//!     .span(SimpleTrace::OperationThing, 0..5_000_000, |b| {
+//!         b.event(SimpleTrace::FooEvent, 2_000_000)
+//!     })
+//!     .event(SimpleTrace::FooEvent, 6_000_000)
+//!     .build();
+//!
+//! assert_eq!(buffer.iter().count(), 4);
+//! ```
+
+use ring_buffer::{Entry, NsSinceEpoch, RingBuffer, TraceKind};
+use std::ops::Range;
+use traits::{Trace, TraceId};
+
+/// A builder for a synthetic `RingBuffer<T>` session.
+#[derive(Clone, Debug)]
+pub struct TraceBuilder<T>
+    where T: Trace
+{
+    start: u64,
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> Default for TraceBuilder<T>
+    where T: Trace
+{
+    fn default() -> TraceBuilder<T> {
+        TraceBuilder {
+            start: 0,
+            entries: vec![],
+        }
+    }
+}
+
+impl<T> TraceBuilder<T>
+    where T: Trace
+{
+    /// Construct a new, empty `TraceBuilder` whose session starts at the epoch.
+    pub fn new() -> TraceBuilder<T> {
+        Default::default()
+    }
+
+    /// Offset every timestamp in this session by the given number of
+    /// nanoseconds since the epoch.
+    pub fn starting_at(mut self, start: NsSinceEpoch) -> TraceBuilder<T> {
+        self.start = start.0;
+        self
+    }
+
+    /// Add a one-off event at the given offset, in nanoseconds.
+    pub fn event(mut self, trace: T, at: u64) -> TraceBuilder<T> {
+        self.push(TraceKind::Event, trace, T::Id::new_id(), at);
+        self
+    }
+
+    /// Add a span covering the given range of offsets, in nanoseconds, whose
+    /// nested spans and events are added by `children`.
+    pub fn span<F>(mut self, trace: T, during: Range<u64>, children: F) -> TraceBuilder<T>
+        where F: FnOnce(TraceBuilder<T>) -> TraceBuilder<T>
+    {
+        assert!(during.start <= during.end);
+
+        let id = T::Id::new_id();
+        self.push(TraceKind::Start, trace, id, during.start);
+
+        let mut nested = children(self);
+
+        nested.push(TraceKind::Stop, trace, id, during.end);
+        nested
+    }
+
+    /// Add a span without any nested spans or events.
+    pub fn leaf_span(self, trace: T, during: Range<u64>) -> TraceBuilder<T> {
+        self.span(trace, during, |b| b)
+    }
+
+    /// Add a span covering the given range of offsets, in nanoseconds, as a
+    /// single `TraceKind::Complete` entry.
+    pub fn complete(mut self, trace: T, during: Range<u64>) -> TraceBuilder<T> {
+        assert!(during.start <= during.end);
+
+        let timestamp = NsSinceEpoch(self.start + during.end);
+        let entry = Entry::new(TraceKind::Complete, trace.tag(), T::Id::new_id(), None, timestamp);
+        self.entries.push(entry.with_value(during.end - during.start));
+        self
+    }
+
+    /// Build a `RingBuffer<T>` just large enough to hold every entry, in
+    /// timestamp order.
+    pub fn build(self) -> RingBuffer<T> {
+        let capacity = (self.entries.len() + 1) * Entry::<T>::size();
+        let mut buffer = RingBuffer::new(capacity);
+        self.build_into(&mut buffer);
+        buffer
+    }
+
+    /// Append every entry, in timestamp order, to the given `buffer`.
+    pub fn build_into(mut self, buffer: &mut RingBuffer<T>) {
+        // Stable, so that entries with equal timestamps stay in the order they
+        // were added, e.g. a parent's start before its child's start.
+        self.entries.sort_by_key(|entry| entry.timestamp().0);
+        for entry in self.entries {
+            buffer.write_entry(entry);
+        }
+    }
+
+    fn push(&mut self, kind: TraceKind, trace: T, id: T::Id, at: u64) {
+        let timestamp = NsSinceEpoch(self.start + at);
+        self.entries.push(Entry::new(kind, trace.tag(), id, None, timestamp));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+
+    #[test]
+    fn nested_spans_in_timestamp_order() {
+        let buffer = TraceBuilder::new()
+            .starting_at(NsSinceEpoch(1_000))
+            .span(SimpleTrace::OperationThing, 0..10, |b| {
+                b.leaf_span(SimpleTrace::OperationAnother, 5..8)
+                    .event(SimpleTrace::FooEvent, 2)
+            })
+            .build();
+
+        let entries: Vec<_> = buffer.iter()
+            .map(|e| (e.kind(), e.label(), e.timestamp().0))
+            .collect();
+        assert_eq!(entries,
+                   vec![(TraceKind::Start, "Thing", 1_000),
+                        (TraceKind::Event, "Foo", 1_002),
+                        (TraceKind::Start, "Another", 1_005),
+                        (TraceKind::Stop, "Another", 1_008),
+                        (TraceKind::Stop, "Thing", 1_010)]);
+
+        let entries: Vec<_> = buffer.iter().collect();
+        assert_eq!(entries[0].id(), entries[4].id());
+        assert_eq!(entries[2].id(), entries[3].id());
+    }
+}
@@ -0,0 +1,535 @@
+//! Per-thread `RingBuffer` sinks that don't require a global lock to trace.
+//!
+//! Each thread lazily gets its own `RingBuffer<T>` the first time it traces
+//! through a `ThreadLocalSink<T>`. The buffers are registered globally so that
+//! `merge` can later collect all of them and interleave their entries by
+//! timestamp.
+//!
+//! ```
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::thread_local_trace::{self, ThreadLocalSink};
+//! use eep::traits::TraceSink;
+//! use std::thread;
+//!
+//! let handle = thread::spawn(|| {
+//!     ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+//! });
+//! ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+//! handle.join().unwrap();
+//!
+//! for (thread, entry) in thread_local_trace::merge::<SimpleTrace>() {
+//!     println!("{:?}: {}", thread, entry.label());
+//! }
+//! ```
+//!
+//! Use `merge_with_sources` instead to also learn which process and which
+//! named sink each entry came from, and to look up the names of events traced
+//! with `trace_event_named`.
+//!
+//! To periodically inspect the buffers, for example from a watchdog thread,
+//! without pausing traced threads for longer than a copy, use `snapshot`.
+//!
+//! Tracing can also happen after the current thread's buffers have been
+//! destroyed, for example from the `Drop` of another thread-local value while
+//! the thread exits. Those traces are routed to the `global` sink instead, and
+//! are dropped if none is installed.
+
+use global;
+use ring_buffer::{Clock, Entry, MergeIters, NsSinceEpoch, RingBuffer, TraceSnapshot};
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::process;
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(all(feature = "fork", unix))]
+use std::sync::MutexGuard;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::vec;
+use traits::{ThreadId, Trace, TraceSink};
+
+type LocalBuffer<T> = Arc<Mutex<RingBuffer<T>>>;
+
+struct Registered {
+    source: Source,
+    trace_type: TypeId,
+    buffer: Arc<dyn Any + Send + Sync>,
+}
+
+/// Where a merged entry was traced.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Source {
+    /// The ID of the process that traced the entry.
+    pub process: u32,
+
+    /// The thread that traced the entry.
+    pub thread: ThreadId,
+
+    /// The name of the sink the entry was traced into, as set by
+    /// `set_sink_name` on that thread before its first trace.
+    pub sink: &'static str,
+}
+
+static REGISTRY: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+static LOCAL_CAPACITY: AtomicUsize = AtomicUsize::new(4096);
+
+static LOCAL_CLOCK: Mutex<Clock> = Mutex::new(NsSinceEpoch::now);
+
+thread_local!(static LOCAL_SINK_NAME: Cell<&'static str> = const { Cell::new("thread_local") });
+
+thread_local!(static LOCAL_BUFFERS: RefCell<HashMap<TypeId, Box<dyn Any>>> =
+                  RefCell::new(HashMap::new()));
+
+/// Set the capacity, in bytes, of per-thread buffers created from now on.
+///
+/// Threads that have already traced keep their existing buffers.
+pub fn set_capacity(capacity: usize) {
+    LOCAL_CAPACITY.store(capacity, Ordering::Release);
+}
+
+/// Set the clock that per-thread buffers created from now on are timestamped
+/// by. Defaults to `NsSinceEpoch::now`.
+///
+/// Threads that have already traced keep their existing buffers' clocks.
+pub fn set_clock(clock: Clock) {
+    *LOCAL_CLOCK.lock().unwrap() = clock;
+}
+
+/// Set the sink name that the current thread's buffers created from now on
+/// report as their `Source::sink`. Defaults to `"thread_local"`.
+pub fn set_sink_name(name: &'static str) {
+    LOCAL_SINK_NAME.with(|n| n.set(name));
+}
+
+// Call `f` with the current thread's buffer, or with the global sink if the
+// thread's buffers have already been destroyed.
+fn with_local_buffer<T, F, R>(f: F) -> R
+    where T: 'static + Send + Trace,
+          F: FnOnce(&mut dyn TraceSink<T>) -> R
+{
+    let buffer = LOCAL_BUFFERS.try_with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        buffers.entry(TypeId::of::<T>())
+            .or_insert_with(|| {
+                let capacity = LOCAL_CAPACITY.load(Ordering::Acquire);
+                let clock = *LOCAL_CLOCK.lock().unwrap();
+                let buffer: LocalBuffer<T> =
+                    Arc::new(Mutex::new(RingBuffer::with_clock(capacity, clock)));
+                REGISTRY.lock().unwrap().push(Registered {
+                    source: Source {
+                        process: process::id(),
+                        thread: ThreadId::get(),
+                        sink: LOCAL_SINK_NAME.with(|n| n.get()),
+                    },
+                    trace_type: TypeId::of::<T>(),
+                    buffer: buffer.clone(),
+                });
+                Box::new(buffer)
+            })
+            .downcast_ref::<LocalBuffer<T>>()
+            .expect("local buffers are keyed by their trace type")
+            .clone()
+    });
+
+    match buffer {
+        Ok(buffer) => {
+            // Only `merge` ever contends for this lock, and a buffer whose
+            // sink panicked mid-trace is still usable.
+            let mut buffer = buffer.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut *buffer)
+        }
+        Err(_) => f(&mut global::global()),
+    }
+}
+
+/// A `TraceSink` that writes into the current thread's own `RingBuffer<T>`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ThreadLocalSink<T>(PhantomData<T>);
+
+impl<T> ThreadLocalSink<T> {
+    /// Get a handle to the current thread's sink.
+    pub fn get() -> ThreadLocalSink<T> {
+        ThreadLocalSink(PhantomData)
+    }
+}
+
+impl<T> TraceSink<T> for ThreadLocalSink<T>
+    where T: 'static + Send + Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        with_local_buffer(|buffer| buffer.trace_event(trace, why))
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        with_local_buffer(|buffer| buffer.trace_start(trace, why))
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        with_local_buffer(|buffer| buffer.trace_stop(id, trace))
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        with_local_buffer(|buffer| buffer.trace_cancel(id, trace))
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        with_local_buffer(|buffer| buffer.trace_complete(trace, start, end, why))
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        with_local_buffer(|buffer| buffer.trace_park(trace))
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        with_local_buffer(|buffer| buffer.trace_unpark(trace))
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        with_local_buffer(|buffer| buffer.trace_counter(trace, value))
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        with_local_buffer(|buffer| buffer.trace_event_named(trace, name))
+    }
+
+    fn max_depth(&self) -> usize {
+        with_local_buffer::<T, _, _>(|buffer| buffer.max_depth())
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        with_local_buffer(|buffer| buffer.trace_depth_exceeded(trace, depth))
+    }
+}
+
+/// Collect the entries from every thread's `RingBuffer<T>`, interleaved by
+/// timestamp, along with the ID of the thread that traced each entry.
+///
+/// Buffers belonging to threads that have since exited are included. Each
+/// thread interns its own names, so use `merge_with_sources` to look up the
+/// names of named events.
+pub fn merge<T>() -> vec::IntoIter<(ThreadId, Entry<T>)>
+    where T: 'static + Send + Trace
+{
+    merge_with_sources()
+        .map(|(source, entry)| (source.thread, entry))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// Like `merge`, but with the full `Source` of each entry, so that entries can
+/// be grouped or filtered by process, thread, or sink before being handed to
+/// an exporter or analysis.
+///
+/// Every thread's interned names are combined into one table, and named
+/// events' name IDs are rewritten to index it. Look them up with
+/// `Merged::names`.
+pub fn merge_with_sources<T>() -> Merged<T>
+    where T: 'static + Send + Trace
+{
+    let snapshots = snapshot::<T>();
+
+    let mut names = vec![];
+    let mut ids = HashMap::new();
+    let remapped: Vec<Vec<u32>> = snapshots.iter()
+        .map(|&(_, ref snapshot)| {
+            snapshot.names()
+                .iter()
+                .map(|name| {
+                    *ids.entry(name.clone()).or_insert_with(|| {
+                        names.push(name.clone());
+                        (names.len() - 1) as u32
+                    })
+                })
+                .collect()
+        })
+        .collect();
+
+    let iters = snapshots.iter().map(|&(_, ref snapshot)| snapshot.iter()).collect();
+    let entries = MergeIters::new(iters)
+        .map(|(index, entry)| {
+            let entry = match entry.name().and_then(|id| remapped[index].get(id as usize)) {
+                Some(&id) => entry.with_value(id as u64 + 1),
+                None => entry,
+            };
+            (snapshots[index].0, entry)
+        })
+        .collect::<Vec<_>>();
+    Merged {
+        entries: entries.into_iter(),
+        names: names,
+    }
+}
+
+/// The entries of every thread's `RingBuffer<T>`, as returned by
+/// `merge_with_sources`, along with the names they refer to.
+#[derive(Debug)]
+pub struct Merged<T> {
+    entries: vec::IntoIter<(Source, Entry<T>)>,
+    names: Vec<String>,
+}
+
+impl<T> Merged<T> {
+    /// Get the name with the given ID, as returned by `Entry::name`.
+    pub fn name(&self, id: u32) -> Option<&str> {
+        self.names.get(id as usize).map(|name| &name[..])
+    }
+
+    /// Get the names interned by every thread's `trace_event_named`, indexed
+    /// by ID, as for `chrome::write_json_with_sources`.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+impl<T> Iterator for Merged<T> {
+    type Item = (Source, Entry<T>);
+
+    fn next(&mut self) -> Option<(Source, Entry<T>)> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<T> ExactSizeIterator for Merged<T> {}
+
+/// Copy out every thread's `RingBuffer<T>`, along with the `Source` of each.
+///
+/// Each thread's buffer is only locked, pausing that thread's tracing, while
+/// its valid bytes are copied. Each snapshot is consistent on its own, but
+/// threads keep tracing while other threads' buffers are copied.
+pub fn snapshot<T>() -> Vec<(Source, TraceSnapshot<T>)>
+    where T: 'static + Send + Trace
+{
+    let registry = REGISTRY.lock().unwrap();
+    registry.iter()
+        .filter(|r| r.trace_type == TypeId::of::<T>())
+        .map(|registered| {
+            let buffer = registered.buffer
+                .downcast_ref::<Mutex<RingBuffer<T>>>()
+                .expect("registered buffers are tagged with their trace type");
+            let snapshot = buffer.lock().unwrap().snapshot();
+            (registered.source, snapshot)
+        })
+        .collect()
+}
+
+/// The registry's and the clock's locks, held from just before a `fork` until
+/// just after it, so that no other thread holds them, or a buffer lock via
+/// `snapshot`, at the moment of forking.
+#[cfg(all(feature = "fork", unix))]
+pub(crate) struct ForkGuard {
+    registry: MutexGuard<'static, Vec<Registered>>,
+    _clock: MutexGuard<'static, Clock>,
+}
+
+#[cfg(all(feature = "fork", unix))]
+pub(crate) fn prepare_fork() -> ForkGuard {
+    // Never held at the same time as the registry's lock elsewhere, so either
+    // order is deadlock-free.
+    ForkGuard {
+        registry: REGISTRY.lock().unwrap_or_else(|e| e.into_inner()),
+        _clock: LOCAL_CLOCK.lock().unwrap_or_else(|e| e.into_inner()),
+    }
+}
+
+/// Forget every buffer in the child: the other threads don't exist there, and
+/// the parent's entries are the parent's to merge. The forking thread gets a
+/// new, empty buffer, registered with the child's process ID, the next time it
+/// traces.
+#[cfg(all(feature = "fork", unix))]
+pub(crate) fn after_fork_child(mut guard: ForkGuard) {
+    guard.registry.clear();
+    let _ = LOCAL_BUFFERS.try_with(|buffers| {
+        if let Ok(mut buffers) = buffers.try_borrow_mut() {
+            buffers.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::{SimpleTrace, SimpleTraceId};
+    use std::thread;
+    use traits::TraceSink;
+
+    #[test]
+    fn merge_across_threads() {
+        let main_thread = ThreadId::get();
+
+        set_sink_name("merge_across_threads");
+        ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+        let other_thread = thread::spawn(|| {
+                set_sink_name("merge_across_threads");
+                let id = ThreadLocalSink::get().trace_start(SimpleTrace::OperationThing, None);
+                ThreadLocalSink::get().trace_stop(id, SimpleTrace::OperationThing);
+                ThreadId::get()
+            })
+            .join()
+            .unwrap();
+        ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+
+        // Other tests may be tracing `SimpleTrace`s from their own threads,
+        // whose IDs may be reused, so find this test's entries by sink name.
+        let merged: Vec<_> = merge_with_sources::<SimpleTrace>()
+            .filter(|&(source, _)| source.sink == "merge_across_threads")
+            .map(|(source, entry)| (source.thread, entry))
+            .collect();
+        assert_eq!(merged.len(), 4);
+
+        let threads: Vec<_> = merged.iter().map(|&(thread, _)| thread).collect();
+        assert_eq!(threads, vec![main_thread, other_thread, other_thread, main_thread]);
+
+        let mut last = 0;
+        for &(_, ref entry) in &merged {
+            assert!(entry.timestamp().0 >= last);
+            last = entry.timestamp().0;
+        }
+    }
+
+    #[test]
+    fn merge_with_named_sources() {
+        let source = thread::spawn(|| {
+                set_sink_name("worker");
+                ThreadLocalSink::get().trace_event(SimpleTrace::FooEvent, None);
+                ThreadId::get()
+            })
+            .join()
+            .unwrap();
+
+        // Thread IDs may be reused by other tests' threads, so find this
+        // test's entry by its sink name.
+        let merged: Vec<_> = merge_with_sources::<SimpleTrace>()
+            .filter(|&(s, _)| s.sink == "worker")
+            .collect();
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].0.thread, source);
+        assert_eq!(merged[0].0.process, process::id());
+    }
+
+    #[test]
+    fn merge_combines_names() {
+        set_sink_name("merge_combines_names");
+        ThreadLocalSink::get().trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+        thread::spawn(|| {
+                set_sink_name("merge_combines_names");
+                let mut sink = ThreadLocalSink::get();
+                sink.trace_event_named(SimpleTrace::FooEvent, "/etc/passwd");
+                sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            })
+            .join()
+            .unwrap();
+
+        let merged = merge_with_sources::<SimpleTrace>();
+        let names: Vec<_> = merged.names().to_vec();
+        let merged: Vec<_> = merged.filter(|&(source, _)| source.sink == "merge_combines_names")
+            .map(|(_, entry)| &names[entry.name().unwrap() as usize][..])
+            .collect();
+        assert_eq!(merged, vec!["/etc/hosts", "/etc/passwd", "/etc/hosts"]);
+    }
+
+    // A trace type only used by `trace_from_tls_drop`, so that its global
+    // sink and buffers aren't shared with other tests.
+    #[derive(Copy, Clone, Debug)]
+    struct LateTrace;
+
+    impl Trace for LateTrace {
+        type Id = SimpleTraceId;
+
+        fn label(_tag: u32) -> &'static str {
+            "Late"
+        }
+
+        fn tag(&self) -> u32 {
+            0
+        }
+    }
+
+    struct SharedSink(Arc<Mutex<RingBuffer<LateTrace>>>);
+
+    impl TraceSink<LateTrace> for SharedSink {
+        fn trace_event(&mut self, trace: LateTrace, why: Option<SimpleTraceId>) -> SimpleTraceId {
+            self.0.lock().unwrap().trace_event(trace, why)
+        }
+
+        fn trace_start(&mut self, trace: LateTrace, why: Option<SimpleTraceId>) -> SimpleTraceId {
+            self.0.lock().unwrap().trace_start(trace, why)
+        }
+
+        fn trace_stop(&mut self, id: SimpleTraceId, trace: LateTrace) {
+            self.0.lock().unwrap().trace_stop(id, trace)
+        }
+    }
+
+    // Traces from its `Drop`, recording whether the thread's buffers were
+    // still alive at the time.
+    struct TraceOnDrop(Option<Arc<Mutex<Vec<bool>>>>);
+
+    impl Drop for TraceOnDrop {
+        fn drop(&mut self) {
+            if let Some(ref alive) = self.0 {
+                let buffers_alive = LOCAL_BUFFERS.try_with(|_| ()).is_ok();
+                ThreadLocalSink::get().trace_event(LateTrace, None);
+                alive.lock().unwrap().push(buffers_alive);
+            }
+        }
+    }
+
+    thread_local!(static BEFORE: RefCell<TraceOnDrop> = RefCell::new(TraceOnDrop(None)));
+    thread_local!(static AFTER: RefCell<TraceOnDrop> = RefCell::new(TraceOnDrop(None)));
+
+    #[test]
+    fn trace_from_tls_drop() {
+        let fallback = Arc::new(Mutex::new(RingBuffer::new(1 << 12)));
+        global::set_global_sink(SharedSink(fallback.clone()));
+
+        // Register one value's destructor before the thread's buffers are
+        // created, and one after, so that both destruction orders are tried.
+        let alive = Arc::new(Mutex::new(vec![]));
+        let thread_alive = alive.clone();
+        thread::spawn(move || {
+                BEFORE.with(|b| *b.borrow_mut() = TraceOnDrop(Some(thread_alive.clone())));
+                ThreadLocalSink::get().trace_event(LateTrace, None);
+                AFTER.with(|a| *a.borrow_mut() = TraceOnDrop(Some(thread_alive)));
+            })
+            .join()
+            .unwrap();
+
+        global::clear_global_sink::<LateTrace>();
+
+        // Every trace ends up somewhere: in the thread's buffer if it was
+        // still alive, and in the global sink otherwise.
+        let alive = alive.lock().unwrap();
+        assert_eq!(alive.len(), 2);
+        let late = alive.iter().filter(|&&a| !a).count();
+        assert_eq!(fallback.lock().unwrap().iter().count(), late);
+        assert_eq!(merge::<LateTrace>().count(), 1 + 2 - late);
+    }
+
+    #[test]
+    fn snapshot_each_thread() {
+        thread::spawn(|| {
+                set_sink_name("snapshotted");
+                let id = ThreadLocalSink::get().trace_start(SimpleTrace::OperationThing, None);
+                ThreadLocalSink::get().trace_stop(id, SimpleTrace::OperationThing);
+            })
+            .join()
+            .unwrap();
+
+        let snapshots: Vec<_> = snapshot::<SimpleTrace>()
+            .into_iter()
+            .filter(|&(s, _)| s.sink == "snapshotted")
+            .collect();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].1.len(), 2);
+    }
+}
@@ -28,3 +28,61 @@ impl TraceId for ThreadedTraceId {
         Some(self.0)
     }
 }
+
+/// A `TraceId` implementation that, like `ThreadedTraceId`, is a pair of a
+/// thread ID and a thread-local counter, but also remembers the ID of the
+/// trace that caused it, and how many causes deep it lies.
+///
+/// IDs made with `TraceId::new_child_id`, as sinks do for traces given a
+/// `why`, link to their parent. Only the parent's own ID is kept, so walk
+/// further up the tree with `analysis::CausalityGraph`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct LinkedTraceId {
+    id: ThreadedTraceId,
+    parent: Option<ThreadedTraceId>,
+    depth: u32,
+}
+
+impl LinkedTraceId {
+    /// Get this ID, without its link to its parent.
+    pub fn id(&self) -> ThreadedTraceId {
+        self.id
+    }
+
+    /// Get the ID of the trace that caused this one, if any.
+    pub fn parent(&self) -> Option<ThreadedTraceId> {
+        self.parent
+    }
+
+    /// Get the number of causes above this trace: `0` if it has no parent,
+    /// `1` if its parent has none, and so on.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+}
+
+impl TraceId for LinkedTraceId {
+    fn new_id() -> Self {
+        LinkedTraceId {
+            id: ThreadedTraceId::new_id(),
+            parent: None,
+            depth: 0,
+        }
+    }
+
+    fn new_child_id(parent: &Self) -> Self {
+        LinkedTraceId {
+            id: ThreadedTraceId::new_id(),
+            parent: Some(parent.id),
+            depth: parent.depth.saturating_add(1),
+        }
+    }
+
+    fn u32(&self) -> u32 {
+        self.id.u32()
+    }
+
+    fn thread(&self) -> Option<ThreadId> {
+        self.id.thread()
+    }
+}
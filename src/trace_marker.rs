@@ -0,0 +1,211 @@
+//! A `TraceSink` for Linux that writes every trace to ftrace's `trace_marker`
+//! file, so that `perf`, `bpftrace`, `trace-cmd`, and other ftrace users see
+//! eep's events and spans interleaved with kernel events.
+//!
+//! Each trace is written as a single line:
+//!
+//! ```text
+//! eep: <kind> <label> <id>
+//! ```
+//!
+//! where `<kind>` is one of `event`, `start`, `stop`, `cancel`, `park`, or
+//! `unpark`, and `<id>` matches a span's start with its stop. Counters are
+//! written as `eep: counter <label> <value>` instead. Completes are only
+//! traced once they have ended, so they are written then, as
+//! `eep: complete <label> <id> <duration in ns>`, and named events have their
+//! name appended, as `eep: event <label> <id> <name>`. The lines are recorded
+//! as `ftrace:print` events, for example:
+//!
+//! ```text
+//! perf record -e ftrace:print -a -- ./my-traced-program
+//! ```
+//!
+//! Writing to `trace_marker` usually requires root, or a tracefs mounted with
+//! permissive modes.
+
+use ring_buffer::NsSinceEpoch;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use traits::{Trace, TraceId, TraceSink};
+
+const TRACE_MARKER_PATHS: [&'static str; 2] = ["/sys/kernel/tracing/trace_marker",
+                                               "/sys/kernel/debug/tracing/trace_marker"];
+
+/// A `TraceSink` that writes to ftrace's `trace_marker` file.
+#[derive(Debug)]
+pub struct TraceMarker<T> {
+    file: File,
+    line: Vec<u8>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> TraceMarker<T>
+    where T: Trace
+{
+    /// Open the `trace_marker` file in tracefs, wherever it is mounted.
+    pub fn open() -> io::Result<TraceMarker<T>> {
+        let mut result = Err(io::Error::new(io::ErrorKind::NotFound, "tracefs is not mounted"));
+        for path in &TRACE_MARKER_PATHS {
+            result = TraceMarker::open_path(path);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Open the `trace_marker` file at the given path.
+    pub fn open_path<P>(path: P) -> io::Result<TraceMarker<T>>
+        where P: AsRef<Path>
+    {
+        let file = try!(OpenOptions::new().write(true).open(path));
+        Ok(TraceMarker {
+            file: file,
+            line: vec![],
+            phantom: PhantomData,
+        })
+    }
+
+    fn mark(&mut self, kind: &str, trace: T, id: T::Id) {
+        self.mark_value(kind, trace, id.u32() as u64);
+    }
+
+    fn mark_value(&mut self, kind: &str, trace: T, value: u64) {
+        // Each line must be written with a single `write`, or ftrace will
+        // record it as several markers.
+        self.line.clear();
+        let _ = writeln!(self.line, "eep: {} {} {}", kind, T::label(trace.tag()), value);
+        self.write_line();
+    }
+
+    fn write_line(&mut self) {
+        // Tracing must never fail the traced program, so drop the marker if
+        // it can't be written.
+        let _ = self.file.write(&self.line);
+    }
+}
+
+impl<T> TraceSink<T> for TraceMarker<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.mark("event", trace, id);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.mark("start", trace, id);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.mark("stop", trace, id);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.mark("cancel", trace, id);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      _why: Option<T::Id>)
+                      -> T::Id {
+        let id = T::Id::new_id();
+        self.line.clear();
+        let _ = writeln!(self.line,
+                         "eep: complete {} {} {}",
+                         T::label(trace.tag()),
+                         id.u32(),
+                         end.0.saturating_sub(start.0));
+        self.write_line();
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.mark("park", trace, T::Id::new_id());
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.mark("unpark", trace, T::Id::new_id());
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.mark_value("counter", trace, value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = T::Id::new_id();
+        self.line.clear();
+        let _ = write!(self.line, "eep: event {} {} ", T::label(trace.tag()), id.u32());
+        // A newline would end the marker early.
+        self.line.extend(name.bytes().map(|b| if b == b'\n' { b' ' } else { b }));
+        self.line.push(b'\n');
+        self.write_line();
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use std::env;
+    use std::fs;
+    use traits::TraceSink;
+
+    #[test]
+    fn writes_one_line_per_trace() {
+        let path = env::temp_dir().join("eep-trace-marker-writes-one-line-per-trace");
+        fs::write(&path, b"").unwrap();
+        {
+            let mut sink = TraceMarker::open_path(&path).unwrap();
+            sink.trace_event(SimpleTrace::FooEvent, None);
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("eep: event Foo "));
+        assert!(lines[1].starts_with("eep: start Thing "));
+        assert!(lines[2].starts_with("eep: stop Thing "));
+        assert_eq!(lines[1].split(' ').last(), lines[2].split(' ').last());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_completes_parks_and_names() {
+        let path = env::temp_dir().join("eep-trace-marker-writes-completes-parks-and-names");
+        fs::write(&path, b"").unwrap();
+        {
+            let mut sink = TraceMarker::open_path(&path).unwrap();
+            sink.trace_complete(SimpleTrace::OperationThing,
+                                NsSinceEpoch(1_000),
+                                NsSinceEpoch(3_500),
+                                None);
+            sink.trace_park(SimpleTrace::FooEvent);
+            sink.trace_unpark(SimpleTrace::FooEvent);
+            sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts\nsecond line");
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("eep: complete Thing "));
+        assert!(lines[0].ends_with(" 2500"));
+        assert!(lines[1].starts_with("eep: park Foo "));
+        assert!(lines[2].starts_with("eep: unpark Foo "));
+        assert!(lines[3].starts_with("eep: event Foo "));
+        assert!(lines[3].ends_with(" /etc/hosts second line"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! Make expensive destructors, such as freeing a large `Vec` or flushing a
+//! file, visible in traces rather than leaving unexplained gaps.
+//!
+//! ```
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::traced_drop::TracedDrop;
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! {
+//!     let big = TracedDrop::new(vec![0u8; 1 << 20], SimpleTrace::OperationThing, &mut buffer);
+//!     assert_eq!(big.len(), 1 << 20);
+//! }
+//! assert_eq!(buffer.iter().count(), 2);
+//! ```
+
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use traits::{Trace, TraceSink};
+
+/// A wrapper around a value that traces a span, with a caller-chosen `Trace`,
+/// around the value's destructor.
+#[derive(Debug)]
+pub struct TracedDrop<V, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    value: ManuallyDrop<V>,
+    trace: T,
+    sink: S,
+}
+
+impl<V, S, T> TracedDrop<V, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    /// Wrap `value` such that dropping it is traced as `trace` into `sink`.
+    pub fn new(value: V, trace: T, sink: S) -> TracedDrop<V, S, T> {
+        TracedDrop {
+            value: ManuallyDrop::new(value),
+            trace: trace,
+            sink: sink,
+        }
+    }
+
+    /// Unwrap the value, without tracing anything.
+    pub fn into_inner(self) -> V {
+        let mut this = ManuallyDrop::new(self);
+        unsafe {
+            ptr::drop_in_place(&mut this.sink);
+            ptr::read(&*this.value)
+        }
+    }
+}
+
+impl<V, S, T> Deref for TracedDrop<V, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<V, S, T> DerefMut for TracedDrop<V, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.value
+    }
+}
+
+impl<V, S, T> Drop for TracedDrop<V, S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn drop(&mut self) {
+        let id = self.sink.trace_start(self.trace, None);
+        unsafe {
+            ManuallyDrop::drop(&mut self.value);
+        }
+        self.sink.trace_stop(id, self.trace);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use ring_buffer::TraceKind;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+
+    #[test]
+    fn traces_drop() {
+        let mut buffer = SimpleTraceBuffer::default();
+        drop(TracedDrop::new(vec![1, 2, 3], SimpleTrace::OperationThing, &mut buffer));
+
+        let entries: Vec<_> = buffer.iter().map(|e| (e.kind(), e.label())).collect();
+        assert_eq!(entries,
+                   vec![(TraceKind::Start, "Thing"), (TraceKind::Stop, "Thing")]);
+    }
+
+    #[test]
+    fn into_inner_is_not_traced() {
+        let mut buffer = SimpleTraceBuffer::default();
+        let value = TracedDrop::new(vec![1, 2, 3], SimpleTrace::OperationThing, &mut buffer)
+            .into_inner();
+        assert_eq!(value, vec![1, 2, 3]);
+        assert_eq!(buffer.iter().count(), 0);
+    }
+}
@@ -0,0 +1,382 @@
+//! Interoperate with the `tracing` ecosystem, so that code doesn't need to be
+//! instrumented twice.
+//!
+//! * `TraceLayer` is a `tracing_subscriber::Layer` that forwards `tracing`
+//!   spans and events into any `TraceSink`. Every time a span is entered, it
+//!   traces a start, and every time it is exited, a stop.
+//!
+//! * `TracingSink` wraps a `TraceSink` and mirrors every trace out as a
+//!   `tracing` event with the `"eep"` target.
+//!
+//! Spans and events are classified into `Trace`s by a plain function; those it
+//! returns `None` for are dropped.
+//!
+//! ```
+//! extern crate eep;
+//! extern crate tracing;
+//! extern crate tracing_subscriber;
+//!
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::tracing_compat::TraceLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! fn classify(metadata: &tracing::Metadata) -> Option<SimpleTrace> {
+//!     match metadata.name() {
+//!         "thing" => Some(SimpleTrace::OperationThing),
+//!         _ => None,
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let layer = TraceLayer::new(SimpleTraceBuffer::default(), classify);
+//! let subscriber = tracing_subscriber::registry().with(layer);
+//! tracing::subscriber::set_global_default(subscriber).unwrap();
+//! # }
+//! ```
+
+extern crate tracing;
+extern crate tracing_subscriber;
+
+use self::tracing::span;
+use self::tracing::{Event, Metadata, Subscriber};
+use self::tracing_subscriber::layer::{Context, Layer};
+use self::tracing_subscriber::registry::LookupSpan;
+use std::sync::{Mutex, MutexGuard};
+use ring_buffer::NsSinceEpoch;
+use traits::{BatchKind, Trace, TraceId, TraceSink};
+
+/// Classify a `tracing` span or event as a `Trace`, or `None` to drop it.
+pub type Classify<T> = fn(&Metadata) -> Option<T>;
+
+/// A `tracing_subscriber::Layer` that forwards spans and events into a
+/// `TraceSink`.
+#[derive(Debug)]
+pub struct TraceLayer<S, T> {
+    sink: Mutex<S>,
+    classify: Classify<T>,
+}
+
+// Stored in a span's extensions: what it was classified as, and the IDs of
+// its currently entered starts, innermost last.
+struct Entered<T>
+    where T: Trace
+{
+    trace: T,
+    ids: Vec<T::Id>,
+}
+
+impl<S, T> TraceLayer<S, T>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    /// Construct a new `TraceLayer` that traces spans and events classified by
+    /// `classify` into `sink`.
+    pub fn new(sink: S, classify: Classify<T>) -> TraceLayer<S, T> {
+        TraceLayer {
+            sink: Mutex::new(sink),
+            classify: classify,
+        }
+    }
+
+    /// Lock and get the underlying sink.
+    pub fn sink(&self) -> MutexGuard<'_, S> {
+        self.sink.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the underlying sink back.
+    pub fn into_inner(self) -> S {
+        self.sink.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<Sub, S, T> Layer<Sub> for TraceLayer<S, T>
+    where Sub: Subscriber + for<'a> LookupSpan<'a>,
+          S: TraceSink<T> + Send + 'static,
+          T: Trace + Send + Sync + 'static,
+          T::Id: Send + Sync
+{
+    fn on_new_span(&self, attrs: &span::Attributes, id: &span::Id, ctx: Context<Sub>) {
+        if let Some(trace) = (self.classify)(attrs.metadata()) {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(Entered {
+                    trace: trace,
+                    ids: vec![],
+                });
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<Sub>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(entered) = span.extensions_mut().get_mut::<Entered<T>>() {
+                entered.ids.push(self.sink().trace_start(entered.trace, None));
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<Sub>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(entered) = span.extensions_mut().get_mut::<Entered<T>>() {
+                if let Some(id) = entered.ids.pop() {
+                    self.sink().trace_stop(id, entered.trace);
+                }
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, _ctx: Context<Sub>) {
+        if let Some(trace) = (self.classify)(event.metadata()) {
+            self.sink().trace_event(trace, None);
+        }
+    }
+}
+
+/// A `TraceSink` that forwards to another sink, and also mirrors every trace
+/// out as a `tracing` event.
+///
+/// The events have the `"eep"` target, `TRACE` level, and `kind`, `label`, and
+/// `id` fields. Counters have a `value` field instead of `id`, and parks and
+/// unparks have no `id`. Completes also have a `duration_ns` field, and named
+/// events a `name` field.
+#[derive(Debug)]
+pub struct TracingSink<S> {
+    sink: S,
+}
+
+impl<S> TracingSink<S> {
+    /// Construct a new `TracingSink` that forwards to `sink`.
+    pub fn new(sink: S) -> TracingSink<S> {
+        TracingSink { sink: sink }
+    }
+
+    /// Get the underlying sink back.
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S> AsRef<S> for TracingSink<S> {
+    fn as_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S> AsMut<S> for TracingSink<S> {
+    fn as_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+fn mirror<T>(kind: &'static str, trace: T, id: T::Id)
+    where T: Trace
+{
+    tracing::event!(target: "eep",
+                    tracing::Level::TRACE,
+                    kind = kind,
+                    label = T::label(trace.tag()),
+                    id = id.u32());
+}
+
+fn mirror_without_id<T>(kind: &'static str, trace: T)
+    where T: Trace
+{
+    tracing::event!(target: "eep",
+                    tracing::Level::TRACE,
+                    kind = kind,
+                    label = T::label(trace.tag()));
+}
+
+impl<S, T> TraceSink<T> for TracingSink<S>
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.sink.trace_event(trace, why);
+        mirror("event", trace, id);
+        id
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        let id = self.sink.trace_start(trace, why);
+        mirror("start", trace, id);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_stop(id, trace);
+        mirror("stop", trace, id);
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.sink.trace_cancel(id, trace);
+        mirror("cancel", trace, id);
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let id = self.sink.trace_complete(trace, start, end, why);
+        tracing::event!(target: "eep",
+                        tracing::Level::TRACE,
+                        kind = "complete",
+                        label = T::label(trace.tag()),
+                        id = id.u32(),
+                        duration_ns = end.0.saturating_sub(start.0));
+        id
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.sink.trace_park(trace);
+        mirror_without_id("park", trace);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.sink.trace_unpark(trace);
+        mirror_without_id("unpark", trace);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.sink.trace_counter(trace, value);
+        tracing::event!(target: "eep",
+                        tracing::Level::TRACE,
+                        kind = "counter",
+                        label = T::label(trace.tag()),
+                        value = value);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let id = self.sink.trace_event_named(trace, name);
+        tracing::event!(target: "eep",
+                        tracing::Level::TRACE,
+                        kind = "event",
+                        label = T::label(trace.tag()),
+                        id = id.u32(),
+                        name = name);
+        id
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        self.sink.trace_batch(traces);
+        for &(trace, kind) in traces {
+            match kind {
+                BatchKind::Event => mirror_without_id("event", trace),
+                BatchKind::Park => mirror_without_id("park", trace),
+                BatchKind::Unpark => mirror_without_id("unpark", trace),
+            }
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.sink.max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.sink.trace_depth_exceeded(trace, depth);
+        tracing::event!(target: "eep",
+                        tracing::Level::TRACE,
+                        kind = "depth_exceeded",
+                        label = T::label(trace.tag()),
+                        depth = depth as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::tracing::dispatcher::{self, Dispatch};
+    use super::tracing_subscriber::layer::SubscriberExt;
+    use ring_buffer::TraceKind;
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::TraceSink;
+
+    type SimpleLayer = TraceLayer<SimpleTraceBuffer, SimpleTrace>;
+
+    fn classify(metadata: &Metadata) -> Option<SimpleTrace> {
+        match (metadata.target(), metadata.name()) {
+            ("eep", _) => Some(SimpleTrace::FooEvent),
+            (_, "thing") => Some(SimpleTrace::OperationThing),
+            (_, "another") => Some(SimpleTrace::OperationAnother),
+            _ => None,
+        }
+    }
+
+    fn dispatch() -> Dispatch {
+        let layer = TraceLayer::new(SimpleTraceBuffer::new(1 << 16), classify);
+        Dispatch::new(tracing_subscriber::registry().with(layer))
+    }
+
+    fn traced(dispatch: &Dispatch) -> Vec<(TraceKind, &'static str)> {
+        let layer = dispatch.downcast_ref::<SimpleLayer>().unwrap();
+        let sink = layer.sink();
+        sink.iter().map(|e| (e.kind(), e.label())).collect()
+    }
+
+    #[test]
+    fn forwards_spans() {
+        let dispatch = dispatch();
+        dispatcher::with_default(&dispatch, || {
+            let thing = tracing::info_span!("thing");
+            let _thing = thing.enter();
+            tracing::info_span!("unclassified").in_scope(|| {
+                tracing::info_span!("another").in_scope(|| {});
+            });
+        });
+
+        assert_eq!(traced(&dispatch),
+                   vec![(TraceKind::Start, "Thing"),
+                        (TraceKind::Start, "Another"),
+                        (TraceKind::Stop, "Another"),
+                        (TraceKind::Stop, "Thing")]);
+    }
+
+    #[test]
+    fn mirrors_traces() {
+        let dispatch = dispatch();
+        dispatcher::with_default(&dispatch, || {
+            let mut sink = TracingSink::new(SimpleTraceBuffer::default());
+            let id = sink.trace_start(SimpleTrace::OperationThing, None);
+            sink.trace_stop(id, SimpleTrace::OperationThing);
+            assert_eq!(sink.as_ref().iter().count(), 2);
+        });
+
+        assert_eq!(traced(&dispatch),
+                   vec![(TraceKind::Event, "Foo"), (TraceKind::Event, "Foo")]);
+    }
+
+    #[test]
+    fn forwards_every_kind() {
+        let dispatch = dispatch();
+        dispatcher::with_default(&dispatch, || {
+            let mut sink = TracingSink::new(SimpleTraceBuffer::default());
+            sink.trace_complete(SimpleTrace::OperationThing,
+                                NsSinceEpoch(1_000),
+                                NsSinceEpoch(2_000),
+                                None);
+            sink.trace_park(SimpleTrace::FooEvent);
+            sink.trace_unpark(SimpleTrace::FooEvent);
+            sink.trace_event_named(SimpleTrace::FooEvent, "/etc/hosts");
+            sink.trace_batch(&[(SimpleTrace::FooEvent, BatchKind::Park),
+                               (SimpleTrace::FooEvent, BatchKind::Unpark)]);
+
+            let buffer = sink.as_ref();
+            let kinds: Vec<_> = buffer.iter().map(|e| e.kind()).collect();
+            assert_eq!(kinds,
+                       vec![TraceKind::Complete,
+                            TraceKind::Park,
+                            TraceKind::Unpark,
+                            TraceKind::Event,
+                            TraceKind::Park,
+                            TraceKind::Unpark]);
+            let complete = buffer.iter().next().unwrap();
+            assert_eq!(complete.timestamp(), NsSinceEpoch(2_000));
+            assert_eq!(complete.duration(), Some(1_000));
+            assert_eq!(buffer.names(), &["/etc/hosts".to_string()]);
+        });
+
+        // Every one is mirrored.
+        assert_eq!(traced(&dispatch).len(), 6);
+    }
+}
@@ -0,0 +1,207 @@
+//! Virtual tracks, for recording spans onto a logical row of their own rather
+//! than onto the OS thread that happens to record them, such as one row per
+//! connection in an async server where a single thread multiplexes many
+//! connections.
+//!
+//! A `Track` stands in for a thread: entries recorded onto it with
+//! `RingBuffer::on_track` carry the track's `thread()` instead of the OS
+//! thread's, so the analyses and exporters that group entries by thread give
+//! every track its own row. Use `thread_name` to label those rows.
+//!
+//! ```
+//! use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+//! use eep::track;
+//! use eep::traits::TraceSink;
+//!
+//! let connections = track::declare("connection-{n}", 16);
+//!
+//! let mut buffer = SimpleTraceBuffer::default();
+//! let id = buffer.on_track(connections.get(3)).trace_start(SimpleTrace::OperationThing, None);
+//! buffer.on_track(connections.get(3)).trace_stop(id, SimpleTrace::OperationThing);
+//!
+//! let entry = buffer.iter().next().unwrap();
+//! let name = track::thread_name(entry.thread().unwrap());
+//! assert_eq!(name, Some("connection-3".to_string()));
+//! ```
+//!
+//! Starts and stops are matched by their thread and ID, so a span's start and
+//! stop must be recorded onto the same track. If several OS threads record
+//! onto the same track, use a `TraceId` type whose IDs are unique across
+//! threads.
+
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+use traits::ThreadId;
+
+/// The number of tracks there can be. Tracks' thread IDs are taken from the
+/// top `MAX_TRACKS` values a `usize` can hold, where no OS thread's ID lies,
+/// whether `usize` is 32 or 64 bits wide.
+pub const MAX_TRACKS: u32 = 1 << 20;
+
+/// A virtual track that spans can be recorded onto, regardless of the OS
+/// thread recording them.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Track(u32);
+
+impl Track {
+    /// Construct the track with the given index. Tracks made this way have no
+    /// name; use `declare` to get named tracks.
+    ///
+    /// Panics if `index` isn't less than `MAX_TRACKS`.
+    pub fn new(index: u32) -> Track {
+        assert!(index < MAX_TRACKS, "track {} of at most {}", index, MAX_TRACKS);
+        Track(index)
+    }
+
+    /// Get this track's index.
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+
+    /// Get the `ThreadId` that entries recorded onto this track carry.
+    ///
+    /// Tracks' thread IDs are taken from the very top of the range, where no
+    /// OS thread's ID lies; see `MAX_TRACKS`.
+    pub fn thread(&self) -> ThreadId {
+        ThreadId(!(self.0 as usize))
+    }
+
+    /// Get the track whose `thread()` is the given thread ID, or `None` if it
+    /// is an OS thread's ID.
+    pub fn from_thread(thread: ThreadId) -> Option<Track> {
+        let index = !thread.0;
+        if index < MAX_TRACKS as usize {
+            Some(Track(index as u32))
+        } else {
+            None
+        }
+    }
+}
+
+/// A group of tracks declared together with `declare`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Tracks {
+    first: u32,
+    len: u32,
+}
+
+impl Tracks {
+    /// Get the `n`th track of this group.
+    ///
+    /// Panics if `n` isn't less than the number of tracks in this group.
+    pub fn get(&self, n: u32) -> Track {
+        assert!(n < self.len, "track {} of a group of {}", n, self.len);
+        Track(self.first + n)
+    }
+
+    /// Get the number of tracks in this group.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Return `true` if this group has no tracks, `false` otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// A group of tracks' name pattern, index of its first track, and length.
+#[cfg(feature = "std")]
+static GROUPS: Mutex<Vec<(&'static str, u32, u32)>> = Mutex::new(Vec::new());
+
+/// Declare a group of `len` new tracks, named by `pattern` with every `{n}` in
+/// it replaced by each track's position in the group.
+///
+/// The tracks' indices never overlap with those of any other declared group,
+/// but may with tracks constructed with `Track::new`.
+///
+/// Panics if there would be more than `MAX_TRACKS` declared tracks.
+#[cfg(feature = "std")]
+pub fn declare(pattern: &'static str, len: u32) -> Tracks {
+    let mut groups = GROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    let first = groups.last().map_or(0, |&(_, first, len)| first + len);
+    match first.checked_add(len) {
+        Some(end) if end <= MAX_TRACKS => {}
+        _ => panic!("more than {} tracks declared", MAX_TRACKS),
+    }
+    groups.push((pattern, first, len));
+    Tracks {
+        first: first,
+        len: len,
+    }
+}
+
+/// Get the name of the given track, if it was declared with `declare`.
+#[cfg(feature = "std")]
+pub fn name(track: Track) -> Option<String> {
+    let groups = GROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    groups.iter()
+        .find(|&&(_, first, len)| first <= track.0 && track.0 - first < len)
+        .map(|&(pattern, first, _)| pattern.replace("{n}", &(track.0 - first).to_string()))
+}
+
+/// Get the name of the track whose `thread()` is the given thread ID, if it
+/// is a track's, and that track was declared with `declare`.
+#[cfg(feature = "std")]
+pub fn thread_name(thread: ThreadId) -> Option<String> {
+    Track::from_thread(thread).and_then(name)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use analysis::{self, Intervals};
+    use simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    use traits::TraceSink;
+
+    #[test]
+    fn tracks_are_not_threads() {
+        let track = Track::new(7);
+        assert_eq!(Track::from_thread(track.thread()), Some(track));
+        assert_eq!(Track::from_thread(ThreadId::get()), None);
+
+        // Only the top of the range is taken by tracks.
+        assert_eq!(Track::from_thread(ThreadId(!0)), Some(Track::new(0)));
+        assert_eq!(Track::from_thread(ThreadId(!(MAX_TRACKS as usize))), None);
+        assert_eq!(Track::from_thread(ThreadId(!0 >> 1)), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_tracks() {
+        declare("overflow-{n}", u32::MAX);
+    }
+
+    #[test]
+    fn named_tracks() {
+        let workers = declare("worker-{n}", 2);
+        let connections = declare("connection-{n}", 3);
+        assert_eq!(name(workers.get(1)), Some("worker-1".to_string()));
+        assert_eq!(name(connections.get(0)), Some("connection-0".to_string()));
+        assert_eq!(thread_name(connections.get(2).thread()), Some("connection-2".to_string()));
+        assert_eq!(thread_name(ThreadId::get()), None);
+    }
+
+    #[test]
+    fn spans_on_tracks() {
+        let tracks = declare("stream-{n}", 2);
+        let mut buffer = SimpleTraceBuffer::default();
+
+        // Interleave a span on each track from this one thread.
+        let a = buffer.on_track(tracks.get(0)).trace_start(SimpleTrace::OperationThing, None);
+        let b = buffer.on_track(tracks.get(1)).trace_start(SimpleTrace::OperationThing, None);
+        buffer.on_track(tracks.get(0)).trace_stop(a, SimpleTrace::OperationThing);
+        buffer.on_track(tracks.get(1)).trace_stop(b, SimpleTrace::OperationThing);
+        buffer.trace_event(SimpleTrace::FooEvent, None);
+
+        let mut threads: Vec<_> = Intervals::new(buffer.iter())
+            .map(|interval| interval.thread.and_then(thread_name))
+            .collect();
+        threads.sort();
+        assert_eq!(threads,
+                   vec![Some("stream-0".to_string()), Some("stream-1".to_string())]);
+
+        // One row per track, plus the OS thread's.
+        assert_eq!(analysis::utilization(buffer.iter()).len(), 3);
+    }
+}
@@ -1,12 +1,42 @@
 //! Trait definitions for EEP.
 
 extern crate serde;
+#[cfg(feature = "std")]
 extern crate thread_id;
 
+#[cfg(feature = "std")]
+use instrument;
+use ring_buffer::{NsSinceEpoch, TraceKind};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// The kinds of trace that `TraceSink::trace_batch` can batch: those that
+/// have no `why`, and whose IDs aren't needed afterwards.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum BatchKind {
+    /// A one off event, as traced by `TraceSink::trace_event`.
+    Event,
+    /// The thread parked, as traced by `TraceSink::trace_park`.
+    Park,
+    /// The thread was unparked, as traced by `TraceSink::trace_unpark`.
+    Unpark,
+}
+
+impl From<BatchKind> for TraceKind {
+    fn from(kind: BatchKind) -> TraceKind {
+        match kind {
+            BatchKind::Event => TraceKind::Event,
+            BatchKind::Park => TraceKind::Park,
+            BatchKind::Unpark => TraceKind::Unpark,
+        }
+    }
+}
+
 /// A unique identifier for a thread.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct ThreadId(pub usize);
 
+#[cfg(feature = "std")]
 impl ThreadId {
     /// Get the current thread's ID.
     pub fn get() -> ThreadId {
@@ -22,6 +52,34 @@ impl serde::Serialize for ThreadId {
     }
 }
 
+impl serde::Deserialize for ThreadId {
+    fn deserialize<D>(deserializer: &mut D) -> Result<ThreadId, D::Error>
+        where D: serde::Deserializer
+    {
+        struct ThreadIdVisitor;
+
+        impl serde::de::Visitor for ThreadIdVisitor {
+            type Value = ThreadId;
+
+            fn visit_u64<E>(&mut self, id: u64) -> Result<ThreadId, E>
+                where E: serde::de::Error
+            {
+                Ok(ThreadId(id as usize))
+            }
+
+            fn visit_newtype_struct<D>(&mut self,
+                                       deserializer: &mut D)
+                                       -> Result<ThreadId, D::Error>
+                where D: serde::Deserializer
+            {
+                serde::Deserialize::deserialize(deserializer).map(ThreadId)
+            }
+        }
+
+        deserializer.deserialize_newtype_struct("ThreadId", ThreadIdVisitor)
+    }
+}
+
 /// A unique identifier for a traced event or start/stop pair.
 ///
 /// The pair of `(id.u32(), id.thread())` must be unique across all IDs of a
@@ -35,6 +93,17 @@ pub trait TraceId: Copy {
     /// Construct a fresh ID.
     fn new_id() -> Self;
 
+    /// Construct a fresh ID for a trace caused by the trace with the ID
+    /// `parent`.
+    ///
+    /// Sinks use this instead of `new_id` for traces given a `why`, so that ID
+    /// types can record where in the tree of causes each trace lies. By
+    /// default, this ignores `parent` and calls `new_id`.
+    fn new_child_id(parent: &Self) -> Self {
+        let _ = parent;
+        Self::new_id()
+    }
+
     /// Turn this `TraceId` into a `u32`.
     fn u32(&self) -> u32;
 
@@ -99,6 +168,23 @@ pub trait Trace: Copy {
 
     /// Get the tag value for this trace instance.
     fn tag(&self) -> u32;
+
+    /// Get the category for the given tag, which exporters use to group and
+    /// filter related traces, or `None` if it has no category.
+    fn category(tag: u32) -> Option<&'static str> {
+        let _ = tag;
+        None
+    }
+
+    /// Get a color hint for the given tag, or `None` to let exporters pick.
+    ///
+    /// Colors are given by name, and should be one of the Chrome trace event
+    /// format's reserved color names, such as `"good"`, `"bad"`, `"terrible"`,
+    /// `"thread_state_running"`, or `"rail_animation"`.
+    fn color(tag: u32) -> Option<&'static str> {
+        let _ = tag;
+        None
+    }
 }
 
 /// TODO FITZGEN
@@ -117,4 +203,212 @@ pub trait TraceSink<T>
     ///
     /// Start the trace by calling `trace_start` to obtain an ID.
     fn trace_stop(&mut self, id: T::Id, trace: T);
+
+    /// Trace that the operation with the given `id` was aborted before it
+    /// completed, for example because it timed out, failed, or its task was
+    /// cancelled.
+    ///
+    /// Like `trace_stop`, this ends the operation, but lets analyses keep
+    /// aborted operations out of duration statistics. Sinks that can't tell
+    /// the difference trace a stop by default.
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.trace_stop(id, trace);
+    }
+
+    /// Trace a whole operation, which started at `start` and ended at `end`,
+    /// at once, for example when its duration was measured externally.
+    ///
+    /// The operation is recorded at `end`, so trace it as soon as it ends to
+    /// keep a buffer's entries in timestamp order.
+    ///
+    /// Sinks that can't record past timestamps trace a start and a stop now
+    /// by default.
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        let _ = (start, end);
+        let id = self.trace_start(trace, why);
+        self.trace_stop(id, trace);
+        id
+    }
+
+    /// Trace that the current thread, typically a thread pool worker, is
+    /// parking to wait for more work.
+    ///
+    /// Time spent parked is subtracted by utilization analyses. Sinks that
+    /// can't tell the difference trace a one-off event by default.
+    fn trace_park(&mut self, trace: T) {
+        self.trace_event(trace, None);
+    }
+
+    /// Trace that the current thread has been unparked after a `trace_park`.
+    ///
+    /// Sinks that can't tell the difference trace a one-off event by default.
+    fn trace_unpark(&mut self, trace: T) {
+        self.trace_event(trace, None);
+    }
+
+    /// Trace a sampled numeric value, such as the heap size or a queue's
+    /// depth, so it can be plotted on the timeline.
+    ///
+    /// Sinks that can't record values trace a one-off event by default.
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        let _ = value;
+        self.trace_event(trace, None);
+    }
+
+    /// Trace a one-off event with a name chosen at runtime, such as a file
+    /// path or URL, in addition to its label.
+    ///
+    /// `Trace::label` is fixed per tag, so a `RingBuffer` interns each
+    /// distinct name once, in a table alongside its entries, and records only
+    /// the name's ID, which `Entry::name` returns. Sinks that can't record
+    /// names trace a plain one-off event by default.
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        let _ = name;
+        self.trace_event(trace, None)
+    }
+
+    /// Trace many one-off traces at once, each of the given kind, amortizing
+    /// the per-trace overhead at very high rates.
+    ///
+    /// Batched traces have no `why`, and their IDs aren't returned. Sinks that
+    /// can't do better trace each one in turn by default.
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        for &(trace, kind) in traces {
+            match kind {
+                BatchKind::Event => {
+                    self.trace_event(trace, None);
+                }
+                BatchKind::Park => self.trace_park(trace),
+                BatchKind::Unpark => self.trace_unpark(trace),
+            }
+        }
+    }
+
+    /// Get the most spans traced into this sink that `Instrumented` futures
+    /// and `time` make current at once on a thread. Spans nested any deeper
+    /// are still traced, but aren't made current, which keeps instrumented
+    /// recursive code from growing the stack of current spans without bound.
+    ///
+    /// Sinks are unbounded by default.
+    fn max_depth(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Trace that a span was nested `depth` deep, at this sink's `max_depth`,
+    /// and so wasn't made current.
+    ///
+    /// A `RingBuffer` counts these in its stats. Sinks trace a counter of the
+    /// depth by default.
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        self.trace_counter(trace, depth as u64);
+    }
+
+    /// Call `f`, tracing the call as an operation, and return its result along
+    /// with how long it took, for example to take a different path when it was
+    /// slow.
+    ///
+    /// The operation is caused by the current span for `T` on this thread, if
+    /// any, and while `f` runs, the operation is the current span, so traces
+    /// made inside `f` can name it as their `why` with `instrument::current`.
+    ///
+    /// ```
+    /// use eep::instrument;
+    /// use eep::simple_trace::{SimpleTrace, SimpleTraceBuffer};
+    /// use eep::traits::TraceSink;
+    ///
+    /// let mut buffer = SimpleTraceBuffer::default();
+    /// let (result, elapsed) = buffer.time(SimpleTrace::OperationThing, || {
+    ///     let why = instrument::current::<SimpleTrace>();
+    ///     assert!(why.is_some());
+    ///     1 + 1
+    /// });
+    /// assert_eq!(result, 2);
+    /// if elapsed.as_millis() > 16 {
+    ///     // Take the slow path.
+    /// }
+    /// ```
+    #[cfg(feature = "std")]
+    fn time<R, F>(&mut self, trace: T, f: F) -> (R, Duration)
+        where Self: Sized,
+              F: FnOnce() -> R,
+              T: 'static
+    {
+        let id = self.trace_start(trace, instrument::current::<T>());
+        let entered = match instrument::enter::<T>(id, self.max_depth()) {
+            Ok(entered) => Some(entered),
+            Err(depth) => {
+                self.trace_depth_exceeded(trace, depth);
+                None
+            }
+        };
+        let start = Instant::now();
+        let result = f();
+        drop(entered);
+        let elapsed = start.elapsed();
+        self.trace_stop(id, trace);
+        (result, elapsed)
+    }
+}
+
+impl<'a, S, T> TraceSink<T> for &'a mut S
+    where S: TraceSink<T>,
+          T: Trace
+{
+    fn trace_event(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        (**self).trace_event(trace, why)
+    }
+
+    fn trace_start(&mut self, trace: T, why: Option<T::Id>) -> T::Id {
+        (**self).trace_start(trace, why)
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        (**self).trace_stop(id, trace)
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        (**self).trace_cancel(id, trace)
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      why: Option<T::Id>)
+                      -> T::Id {
+        (**self).trace_complete(trace, start, end, why)
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        (**self).trace_park(trace)
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        (**self).trace_unpark(trace)
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        (**self).trace_counter(trace, value)
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        (**self).trace_event_named(trace, name)
+    }
+
+    fn trace_batch(&mut self, traces: &[(T, BatchKind)]) {
+        (**self).trace_batch(traces)
+    }
+
+    fn max_depth(&self) -> usize {
+        (**self).max_depth()
+    }
+
+    fn trace_depth_exceeded(&mut self, trace: T, depth: usize) {
+        (**self).trace_depth_exceeded(trace, depth)
+    }
 }
@@ -0,0 +1,203 @@
+//! A `TraceSink` for wasm32 in the browser that writes every trace to the User
+//! Timing API, so that spans and events show up in the DevTools performance
+//! panel.
+//!
+//! `trace_event` records a `performance.mark` named with `Trace::label`.
+//! `trace_start` records a mark, and `trace_stop` records a
+//! `performance.measure` named with the label from that mark until now, which
+//! DevTools draws as a span in its Timings track. Cancelled spans are measured
+//! too, with ` (cancelled)` appended to their name, and counters are recorded
+//! as marks named `<label> = <value>`. Completes are measured between their
+//! own start and end times, named events are recorded as marks named
+//! `<label>: <name>`, and the time a thread spends parked is measured with
+//! ` (parked)` appended to the label passed to `trace_park`.
+//!
+//! ```no_run
+//! use eep::simple_trace::SimpleTrace;
+//! use eep::traits::TraceSink;
+//! use eep::web_performance::WebPerformance;
+//!
+//! let mut sink = WebPerformance::<SimpleTrace>::new();
+//! let id = sink.trace_start(SimpleTrace::OperationThing, None);
+//! sink.trace_stop(id, SimpleTrace::OperationThing);
+//! ```
+//!
+//! The global `performance` object exists in both windows and workers, so the
+//! sink works in either. Start marks are cleared once their span is measured,
+//! so that long-running pages don't accumulate them.
+
+extern crate wasm_bindgen;
+
+use self::wasm_bindgen::prelude::*;
+use ring_buffer::NsSinceEpoch;
+use std::fmt::Write;
+use std::marker::PhantomData;
+use traits::{Trace, TraceId, TraceSink};
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance, js_name = mark, catch)]
+    fn mark(name: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_namespace = performance, js_name = measure, catch)]
+    fn measure(name: &str, start_mark: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_namespace = performance, js_name = clearMarks)]
+    fn clear_marks(name: &str);
+}
+
+// `performance.measure` only takes explicit times in an options object, which
+// is relative to `performance.timeOrigin`.
+#[wasm_bindgen(inline_js = "export function measure_between(name, start, end) {
+    performance.measure(name, {
+        start: start - performance.timeOrigin,
+        end: end - performance.timeOrigin,
+    });
+}")]
+extern "C" {
+    #[wasm_bindgen(catch)]
+    fn measure_between(name: &str, start_ms: f64, end_ms: f64) -> Result<JsValue, JsValue>;
+}
+
+// Convert a timestamp to milliseconds since the Unix epoch, as
+// `performance.timeOrigin` is.
+fn epoch_ms(time: NsSinceEpoch) -> f64 {
+    time.0 as f64 / 1_000_000.0
+}
+
+/// A `TraceSink` that writes to the browser's User Timing API.
+#[derive(Clone, Debug)]
+pub struct WebPerformance<T> {
+    name: String,
+    start: String,
+    phantom: PhantomData<T>,
+}
+
+impl<T> WebPerformance<T>
+    where T: Trace
+{
+    /// Construct a new `WebPerformance` sink.
+    pub fn new() -> WebPerformance<T> {
+        WebPerformance {
+            name: String::new(),
+            start: String::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    // Name the mark that a span with the given ID started at, which must be
+    // unique among open spans, in `start`.
+    fn name_start(&mut self, trace: T, id: T::Id) {
+        self.start.clear();
+        let _ = write!(self.start, "{} #{}", T::label(trace.tag()), id.u32());
+    }
+
+    // Name the mark that a thread parked at in `start`.
+    fn name_park(&mut self, trace: T) {
+        self.start.clear();
+        self.start.push_str(T::label(trace.tag()));
+        self.start.push_str(" (parked)");
+    }
+
+    fn measure(&mut self, id: T::Id, trace: T, suffix: &str) {
+        self.name.clear();
+        self.name.push_str(T::label(trace.tag()));
+        self.name.push_str(suffix);
+        self.name_start(trace, id);
+
+        // Tracing must never fail the traced program, so drop the measure if
+        // its start mark is missing, for example because it was cleared.
+        let _ = measure(&self.name, &self.start);
+        clear_marks(&self.start);
+    }
+}
+
+impl<T> Default for WebPerformance<T>
+    where T: Trace
+{
+    fn default() -> WebPerformance<T> {
+        WebPerformance::new()
+    }
+}
+
+impl<T> TraceSink<T> for WebPerformance<T>
+    where T: Trace
+{
+    fn trace_event(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let _ = mark(T::label(trace.tag()));
+        T::Id::new_id()
+    }
+
+    fn trace_start(&mut self, trace: T, _why: Option<T::Id>) -> T::Id {
+        let id = T::Id::new_id();
+        self.name_start(trace, id);
+        let _ = mark(&self.start);
+        id
+    }
+
+    fn trace_stop(&mut self, id: T::Id, trace: T) {
+        self.measure(id, trace, "");
+    }
+
+    fn trace_cancel(&mut self, id: T::Id, trace: T) {
+        self.measure(id, trace, " (cancelled)");
+    }
+
+    fn trace_complete(&mut self,
+                      trace: T,
+                      start: NsSinceEpoch,
+                      end: NsSinceEpoch,
+                      _why: Option<T::Id>)
+                      -> T::Id {
+        let _ = measure_between(T::label(trace.tag()), epoch_ms(start), epoch_ms(end));
+        T::Id::new_id()
+    }
+
+    fn trace_park(&mut self, trace: T) {
+        self.name_park(trace);
+        let _ = mark(&self.start);
+    }
+
+    fn trace_unpark(&mut self, trace: T) {
+        self.name_park(trace);
+        let _ = measure(&self.start, &self.start);
+        clear_marks(&self.start);
+    }
+
+    fn trace_counter(&mut self, trace: T, value: u64) {
+        self.name.clear();
+        let _ = write!(self.name, "{} = {}", T::label(trace.tag()), value);
+        let _ = mark(&self.name);
+    }
+
+    fn trace_event_named(&mut self, trace: T, name: &str) -> T::Id {
+        self.name.clear();
+        let _ = write!(self.name, "{}: {}", T::label(trace.tag()), name);
+        let _ = mark(&self.name);
+        T::Id::new_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simple_trace::SimpleTrace;
+    use traits::TraceSink;
+
+    #[test]
+    fn web_performance_sanity_check() {
+        let mut sink = WebPerformance::new();
+
+        sink.trace_event(SimpleTrace::FooEvent, None);
+        let thing_id = sink.trace_start(SimpleTrace::OperationThing, None);
+        sink.trace_counter(SimpleTrace::FooEvent, 42);
+        sink.trace_stop(thing_id, SimpleTrace::OperationThing);
+        let cancelled_id = sink.trace_start(SimpleTrace::OperationAnother, None);
+        sink.trace_cancel(cancelled_id, SimpleTrace::OperationAnother);
+        let now = NsSinceEpoch::now();
+        sink.trace_complete(SimpleTrace::OperationThing, NsSinceEpoch(now.0 - 1_000), now, None);
+        sink.trace_park(SimpleTrace::FooEvent);
+        sink.trace_unpark(SimpleTrace::FooEvent);
+        sink.trace_event_named(SimpleTrace::FooEvent, "/index.html");
+    }
+}